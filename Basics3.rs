@@ -3,32 +3,87 @@
 // structs mean you have to name each piece of data
 // structs typically own the data within them, but they can also store references, but this requires the use of lifetimes
 
+#[derive(Clone)]  // lets the stores hand out copies that can be changed and written back
+// Debug is not derived like Rectangle's, users/redact.rs writes it by hand so {:?} masks the email and username
 struct User  // struct and fields, struct definition is a general template for the type
 {
-    active: bool,
+    lifecycle: Lifecycle,  // replaces the old active: bool, an account can be in more states than on or off (see users/lifecycle.rs)
     username: String,
     email: String,
-    sign_in_count: u64
+    sign_in_count: u64,
+    credentials: Credentials,  // password hash and sign-in tracking (see users/auth.rs)
+    roles: Vec<String>  // names of roles in a Policy, which decides what they allow (see users/rbac.rs)
 }
 
 // the entire instance of a struct must be mutable if we want to change something
 // Rust does not let us label specific fields as mutable
 
 // function that returns a struct
-fn build_user(email : String, username : String) -> User  
+// the email and username are checked first, any rule that fails comes back as a UserError (see users/validation.rs)
+// the account starts out waiting for its email to be confirmed, an EmailVerifier token activates it (see users/verification.rs)
+fn build_user(email : String, username : String) -> Result<User, UserError>
 {
-    User{
-        active: true,
+    let email = validate_email(&email)?;  // ? returns the error to the caller straight away
+    let username = validate_username(&username)?;
+    Ok(User{
+        lifecycle: Lifecycle::new(AccountState::PendingVerification, SystemClock.now()),
         username,  // username and email will be taken from the Strings passed in, this is the syntax for that
         email,
-        sign_in_count : 1
-    }  // don't put ; because we are returning this
+        sign_in_count : 1,
+        credentials: Credentials::default(),  // no password yet, set_password adds one
+        roles: Vec::new()
+    })  // don't put ; because we are returning this
+}
+
+// the User returned by build_user is dropped at the end of whatever scope receives it
+// a store keeps users around, either in memory or in a file that survives a restart (see users/store.rs)
+mod users;
+
+use colour::blend::BlendMode;
+use colour::catalogue::Catalogue;
+use colour::contrast::{accessible_palette, TextSize, WcagLevel};
+use colour::css::Notation;
+use colour::difference::DeltaE;
+use colour::gradient::{Gradient, Space};
+use colour::spaces::Hsl;
+use users::analytics::{activity_csv, churn_csv, churn_rate, churned, daily_activity, retention, retention_csv, ActiveUsers, SignInLog, DAY, MONTH, WEEK};
+use users::api::ApiServer;
+use users::auth::{Authenticator, Credentials, LockoutPolicy};
+use users::bulk::{export_csv, export_json, import_csv, import_json, ImportOptions};
+use users::clock::{Clock, SystemClock};
+use users::duplicates::{find_duplicates, merge_users, Linked, DEFAULT_THRESHOLD};
+use users::lifecycle::{AccountState, Lifecycle};
+use users::logging::{Event, Level, Logger};
+use users::mail::{FileMailer, MemoryMailer};
+use users::migrate::{migrate_file, Migrations};
+use users::orgs::{OrgError, OrgRole, Organisations};
+use users::patch::{AuditLog, UserPatch};
+use users::privacy::{erase_subject, export_subject};
+use users::query::Query;
+use users::rbac::{can, Policy};
+use users::redact::{Reveal, Sensitive};
+use users::search::IndexedUserStore;
+use users::session::SessionManager;
+use users::store::{FileUserStore, MemoryUserStore, StoreError, UserStore};
+use users::totp::TotpConfig;
+use users::validation::{validate_email, validate_username, UserError};
+use users::verification::EmailVerifier;
+
+// builds the user and hands ownership straight to the store, a taken username comes back as StoreError::Duplicate
+fn register_user(store: &mut dyn UserStore, email : String, username : String) -> Result<(), StoreError>
+{
+    store.insert(build_user(email, username)?)  // a UserError converts into StoreError::Invalid
 }
 
 // rust also supports structs that look similar to tuples, called tuple structs
 // tuple structs dont have names associated with their fields
+// each channel is a u8, so a colour that cannot exist such as Colour(-5, 900, 0) does not compile
+// Colour::new checks numbers that only turn up at run time, and colour/ converts to HSL, CMYK, Lab and the rest
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Colour(u8, u8, u8);
 
-struct Colour(i32, i32, i32);
+mod colour;
 
 // we can also implement structs without any data
 
@@ -126,23 +181,150 @@ fn value_in_pennies(coin : Coin) -> u32  // whichever one matches with what we p
 
 fn main()
 {
+    // "migrate [file] [version]" rewrites a store file to one schema version, the newest unless one is given, and stops there
+    // the file is copied to <file>.bak first, try: migrate users.jsonl 3
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        let path = args.get(2).map_or("users.jsonl", String::as_str);
+        let migrations = Migrations::standard();
+        let to = match args.get(3).map(|v| v.trim_start_matches('v').parse::<u64>()) {
+            None => migrations.latest(),
+            Some(Ok(version)) => version,
+            Some(Err(_)) => {
+                println!("the version should be a number such as 3");
+                return;
+            }
+        };
+        match migrate_file(path, to, &migrations) {
+            Ok(report) => println!("{} of {} records moved to v{}, the original is in {}", report.changed, report.records, report.to, report.backup.display()),
+            Err(e) => println!("{path} was left as it was: {e}")
+        }
+        return;
+    }
+
+    // "report activity|retention|churn" prints that report from users.jsonl and sign_ins.jsonl as CSV: daily active users
+    // for the last 30 days, the signup cohorts of the last 8 weeks, or active accounts not seen for 30 days
+    // "report" on its own prints today's numbers and the share of last month's users who did not come back
+    if args.get(1).map(String::as_str) == Some("report") {
+        let store = FileUserStore::open("users.jsonl").expect("could not open users.jsonl");
+        let sign_ins = SignInLog::open("sign_ins.jsonl").expect("could not open sign_ins.jsonl");
+        let (events, now) = (sign_ins.events(), SystemClock.now());
+        match args.get(2).map(String::as_str) {
+            Some("activity") => print!("{}", activity_csv(&daily_activity(events, now.saturating_sub(30 * DAY), now))),
+            Some("retention") => print!("{}", retention_csv(&retention(&store, events, now.saturating_sub(8 * WEEK), now, 8))),
+            Some("churn") => print!("{}", churn_csv(&churned(&store, events, now, MONTH))),
+            Some(other) => println!("unknown report '{other}', try activity, retention or churn"),
+            None => {
+                let active = ActiveUsers::at(events, now);
+                println!("{} daily, {} weekly and {} monthly active users, stickiness {:.2}", active.daily, active.weekly, active.monthly, active.stickiness());
+                println!("{:.1}% of the users from the month before did not come back in the last month", churn_rate(events, now, MONTH) * 100.0);
+            }
+        }
+        return;
+    }
+
+    // "import <file> [--dry-run] [--update]" adds the users in a .csv or .json file to users.jsonl and prints each row
+    // it turned down with its line number, --dry-run only reports, --update also changes users that already exist
+    // "dump csv|json" prints every user in users.jsonl in the form import reads
+    if let (Some("import"), Some(path)) = (args.get(1).map(String::as_str), args.get(2)) {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("could not read {path}: {e}");
+                return;
+            }
+        };
+        let options = ImportOptions {
+            dry_run: args[3..].iter().any(|a| a == "--dry-run"),
+            update_existing: args[3..].iter().any(|a| a == "--update"),
+            ..ImportOptions::default()
+        };
+        let mut store = FileUserStore::open("users.jsonl").expect("could not open users.jsonl");
+        let summary = if path.ends_with(".json") || path.ends_with(".jsonl") {
+            import_json(&mut store, &text, &options, &SystemClock)
+        } else {
+            import_csv(&mut store, &text, &options, &SystemClock)
+        };
+        let (created, updated, skipped) = (summary.created, summary.updated, summary.skipped);
+        if options.dry_run {
+            println!("would create {created}, update {updated} and skip {skipped}, nothing was written");
+        } else {
+            println!("created {created}, updated {updated} and skipped {skipped}");
+        }
+        for error in &summary.errors {
+            println!("line {}: {}", error.line, error.message);
+        }
+        if !summary.ignored_columns.is_empty() {
+            println!("ignored columns: {}", summary.ignored_columns.join(", "));
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("dump") {
+        let store = FileUserStore::open("users.jsonl").expect("could not open users.jsonl");
+        match args.get(2).map(String::as_str) {
+            Some("json") => println!("{}", export_json(&store.list())),
+            Some("csv") | None => print!("{}", export_csv(&store.list())),
+            Some(other) => println!("unknown format '{other}', try csv or json")
+        }
+        return;
+    }
+
+    // "search <text>" lists the accounts in users.jsonl whose username or email is close to the text, best first
+    if let (Some("search"), Some(text)) = (args.get(1).map(String::as_str), args.get(2)) {
+        let store = IndexedUserStore::new(FileUserStore::open("users.jsonl").expect("could not open users.jsonl"));
+        for suggestion in store.suggest(text, 10) {
+            println!("{} ({:?} {:?} match on {})", suggestion.username, suggestion.field, suggestion.kind, suggestion.matched);
+        }
+        return;
+    }
+
+    // "export <username>" prints everything kept about a user as JSON, for a subject access request
+    // "erase <username>" replaces their name and email with a stand-in in users.jsonl, sign_ins.jsonl, audit.jsonl
+    // and organisations.json, and says whether any of them still holds them afterwards
+    // sessions are not kept on disk, the server's signing key is new every time it starts, so stop it first
+    if let (Some(command @ ("export" | "erase")), Some(username)) = (args.get(1).map(String::as_str), args.get(2)) {
+        let mut store = FileUserStore::open("users.jsonl").expect("could not open users.jsonl");
+        let mut sign_ins = SignInLog::open("sign_ins.jsonl").expect("could not open sign_ins.jsonl");
+        let mut audit = AuditLog::open("audit.jsonl").expect("could not open audit.jsonl");
+        let mut orgs = Organisations::open("organisations.json").expect("could not open organisations.json");
+        let result = if command == "export" {
+            export_subject(&store, &[&sign_ins, &audit, &orgs], username, &SystemClock).map(|export| export.pretty())
+        } else {
+            erase_subject(&mut store, &mut [&mut sign_ins, &mut audit, &mut orgs], username, &SystemClock).map(|report| report.to_json().pretty())
+        };
+        match result {
+            Ok(json) => println!("{json}"),
+            Err(e) => println!("could not {command} {username}: {e}")
+        }
+        return;
+    }
+
     // create an instance of the struct, assigning all fields
+    // a struct literal skips the checks in build_user, so "Yahoo" is accepted as an email here
+    let clock = SystemClock;
     let mut u1 = User {  // this instance is mutable
-        active : true,
+        lifecycle : Lifecycle::new(AccountState::Active, clock.now()),
         username : String::from("AHus"),
         email : String::from("Yahoo"),
-        sign_in_count : 1
+        sign_in_count : 1,
+        credentials : Credentials::default(),
+        roles : Vec::new()
     };
     
-    u1.active = false;  // change the mutable type
+    u1.deactivate(&clock).unwrap();  // change the mutable type, Active -> Deactivated is an allowed move
+    if let Err(e) = u1.suspend("spam", None, &clock) {
+        println!("{e}");  // an account cannot go from deactivated to suspended
+    }
     
     // we can create instances from other instances with struct update syntax
 
     let mut u2 = User{
-        active : u1.active,  // active and username is taken from user1
+        lifecycle : u1.lifecycle,  // lifecycle and username is taken from user1
         username : u1.username,
         email : String::from("Google"),
-        sign_in_count : 1
+        sign_in_count : 1,
+        credentials : Credentials::default(),
+        roles : Vec::new()
     };   
     
     // we can do this in an easier way using the .. notation
@@ -156,14 +338,203 @@ fn main()
 
     // struct update notation uses = like an assignment, it moves the data
     // so we cannot use u2 as a whole after creating u3 because the String in the username of u2 was moved into u3
-    // If we had given u3 new String values for both email and username, and thus only used the active and sign_in_count values from u2, then u2 would still be valid after creating u3. 
-    // Both active and sign_in_count are types that implement the Copy trait
+    // If we had given u3 new String values for both email and username, only the lifecycle and sign_in_count values would come from u2. 
+    // sign_in_count implements the Copy trait, but Lifecycle holds a Vec of its history, so it is moved just like the Strings are
+
+    // to change a few fields of an existing user without moving anything out of it, apply a UserPatch through a &mut reference
+    // the patch uses struct update syntax itself, ..UserPatch::default() fills the fields we are not changing with None
+    let patch = UserPatch { email : Some(String::from("ahus@outlook.com")), ..UserPatch::default() };
+    let diff = patch.apply(&mut u3).unwrap();
+    for change in diff.changes() {
+        println!("{}: {} -> {}", change.field, change.old, change.new);  // email: Hotmail -> ahus@outlook.com
+    }
+    diff.revert(&mut u3);  // and back to "Hotmail", the diff remembers the old values
+
+    // a store takes ownership of the users given to it, so they outlive the variables that built them
+    let mut memory = IndexedUserStore::new(MemoryUserStore::new());  // keeps a search index of its users, see below
+    let mut verifier = EmailVerifier::generate();
+    let mut mailer = MemoryMailer::new();
+    let token = verifier.register(&mut memory, &mut mailer, String::from("AHus@Yahoo.com"), String::from("AHus"), &clock).unwrap();
+    println!("{}", mailer.last_to("ahus@yahoo.com").unwrap().body);  // the token is mailed as well as returned
+    if let Err(e) = verifier.resend(&memory, &mut mailer, "AHus", &clock) {
+        println!("{e}");  // a verification email was sent recently, try again at ...
+    }
+    verifier.verify(&mut memory, &token, &clock).unwrap();  // PendingVerification -> Active
+    if let Err(e) = register_user(&mut memory, String::from("ahus@google.com"), String::from("AHus")) {
+        println!("{e}");  // username 'AHus' is already taken
+    }
+    if let Err(e) = build_user(String::from("Yahoo"), String::from("AHus2")) {
+        println!("{e}");  // email address must contain '@'
+    }
+
+    // signing in checks the password hash and bumps sign_in_count, five wrong passwords in 15 minutes lock the account
+    let mut ahus = memory.get("AHus").unwrap().clone();
+    ahus.set_password("correct horse").unwrap();
+    memory.update(ahus).unwrap();
+    // each successful sign-in is also kept as a timestamped event, which the analytics count up
+    let mut sign_ins = SignInLog::new();
+    let auth = Authenticator::new(LockoutPolicy::default(), &clock).with_sign_in_log(&mut sign_ins);
+    if let Err(e) = auth.sign_in(&mut memory, "AHus", "battery staple") {
+        println!("{e}");  // username or password is incorrect
+    }
+    auth.sign_in(&mut memory, "AHus", "correct horse").unwrap();
+    println!("AHus has signed in {} times", memory.get("AHus").unwrap().sign_in_count);
+    let active = ActiveUsers::at(sign_ins.events(), clock.now() + 1);
+    println!("{} daily, {} weekly and {} monthly active users", active.daily, active.weekly, active.monthly);
+    println!("{:?}", memory.get("AHus").unwrap());  // User { username: A***, email: ***@yahoo.com, .. }
+
+    // a signed session token stands in for the password on later requests, until it expires or is revoked
+    let mut sessions = SessionManager::new("2024-01", b"keep this key out of the source", SystemClock).unwrap();
+    let (token, _) = sessions.issue(memory.get("AHus").unwrap()).unwrap();
+    println!("signed in as {}", sessions.verify(&token).unwrap().username);
+    sessions.revoke(&token).unwrap();
+    if let Err(e) = sessions.verify(&token) {
+        println!("{e}");  // the session has been signed out
+    }
+
+    // the logger writes JSON lines and refuses any event that would put an email or username in the clear
+    let mut logger = Logger::new(std::io::stdout(), &clock);
+    let ahus = memory.get("AHus").unwrap();
+    logger.log(Event::new(Level::Info, "signed in").field("email", Sensitive::new(ahus.email.as_str(), Reveal::EmailDomain)).field("count", ahus.sign_in_count)).unwrap();
+    if let Err(e) = logger.log(Event::new(Level::Info, "signed in").field("email", ahus.email.as_str())) {
+        println!("{e}");  // field 'email' holds personal data and must be wrapped in Sensitive
+    }
+
+    // the whole store can be written out for a spreadsheet, import_csv reads the same format back in
+    print!("{}", export_csv(&memory.list()));
+
+    // support staff rarely type a name exactly, the search index ranks near misses as well as exact matches
+    // the store has kept it up to date through every insert, update and delete so far
+    for suggestion in memory.suggest("ahsu", 5) {
+        println!("did you mean {} ({:?})", suggestion.username, suggestion.kind);  // AHus, two letters swapped is one edit
+    }
+
+    // u1 and u2 above are the same person twice over, which happens for real too
+    // find_duplicates scores likely pairs, merge_users folds one account into the other and undo_merge splits them again
+    register_user(&mut memory, String::from("ahus@yahoo.com"), String::from("A_Hus")).unwrap();
+    for candidate in find_duplicates(&memory, DEFAULT_THRESHOLD) {
+        println!("{} and {} look like one person ({:.2})", candidate.first, candidate.second, candidate.score);
+    }
+    // the sign-in log and sessions follow, A_Hus's sign-ins count for AHus and any session of A_Hus ends
+    let linked = Linked { sign_ins: Some(&mut sign_ins), sessions: Some(&mut sessions), ..Linked::default() };
+    let merge = merge_users(&mut memory, linked, "AHus", "A_Hus", "support", &clock).unwrap();
+    println!("{} now has the sign-ins of {}", merge.survivor, merge.absorbed);
+
+    // a query picks users out by their fields, mistakes are caught before any user is looked at
+    let query = Query::parse("active = true AND sign_in_count > 0 AND email ENDS WITH \"@yahoo.com\"").unwrap();
+    for user in query.filter(memory.list(), &clock) {
+        println!("{} matches {query}", user.username);
+    }
+    let text = "sign_in_count > \"5\"";
+    if let Err(e) = Query::parse(text) {
+        println!("{}", e.pointer(text));  // sign_in_count is compared with a number, not text
+    }
+
+    // organisations group users, members can see each other and people outside cannot see in
+    // each member picks a name for the organisation, which only has to be unique inside it
+    // an invitation can also be taken up with a new account that belongs to the organisation, "basics:alex" here,
+    // so another organisation can have an alex of its own
+    // they are kept in organisations.json, the first run sets basics up and later runs find it there
+    let mut orgs = Organisations::open("organisations.json").expect("could not open organisations.json");
+    register_user(&mut memory, String::from("sam@basics.cafe"), String::from("Sam")).unwrap();
+    if let Err(OrgError::NotFound(_)) = orgs.get("basics", "AHus") {
+        orgs.create("basics", "Basics Cafe", memory.get("AHus").unwrap(), &clock).unwrap();
+        let code = orgs.invite("basics", "AHus", "sam@basics.cafe", OrgRole::Admin, &clock).unwrap();
+        orgs.accept(&code, memory.get("Sam").unwrap(), Some("Samantha"), &clock).unwrap();
+        let code = orgs.invite("basics", "Sam", "alex@basics.cafe", OrgRole::Member, &clock).unwrap();
+        let alex = orgs.join(&mut memory, &code, "alex", &clock).unwrap();
+        println!("{alex} signs in with the name alex and the organisation basics");
+        orgs.save().expect("could not write organisations.json");
+    }
+    for member in orgs.members("basics", "Sam").unwrap() {
+        println!("{} is {} of basics", member.name, member.role);
+    }
+
+    // with two-factor turned on, the password alone is not enough
+    let mut ahus = memory.get("AHus").unwrap().clone();
+    let enrolment = ahus.enrol_totp("Basics", TotpConfig::default()).expect("the default settings are valid");
+    println!("scan {} and keep these codes safe: {:?}", enrolment.uri, enrolment.recovery_codes);
+
+    // roles come from a policy file, a role can inherit everything another role allows
+    let policy = Policy::parse("role server\n allow take_order orders\nrole manager inherits server\n allow refund orders").unwrap();
+    ahus.grant_role("server").unwrap();
+    println!("can AHus take orders? {}", can(&policy, &ahus, "take_order", "orders"));  // true
+    println!("can AHus refund orders? {}", can(&policy, &ahus, "refund", "orders"));  // false, that needs manager
+
+    // the file store appends every change to a log, so opening the same file again brings the users back
+    let mut file_store = FileUserStore::open("users.jsonl").expect("could not open users.jsonl");
+    for user in memory.list() {
+        println!("{} <{}>", user.username, user.email);
+    }
+    match register_user(&mut file_store, String::from("ahus@yahoo.com"), String::from("AHus")) {
+        Ok(()) => println!("AHus saved to {}", file_store.path().display()),
+        Err(e) => println!("{e}")  // on the second run AHus is already in the file
+    }
+
+    // "serve" hands the file store to the HTTP API instead, POST /sessions for a token and send it as "Authorization: Bearer <token>"
+    // verification emails for new accounts land in the outbox directory rather than going anywhere
+    // the basics organisation above decides who sees whom, AHus and Sam see each other and everyone else only themselves
+    if std::env::args().nth(1).as_deref() == Some("serve") {
+        let outbox = FileMailer::new("outbox", "accounts@localhost").expect("could not create the outbox directory");
+        let sign_ins = SignInLog::open("sign_ins.jsonl").expect("could not open sign_ins.jsonl");
+        let audit = AuditLog::open("audit.jsonl").expect("could not open audit.jsonl");
+        let server = ApiServer::bind("127.0.0.1:8080", file_store, SystemClock, outbox)
+            .expect("could not bind 127.0.0.1:8080")
+            .with_sign_in_log(sign_ins)
+            .with_audit_log(audit)
+            .with_organisations(orgs);
+        println!("serving users on http://{}", server.local_addr().unwrap());
+        server.run().unwrap();
+        return;
+    }
 
     let black = Colour(0,0,0);   
     // tuple structs can also be destructed into their constituent fields, like regular tuples
 
     let colour1 = black.0;
 
+    // designers hand over colours in HSL and the print vendor wants CMYK, both convert through the same Colour
+    match Colour::new(255, 128, 300) {
+        Ok(colour) => println!("{colour:?}"),
+        Err(e) => println!("not a colour: {e}")
+    }
+    let brand = Colour::from_hsl(Hsl::new(210.0, 0.5, 0.4).expect("a valid HSL colour"));
+    let ink = brand.to_cmyk();
+    let lab = brand.to_lab();
+    // colours in config files are written the CSS way, a typo is pointed out where it is
+    for text in ["#336699", "hsl(210, 50%, 40%)", "rebeccapurple", "rgb(51, 102, 1530)"] {
+        match text.parse::<Colour>() {
+            Ok(colour) => println!("{text} is {colour}, {}", colour.to_css(Notation::Rgb)),
+            Err(e) => println!("{}", e.pointer(text))
+        }
+    }
+    // chart palettes are mixed in OKLab, halfway from blue to yellow in plain sRGB comes out a muddy grey
+    let blue_to_yellow = [Colour(0, 0, 255), Colour(255, 255, 0)];
+    for space in [Space::Srgb, Space::Oklab] {
+        let gradient = Gradient::even(space, &blue_to_yellow).expect("two stops");
+        let steps: Vec<String> = gradient.sample(5).iter().map(|c| c.to_string()).collect();
+        println!("blue to yellow in {space:?}: {}", steps.join(" "));
+    }
+    let highlight = brand.with_alpha(0.5).expect("a valid alpha");
+    println!("half-transparent brand blue on white shows as {}, multiplied onto orange it is {}", highlight.flatten(Colour(255, 255, 255)), brand.blend(Colour(255, 165, 0), BlendMode::Multiply));
+    // text has to stand out from its background, WCAG puts numbers on how much
+    println!("brand blue on white: {}", brand.contrast(Colour(255, 255, 255)));
+    let chart = accessible_palette(6, Colour(255, 255, 255), WcagLevel::Aa.required(TextSize::Large)).expect("3:1 is reachable on white");
+    let chart: Vec<String> = chart.iter().map(|c| format!("{c} {:.1}:1", c.contrast_ratio(Colour(255, 255, 255)))).collect();
+    println!("chart colours for a white page: {}", chart.join(", "));
+    // a scanned swatch is matched to the closest catalogue colour by how different they look, not by RGB distance
+    let paints = Catalogue::parse("# paints\nDuck Egg Blue = #a8c3bc\nPillar Box Red = rgb(200, 16, 46)\nSlate = hsl(210, 13%, 40%)\n").expect("a valid catalogue");
+    let swatch = Colour(92, 100, 112);
+    if let Some(found) = paints.nearest(swatch, DeltaE::Ciede2000) {
+        println!("swatch {swatch} is closest to {} (delta E {:.1}), in CSS terms it is {}", found.name, found.delta_e, swatch.nearest_name());
+    }
+    println!("brand blue is {brand:?}, CMYK {:.0}% {:.0}% {:.0}% {:.0}%, Lab {:.1} {:.1} {:.1}", ink.c * 100.0, ink.m * 100.0, ink.y * 100.0, ink.k * 100.0, lab.l, lab.a, lab.b);
+
+    // every user gets a default avatar, its foreground is a Colour picked from a hash of the username
+    let avatar = ahus.identicon();
+    println!("AHus's avatar is drawn in {} ({:?})", avatar.foreground, avatar.foreground);
+    std::fs::write("AHus.svg", avatar.to_svg(120)).expect("could not write AHus.svg");
+
     let rect1 = Rectangle{
         width : 30,
         length : 50
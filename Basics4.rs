@@ -53,6 +53,29 @@ pub mod Garden;  //  this line tells the compiler to include the code it finds i
 
 use crate::Garden::Vegetables::Asparagus;  // now we no longer have to include the whole path to use Asparagus
 
+// the #[path] attribute points mod at a file the compiler would not find on its own
+// here we reuse the roles and policy checks written for the users module in Basics3.rs
+#[path = "users/rbac.rs"]
+mod rbac;
+
+use rbac::{Policy, Principal};
+
+// a member of staff, the roles they hold decide which parts of front_of_house they can use
+struct Staff {
+    name: String,
+    roles: Vec<String>
+}
+
+impl Principal for Staff {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn roles(&self) -> &[String] {
+        &self.roles
+    }
+}
+
 // Modules allow us to organise code within a crate for readability and reuse
 // Modules also allow us to control the privacy of items because code within a module is private by default
 
@@ -66,8 +89,13 @@ mod front_of_house{  // define a module and then the modules within it after the
     }
 
     pub mod serving {  // serving is public
-        pub fn take_order(){
+        use crate::rbac::{AccessDenied, Policy, Principal};
+
+        // only someone whose roles allow take_order on orders gets past the first line, in restaurant.policy that is the server role
+        pub fn take_order(policy: &Policy, who: &dyn Principal) -> Result<(), AccessDenied> {
+            policy.require(who, "take_order", "orders")?;
             super::external_func();  // use super keyword to access parent module scope, just like python
+            Ok(())
         }
     }
 
@@ -126,5 +154,29 @@ fn main() {
 
     let a1 = Asparagus{};
 
-    crate::front_of_house::serving::take_order();  // call the public function
+    // the policy is read once at startup, a typo in the file stops the program here rather than at the first order
+    let policy = Policy::load("restaurant.policy").unwrap_or_else(|e| panic!("{e}"));
+
+    let sam = Staff { name: String::from("Sam"), roles: vec![String::from("server")] };
+    let alex = Staff { name: String::from("Alex"), roles: vec![String::from("host")] };
+
+    crate::front_of_house::serving::take_order(&policy, &sam).unwrap();  // call the public function
+    if let Err(e) = crate::front_of_house::serving::take_order(&policy, &alex) {
+        println!("{e}");  // Alex may not take_order orders
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_server_can_take_an_order() {
+        let policy = Policy::load("restaurant.policy").unwrap();
+        let sam = Staff { name: String::from("Sam"), roles: vec![String::from("server")] };
+        let alex = Staff { name: String::from("Alex"), roles: vec![String::from("host")] };
+        assert!(front_of_house::serving::take_order(&policy, &sam).is_ok());
+        let err = front_of_house::serving::take_order(&policy, &alex).unwrap_err();
+        assert_eq!(err.to_string(), "Alex may not take_order orders");
+    }
 }
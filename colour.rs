@@ -0,0 +1,12 @@
+// the colour module holds everything built on top of the Colour tuple struct in the crate root
+// laid out like users.rs, each submodule lives in its own file under colour/
+
+pub mod blend;
+pub mod catalogue;
+pub mod contrast;
+pub mod css;
+pub mod difference;
+pub mod gradient;
+pub mod named;
+pub mod rgb;
+pub mod spaces;
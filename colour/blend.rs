@@ -0,0 +1,203 @@
+// putting one colour on top of another
+//
+// Porter-Duff operators decide how much of the source (the colour being drawn) and of the backdrop (what is
+// already there) survive, by coverage alone:
+//
+//     result = source * Fs + backdrop * Fb        with premultiplied colours, and the alphas the same way
+//
+//     Clear            Fs = 0          Fb = 0          SourceAtop       Fs = ab         Fb = 1 - as
+//     Source           Fs = 1          Fb = 0          DestinationAtop  Fs = 1 - ab     Fb = as
+//     Destination      Fs = 0          Fb = 1          SourceIn         Fs = ab         Fb = 0
+//     SourceOver       Fs = 1          Fb = 1 - as     DestinationIn    Fs = 0          Fb = as
+//     DestinationOver  Fs = 1 - ab     Fb = 1          SourceOut        Fs = 1 - ab     Fb = 0
+//     Xor              Fs = 1 - ab     Fb = 1 - as     DestinationOut   Fs = 0          Fb = 1 - as
+//     Plus             Fs = 1          Fb = 1, clamped to 1
+//
+// blend modes mix the two colours where they overlap and are then drawn SourceOver, as in the W3C
+// compositing spec, except that everything here is done in linear light: a browser blends the gamma-encoded
+// values, which darkens midtones, so results will not match CSS mix-blend-mode exactly
+
+use crate::colour::rgb::{ColourError, Rgba, Srgb};
+use crate::colour::spaces::LinearRgb;
+use crate::Colour;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Clear,
+    Source,
+    Destination,
+    SourceOver,
+    DestinationOver,
+    SourceIn,
+    DestinationIn,
+    SourceOut,
+    DestinationOut,
+    SourceAtop,
+    DestinationAtop,
+    Xor,
+    Plus
+}
+
+impl Operator {
+    // the fractions of source and backdrop kept, given their alphas
+    fn fractions(self, source: f64, backdrop: f64) -> (f64, f64) {
+        match self {
+            Operator::Clear => (0.0, 0.0),
+            Operator::Source => (1.0, 0.0),
+            Operator::Destination => (0.0, 1.0),
+            Operator::SourceOver => (1.0, 1.0 - source),
+            Operator::DestinationOver => (1.0 - backdrop, 1.0),
+            Operator::SourceIn => (backdrop, 0.0),
+            Operator::DestinationIn => (0.0, source),
+            Operator::SourceOut => (1.0 - backdrop, 0.0),
+            Operator::DestinationOut => (0.0, 1.0 - source),
+            Operator::SourceAtop => (backdrop, 1.0 - source),
+            Operator::DestinationAtop => (1.0 - backdrop, source),
+            Operator::Xor => (1.0 - backdrop, 1.0 - source),
+            Operator::Plus => (1.0, 1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,  // the source as it is
+    Multiply,  // darkens, white leaves the backdrop alone
+    Screen,  // lightens, black leaves the backdrop alone
+    Overlay  // multiplies the backdrop's darks and screens its lights, keeping its contrast
+}
+
+impl BlendMode {
+    // one channel of the backdrop and the source, both in linear light
+    fn channel(self, backdrop: f64, source: f64) -> f64 {
+        let multiply = |a: f64, b: f64| a * b;
+        let screen = |a: f64, b: f64| a + b - a * b;
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => multiply(backdrop, source),
+            BlendMode::Screen => screen(backdrop, source),
+            BlendMode::Overlay if backdrop <= 0.5 => multiply(source, 2.0 * backdrop),
+            BlendMode::Overlay => screen(source, 2.0 * backdrop - 1.0)
+        }
+    }
+}
+
+// a colour in linear light with its alpha, not premultiplied
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Linear {
+    rgb: [f64; 3],
+    alpha: f64
+}
+
+impl Linear {
+    fn from_rgba(rgba: Rgba) -> Linear {
+        let LinearRgb { r, g, b } = rgba.colour.to_linear();
+        Linear { rgb: [r, g, b], alpha: rgba.alpha }
+    }
+
+    fn to_rgba(self) -> Rgba {
+        let [r, g, b] = self.rgb;
+        Rgba { colour: Colour::from_srgb(Srgb::from(LinearRgb { r, g, b })), alpha: self.alpha.clamp(0.0, 1.0) }
+    }
+}
+
+impl Rgba {
+    // this colour drawn onto backdrop with a Porter-Duff operator
+    pub fn composite(self, backdrop: Rgba, operator: Operator) -> Rgba {
+        let (source, backdrop) = (Linear::from_rgba(self), Linear::from_rgba(backdrop));
+        let (fs, fb) = operator.fractions(source.alpha, backdrop.alpha);
+        let alpha = (source.alpha * fs + backdrop.alpha * fb).min(1.0);
+        if alpha == 0.0 {
+            return Rgba { colour: Colour(0, 0, 0), alpha: 0.0 };
+        }
+        let channel = |i: usize| ((source.rgb[i] * source.alpha * fs + backdrop.rgb[i] * backdrop.alpha * fb) / alpha).min(1.0);
+        Linear { rgb: [channel(0), channel(1), channel(2)], alpha }.to_rgba()
+    }
+
+    // this colour drawn onto backdrop with a blend mode, where the backdrop is translucent the source shows through unblended
+    pub fn blend(self, backdrop: Rgba, mode: BlendMode) -> Rgba {
+        let (source, under) = (Linear::from_rgba(self), Linear::from_rgba(backdrop));
+        let channel = |i: usize| (1.0 - under.alpha) * source.rgb[i] + under.alpha * mode.channel(under.rgb[i], source.rgb[i]);
+        let blended = Linear { rgb: [channel(0), channel(1), channel(2)], alpha: source.alpha };
+        blended.to_rgba().composite(backdrop, Operator::SourceOver)
+    }
+
+    // what the colour looks like once drawn on an opaque background, such as a page
+    pub fn flatten(self, background: Colour) -> Colour {
+        self.composite(Rgba::opaque(background), Operator::SourceOver).colour
+    }
+}
+
+impl Colour {
+    pub fn with_alpha(self, alpha: f64) -> Result<Rgba, ColourError> {
+        Rgba::new(self, alpha)
+    }
+
+    // opaque colours blend to an opaque colour
+    pub fn blend(self, backdrop: Colour, mode: BlendMode) -> Colour {
+        Rgba::opaque(self).blend(Rgba::opaque(backdrop), mode).colour
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Colour = Colour(255, 0, 0);
+    const BLUE: Colour = Colour(0, 0, 255);
+    const WHITE: Colour = Colour(255, 255, 255);
+    const BLACK: Colour = Colour(0, 0, 0);
+
+    fn rgba(colour: Colour, alpha: f64) -> Rgba {
+        Rgba { colour, alpha }
+    }
+
+    fn close(actual: Rgba, expected: Rgba) {
+        assert_eq!(actual.colour, expected.colour, "{actual:?}");
+        assert!((actual.alpha - expected.alpha).abs() < 1e-9, "{actual:?}");
+    }
+
+    #[test]
+    fn operators_keep_the_covered_fractions() {
+        // half of red's light over blue: 0.5 linear in red and blue, which encodes to 188
+        close(rgba(RED, 0.5).composite(Rgba::opaque(BLUE), Operator::SourceOver), rgba(Colour(188, 0, 188), 1.0));
+        assert_eq!(rgba(RED, 0.5).flatten(BLUE), Colour(188, 0, 188));
+
+        // red at 0.6 and blue at 0.5 each keep the part of themselves the other does not cover:
+        // 0.6 * 0.5 of red and 0.5 * 0.4 of blue, 0.5 alpha in all, so 0.6 and 0.4 linear once unpremultiplied
+        let (red, blue) = (rgba(RED, 0.6), rgba(BLUE, 0.5));
+        close(red.composite(blue, Operator::Xor), rgba(Colour(203, 0, 170), 0.5));
+        close(red.composite(blue, Operator::DestinationOut), rgba(BLUE, 0.2));  // blue thinned out where red is
+        close(red.composite(blue, Operator::DestinationIn), rgba(BLUE, 0.3));
+        close(red.composite(blue, Operator::SourceIn), rgba(RED, 0.3));
+        close(red.composite(blue, Operator::SourceOut), rgba(RED, 0.3));
+        close(red.composite(blue, Operator::SourceAtop), rgba(Colour(203, 0, 170), 0.5));
+        close(red.composite(blue, Operator::SourceOver), rgba(Colour(225, 0, 137), 0.8));  // 0.6 and 0.2 of 0.8, 0.75 and 0.25
+        close(red.composite(blue, Operator::Source), red);
+        close(red.composite(blue, Operator::Destination), blue);
+        close(red.composite(blue, Operator::Clear), rgba(BLACK, 0.0));
+        close(rgba(RED, 0.7).composite(rgba(RED, 0.7), Operator::Plus), rgba(RED, 1.0));  // clamped
+
+        // nothing over nothing stays transparent instead of dividing by zero
+        close(rgba(WHITE, 0.0).composite(rgba(WHITE, 0.0), Operator::SourceOver), rgba(BLACK, 0.0));
+    }
+
+    #[test]
+    fn white_and_black_are_the_identities_of_each_mode() {
+        for backdrop in [Colour(200, 100, 30), Colour(12, 240, 99), WHITE, BLACK] {
+            assert_eq!(WHITE.blend(backdrop, BlendMode::Multiply), backdrop);
+            assert_eq!(BLACK.blend(backdrop, BlendMode::Multiply), BLACK);
+            assert_eq!(BLACK.blend(backdrop, BlendMode::Screen), backdrop);
+            assert_eq!(WHITE.blend(backdrop, BlendMode::Screen), WHITE);
+            assert_eq!(Colour(200, 100, 30).blend(backdrop, BlendMode::Normal), Colour(200, 100, 30));
+        }
+        // overlay keeps the backdrop's extremes, whatever is drawn over them
+        for source in [Colour(200, 100, 30), WHITE, BLACK] {
+            assert_eq!(source.blend(WHITE, BlendMode::Overlay), WHITE);
+            assert_eq!(source.blend(BLACK, BlendMode::Overlay), BLACK);
+        }
+        // over a translucent backdrop the source shows through where the backdrop does not cover
+        let seen = rgba(WHITE, 1.0).blend(rgba(BLACK, 0.0), BlendMode::Multiply);
+        close(seen, rgba(WHITE, 1.0));
+    }
+}
@@ -0,0 +1,346 @@
+// named colours to match against, the CSS names or a catalogue of our own, and the nearest one to any colour
+//
+// a catalogue file has one colour a line, its name, '=' and the colour in any notation colour/css.rs reads:
+//
+//     # lines starting with # are comments
+//     Duck Egg Blue = #a8c3bc
+//     Pillar Box Red = rgb(200, 16, 46)
+//
+// the colours are kept in a k-d tree over CIELAB, so finding the nearest takes a few dozen comparisons
+// rather than one per colour, and the distance it uses is a perceptual one rather than one between RGB bytes
+//
+// the tree measures straight-line Lab distance (CIE76), for CIE94 and CIEDE2000 it takes the closest few by
+// that, and then every colour near enough in Lab that it could still beat them by the metric asked for (see
+// reach), so the answer is always the one checking every colour would give
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::colour::difference::DeltaE;
+use crate::colour::named::CSS_COLOURS;
+use crate::colour::spaces::Lab;
+use crate::Colour;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogueError {
+    pub line: usize,  // 0 when the problem is not on any one line
+    pub message: String
+}
+
+impl fmt::Display for CatalogueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "catalogue error: {}", self.message)
+        } else {
+            write!(f, "catalogue error on line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for CatalogueError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match<'a> {
+    pub name: &'a str,
+    pub colour: Colour,
+    pub delta_e: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct Catalogue {
+    entries: Vec<(String, Colour, Lab)>,
+    tree: KdTree,
+    max_chroma: f64  // of any colour in the catalogue, in Lab
+}
+
+impl Catalogue {
+    // a colour listed under two names is found under the first
+    pub fn new(entries: Vec<(String, Colour)>) -> Catalogue {
+        let entries: Vec<(String, Colour, Lab)> = entries.into_iter().map(|(name, colour)| (name, colour, colour.to_lab())).collect();
+        let tree = KdTree::build(entries.iter().map(|(_, _, lab)| [lab.l, lab.a, lab.b]).collect());
+        let max_chroma = entries.iter().map(|(_, _, lab)| lab.a.hypot(lab.b)).fold(0.0, f64::max);
+        Catalogue { entries, tree, max_chroma }
+    }
+
+    pub fn css() -> Catalogue {
+        Catalogue::new(CSS_COLOURS.iter().map(|(name, colour)| (name.to_string(), *colour)).collect())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Catalogue, CatalogueError> {
+        let text = fs::read_to_string(path.as_ref())
+            .map_err(|e| CatalogueError { line: 0, message: format!("cannot read {}: {e}", path.as_ref().display()) })?;
+        Catalogue::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Catalogue, CatalogueError> {
+        let mut entries = Vec::new();
+        let mut names = HashSet::new();
+        for (index, raw) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let error = |message: String| CatalogueError { line: line_no, message };
+            let line = raw.trim();
+            // only whole lines are comments, a '#' further on starts a hex colour
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = raw.split_once('=').ok_or_else(|| error("expected '<name> = <colour>'".to_string()))?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(error("expected a name before '='".to_string()));
+            }
+            if !names.insert(name.to_string()) {
+                return Err(error(format!("'{name}' is listed twice")));
+            }
+            // columns count from 1, as editors show them
+            let colour = value.parse::<Colour>().map_err(|e| error(format!("{} (column {})", e.message, raw[..raw.len() - value.len()].chars().count() + value[..e.position].chars().count() + 1)))?;
+            entries.push((name.to_string(), colour));
+        }
+        if entries.is_empty() {
+            return Err(CatalogueError { line: 0, message: "the catalogue has no colours".to_string() });
+        }
+        Ok(Catalogue::new(entries))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Colour> {
+        self.entries.iter().find(|(n, _, _)| n == name).map(|(_, colour, _)| *colour)
+    }
+
+    // None only for an empty catalogue
+    pub fn nearest(&self, colour: Colour, metric: DeltaE) -> Option<Match<'_>> {
+        self.nearest_few(colour, 1, metric).into_iter().next()
+    }
+
+    // the count closest colours by metric, closest first
+    pub fn nearest_few(&self, colour: Colour, count: usize, metric: DeltaE) -> Vec<Match<'_>> {
+        let lab = colour.to_lab();
+        let target = [lab.l, lab.a, lab.b];
+        let score = |(_, index): (f64, usize)| (metric.between(self.entries[index].2, lab), index);
+        let mut found: Vec<(f64, usize)> = self.tree.nearest(target, count).into_iter().map(score).collect();
+        if metric != DeltaE::Cie76 && found.len() == count && count > 0 {
+            // whatever beats the worst of these by metric is within reach of it in Lab
+            let worst = found.iter().map(|(delta_e, _)| *delta_e).fold(0.0, f64::max);
+            let radius = worst * self.reach(lab, metric);
+            found = self.tree.within(target, radius * radius).into_iter().map(score).collect();
+        }
+        found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        found.truncate(count);
+        found.into_iter().map(|(delta_e, index)| Match { name: &self.entries[index].0, colour: self.entries[index].1, delta_e }).collect()
+    }
+
+    // how many times the metric's delta E the CIE76 distance can be, between lab and any colour in the catalogue
+    //     CIE94 divides chroma and hue differences by at most SC = 1 + 0.045 C of the reference, lightness by 1
+    //     CIEDE2000 divides them by at most its SC, whose C' is at most 1.5 C, and the rotation term takes away
+    //     at most 1 - sin 60° of what is left; its SL never goes past 1.75, which is always less than the rest
+    fn reach(&self, lab: Lab, metric: DeltaE) -> f64 {
+        match metric {
+            DeltaE::Cie76 => 1.0,
+            DeltaE::Cie94 => 1.0 + 0.045 * self.max_chroma,
+            DeltaE::Ciede2000 => {
+                let sc = 1.0 + 0.045 * 1.5 * self.max_chroma.max(lab.a.hypot(lab.b));
+                sc / (1.0 - 60f64.to_radians().sin()).sqrt()
+            }
+        }
+    }
+}
+
+impl Colour {
+    // the closest CSS colour name, by CIEDE2000
+    pub fn nearest_name(self) -> &'static str {
+        static CSS: OnceLock<Catalogue> = OnceLock::new();
+        CSS.get_or_init(Catalogue::css).nearest(self, DeltaE::Ciede2000).expect("the CSS catalogue is not empty").name
+    }
+}
+
+// a k-d tree over three dimensions, each level splits the points at the median of one axis in turn
+#[derive(Debug, Clone)]
+struct KdTree {
+    nodes: Vec<Node>,
+    root: Option<usize>
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    point: [f64; 3],
+    index: usize,  // into the catalogue's entries
+    axis: usize,
+    left: Option<usize>,  // points below the split on axis
+    right: Option<usize>
+}
+
+impl KdTree {
+    fn build(points: Vec<[f64; 3]>) -> KdTree {
+        let mut points: Vec<(usize, [f64; 3])> = points.into_iter().enumerate().collect();
+        let mut nodes = Vec::with_capacity(points.len());
+        let root = KdTree::split(&mut points, 0, &mut nodes);
+        KdTree { nodes, root }
+    }
+
+    fn split(points: &mut [(usize, [f64; 3])], depth: usize, nodes: &mut Vec<Node>) -> Option<usize> {
+        if points.is_empty() {
+            return None;
+        }
+        let axis = depth % 3;
+        points.sort_by(|a, b| a.1[axis].total_cmp(&b.1[axis]).then(a.0.cmp(&b.0)));
+        let median = points.len() / 2;
+        let (below, rest) = points.split_at_mut(median);
+        let ((index, point), above) = rest.split_first_mut().expect("median is inside the slice");
+        let (index, point) = (*index, *point);
+        let left = KdTree::split(below, depth + 1, nodes);
+        let right = KdTree::split(above, depth + 1, nodes);
+        nodes.push(Node { point, index, axis, left, right });
+        Some(nodes.len() - 1)
+    }
+
+    // the count nearest points as (squared distance, index), nearest first, earlier entries first on a tie
+    fn nearest(&self, target: [f64; 3], count: usize) -> Vec<(f64, usize)> {
+        let mut best = Vec::with_capacity(count + 1);
+        if count > 0 {
+            self.search(self.root, target, count, &mut best);
+        }
+        best
+    }
+
+    // every point within the squared radius, in no particular order
+    fn within(&self, target: [f64; 3], radius_squared: f64) -> Vec<(f64, usize)> {
+        let mut found = Vec::new();
+        self.collect(self.root, target, radius_squared, &mut found);
+        found
+    }
+
+    fn collect(&self, node: Option<usize>, target: [f64; 3], radius_squared: f64, found: &mut Vec<(f64, usize)>) {
+        let Some(node) = node.map(|i| &self.nodes[i]) else {
+            return;
+        };
+        let distance: f64 = (0..3).map(|axis| (node.point[axis] - target[axis]).powi(2)).sum();
+        if distance <= radius_squared {
+            found.push((distance, node.index));
+        }
+        let offset = target[node.axis] - node.point[node.axis];
+        let (near, far) = if offset < 0.0 { (node.left, node.right) } else { (node.right, node.left) };
+        self.collect(near, target, radius_squared, found);
+        if offset * offset <= radius_squared {
+            self.collect(far, target, radius_squared, found);
+        }
+    }
+
+    fn search(&self, node: Option<usize>, target: [f64; 3], count: usize, best: &mut Vec<(f64, usize)>) {
+        let Some(node) = node.map(|i| &self.nodes[i]) else {
+            return;
+        };
+        let distance: f64 = (0..3).map(|axis| (node.point[axis] - target[axis]).powi(2)).sum();
+        let at = best.partition_point(|&(d, i)| d < distance || (d == distance && i < node.index));
+        if at < count {
+            best.insert(at, (distance, node.index));
+            best.truncate(count);
+        }
+        let offset = target[node.axis] - node.point[node.axis];
+        let (near, far) = if offset < 0.0 { (node.left, node.right) } else { (node.right, node.left) };
+        self.search(near, target, count, best);
+        // the other side can only hold something closer if the splitting plane is closer than the worst kept so far
+        if best.len() < count || offset * offset <= best[best.len() - 1].0 {
+            self.search(far, target, count, best);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fixed stream of pseudo-random numbers, so a failure can be replayed
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        fn lab(&mut self) -> [f64; 3] {
+            [self.next() * 100.0, self.next() * 256.0 - 128.0, self.next() * 256.0 - 128.0]
+        }
+
+        fn colour(&mut self) -> Colour {
+            Colour((self.next() * 256.0) as u8, (self.next() * 256.0) as u8, (self.next() * 256.0) as u8)
+        }
+    }
+
+    fn squared(a: [f64; 3], b: [f64; 3]) -> f64 {
+        (0..3).map(|axis| (a[axis] - b[axis]).powi(2)).sum()
+    }
+
+    // every point by distance, ties to the earlier index, which is the order the tree promises
+    fn brute_force(points: &[[f64; 3]], target: [f64; 3]) -> Vec<(f64, usize)> {
+        let mut all: Vec<(f64, usize)> = points.iter().enumerate().map(|(index, point)| (squared(*point, target), index)).collect();
+        all.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        all
+    }
+
+    #[test]
+    fn the_tree_finds_what_checking_every_point_finds() {
+        let mut random = Lcg(12_345);
+        let mut points: Vec<[f64; 3]> = (0..500).map(|_| random.lab()).collect();
+        // repeated points and points sharing a coordinate with their neighbour exercise the ties
+        points.extend_from_within(..20);
+        points.extend((0..20).map(|i| [50.0, i as f64, -(i as f64)]));
+        let tree = KdTree::build(points.clone());
+        for _ in 0..300 {
+            let target = if random.next() < 0.1 { points[(random.next() * points.len() as f64) as usize] } else { random.lab() };
+            let all = brute_force(&points, target);
+            for count in [1, 5, 40, points.len() + 3] {
+                assert_eq!(tree.nearest(target, count), all[..count.min(all.len())].to_vec(), "{count} nearest {target:?}");
+            }
+            let radius_squared = random.next() * 900.0;
+            let mut within = tree.within(target, radius_squared);
+            within.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+            let expected: Vec<(f64, usize)> = all.iter().copied().take_while(|(d, _)| *d <= radius_squared).collect();
+            assert_eq!(within, expected, "within {radius_squared} of {target:?}");
+        }
+        assert!(KdTree::build(Vec::new()).nearest([0.0; 3], 3).is_empty());
+        assert!(tree.nearest([0.0; 3], 0).is_empty());
+    }
+
+    #[test]
+    fn every_metric_matches_checking_every_colour() {
+        let css = Catalogue::css();
+        assert_eq!(css.len(), CSS_COLOURS.len());
+        let mut random = Lcg(54_321);
+        for _ in 0..1_000 {
+            let colour = random.colour();
+            for metric in [DeltaE::Cie76, DeltaE::Cie94, DeltaE::Ciede2000] {
+                let mut all: Vec<f64> = CSS_COLOURS.iter().map(|(_, swatch)| metric.between(swatch.to_lab(), colour.to_lab())).collect();
+                all.sort_by(f64::total_cmp);
+                let found: Vec<f64> = css.nearest_few(colour, 5, metric).iter().map(|m| m.delta_e).collect();
+                assert_eq!(found, all[..5].to_vec(), "{colour} by {metric:?}");
+            }
+        }
+        assert_eq!(Colour(250, 2, 3).nearest_name(), "red");
+        assert_eq!(Colour(0, 255, 255).nearest_name(), "aqua");  // cyan is the same colour, listed after it
+    }
+
+    #[test]
+    fn a_catalogue_file_reports_the_line_and_column_of_a_mistake() {
+        let paints = Catalogue::parse("# paints\nDuck Egg Blue = #a8c3bc\n\nPillar Box Red = rgb(200, 16, 46)\n  Night = black\n").unwrap();
+        assert_eq!(paints.len(), 3);
+        assert_eq!(paints.get("Night"), Some(Colour(0, 0, 0)));
+        assert_eq!(paints.nearest(Colour(190, 20, 40), DeltaE::Ciede2000).unwrap().name, "Pillar Box Red");
+        let err = Catalogue::parse("a = #fff\nBad = rgb(1, 2, 300)\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("column 17"), "{}", err.message);
+        assert_eq!(Catalogue::parse("a = #fff\na = #000").unwrap_err().line, 2);
+        assert_eq!(Catalogue::parse("no equals").unwrap_err().line, 1);
+        assert_eq!(Catalogue::parse("# nothing here\n").unwrap_err().line, 0);
+        assert!(Catalogue::new(Vec::new()).nearest(Colour(0, 0, 0), DeltaE::Cie76).is_none());
+    }
+}
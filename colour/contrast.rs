@@ -0,0 +1,235 @@
+// WCAG 2.x contrast between text and its background, and palettes that keep to it
+//
+// relative luminance is the Y of linear sRGB, 0 for black and 1 for white, and the contrast ratio is
+// (lighter + 0.05) / (darker + 0.05), from 1:1 for a colour on itself to 21:1 for black on white
+//
+//                  normal text     large text (18pt, or 14pt bold)
+//     AA           4.5             3
+//     AAA          7               4.5
+//
+// WCAG 2.x still quotes 0.03928 as the sRGB threshold, the sRGB standard's 0.04045 is used here, for
+// 8-bit colours the two give the same luminance
+
+use std::fmt;
+
+use crate::colour::difference::DeltaE;
+use crate::colour::rgb::{in_range, ColourError};
+use crate::colour::spaces::Oklab;
+use crate::Colour;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WcagLevel {
+    Aa,
+    Aaa
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextSize {
+    Normal,
+    Large
+}
+
+impl WcagLevel {
+    pub fn required(self, size: TextSize) -> f64 {
+        match (self, size) {
+            (WcagLevel::Aa, TextSize::Normal) => 4.5,
+            (WcagLevel::Aa, TextSize::Large) => 3.0,
+            (WcagLevel::Aaa, TextSize::Normal) => 7.0,
+            (WcagLevel::Aaa, TextSize::Large) => 4.5
+        }
+    }
+}
+
+// how one colour on another does against every WCAG level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contrast {
+    pub ratio: f64
+}
+
+impl Contrast {
+    pub fn passes(self, level: WcagLevel, size: TextSize) -> bool {
+        self.ratio >= level.required(size)
+    }
+}
+
+impl fmt::Display for Contrast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = |level, size| if self.passes(level, size) { "pass" } else { "fail" };
+        write!(
+            f,
+            "{:.2}:1, AA {} (large {}), AAA {} (large {})",
+            self.ratio,
+            verdict(WcagLevel::Aa, TextSize::Normal),
+            verdict(WcagLevel::Aa, TextSize::Large),
+            verdict(WcagLevel::Aaa, TextSize::Normal),
+            verdict(WcagLevel::Aaa, TextSize::Large)
+        )
+    }
+}
+
+impl Colour {
+    pub fn luminance(self) -> f64 {
+        let linear = self.to_linear();
+        0.2126 * linear.r + 0.7152 * linear.g + 0.0722 * linear.b
+    }
+
+    // the same whichever way round the two colours are
+    pub fn contrast_ratio(self, other: Colour) -> f64 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    pub fn contrast(self, other: Colour) -> Contrast {
+        Contrast { ratio: self.contrast_ratio(other) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteError {
+    InvalidRatio(ColourError),  // contrast ratios go from 1 to 21
+    Unreachable { required: f64, best: f64 },  // not even black or white gets that far from the background
+    TooSimilar { count: usize, closest: f64 }  // the two nearest colours are closer than MIN_DIFFERENCE
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::InvalidRatio(e) => write!(f, "{e}"),
+            PaletteError::Unreachable { required, best } => write!(f, "no colour reaches {required}:1 on this background, the most any colour gets is {best:.2}:1"),
+            PaletteError::TooSimilar { count, closest } => write!(
+                f,
+                "{count} colours at this contrast cannot all be told apart, the closest two are {closest:.1} apart and {MIN_DIFFERENCE} is needed"
+            )
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<ColourError> for PaletteError {
+    fn from(e: ColourError) -> Self {
+        PaletteError::InvalidRatio(e)
+    }
+}
+
+// how colourful the palette is in OKLab, high enough to tell hues apart and low enough to stay mostly in gamut
+const CHROMA: f64 = 0.13;
+const PREFERRED_LIGHTNESS: f64 = 0.65;
+const FIRST_HUE: f64 = 25.0;  // degrees in OKLab, a red, so the first series gets the colour people expect first
+// the least CIEDE2000 difference between any two colours, 2 is just noticeable side by side but a series has to be
+// matched to its legend across the chart, near the extremes of lightness every hue runs into black or white
+pub const MIN_DIFFERENCE: f64 = 10.0;
+
+// count colours with hues spread evenly around the OKLab circle, each at least ratio:1 against background
+// a colour starts at a middling lightness and, when that is not enough, is made darker on a light background
+// or lighter on a dark one, just as far as it has to be, so the colours stay as colourful as they can
+// asking for many colours, or a ratio that leaves little room, fails with TooSimilar rather than repeating colours
+pub fn accessible_palette(count: usize, background: Colour, ratio: f64) -> Result<Vec<Colour>, PaletteError> {
+    let ratio = in_range("contrast ratio", ratio, 1.0, 21.0)?;
+    let (to_black, to_white) = (background.contrast_ratio(Colour(0, 0, 0)), background.contrast_ratio(Colour(255, 255, 255)));
+    let darken = to_black >= to_white;
+    let best = to_black.max(to_white);
+    if best < ratio {
+        return Err(PaletteError::Unreachable { required: ratio, best });
+    }
+    let palette = (0..count)
+        .map(|i| {
+            let hue = (FIRST_HUE + 360.0 * i as f64 / count as f64).to_radians();
+            let at = |l: f64| Colour::from_oklab(Oklab { l, a: CHROMA * hue.cos(), b: CHROMA * hue.sin() });
+            let passes = |l: f64| at(l).contrast_ratio(background) >= ratio;
+            if passes(PREFERRED_LIGHTNESS) {
+                return at(PREFERRED_LIGHTNESS);
+            }
+            // the lightness nearest the preferred one that passes, found by halving the gap
+            let (mut near, mut far) = if darken { (PREFERRED_LIGHTNESS, 0.0) } else { (PREFERRED_LIGHTNESS, 1.0) };
+            for _ in 0..32 {
+                let middle = (near + far) / 2.0;
+                if passes(middle) { far = middle } else { near = middle }
+            }
+            // clamping to the gamut can cost a little contrast, black or white is always enough in the end
+            let extreme = if darken { Colour(0, 0, 0) } else { Colour(255, 255, 255) };
+            if passes(far) { at(far) } else { extreme }
+        })
+        .collect::<Vec<Colour>>();
+    let closest = palette
+        .iter()
+        .enumerate()
+        .flat_map(|(i, a)| palette[i + 1..].iter().map(move |b| a.delta_e(*b, DeltaE::Ciede2000)))
+        .fold(f64::INFINITY, f64::min);
+    if closest < MIN_DIFFERENCE {
+        return Err(PaletteError::TooSimilar { count, closest });
+    }
+    Ok(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Colour = Colour(255, 255, 255);
+
+    #[test]
+    fn luminance_and_ratio_match_the_wcag_examples() {
+        assert_eq!(Colour(0, 0, 0).luminance(), 0.0);
+        assert!((WHITE.luminance() - 1.0).abs() < 1e-12);
+        assert!((Colour(0, 255, 0).luminance() - 0.7152).abs() < 1e-12);
+        assert!((Colour(0, 0, 0).contrast_ratio(WHITE) - 21.0).abs() < 1e-9);
+        let grey = Colour(0x77, 0x77, 0x77);
+        assert!((grey.contrast_ratio(WHITE) - 4.48).abs() < 0.005, "{}", grey.contrast_ratio(WHITE));
+        assert_eq!(grey.contrast_ratio(WHITE), WHITE.contrast_ratio(grey));
+        assert_eq!(grey.contrast_ratio(grey), 1.0);
+        // #777 is the classic near miss: large text only
+        assert!(!grey.contrast(WHITE).passes(WcagLevel::Aa, TextSize::Normal));
+        assert!(grey.contrast(WHITE).passes(WcagLevel::Aa, TextSize::Large));
+        assert!(Colour(0x76, 0x76, 0x76).contrast(WHITE).passes(WcagLevel::Aa, TextSize::Normal));
+    }
+
+    #[test]
+    fn each_level_passes_from_its_threshold_up() {
+        let cases = [(WcagLevel::Aa, TextSize::Normal, 4.5), (WcagLevel::Aa, TextSize::Large, 3.0), (WcagLevel::Aaa, TextSize::Normal, 7.0), (WcagLevel::Aaa, TextSize::Large, 4.5)];
+        for (level, size, ratio) in cases {
+            assert_eq!(level.required(size), ratio);
+            assert!(Contrast { ratio }.passes(level, size), "{level:?} {size:?}");
+            assert!(!Contrast { ratio: ratio - 0.001 }.passes(level, size), "{level:?} {size:?}");
+        }
+    }
+
+    #[test]
+    fn display_gives_the_ratio_and_every_verdict() {
+        assert_eq!(Colour(0, 0, 0).contrast(WHITE).to_string(), "21.00:1, AA pass (large pass), AAA pass (large pass)");
+        assert_eq!(Contrast { ratio: 4.5 }.to_string(), "4.50:1, AA pass (large pass), AAA fail (large pass)");
+        assert_eq!(Contrast { ratio: 3.2 }.to_string(), "3.20:1, AA fail (large pass), AAA fail (large fail)");
+        assert_eq!(Contrast { ratio: 1.0 }.to_string(), "1.00:1, AA fail (large fail), AAA fail (large fail)");
+    }
+
+    #[test]
+    fn every_colour_passes_and_no_two_are_alike() {
+        for (background, ratio) in [(WHITE, 4.5), (Colour(0, 0, 0), 4.5), (Colour(30, 40, 60), 3.0), (Colour(240, 230, 200), 7.0)] {
+            let palette = accessible_palette(8, background, ratio).unwrap();
+            assert_eq!(palette.len(), 8);
+            for (i, colour) in palette.iter().enumerate() {
+                assert!(colour.contrast_ratio(background) >= ratio, "{colour} on {background}");
+                for other in &palette[i + 1..] {
+                    assert!(colour.delta_e(*other, DeltaE::Ciede2000) >= MIN_DIFFERENCE, "{colour} and {other} on {background}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_palette_squeezed_against_black_or_white_is_refused() {
+        // on mid grey 4.5:1 leaves only colours near black, which used to come back as copies of black
+        assert!(matches!(accessible_palette(8, Colour(119, 119, 119), 4.5), Err(PaletteError::TooSimilar { count: 8, .. })));
+        assert!(matches!(accessible_palette(8, WHITE, 15.0), Err(PaletteError::TooSimilar { .. })));
+        assert!(matches!(accessible_palette(24, WHITE, 3.0), Err(PaletteError::TooSimilar { .. })));
+        assert_eq!(accessible_palette(2, Colour(119, 119, 119), 4.5).unwrap().len(), 2);
+        assert_eq!(accessible_palette(1, WHITE, 21.0).unwrap(), vec![Colour(0, 0, 0)]);
+    }
+
+    #[test]
+    fn ratios_that_cannot_be_met_are_refused() {
+        assert!(matches!(accessible_palette(3, Colour(119, 119, 119), 7.0), Err(PaletteError::Unreachable { .. })));
+        assert!(matches!(accessible_palette(3, WHITE, 30.0), Err(PaletteError::InvalidRatio(_))));
+        assert!(accessible_palette(0, WHITE, 3.0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+// reading and writing colours the way CSS, and so our config files, spell them
+//
+//     #f80  #f80c  #ff8800  #ff8800cc                    hex, the last digits are alpha
+//     rgb(255, 136, 0)  rgba(255, 136, 0, 0.8)           comma syntax
+//     rgb(255 136 0 / 80%)  rgb(100% 53.3% 0%)           space syntax, channels as numbers or percentages
+//     hsl(32, 100%, 50%)  hsla(32deg 100% 50% / 0.8)     hue in deg (the default), rad, grad or turn
+//     orange  RebeccaPurple  transparent                 the CSS named colours, ignoring case
+//
+// a browser clamps numbers that are out of range, here they are errors because in a config file they are typos
+// every CssError carries the byte position of the problem, so it can be pointed to the same way as a QueryError
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::colour::named;
+use crate::colour::rgb::{in_range, ColourError, Rgba};
+use crate::colour::spaces::Hsl;
+use crate::Colour;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CssError {
+    pub position: usize,  // byte offset into the text
+    pub message: String
+}
+
+impl CssError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        CssError { position, message: message.into() }
+    }
+
+    fn out_of_range(position: usize, error: ColourError) -> Self {
+        CssError::new(position, error.to_string())
+    }
+
+    // the text with a ^ under the place the error was found
+    pub fn pointer(&self, text: &str) -> String {
+        let column = text[..self.position.min(text.len())].chars().count();
+        format!("{text}\n{}^ {}", " ".repeat(column), self.message)
+    }
+}
+
+impl fmt::Display for CssError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid colour at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for CssError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notation {
+    Hex,  // #rrggbb, or #rrggbbaa when translucent
+    ShortHex,  // #rgb or #rgba when every byte is a doubled digit, otherwise as Hex
+    Rgb,  // rgb(r, g, b), or rgba(r, g, b, a) when translucent
+    Hsl,  // hsl(h, s%, l%), or hsla(h, s%, l%, a) when translucent
+    Name  // the CSS name when there is one, otherwise as Hex
+}
+
+pub fn parse(text: &str) -> Result<Rgba, CssError> {
+    let start = text.len() - text.trim_start().len();
+    let body = text.trim();
+    let Some(first) = body.chars().next() else {
+        return Err(CssError::new(start, "expected a colour"));
+    };
+    if let Some(digits) = body.strip_prefix('#') {
+        return hex(digits, start + 1);
+    }
+    if !first.is_ascii_alphabetic() {
+        return Err(CssError::new(start, format!("unexpected '{first}', a colour starts with '#', a function such as rgb( or a name")));
+    }
+    let name_len = body.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(body.len());
+    let name = &body[..name_len];
+    match body[name_len..].chars().next() {
+        None if name.eq_ignore_ascii_case("transparent") => Ok(Rgba { colour: Colour(0, 0, 0), alpha: 0.0 }),
+        None => named::lookup(name).map(Rgba::opaque).ok_or_else(|| CssError::new(start, format!("'{name}' is not a colour name"))),
+        Some('(') => function(name, &body[name_len + 1..], start, start + name_len + 1),
+        Some(c) => Err(CssError::new(start + name_len, format!("unexpected '{c}' after '{name}'")))
+    }
+}
+
+// the digits after '#', which start at byte at
+fn hex(digits: &str, at: usize) -> Result<Rgba, CssError> {
+    if let Some((i, c)) = digits.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        return Err(CssError::new(at + i, format!("'{c}' is not a hex digit")));
+    }
+    // only ASCII hex digits are left, so every slice below is on a character boundary
+    let byte = |i: usize, len: usize| u8::from_str_radix(&digits[i * len..(i + 1) * len], 16).expect("hex digits") * if len == 1 { 17 } else { 1 };
+    let len = match digits.len() {
+        3 | 4 => 1,
+        6 | 8 => 2,
+        n => return Err(CssError::new(at - 1, format!("expected 3, 4, 6 or 8 hex digits, not {n}")))
+    };
+    let colour = Colour(byte(0, len), byte(1, len), byte(2, len));
+    let alpha = if digits.len() / len == 4 { byte(3, len) as f64 / 255.0 } else { 1.0 };
+    Ok(Rgba { colour, alpha })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Unit {
+    Number,
+    Percent,
+    Degrees,
+    Radians,
+    Gradians,
+    Turns
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Argument {
+    Number(f64, Unit),
+    Comma,
+    Slash
+}
+
+// name( has been read, arguments is everything after the bracket, which starts at byte at
+fn function(name: &str, arguments: &str, start: usize, at: usize) -> Result<Rgba, CssError> {
+    let lower = name.to_ascii_lowercase();
+    if !matches!(lower.as_str(), "rgb" | "rgba" | "hsl" | "hsla") {
+        return Err(CssError::new(start, format!("'{name}' is not a colour function, use rgb, rgba, hsl or hsla")));
+    }
+    let Some(close) = arguments.find(')') else {
+        return Err(CssError::new(at + arguments.len(), format!("{name}( is missing its ')'")));
+    };
+    let after = &arguments[close + 1..];
+    if let Some(c) = after.trim_start().chars().next() {
+        return Err(CssError::new(at + arguments.len() - after.trim_start().len(), format!("unexpected '{c}' after the colour")));
+    }
+    let tokens = tokenize(&arguments[..close], at)?;
+    let end = at + close;
+
+    // rgb(1, 2, 3, 0.5) or rgb(1 2 3 / 0.5), whichever the first separator says
+    let commas = matches!(tokens.get(1), Some((_, Argument::Comma)));
+    let mut tokens = tokens.into_iter();
+    let number = |tokens: &mut std::vec::IntoIter<(usize, Argument)>| match tokens.next() {
+        Some((position, Argument::Number(value, unit))) => Ok((position, value, unit)),
+        Some((position, _)) => Err(CssError::new(position, "expected a number")),
+        None => Err(CssError::new(end, format!("{name}() takes three numbers and an optional alpha")))
+    };
+    let mut components = Vec::new();
+    for i in 0..3 {
+        if i > 0 && commas {
+            match tokens.next() {
+                Some((_, Argument::Comma)) => {}
+                Some((position, _)) => return Err(CssError::new(position, "expected ',', the arguments started out separated by commas")),
+                None => return Err(CssError::new(end, format!("{name}() takes three numbers and an optional alpha")))
+            }
+        }
+        components.push(number(&mut tokens)?);
+    }
+    let alpha = match tokens.next() {
+        None => 1.0,
+        Some((_, Argument::Comma)) if commas => alpha(number(&mut tokens)?)?,
+        Some((_, Argument::Slash)) if !commas => alpha(number(&mut tokens)?)?,
+        Some((position, _)) => return Err(CssError::new(position, if commas { "expected ',' before the alpha" } else { "expected '/' before the alpha" }))
+    };
+    if let Some((position, _)) = tokens.next() {
+        return Err(CssError::new(position, "expected ')'"));
+    }
+
+    let colour = if lower.starts_with("rgb") {
+        let channel = |(position, value, unit): (usize, f64, Unit), component| {
+            let value = match unit {
+                Unit::Number => in_range(component, value, 0.0, 255.0),
+                Unit::Percent => in_range(component, value, 0.0, 100.0).map(|v| v / 100.0 * 255.0),
+                _ => return Err(CssError::new(position, format!("{component} cannot be an angle")))
+            };
+            value.map(|v| v.round() as u8).map_err(|e| CssError::out_of_range(position, e))
+        };
+        Colour(channel(components[0], "red")?, channel(components[1], "green")?, channel(components[2], "blue")?)
+    } else {
+        let (position, value, unit) = components[0];
+        let h = match unit {
+            Unit::Number | Unit::Degrees => value,
+            Unit::Radians => value.to_degrees(),
+            Unit::Gradians => value * 0.9,
+            Unit::Turns => value * 360.0,
+            Unit::Percent => return Err(CssError::new(position, "hue cannot be a percentage"))
+        };
+        // CSS writes these as percentages, a bare number is taken as one too
+        let percentage = |(position, value, unit): (usize, f64, Unit), component| match unit {
+            Unit::Number | Unit::Percent => in_range(component, value, 0.0, 100.0).map(|v| v / 100.0).map_err(|e| CssError::out_of_range(position, e)),
+            _ => Err(CssError::new(position, format!("{component} cannot be an angle")))
+        };
+        let hsl = Hsl::new(h, percentage(components[1], "saturation")?, percentage(components[2], "lightness")?).map_err(|e| CssError::out_of_range(position, e))?;
+        Colour::from_hsl(hsl)
+    };
+    Ok(Rgba { colour, alpha })
+}
+
+fn alpha((position, value, unit): (usize, f64, Unit)) -> Result<f64, CssError> {
+    match unit {
+        Unit::Number => in_range("alpha", value, 0.0, 1.0),
+        Unit::Percent => in_range("alpha", value, 0.0, 100.0).map(|v| v / 100.0),
+        _ => return Err(CssError::new(position, "alpha cannot be an angle"))
+    }
+    .map_err(|e| CssError::out_of_range(position, e))
+}
+
+fn tokenize(arguments: &str, at: usize) -> Result<Vec<(usize, Argument)>, CssError> {
+    let mut tokens = Vec::new();
+    let mut chars = arguments.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        let position = at + i;
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            ',' | '/' => {
+                chars.next();
+                tokens.push((position, if c == ',' { Argument::Comma } else { Argument::Slash }));
+            }
+            c if c.is_ascii_digit() || c == '.' || c == '+' || c == '-' => {
+                let mut end = i + c.len_utf8();
+                chars.next();
+                while let Some(&(j, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                let text = &arguments[i..end];
+                let value: f64 = text.parse().map_err(|_| CssError::new(position, format!("'{text}' is not a number")))?;
+                let unit_start = end;
+                while let Some(&(j, c)) = chars.peek().filter(|(_, c)| c.is_ascii_alphabetic() || *c == '%') {
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                let unit = match arguments[unit_start..end].to_ascii_lowercase().as_str() {
+                    "" => Unit::Number,
+                    "%" => Unit::Percent,
+                    "deg" => Unit::Degrees,
+                    "rad" => Unit::Radians,
+                    "grad" => Unit::Gradians,
+                    "turn" => Unit::Turns,
+                    other => return Err(CssError::new(at + unit_start, format!("'{other}' is not a unit, use %, deg, rad, grad or turn")))
+                };
+                tokens.push((position, Argument::Number(value, unit)));
+            }
+            c => return Err(CssError::new(position, format!("unexpected '{c}'")))
+        }
+    }
+    Ok(tokens)
+}
+
+// up to decimals places, without trailing zeros
+fn number(value: f64, decimals: usize) -> String {
+    let text = format!("{value:.decimals$}");
+    let text = if text.contains('.') { text.trim_end_matches('0').trim_end_matches('.') } else { &text };
+    if text == "-0" { "0".to_string() } else { text.to_string() }
+}
+
+impl Rgba {
+    pub fn to_css(self, notation: Notation) -> String {
+        let Colour(r, g, b) = self.colour;
+        let alpha_byte = (self.alpha * 255.0).round() as u8;
+        let opaque = self.is_opaque();
+        match notation {
+            Notation::Hex if opaque => format!("#{r:02x}{g:02x}{b:02x}"),
+            Notation::Hex => format!("#{r:02x}{g:02x}{b:02x}{alpha_byte:02x}"),
+            Notation::ShortHex => {
+                let bytes = if opaque { vec![r, g, b] } else { vec![r, g, b, alpha_byte] };
+                if bytes.iter().all(|byte| byte % 17 == 0) {
+                    bytes.iter().fold("#".to_string(), |text, byte| format!("{text}{:x}", byte / 17))
+                } else {
+                    self.to_css(Notation::Hex)
+                }
+            }
+            Notation::Rgb if opaque => format!("rgb({r}, {g}, {b})"),
+            Notation::Rgb => format!("rgba({r}, {g}, {b}, {})", number(self.alpha, 3)),
+            Notation::Hsl => {
+                // two decimals are enough for the text to read back into the same bytes
+                let hsl = self.colour.to_hsl();
+                let (h, s, l) = (number(hsl.h, 2), number(hsl.s * 100.0, 2), number(hsl.l * 100.0, 2));
+                if opaque { format!("hsl({h}, {s}%, {l}%)") } else { format!("hsla({h}, {s}%, {l}%, {})", number(self.alpha, 3)) }
+            }
+            Notation::Name if self.alpha == 0.0 && self.colour == Colour(0, 0, 0) => "transparent".to_string(),
+            Notation::Name => match self.colour.name() {
+                Some(name) if opaque => name.to_string(),
+                _ => self.to_css(Notation::Hex)
+            }
+        }
+    }
+}
+
+impl Colour {
+    pub fn to_css(self, notation: Notation) -> String {
+        Rgba::opaque(self).to_css(notation)
+    }
+}
+
+impl fmt::Display for Rgba {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_css(Notation::Hex))
+    }
+}
+
+impl fmt::Display for Colour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_css(Notation::Hex))
+    }
+}
+
+impl FromStr for Rgba {
+    type Err = CssError;
+
+    fn from_str(text: &str) -> Result<Rgba, CssError> {
+        parse(text)
+    }
+}
+
+// a Colour has nowhere to keep an alpha, so a translucent one is an error rather than quietly made opaque
+impl FromStr for Colour {
+    type Err = CssError;
+
+    fn from_str(text: &str) -> Result<Colour, CssError> {
+        let rgba = parse(text)?;
+        if !rgba.is_opaque() {
+            let start = text.len() - text.trim_start().len();
+            return Err(CssError::new(start, format!("expected an opaque colour, this one has an alpha of {}", number(rgba.alpha, 3))));
+        }
+        Ok(rgba.colour)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(r: u8, g: u8, b: u8, alpha: f64) -> Rgba {
+        Rgba { colour: Colour(r, g, b), alpha }
+    }
+
+    // alpha read from text is a fraction, compared as the byte it would be written back as
+    fn bytes(rgba: Rgba) -> [u8; 4] {
+        let Colour(r, g, b) = rgba.colour;
+        [r, g, b, (rgba.alpha * 255.0).round() as u8]
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        let err = parse(text).unwrap_err();
+        (err.position, err.message)
+    }
+
+    #[test]
+    fn every_notation_reads() {
+        let orange = [255, 136, 0, 255];
+        let translucent = [255, 136, 0, 204];
+        for text in ["#f80", "#F80", "#ff8800", "rgb(255, 136, 0)", "RGB(255,136,0)", "rgb(255 136 0)", "rgb(100% 53.3% 0%)", "rgba(255, 136, 0, 1)"] {
+            assert_eq!(bytes(parse(text).unwrap()), orange, "{text}");
+        }
+        for text in ["#f80c", "#ff8800cc", "rgba(255, 136, 0, 0.8)", "rgb(255 136 0 / 80%)", "rgb(255 136 0 / 0.8)", "rgba(255, 136, 0, 80%)"] {
+            assert_eq!(bytes(parse(text).unwrap()), translucent, "{text}");
+        }
+        // the same orange hue written in every unit
+        for text in ["hsl(32, 100%, 50%)", "hsl(32 100 50)", "hsl(32deg 100% 50%)", "hsl(0.08889turn 100% 50%)", "hsl(35.5556grad 100% 50%)", "hsl(0.5585rad 100% 50%)"] {
+            assert_eq!(parse(text).unwrap(), rgba(255, 136, 0, 1.0), "{text}");
+        }
+        assert_eq!(bytes(parse("hsla(32deg 100% 50% / 0.8)").unwrap()), translucent);
+        assert_eq!(parse("hsl(-328, 100%, 50%)").unwrap(), rgba(255, 136, 0, 1.0));  // hue wraps around
+        assert_eq!(parse("orange").unwrap(), rgba(255, 165, 0, 1.0));
+        assert_eq!(parse("  RebeccaPurple\n").unwrap(), rgba(0x66, 0x33, 0x99, 1.0));
+        assert_eq!(parse("Transparent").unwrap(), rgba(0, 0, 0, 0.0));
+        assert_eq!("#f80".parse::<Colour>(), Ok(Colour(255, 136, 0)));
+    }
+
+    #[test]
+    fn each_notation_writes_what_it_reads() {
+        let colours = [rgba(255, 136, 0, 1.0), rgba(255, 136, 0, 0.8), rgba(0x66, 0x33, 0x99, 1.0), rgba(0x12, 0x34, 0x56, 0.5), rgba(0, 0, 0, 0.0)];
+        for notation in [Notation::Hex, Notation::ShortHex, Notation::Rgb, Notation::Hsl, Notation::Name] {
+            for colour in colours {
+                let text = colour.to_css(notation);
+                assert_eq!(bytes(parse(&text).unwrap()), bytes(colour), "{notation:?} {text}");
+            }
+        }
+        let orange = rgba(255, 136, 0, 1.0);
+        assert_eq!(orange.to_css(Notation::Hex), "#ff8800");
+        assert_eq!(orange.to_css(Notation::ShortHex), "#f80");
+        assert_eq!(rgba(255, 136, 0, 0.8).to_css(Notation::ShortHex), "#f80c");
+        assert_eq!(rgba(0x12, 0x34, 0x56, 1.0).to_css(Notation::ShortHex), "#123456");
+        assert_eq!(orange.to_css(Notation::Rgb), "rgb(255, 136, 0)");
+        assert_eq!(rgba(255, 136, 0, 0.8).to_css(Notation::Rgb), "rgba(255, 136, 0, 0.8)");
+        assert_eq!(orange.to_css(Notation::Hsl), "hsl(32, 100%, 50%)");
+        assert_eq!(rgba(0x66, 0x33, 0x99, 1.0).to_css(Notation::Name), "rebeccapurple");
+        assert_eq!(rgba(0x66, 0x33, 0x99, 0.5).to_css(Notation::Name), "#66339980");  // a name has no alpha
+        assert_eq!(rgba(0, 0, 0, 0.0).to_css(Notation::Name), "transparent");
+        assert_eq!(orange.to_string(), "#ff8800");
+    }
+
+    #[test]
+    fn two_decimals_of_hsl_read_back_into_the_same_bytes() {
+        // every colour holds, checked once in a release build; a grid of them keeps the test quick
+        let steps: Vec<u8> = (0..=255).step_by(5).chain([1, 254]).collect();
+        for &r in &steps {
+            for &g in &steps {
+                for &b in &steps {
+                    let colour = Colour(r, g, b);
+                    let text = colour.to_css(Notation::Hsl);
+                    assert_eq!(text.parse::<Colour>(), Ok(colour), "{text}");
+                }
+            }
+        }
+        for alpha in 0..=255 {
+            let colour = rgba(10, 20, 30, alpha as f64 / 255.0);
+            assert_eq!(bytes(parse(&colour.to_css(Notation::Hsl)).unwrap())[3], alpha);
+            assert_eq!(bytes(parse(&colour.to_css(Notation::Rgb)).unwrap())[3], alpha);
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(error("  #12g"), (5, "'g' is not a hex digit".to_string()));
+        assert_eq!(error("  #12345").0, 2);
+        assert_eq!(error(""), (0, "expected a colour".to_string()));
+        assert_eq!(error("   "), (3, "expected a colour".to_string()));
+        assert_eq!(error("  blurple"), (2, "'blurple' is not a colour name".to_string()));
+        assert_eq!(error(" rgb(255, 300, 0)").0, 10);
+        assert_eq!(error(" rgb(255, 0, 0").0, 14);
+        assert_eq!(error("rgb(1 2 3) x"), (11, "unexpected 'x' after the colour".to_string()));
+        assert_eq!(error("rgb(1, 2 3)"), (9, "expected ',', the arguments started out separated by commas".to_string()));
+        assert_eq!(error("rgb(1 2 3 0.5)"), (10, "expected '/' before the alpha".to_string()));
+        assert_eq!(error("rgb(1 2)").0, 7);
+        assert_eq!(error("hsl(30% 50% 50%)"), (4, "hue cannot be a percentage".to_string()));
+        assert_eq!(error("hsl(30 50px 50%)"), (9, "'px' is not a unit, use %, deg, rad, grad or turn".to_string()));
+        assert_eq!(error("rgb(1deg 2 3)"), (4, "red cannot be an angle".to_string()));
+        assert_eq!(error("cmyk(0 0 0 0)"), (0, "'cmyk' is not a colour function, use rgb, rgba, hsl or hsla".to_string()));
+        assert!("#f80c".parse::<Colour>().is_err());
+
+        // positions are bytes, the pointer counts characters so it lines up under multibyte text
+        let text = "  rgb(1, 2, «3»)";
+        let err = parse(text).unwrap_err();
+        assert_eq!((err.position, err.message.as_str()), (12, "unexpected '«'"));
+        assert_eq!(err.pointer(text), format!("{text}\n{}^ unexpected '«'", " ".repeat(12)));
+        let text = "rgb(1\u{a0}2\u{a0}3 x)";  // no-break spaces, two bytes each
+        let err = parse(text).unwrap_err();
+        assert_eq!(err.position, 12);
+        assert_eq!(err.pointer(text), format!("{text}\n{}^ unexpected 'x'", " ".repeat(10)));
+        assert_eq!(error("été").0, 0);
+        let text = "#ffé";
+        assert_eq!(parse(text).unwrap_err().pointer(text), "#ffé\n   ^ 'é' is not a hex digit");
+        assert_eq!(parse("  #12g").unwrap_err().to_string(), "invalid colour at position 5: 'g' is not a hex digit");
+    }
+}
@@ -0,0 +1,176 @@
+// how different two colours look, as a delta E in CIELAB, where about 1 is the smallest difference people notice
+//
+//     Cie76        straight-line distance in Lab, quick but too large for saturated colours
+//     Cie94        weights chroma and hue by how saturated the reference is (graphic arts constants)
+//     Ciede2000    adds corrections for blues, greys and lightness, the one to use for matching
+//
+// distance between the RGB bytes is no use for this, equal steps there are nowhere near equal to the eye
+// CIEDE2000 follows Sharma, Wu and Dalal's notes on implementing it, whose test pairs it reproduces
+
+use crate::colour::spaces::Lab;
+use crate::Colour;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaE {
+    Cie76,
+    Cie94,
+    Ciede2000
+}
+
+impl DeltaE {
+    // CIE94 is not symmetric, reference is the colour being matched against, such as the catalogue swatch
+    pub fn between(self, reference: Lab, sample: Lab) -> f64 {
+        match self {
+            DeltaE::Cie76 => cie76(reference, sample),
+            DeltaE::Cie94 => cie94(reference, sample),
+            DeltaE::Ciede2000 => ciede2000(reference, sample)
+        }
+    }
+}
+
+pub fn cie76(a: Lab, b: Lab) -> f64 {
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+pub fn cie94(reference: Lab, sample: Lab) -> f64 {
+    const K1: f64 = 0.045;
+    const K2: f64 = 0.015;
+    let (c1, c2) = (reference.a.hypot(reference.b), sample.a.hypot(sample.b));
+    let dl = reference.l - sample.l;
+    let dc = c1 - c2;
+    // what is left of the a, b distance once chroma is taken out is the hue difference
+    let dh_squared = ((reference.a - sample.a).powi(2) + (reference.b - sample.b).powi(2) - dc * dc).max(0.0);
+    let (sc, sh) = (1.0 + K1 * c1, 1.0 + K2 * c1);
+    (dl * dl + (dc / sc).powi(2) + dh_squared / (sh * sh)).sqrt()
+}
+
+pub fn ciede2000(first: Lab, second: Lab) -> f64 {
+    const POW25_7: f64 = 6103515625.0;  // 25^7
+    let c_bar = (first.a.hypot(first.b) + second.a.hypot(second.b)) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + POW25_7)).sqrt());
+
+    // a is stretched so that greys, whose hue is meaningless, weigh less
+    let (a1, a2) = ((1.0 + g) * first.a, (1.0 + g) * second.a);
+    let (c1, c2) = (a1.hypot(first.b), a2.hypot(second.b));
+    let hue = |b: f64, a: f64| if a == 0.0 && b == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) };
+    let (h1, h2) = (hue(first.b, a1), hue(second.b, a2));
+
+    let dl = second.l - first.l;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let dh_big = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_bar = (first.l + second.l) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let cos = |degrees: f64| degrees.to_radians().cos();
+    let t = 1.0 - 0.17 * cos(h_bar - 30.0) + 0.24 * cos(2.0 * h_bar) + 0.32 * cos(3.0 * h_bar + 6.0) - 0.20 * cos(4.0 * h_bar - 63.0);
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let rc = 2.0 * (c_bar.powi(7) / (c_bar.powi(7) + POW25_7)).sqrt();
+    let sl = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let sc = 1.0 + 0.045 * c_bar;
+    let sh = 1.0 + 0.015 * c_bar * t;
+    // the rotation term, which fixes the tilt of the ellipses of equal difference in the blues
+    let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+
+    let (l, c, h) = (dl / sl, dc / sc, dh_big / sh);
+    (l * l + c * c + h * h + rt * c * h).max(0.0).sqrt()
+}
+
+impl Colour {
+    pub fn delta_e(self, other: Colour, metric: DeltaE) -> f64 {
+        metric.between(self.to_lab(), other.to_lab())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every pair from Sharma, Wu and Dalal's table: L, a, b of each colour and the CIEDE2000 difference
+    // pairs 9 to 15 sit either side of a hue jump, pairs 21 to 24 are all exactly 1 apart
+    const SHARMA: [([f64; 3], [f64; 3], f64); 34] = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+        ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+        ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+        ([50.0, 2.5, 0.0], [50.0, 0.0, -2.5], 4.3065),
+        ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+        ([50.0, 2.5, 0.0], [61.0, -5.0, 29.0], 22.8977),
+        ([50.0, 2.5, 0.0], [56.0, -27.0, -3.0], 31.9030),
+        ([50.0, 2.5, 0.0], [58.0, 24.0, 15.0], 19.4535),
+        ([50.0, 2.5, 0.0], [50.0, 3.1736, 0.5854], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 3.2972, 0.0], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 1.8634, 0.5757], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082)
+    ];
+
+    fn lab([l, a, b]: [f64; 3]) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn ciede2000_reproduces_every_sharma_pair_both_ways_round() {
+        for (number, (first, second, expected)) in SHARMA.into_iter().enumerate() {
+            let forward = ciede2000(lab(first), lab(second));
+            let backward = ciede2000(lab(second), lab(first));
+            assert!((forward - expected).abs() < 5e-5, "pair {}: {forward:.4}, expected {expected}", number + 1);
+            assert!((forward - backward).abs() < 1e-9, "pair {}: {forward} one way, {backward} the other", number + 1);
+        }
+    }
+
+    #[test]
+    fn the_older_formulas_agree_with_hand_worked_values() {
+        let (reference, sample) = (lab(SHARMA[0].0), lab(SHARMA[0].1));
+        assert!((cie76(reference, sample) - 4.0011).abs() < 1e-4);
+        assert!((cie94(reference, sample) - 1.3950).abs() < 1e-4);
+        // CIE94 scales by the reference's chroma, so swapping the two changes the answer
+        assert!((cie94(sample, reference) - cie94(reference, sample)).abs() > 1e-3);
+    }
+
+    #[test]
+    fn a_colour_is_no_distance_from_itself() {
+        for metric in [DeltaE::Cie76, DeltaE::Cie94, DeltaE::Ciede2000] {
+            assert_eq!(Colour(10, 20, 30).delta_e(Colour(10, 20, 30), metric), 0.0);
+            assert_eq!(Colour(128, 128, 128).delta_e(Colour(128, 128, 128), metric), 0.0);
+        }
+    }
+}
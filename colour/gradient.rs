@@ -0,0 +1,215 @@
+// gradients through any number of colour stops, for chart palettes and heat maps
+//
+// the space the colours are mixed in changes the midpoints:
+//     Srgb      the gamma-encoded values, what CSS gradients do by default, midpoints come out dark and muddy
+//     Linear    linear light, physically right for mixing light but midpoints look too bright
+//     Oklab     perceptually even steps, the one to use for charts
+//
+// alpha is mixed premultiplied, as CSS does, so a fade to transparent does not pass through grey
+
+use std::fmt;
+
+use crate::colour::rgb::{Rgba, Srgb};
+use crate::colour::spaces::{LinearRgb, Oklab};
+use crate::Colour;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Srgb,
+    Linear,
+    Oklab
+}
+
+impl Space {
+    fn components_of(self, colour: Colour) -> [f64; 3] {
+        match self {
+            Space::Srgb => {
+                let Srgb { r, g, b } = colour.to_srgb();
+                [r, g, b]
+            }
+            Space::Linear => {
+                let LinearRgb { r, g, b } = colour.to_linear();
+                [r, g, b]
+            }
+            Space::Oklab => {
+                let Oklab { l, a, b } = colour.to_oklab();
+                [l, a, b]
+            }
+        }
+    }
+
+    fn colour_from(self, [x, y, z]: [f64; 3]) -> Colour {
+        match self {
+            Space::Srgb => Colour::from_srgb(Srgb { r: x, g: y, b: z }),
+            Space::Linear => Colour::from_linear(LinearRgb { r: x, g: y, b: z }),
+            Space::Oklab => Colour::from_oklab(Oklab { l: x, a: y, b: z })
+        }
+    }
+
+    // t of the way from one colour to the other
+    fn mix(self, from: Rgba, to: Rgba, t: f64) -> Rgba {
+        let alpha = from.alpha + (to.alpha - from.alpha) * t;
+        if alpha == 0.0 {
+            return Rgba { colour: Colour(0, 0, 0), alpha: 0.0 };
+        }
+        let (a, b) = (self.components_of(from.colour), self.components_of(to.colour));
+        let channel = |i: usize| (a[i] * from.alpha + (b[i] * to.alpha - a[i] * from.alpha) * t) / alpha;
+        Rgba { colour: self.colour_from([channel(0), channel(1), channel(2)]), alpha }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GradientError {
+    NoStops,
+    BadPosition { index: usize, position: f64 }  // not a number from 0 to 1
+}
+
+impl fmt::Display for GradientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GradientError::NoStops => write!(f, "a gradient needs at least one colour stop"),
+            GradientError::BadPosition { index, position } => write!(f, "stop {index} is at {position}, stops go from 0 to 1")
+        }
+    }
+}
+
+impl std::error::Error for GradientError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stop {
+    pub position: f64,  // from 0 to 1
+    pub colour: Rgba
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    stops: Vec<Stop>,  // sorted by position, stops at the same position make a hard edge
+    pub space: Space
+}
+
+impl Gradient {
+    // stops can come in any order, ones at the same position keep the order they were given in
+    pub fn new(space: Space, stops: &[(f64, Rgba)]) -> Result<Gradient, GradientError> {
+        if stops.is_empty() {
+            return Err(GradientError::NoStops);
+        }
+        if let Some((index, &(position, _))) = stops.iter().enumerate().find(|(_, (p, _))| !(0.0..=1.0).contains(p)) {
+            return Err(GradientError::BadPosition { index, position });
+        }
+        let mut stops: Vec<Stop> = stops.iter().map(|&(position, colour)| Stop { position, colour }).collect();
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        Ok(Gradient { stops, space })
+    }
+
+    // colours spread out evenly from 0 to 1, the usual way to give a chart its palette
+    pub fn even(space: Space, colours: &[Colour]) -> Result<Gradient, GradientError> {
+        let last = colours.len().saturating_sub(1).max(1) as f64;
+        let stops: Vec<(f64, Rgba)> = colours.iter().enumerate().map(|(i, &c)| (i as f64 / last, Rgba::opaque(c))).collect();
+        Gradient::new(space, &stops)
+    }
+
+    pub fn stops(&self) -> &[Stop] {
+        &self.stops
+    }
+
+    // the colour at t, anything before the first stop or after the last takes that stop's colour
+    pub fn at(&self, t: f64) -> Rgba {
+        let first = self.stops[0];
+        if t.is_nan() || t <= first.position {
+            return first.colour;
+        }
+        // the first stop past t, with a hard edge the colour after it wins
+        match self.stops.iter().position(|stop| stop.position > t) {
+            None => self.stops[self.stops.len() - 1].colour,
+            Some(i) => {
+                let (from, to) = (self.stops[i - 1], self.stops[i]);
+                let local = (t - from.position) / (to.position - from.position);
+                self.space.mix(from.colour, to.colour, local)
+            }
+        }
+    }
+
+    // count colours evenly spaced from the first stop to the last, ends included
+    pub fn sample(&self, count: usize) -> Vec<Rgba> {
+        let (start, end) = (self.stops[0].position, self.stops[self.stops.len() - 1].position);
+        match count {
+            0 => Vec::new(),
+            1 => vec![self.at(start)],
+            _ => (0..count).map(|i| self.at(start + (end - start) * i as f64 / (count - 1) as f64)).collect()
+        }
+    }
+}
+
+impl Colour {
+    // t of the way from this colour to other, t from 0 to 1
+    pub fn mix(self, other: Colour, t: f64, space: Space) -> Colour {
+        space.mix(Rgba::opaque(self), Rgba::opaque(other), t.clamp(0.0, 1.0)).colour
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Colour = Colour(255, 0, 0);
+    const BLUE: Colour = Colour(0, 0, 255);
+
+    #[test]
+    fn stops_at_one_position_make_a_hard_edge() {
+        let stops = [(0.0, Rgba::opaque(RED)), (0.5, Rgba::opaque(RED)), (0.5, Rgba::opaque(BLUE)), (1.0, Rgba::opaque(BLUE))];
+        let gradient = Gradient::new(Space::Oklab, &stops).unwrap();
+        assert_eq!(gradient.at(0.4999).colour, RED);
+        assert_eq!(gradient.at(0.5).colour, BLUE);  // the stop given last wins at the edge
+        assert_eq!(gradient.at(0.75).colour, BLUE);
+        // the order of the two stops at the edge is the order they were given in, not their colours
+        let reversed = [(0.5, Rgba::opaque(BLUE)), (1.0, Rgba::opaque(RED)), (0.0, Rgba::opaque(RED)), (0.5, Rgba::opaque(RED))];
+        assert_eq!(Gradient::new(Space::Oklab, &reversed).unwrap().at(0.5).colour, RED);
+    }
+
+    #[test]
+    fn t_outside_the_stops_takes_the_nearest_end() {
+        let gradient = Gradient::new(Space::Srgb, &[(0.2, Rgba::opaque(RED)), (0.8, Rgba::opaque(BLUE))]).unwrap();
+        for t in [-1.0, 0.0, 0.2, f64::NAN, f64::NEG_INFINITY] {
+            assert_eq!(gradient.at(t).colour, RED, "{t}");
+        }
+        for t in [0.8, 1.0, 7.0, f64::INFINITY] {
+            assert_eq!(gradient.at(t).colour, BLUE, "{t}");
+        }
+        assert_eq!(gradient.sample(3).iter().map(|c| c.colour).collect::<Vec<_>>(), [RED, gradient.at(0.5).colour, BLUE]);
+        assert_eq!(RED.mix(BLUE, 5.0, Space::Srgb), BLUE);
+        assert_eq!(RED.mix(BLUE, -5.0, Space::Srgb), RED);
+    }
+
+    #[test]
+    fn a_fade_to_transparent_does_not_pass_through_grey() {
+        let transparent = Rgba { colour: Colour(0, 0, 0), alpha: 0.0 };
+        for space in [Space::Srgb, Space::Linear, Space::Oklab] {
+            let gradient = Gradient::new(space, &[(0.0, Rgba::opaque(RED)), (1.0, transparent)]).unwrap();
+            for t in [0.25, 0.5, 0.75] {
+                let colour = gradient.at(t);
+                assert_eq!(colour.colour, RED, "{space:?} at {t}");
+                assert!((colour.alpha - (1.0 - t)).abs() < 1e-9);
+            }
+            assert_eq!(gradient.at(1.0), transparent);
+        }
+    }
+
+    #[test]
+    fn the_space_moves_the_midpoint() {
+        let mid = |space| Colour(0, 0, 0).mix(Colour(255, 255, 255), 0.5, space);
+        assert_eq!(mid(Space::Srgb), Colour(128, 128, 128));
+        assert_eq!(mid(Space::Linear), Colour(188, 188, 188));
+        assert!(mid(Space::Oklab).0 > 90 && mid(Space::Oklab).0 < 128, "{:?}", mid(Space::Oklab));
+    }
+
+    #[test]
+    fn stops_must_exist_and_lie_from_zero_to_one() {
+        assert_eq!(Gradient::new(Space::Srgb, &[]), Err(GradientError::NoStops));
+        let err = Gradient::new(Space::Srgb, &[(0.0, Rgba::opaque(RED)), (1.5, Rgba::opaque(BLUE))]).unwrap_err();
+        assert_eq!(err, GradientError::BadPosition { index: 1, position: 1.5 });
+        assert_eq!(err.to_string(), "stop 1 is at 1.5, stops go from 0 to 1");
+        assert!(Gradient::new(Space::Srgb, &[(f64::NAN, Rgba::opaque(RED))]).is_err());
+        let single = Gradient::even(Space::Oklab, &[RED]).unwrap();
+        assert_eq!((single.at(0.3).colour, single.sample(2).len()), (RED, 2));
+    }
+}
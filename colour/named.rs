@@ -0,0 +1,167 @@
+// the named colours of CSS Color Level 4, in alphabetical order
+// a few colours have two names (aqua and cyan, fuchsia and magenta, gray and grey), name() gives the first
+
+use crate::Colour;
+
+pub const CSS_COLOURS: [(&str, Colour); 148] = [
+    ("aliceblue", Colour(0xf0, 0xf8, 0xff)),
+    ("antiquewhite", Colour(0xfa, 0xeb, 0xd7)),
+    ("aqua", Colour(0x00, 0xff, 0xff)),
+    ("aquamarine", Colour(0x7f, 0xff, 0xd4)),
+    ("azure", Colour(0xf0, 0xff, 0xff)),
+    ("beige", Colour(0xf5, 0xf5, 0xdc)),
+    ("bisque", Colour(0xff, 0xe4, 0xc4)),
+    ("black", Colour(0x00, 0x00, 0x00)),
+    ("blanchedalmond", Colour(0xff, 0xeb, 0xcd)),
+    ("blue", Colour(0x00, 0x00, 0xff)),
+    ("blueviolet", Colour(0x8a, 0x2b, 0xe2)),
+    ("brown", Colour(0xa5, 0x2a, 0x2a)),
+    ("burlywood", Colour(0xde, 0xb8, 0x87)),
+    ("cadetblue", Colour(0x5f, 0x9e, 0xa0)),
+    ("chartreuse", Colour(0x7f, 0xff, 0x00)),
+    ("chocolate", Colour(0xd2, 0x69, 0x1e)),
+    ("coral", Colour(0xff, 0x7f, 0x50)),
+    ("cornflowerblue", Colour(0x64, 0x95, 0xed)),
+    ("cornsilk", Colour(0xff, 0xf8, 0xdc)),
+    ("crimson", Colour(0xdc, 0x14, 0x3c)),
+    ("cyan", Colour(0x00, 0xff, 0xff)),
+    ("darkblue", Colour(0x00, 0x00, 0x8b)),
+    ("darkcyan", Colour(0x00, 0x8b, 0x8b)),
+    ("darkgoldenrod", Colour(0xb8, 0x86, 0x0b)),
+    ("darkgray", Colour(0xa9, 0xa9, 0xa9)),
+    ("darkgreen", Colour(0x00, 0x64, 0x00)),
+    ("darkgrey", Colour(0xa9, 0xa9, 0xa9)),
+    ("darkkhaki", Colour(0xbd, 0xb7, 0x6b)),
+    ("darkmagenta", Colour(0x8b, 0x00, 0x8b)),
+    ("darkolivegreen", Colour(0x55, 0x6b, 0x2f)),
+    ("darkorange", Colour(0xff, 0x8c, 0x00)),
+    ("darkorchid", Colour(0x99, 0x32, 0xcc)),
+    ("darkred", Colour(0x8b, 0x00, 0x00)),
+    ("darksalmon", Colour(0xe9, 0x96, 0x7a)),
+    ("darkseagreen", Colour(0x8f, 0xbc, 0x8f)),
+    ("darkslateblue", Colour(0x48, 0x3d, 0x8b)),
+    ("darkslategray", Colour(0x2f, 0x4f, 0x4f)),
+    ("darkslategrey", Colour(0x2f, 0x4f, 0x4f)),
+    ("darkturquoise", Colour(0x00, 0xce, 0xd1)),
+    ("darkviolet", Colour(0x94, 0x00, 0xd3)),
+    ("deeppink", Colour(0xff, 0x14, 0x93)),
+    ("deepskyblue", Colour(0x00, 0xbf, 0xff)),
+    ("dimgray", Colour(0x69, 0x69, 0x69)),
+    ("dimgrey", Colour(0x69, 0x69, 0x69)),
+    ("dodgerblue", Colour(0x1e, 0x90, 0xff)),
+    ("firebrick", Colour(0xb2, 0x22, 0x22)),
+    ("floralwhite", Colour(0xff, 0xfa, 0xf0)),
+    ("forestgreen", Colour(0x22, 0x8b, 0x22)),
+    ("fuchsia", Colour(0xff, 0x00, 0xff)),
+    ("gainsboro", Colour(0xdc, 0xdc, 0xdc)),
+    ("ghostwhite", Colour(0xf8, 0xf8, 0xff)),
+    ("gold", Colour(0xff, 0xd7, 0x00)),
+    ("goldenrod", Colour(0xda, 0xa5, 0x20)),
+    ("gray", Colour(0x80, 0x80, 0x80)),
+    ("green", Colour(0x00, 0x80, 0x00)),
+    ("greenyellow", Colour(0xad, 0xff, 0x2f)),
+    ("grey", Colour(0x80, 0x80, 0x80)),
+    ("honeydew", Colour(0xf0, 0xff, 0xf0)),
+    ("hotpink", Colour(0xff, 0x69, 0xb4)),
+    ("indianred", Colour(0xcd, 0x5c, 0x5c)),
+    ("indigo", Colour(0x4b, 0x00, 0x82)),
+    ("ivory", Colour(0xff, 0xff, 0xf0)),
+    ("khaki", Colour(0xf0, 0xe6, 0x8c)),
+    ("lavender", Colour(0xe6, 0xe6, 0xfa)),
+    ("lavenderblush", Colour(0xff, 0xf0, 0xf5)),
+    ("lawngreen", Colour(0x7c, 0xfc, 0x00)),
+    ("lemonchiffon", Colour(0xff, 0xfa, 0xcd)),
+    ("lightblue", Colour(0xad, 0xd8, 0xe6)),
+    ("lightcoral", Colour(0xf0, 0x80, 0x80)),
+    ("lightcyan", Colour(0xe0, 0xff, 0xff)),
+    ("lightgoldenrodyellow", Colour(0xfa, 0xfa, 0xd2)),
+    ("lightgray", Colour(0xd3, 0xd3, 0xd3)),
+    ("lightgreen", Colour(0x90, 0xee, 0x90)),
+    ("lightgrey", Colour(0xd3, 0xd3, 0xd3)),
+    ("lightpink", Colour(0xff, 0xb6, 0xc1)),
+    ("lightsalmon", Colour(0xff, 0xa0, 0x7a)),
+    ("lightseagreen", Colour(0x20, 0xb2, 0xaa)),
+    ("lightskyblue", Colour(0x87, 0xce, 0xfa)),
+    ("lightslategray", Colour(0x77, 0x88, 0x99)),
+    ("lightslategrey", Colour(0x77, 0x88, 0x99)),
+    ("lightsteelblue", Colour(0xb0, 0xc4, 0xde)),
+    ("lightyellow", Colour(0xff, 0xff, 0xe0)),
+    ("lime", Colour(0x00, 0xff, 0x00)),
+    ("limegreen", Colour(0x32, 0xcd, 0x32)),
+    ("linen", Colour(0xfa, 0xf0, 0xe6)),
+    ("magenta", Colour(0xff, 0x00, 0xff)),
+    ("maroon", Colour(0x80, 0x00, 0x00)),
+    ("mediumaquamarine", Colour(0x66, 0xcd, 0xaa)),
+    ("mediumblue", Colour(0x00, 0x00, 0xcd)),
+    ("mediumorchid", Colour(0xba, 0x55, 0xd3)),
+    ("mediumpurple", Colour(0x93, 0x70, 0xdb)),
+    ("mediumseagreen", Colour(0x3c, 0xb3, 0x71)),
+    ("mediumslateblue", Colour(0x7b, 0x68, 0xee)),
+    ("mediumspringgreen", Colour(0x00, 0xfa, 0x9a)),
+    ("mediumturquoise", Colour(0x48, 0xd1, 0xcc)),
+    ("mediumvioletred", Colour(0xc7, 0x15, 0x85)),
+    ("midnightblue", Colour(0x19, 0x19, 0x70)),
+    ("mintcream", Colour(0xf5, 0xff, 0xfa)),
+    ("mistyrose", Colour(0xff, 0xe4, 0xe1)),
+    ("moccasin", Colour(0xff, 0xe4, 0xb5)),
+    ("navajowhite", Colour(0xff, 0xde, 0xad)),
+    ("navy", Colour(0x00, 0x00, 0x80)),
+    ("oldlace", Colour(0xfd, 0xf5, 0xe6)),
+    ("olive", Colour(0x80, 0x80, 0x00)),
+    ("olivedrab", Colour(0x6b, 0x8e, 0x23)),
+    ("orange", Colour(0xff, 0xa5, 0x00)),
+    ("orangered", Colour(0xff, 0x45, 0x00)),
+    ("orchid", Colour(0xda, 0x70, 0xd6)),
+    ("palegoldenrod", Colour(0xee, 0xe8, 0xaa)),
+    ("palegreen", Colour(0x98, 0xfb, 0x98)),
+    ("paleturquoise", Colour(0xaf, 0xee, 0xee)),
+    ("palevioletred", Colour(0xdb, 0x70, 0x93)),
+    ("papayawhip", Colour(0xff, 0xef, 0xd5)),
+    ("peachpuff", Colour(0xff, 0xda, 0xb9)),
+    ("peru", Colour(0xcd, 0x85, 0x3f)),
+    ("pink", Colour(0xff, 0xc0, 0xcb)),
+    ("plum", Colour(0xdd, 0xa0, 0xdd)),
+    ("powderblue", Colour(0xb0, 0xe0, 0xe6)),
+    ("purple", Colour(0x80, 0x00, 0x80)),
+    ("rebeccapurple", Colour(0x66, 0x33, 0x99)),
+    ("red", Colour(0xff, 0x00, 0x00)),
+    ("rosybrown", Colour(0xbc, 0x8f, 0x8f)),
+    ("royalblue", Colour(0x41, 0x69, 0xe1)),
+    ("saddlebrown", Colour(0x8b, 0x45, 0x13)),
+    ("salmon", Colour(0xfa, 0x80, 0x72)),
+    ("sandybrown", Colour(0xf4, 0xa4, 0x60)),
+    ("seagreen", Colour(0x2e, 0x8b, 0x57)),
+    ("seashell", Colour(0xff, 0xf5, 0xee)),
+    ("sienna", Colour(0xa0, 0x52, 0x2d)),
+    ("silver", Colour(0xc0, 0xc0, 0xc0)),
+    ("skyblue", Colour(0x87, 0xce, 0xeb)),
+    ("slateblue", Colour(0x6a, 0x5a, 0xcd)),
+    ("slategray", Colour(0x70, 0x80, 0x90)),
+    ("slategrey", Colour(0x70, 0x80, 0x90)),
+    ("snow", Colour(0xff, 0xfa, 0xfa)),
+    ("springgreen", Colour(0x00, 0xff, 0x7f)),
+    ("steelblue", Colour(0x46, 0x82, 0xb4)),
+    ("tan", Colour(0xd2, 0xb4, 0x8c)),
+    ("teal", Colour(0x00, 0x80, 0x80)),
+    ("thistle", Colour(0xd8, 0xbf, 0xd8)),
+    ("tomato", Colour(0xff, 0x63, 0x47)),
+    ("turquoise", Colour(0x40, 0xe0, 0xd0)),
+    ("violet", Colour(0xee, 0x82, 0xee)),
+    ("wheat", Colour(0xf5, 0xde, 0xb3)),
+    ("white", Colour(0xff, 0xff, 0xff)),
+    ("whitesmoke", Colour(0xf5, 0xf5, 0xf5)),
+    ("yellow", Colour(0xff, 0xff, 0x00)),
+    ("yellowgreen", Colour(0x9a, 0xcd, 0x32))
+];
+
+// ignores case, as CSS does
+pub fn lookup(name: &str) -> Option<Colour> {
+    let name = name.to_ascii_lowercase();
+    CSS_COLOURS.binary_search_by(|(n, _)| n.cmp(&name.as_str())).ok().map(|i| CSS_COLOURS[i].1)
+}
+
+impl Colour {
+    pub fn name(self) -> Option<&'static str> {
+        CSS_COLOURS.iter().find(|(_, c)| *c == self).map(|(n, _)| *n)
+    }
+}
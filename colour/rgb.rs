@@ -0,0 +1,132 @@
+// Colour is 8-bit sRGB, the form colours are stored, sent and drawn in
+// Srgb is the same colour as floats from 0 to 1, the other colour spaces (see colour/spaces.rs) are converted
+// through it, so a chain of conversions only rounds once, when it comes back to a Colour
+
+use std::fmt;
+
+use crate::Colour;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColourError {
+    OutOfRange { component: &'static str, value: f64, min: f64, max: f64 },
+    NotANumber(&'static str)  // NaN or infinite
+}
+
+impl fmt::Display for ColourError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColourError::OutOfRange { component, value, min, max } => write!(f, "{component} must be between {min} and {max}, not {value}"),
+            ColourError::NotANumber(component) => write!(f, "{component} must be a number")
+        }
+    }
+}
+
+impl std::error::Error for ColourError {}
+
+// the check every validating constructor makes, min and max are allowed
+pub fn in_range(component: &'static str, value: f64, min: f64, max: f64) -> Result<f64, ColourError> {
+    if !value.is_finite() {
+        return Err(ColourError::NotANumber(component));
+    }
+    if value < min || value > max {
+        return Err(ColourError::OutOfRange { component, value, min, max });
+    }
+    Ok(value)
+}
+
+impl Colour {
+    // for channels that arrive as wider numbers, a u8 literal can go straight into Colour(r, g, b)
+    pub fn new(red: i32, green: i32, blue: i32) -> Result<Colour, ColourError> {
+        let channel = |component, value: i32| in_range(component, value as f64, 0.0, 255.0).map(|v| v as u8);
+        Ok(Colour(channel("red", red)?, channel("green", green)?, channel("blue", blue)?))
+    }
+
+    pub fn red(self) -> u8 {
+        self.0
+    }
+
+    pub fn green(self) -> u8 {
+        self.1
+    }
+
+    pub fn blue(self) -> u8 {
+        self.2
+    }
+
+    pub fn channels(self) -> [u8; 3] {
+        [self.0, self.1, self.2]
+    }
+
+    pub fn to_srgb(self) -> Srgb {
+        Srgb { r: self.0 as f64 / 255.0, g: self.1 as f64 / 255.0, b: self.2 as f64 / 255.0 }
+    }
+
+    // the nearest Colour, anything outside the sRGB gamut is clamped channel by channel
+    pub fn from_srgb(srgb: Srgb) -> Colour {
+        let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Colour(channel(srgb.r), channel(srgb.g), channel(srgb.b))
+    }
+}
+
+// gamma-encoded sRGB, each channel from 0 to 1 when the colour is in gamut
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Srgb {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64
+}
+
+impl Srgb {
+    pub fn new(r: f64, g: f64, b: f64) -> Result<Srgb, ColourError> {
+        Ok(Srgb { r: in_range("red", r, 0.0, 1.0)?, g: in_range("green", g, 0.0, 1.0)?, b: in_range("blue", b, 0.0, 1.0)? })
+    }
+
+    // Lab and OKLab reach colours a screen cannot show, they come back with channels past 0 or 1
+    pub fn in_gamut(&self) -> bool {
+        const EPSILON: f64 = 1e-9;  // rounding error on the way back from another space
+        [self.r, self.g, self.b].iter().all(|c| (-EPSILON..=1.0 + EPSILON).contains(c))
+    }
+
+    pub fn clamped(&self) -> Srgb {
+        Srgb { r: self.r.clamp(0.0, 1.0), g: self.g.clamp(0.0, 1.0), b: self.b.clamp(0.0, 1.0) }
+    }
+}
+
+impl From<Colour> for Srgb {
+    fn from(colour: Colour) -> Srgb {
+        colour.to_srgb()
+    }
+}
+
+impl From<Srgb> for Colour {
+    fn from(srgb: Srgb) -> Colour {
+        Colour::from_srgb(srgb)
+    }
+}
+
+// a Colour with an alpha from 0 (transparent) to 1 (opaque), what #rrggbbaa and rgba() read into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgba {
+    pub colour: Colour,
+    pub alpha: f64
+}
+
+impl Rgba {
+    pub fn new(colour: Colour, alpha: f64) -> Result<Rgba, ColourError> {
+        Ok(Rgba { colour, alpha: in_range("alpha", alpha, 0.0, 1.0)? })
+    }
+
+    pub fn opaque(colour: Colour) -> Rgba {
+        Rgba { colour, alpha: 1.0 }
+    }
+
+    pub fn is_opaque(self) -> bool {
+        self.alpha >= 1.0
+    }
+}
+
+impl From<Colour> for Rgba {
+    fn from(colour: Colour) -> Rgba {
+        Rgba::opaque(colour)
+    }
+}
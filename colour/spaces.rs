@@ -0,0 +1,436 @@
+// the colour spaces a Colour converts to and from
+//
+//     Hsl, Hsv      hue in degrees, the rest from 0 to 1        what designers pick colours in
+//     Cmyk          ink coverage from 0 to 1                     what the print vendor wants
+//     LinearRgb     sRGB with the gamma curve taken off          where light adds up, for blending
+//     Xyz           CIE 1931, D65 white, Y from 0 to 1           the bridge to Lab
+//     Lab           CIELAB, D65 white, L from 0 to 100           perceptual, for colour differences
+//     Oklab         L from 0 to 1                                perceptual, better behaved hues than Lab
+//
+//     Colour <-> Srgb <-> Hsl | Hsv | Cmyk
+//                 Srgb <-> LinearRgb <-> Xyz <-> Lab
+//                              LinearRgb <-> Oklab
+//
+// the matrices are the published ones: IEC 61966-2-1 for sRGB to XYZ, Björn Ottosson's for OKLab
+// converting is exact up to floating point, only coming back to a Colour rounds and clamps
+
+use crate::colour::rgb::{in_range, ColourError, Srgb};
+use crate::Colour;
+
+// the D65 white point, the XYZ of sRGB white
+pub const D65: Xyz = Xyz { x: 0.95047, y: 1.0, z: 1.08883 };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsl {
+    pub h: f64,  // degrees, 0 up to 360
+    pub s: f64,
+    pub l: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsv {
+    pub h: f64,  // degrees, 0 up to 360
+    pub s: f64,
+    pub v: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cmyk {
+    pub c: f64,
+    pub m: f64,
+    pub y: f64,
+    pub k: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearRgb {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Xyz {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,  // green (negative) to red (positive)
+    pub b: f64  // blue (negative) to yellow (positive)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oklab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64
+}
+
+impl Hsl {
+    // any hue is accepted and turned into 0 up to 360, so -30 and 690 are both 330
+    pub fn new(h: f64, s: f64, l: f64) -> Result<Hsl, ColourError> {
+        Ok(Hsl { h: hue("hue", h)?, s: in_range("saturation", s, 0.0, 1.0)?, l: in_range("lightness", l, 0.0, 1.0)? })
+    }
+}
+
+impl Hsv {
+    pub fn new(h: f64, s: f64, v: f64) -> Result<Hsv, ColourError> {
+        Ok(Hsv { h: hue("hue", h)?, s: in_range("saturation", s, 0.0, 1.0)?, v: in_range("value", v, 0.0, 1.0)? })
+    }
+}
+
+impl Cmyk {
+    pub fn new(c: f64, m: f64, y: f64, k: f64) -> Result<Cmyk, ColourError> {
+        Ok(Cmyk {
+            c: in_range("cyan", c, 0.0, 1.0)?,
+            m: in_range("magenta", m, 0.0, 1.0)?,
+            y: in_range("yellow", y, 0.0, 1.0)?,
+            k: in_range("black", k, 0.0, 1.0)?
+        })
+    }
+}
+
+fn hue(component: &'static str, degrees: f64) -> Result<f64, ColourError> {
+    if !degrees.is_finite() {
+        return Err(ColourError::NotANumber(component));
+    }
+    Ok(degrees.rem_euclid(360.0))
+}
+
+// the hue every cylindrical space shares, 0 for greys, which have none
+fn hue_of(srgb: &Srgb, max: f64, chroma: f64) -> f64 {
+    if chroma == 0.0 {
+        return 0.0;
+    }
+    let Srgb { r, g, b } = *srgb;
+    let sector = if max == r {
+        (g - b) / chroma
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    (sector * 60.0).rem_euclid(360.0)
+}
+
+// the inverse of hue_of, chroma spread over the channels, before lightness is added
+fn from_hue(h: f64, chroma: f64) -> (f64, f64, f64) {
+    let sector = h.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x)
+    }
+}
+
+fn max_min(srgb: &Srgb) -> (f64, f64) {
+    (srgb.r.max(srgb.g).max(srgb.b), srgb.r.min(srgb.g).min(srgb.b))
+}
+
+impl From<Srgb> for Hsl {
+    fn from(srgb: Srgb) -> Hsl {
+        let (max, min) = max_min(&srgb);
+        let chroma = max - min;
+        let l = (max + min) / 2.0;
+        let s = if chroma == 0.0 { 0.0 } else { chroma / (1.0 - (2.0 * l - 1.0).abs()) };
+        Hsl { h: hue_of(&srgb, max, chroma), s, l }
+    }
+}
+
+impl From<Hsl> for Srgb {
+    fn from(hsl: Hsl) -> Srgb {
+        let chroma = (1.0 - (2.0 * hsl.l - 1.0).abs()) * hsl.s;
+        let (r, g, b) = from_hue(hsl.h, chroma);
+        let m = hsl.l - chroma / 2.0;
+        Srgb { r: r + m, g: g + m, b: b + m }
+    }
+}
+
+impl From<Srgb> for Hsv {
+    fn from(srgb: Srgb) -> Hsv {
+        let (max, min) = max_min(&srgb);
+        let chroma = max - min;
+        let s = if max == 0.0 { 0.0 } else { chroma / max };
+        Hsv { h: hue_of(&srgb, max, chroma), s, v: max }
+    }
+}
+
+impl From<Hsv> for Srgb {
+    fn from(hsv: Hsv) -> Srgb {
+        let chroma = hsv.v * hsv.s;
+        let (r, g, b) = from_hue(hsv.h, chroma);
+        let m = hsv.v - chroma;
+        Srgb { r: r + m, g: g + m, b: b + m }
+    }
+}
+
+// naive CMYK with full black generation, the vendor's ICC profile is what turns it into ink on their press
+impl From<Srgb> for Cmyk {
+    fn from(srgb: Srgb) -> Cmyk {
+        let (max, _) = max_min(&srgb);
+        let k = 1.0 - max;
+        if max == 0.0 {
+            return Cmyk { c: 0.0, m: 0.0, y: 0.0, k: 1.0 };
+        }
+        let ink = |channel: f64| (max - channel) / max;
+        Cmyk { c: ink(srgb.r), m: ink(srgb.g), y: ink(srgb.b), k }
+    }
+}
+
+impl From<Cmyk> for Srgb {
+    fn from(cmyk: Cmyk) -> Srgb {
+        let light = |ink: f64| (1.0 - ink) * (1.0 - cmyk.k);
+        Srgb { r: light(cmyk.c), g: light(cmyk.m), b: light(cmyk.y) }
+    }
+}
+
+// the sRGB transfer function, a short straight segment near black and a 2.4 power curve above it
+fn decode(channel: f64) -> f64 {
+    if channel.abs() <= 0.04045 { channel / 12.92 } else { channel.signum() * ((channel.abs() + 0.055) / 1.055).powf(2.4) }
+}
+
+fn encode(channel: f64) -> f64 {
+    if channel.abs() <= 0.0031308 { channel * 12.92 } else { channel.signum() * (1.055 * channel.abs().powf(1.0 / 2.4) - 0.055) }
+}
+
+impl From<Srgb> for LinearRgb {
+    fn from(srgb: Srgb) -> LinearRgb {
+        LinearRgb { r: decode(srgb.r), g: decode(srgb.g), b: decode(srgb.b) }
+    }
+}
+
+impl From<LinearRgb> for Srgb {
+    fn from(linear: LinearRgb) -> Srgb {
+        Srgb { r: encode(linear.r), g: encode(linear.g), b: encode(linear.b) }
+    }
+}
+
+impl From<LinearRgb> for Xyz {
+    fn from(rgb: LinearRgb) -> Xyz {
+        Xyz {
+            x: 0.4124564 * rgb.r + 0.3575761 * rgb.g + 0.1804375 * rgb.b,
+            y: 0.2126729 * rgb.r + 0.7151522 * rgb.g + 0.0721750 * rgb.b,
+            z: 0.0193339 * rgb.r + 0.1191920 * rgb.g + 0.9503041 * rgb.b
+        }
+    }
+}
+
+impl From<Xyz> for LinearRgb {
+    fn from(xyz: Xyz) -> LinearRgb {
+        LinearRgb {
+            r: 3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+            g: -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+            b: 0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z
+        }
+    }
+}
+
+// Lab's cube root, with a straight segment near zero so dark colours do not blow up
+const DELTA: f64 = 6.0 / 29.0;
+
+fn lab_f(t: f64) -> f64 {
+    if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3.0 * DELTA * DELTA) + 4.0 / 29.0 }
+}
+
+fn lab_f_inverse(t: f64) -> f64 {
+    if t > DELTA { t * t * t } else { 3.0 * DELTA * DELTA * (t - 4.0 / 29.0) }
+}
+
+impl From<Xyz> for Lab {
+    fn from(xyz: Xyz) -> Lab {
+        let (fx, fy, fz) = (lab_f(xyz.x / D65.x), lab_f(xyz.y / D65.y), lab_f(xyz.z / D65.z));
+        Lab { l: 116.0 * fy - 16.0, a: 500.0 * (fx - fy), b: 200.0 * (fy - fz) }
+    }
+}
+
+impl From<Lab> for Xyz {
+    fn from(lab: Lab) -> Xyz {
+        let fy = (lab.l + 16.0) / 116.0;
+        let (fx, fz) = (fy + lab.a / 500.0, fy - lab.b / 200.0);
+        Xyz { x: D65.x * lab_f_inverse(fx), y: D65.y * lab_f_inverse(fy), z: D65.z * lab_f_inverse(fz) }
+    }
+}
+
+impl From<LinearRgb> for Oklab {
+    fn from(rgb: LinearRgb) -> Oklab {
+        let l = (0.4122214708 * rgb.r + 0.5363325363 * rgb.g + 0.0514459929 * rgb.b).cbrt();
+        let m = (0.2119034982 * rgb.r + 0.6806995451 * rgb.g + 0.1073969566 * rgb.b).cbrt();
+        let s = (0.0883024619 * rgb.r + 0.2817188376 * rgb.g + 0.6299787005 * rgb.b).cbrt();
+        Oklab {
+            l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s
+        }
+    }
+}
+
+impl From<Oklab> for LinearRgb {
+    fn from(lab: Oklab) -> LinearRgb {
+        let l = (lab.l + 0.3963377774 * lab.a + 0.2158037573 * lab.b).powi(3);
+        let m = (lab.l - 0.1055613458 * lab.a - 0.0638541728 * lab.b).powi(3);
+        let s = (lab.l - 0.0894841775 * lab.a - 1.2914855480 * lab.b).powi(3);
+        LinearRgb {
+            r: 4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+            g: -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+            b: -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s
+        }
+    }
+}
+
+// the longer paths, so every space can be reached from Srgb in one step
+impl From<Srgb> for Xyz {
+    fn from(srgb: Srgb) -> Xyz {
+        LinearRgb::from(srgb).into()
+    }
+}
+
+impl From<Xyz> for Srgb {
+    fn from(xyz: Xyz) -> Srgb {
+        LinearRgb::from(xyz).into()
+    }
+}
+
+impl From<Srgb> for Lab {
+    fn from(srgb: Srgb) -> Lab {
+        Xyz::from(srgb).into()
+    }
+}
+
+impl From<Lab> for Srgb {
+    fn from(lab: Lab) -> Srgb {
+        Xyz::from(lab).into()
+    }
+}
+
+impl From<Srgb> for Oklab {
+    fn from(srgb: Srgb) -> Oklab {
+        LinearRgb::from(srgb).into()
+    }
+}
+
+impl From<Oklab> for Srgb {
+    fn from(lab: Oklab) -> Srgb {
+        LinearRgb::from(lab).into()
+    }
+}
+
+// coming back to a Colour rounds to the nearest byte and clamps whatever is out of gamut
+impl Colour {
+    pub fn to_hsl(self) -> Hsl {
+        self.to_srgb().into()
+    }
+
+    pub fn from_hsl(hsl: Hsl) -> Colour {
+        Colour::from_srgb(hsl.into())
+    }
+
+    pub fn to_hsv(self) -> Hsv {
+        self.to_srgb().into()
+    }
+
+    pub fn from_hsv(hsv: Hsv) -> Colour {
+        Colour::from_srgb(hsv.into())
+    }
+
+    pub fn to_cmyk(self) -> Cmyk {
+        self.to_srgb().into()
+    }
+
+    pub fn from_cmyk(cmyk: Cmyk) -> Colour {
+        Colour::from_srgb(cmyk.into())
+    }
+
+    pub fn to_linear(self) -> LinearRgb {
+        self.to_srgb().into()
+    }
+
+    pub fn from_linear(linear: LinearRgb) -> Colour {
+        Colour::from_srgb(linear.into())
+    }
+
+    pub fn to_xyz(self) -> Xyz {
+        self.to_srgb().into()
+    }
+
+    pub fn from_xyz(xyz: Xyz) -> Colour {
+        Colour::from_srgb(xyz.into())
+    }
+
+    pub fn to_lab(self) -> Lab {
+        self.to_srgb().into()
+    }
+
+    pub fn from_lab(lab: Lab) -> Colour {
+        Colour::from_srgb(lab.into())
+    }
+
+    pub fn to_oklab(self) -> Oklab {
+        self.to_srgb().into()
+    }
+
+    pub fn from_oklab(lab: Oklab) -> Colour {
+        Colour::from_srgb(lab.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every channel at 0, 1, 17, 34, ... 255 and 254, 18^3 colours including the corners of the cube
+    fn grid() -> Vec<Colour> {
+        let steps: Vec<u8> = (0..=255).step_by(17).chain([1, 254]).collect();
+        let mut colours = Vec::new();
+        for &r in &steps {
+            for &g in &steps {
+                for &b in &steps {
+                    colours.push(Colour(r, g, b));
+                }
+            }
+        }
+        colours
+    }
+
+    #[test]
+    fn every_space_round_trips_back_to_the_same_colour() {
+        for colour in grid() {
+            assert_eq!(Colour::from_hsl(colour.to_hsl()), colour, "HSL");
+            assert_eq!(Colour::from_hsv(colour.to_hsv()), colour, "HSV");
+            assert_eq!(Colour::from_cmyk(colour.to_cmyk()), colour, "CMYK");
+            assert_eq!(Colour::from_linear(colour.to_linear()), colour, "linear");
+            assert_eq!(Colour::from_xyz(colour.to_xyz()), colour, "XYZ");
+            assert_eq!(Colour::from_lab(colour.to_lab()), colour, "Lab");
+            assert_eq!(Colour::from_oklab(colour.to_oklab()), colour, "OKLab");
+        }
+    }
+
+    #[test]
+    fn white_is_the_d65_white_point() {
+        let white = Colour(255, 255, 255);
+        let (xyz, lab, oklab) = (white.to_xyz(), white.to_lab(), white.to_oklab());
+        assert!((xyz.x - D65.x).abs() < 1e-4 && (xyz.y - D65.y).abs() < 1e-4 && (xyz.z - D65.z).abs() < 1e-4, "{xyz:?}");
+        assert!((lab.l - 100.0).abs() < 1e-3 && lab.a.abs() < 1e-3 && lab.b.abs() < 1e-3, "{lab:?}");
+        assert!((oklab.l - 1.0).abs() < 1e-3 && oklab.a.abs() < 1e-3 && oklab.b.abs() < 1e-3, "{oklab:?}");
+    }
+
+    #[test]
+    fn channels_outside_a_byte_are_rejected() {
+        assert_eq!(
+            Colour::new(-5, 900, 0),
+            Err(ColourError::OutOfRange { component: "red", value: -5.0, min: 0.0, max: 255.0 })
+        );
+        assert!(matches!(Colour::new(0, 900, 0), Err(ColourError::OutOfRange { component: "green", .. })));
+        assert_eq!(Colour::new(0, 255, 128), Ok(Colour(0, 255, 128)));
+        assert!(Hsl::new(30.0, 1.5, 0.5).is_err());
+        assert_eq!(Hsl::new(-30.0, 0.5, 0.5).map(|hsl| hsl.h), Ok(330.0));
+        assert!(Cmyk::new(0.0, 0.0, 0.0, f64::NAN).is_err());
+    }
+}
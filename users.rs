@@ -0,0 +1,32 @@
+// the users module holds everything built on top of the User struct in the crate root
+// each submodule lives in its own file under users/, the same layout described in Basics4.rs
+// child modules can see the private fields of User because User is defined in an ancestor module (the crate root)
+
+pub mod analytics;
+pub mod api;
+pub mod auth;
+pub mod bulk;
+pub mod clock;
+pub mod crypto;
+pub mod duplicates;
+pub mod http;
+pub mod identicon;
+pub mod json;
+pub mod lifecycle;
+pub mod logging;
+pub mod mail;
+pub mod migrate;
+pub mod orgs;
+pub mod patch;
+pub mod privacy;
+pub mod query;
+pub mod rbac;
+pub mod record;
+pub mod redact;
+pub mod roles;
+pub mod search;
+pub mod session;
+pub mod store;
+pub mod totp;
+pub mod validation;
+pub mod verification;
@@ -0,0 +1,450 @@
+// sign-in analytics: who signed in when, and the weekly numbers product asks for
+//
+// sign_in_count only says how often, so every successful sign-in is also kept as an event in a SignInLog,
+// in memory or appended to a file of JSON lines ({"username":"AHus","at":1700000000})
+// an Authenticator given the log with with_sign_in_log records into it
+//
+//     active users    distinct users signed in during the last 1, 7 and 30 days (DAU, WAU, MAU)
+//     retention       users grouped by the week they signed up, and how many of each group signed in
+//                     during each week after that, week 0 being the signup week itself
+//     churn           active accounts whose last sign-in is longer ago than a cutoff
+//
+// weeks start on Monday and days at midnight, both in UTC, and every report can be written out as CSV
+// events are kept by username, so call rename when a user is renamed to keep their history together
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::users::bulk::csv_escape;
+use crate::users::json::Json;
+use crate::users::lifecycle::AccountState;
+use crate::users::privacy::PersonalData;
+use crate::users::store::UserStore;
+use crate::User;
+
+pub const DAY: u64 = 24 * 60 * 60;
+pub const WEEK: u64 = 7 * DAY;
+pub const MONTH: u64 = 30 * DAY;  // the MAU window, not a calendar month
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignInEvent {
+    pub username: String,
+    pub at: u64
+}
+
+impl SignInEvent {
+    fn to_json(&self) -> Json {
+        Json::object(vec![("username", Json::str(&self.username)), ("at", Json::uint(self.at))])
+    }
+
+    fn from_json(value: &Json) -> Option<SignInEvent> {
+        Some(SignInEvent { username: value.get("username")?.as_str()?.to_string(), at: value.get("at")?.as_u64()? })
+    }
+}
+
+pub struct SignInLog {
+    events: Vec<SignInEvent>,  // in the order they were recorded
+    file: Option<(PathBuf, File)>
+}
+
+impl Default for SignInLog {
+    fn default() -> Self {
+        SignInLog::new()
+    }
+}
+
+impl SignInLog {
+    // a log that lives as long as the value does
+    pub fn new() -> Self {
+        SignInLog { events: Vec::new(), file: None }
+    }
+
+    // reads the events already in the file and appends new ones to it
+    // a half-written last line, left by a crash, is dropped
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut events = Vec::new();
+        let mut torn_tail = false;
+        if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = contents.lines().collect();
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match Json::parse(line).ok().as_ref().and_then(SignInEvent::from_json) {
+                    Some(event) => events.push(event),
+                    None if index + 1 == lines.len() && !contents.ends_with('\n') => torn_tail = true,
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: bad sign-in event on line {}", path.display(), index + 1)))
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut log = SignInLog { events, file: Some((path, file)) };
+        if torn_tail {
+            log.rewrite()?;
+        }
+        Ok(log)
+    }
+
+    pub fn record(&mut self, username: &str, at: u64) -> io::Result<()> {
+        let event = SignInEvent { username: username.to_string(), at };
+        if let Some((_, file)) = &mut self.file {
+            writeln!(file, "{}", event.to_json())?;
+        }
+        self.events.push(event);
+        Ok(())
+    }
+
+    pub fn events(&self) -> &[SignInEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    // moves a user's events over to their new name, returns how many moved
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<usize> {
+        let mut moved = 0;
+        for event in self.events.iter_mut().filter(|e| e.username == from) {
+            event.username = to.to_string();
+            moved += 1;
+        }
+        if moved > 0 {
+            self.rewrite()?;
+        }
+        Ok(moved)
+    }
+
+    // moves one of from's events at each of the times over to `to`, returns how many moved
+    // merge_users uses it to hand sign-ins back when a merge is undone, rename would take the survivor's own too
+    pub fn reassign(&mut self, from: &str, to: &str, times: &[u64]) -> io::Result<usize> {
+        let mut moved = 0;
+        for at in times {
+            if let Some(event) = self.events.iter_mut().find(|e| e.username == from && e.at == *at) {
+                event.username = to.to_string();
+                moved += 1;
+            }
+        }
+        if moved > 0 {
+            self.rewrite()?;
+        }
+        Ok(moved)
+    }
+
+    // writes every event out again through a temporary file, so old usernames do not stay on disk
+    fn rewrite(&mut self) -> io::Result<()> {
+        let Some((path, _)) = &self.file else { return Ok(()) };
+        let path = path.clone();
+        let tmp = path.with_extension("rewrite");
+        {
+            let mut out = File::create(&tmp)?;
+            for event in &self.events {
+                writeln!(out, "{}", event.to_json())?;
+            }
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        self.file = Some((path, file));
+        Ok(())
+    }
+}
+
+impl PersonalData for SignInLog {
+    fn name(&self) -> &str {
+        "sign_ins"
+    }
+
+    fn export(&self, subject: &User) -> Json {
+        let times: Vec<Json> = self.events.iter().filter(|e| e.username == subject.username).map(|e| Json::uint(e.at)).collect();
+        if times.is_empty() { Json::Null } else { Json::Array(times) }
+    }
+
+    // the times stay, they are what the analytics need and say nothing on their own
+    fn erase(&mut self, subject: &User, pseudonym: &str, _email: &str) -> Result<usize, String> {
+        self.rename(&subject.username, pseudonym).map_err(|e| e.to_string())
+    }
+
+    fn all_identifiers(&self) -> Vec<String> {
+        self.events.iter().map(|e| e.username.clone()).collect()
+    }
+}
+
+// midnight UTC at the start of the day `at` falls in
+pub fn day_start(at: u64) -> u64 {
+    at - at % DAY
+}
+
+// midnight UTC on the Monday of the week `at` falls in, 1 January 1970 was a Thursday
+pub fn week_start(at: u64) -> u64 {
+    let day = at / DAY;
+    day.saturating_sub((day + 3) % 7) * DAY
+}
+
+// the date as YYYY-MM-DD in UTC
+pub fn date(at: u64) -> String {
+    // Howard Hinnant's civil_from_days, counting from 1 March so the leap day falls at the end of a year
+    let z = (at / DAY) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+// distinct users with a sign-in at or after `from` and before `to`
+pub fn active_users(events: &[SignInEvent], from: u64, to: u64) -> usize {
+    events.iter().filter(|e| (from..to).contains(&e.at)).map(|e| e.username.as_str()).collect::<BTreeSet<_>>().len()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActiveUsers {
+    pub at: u64,  // the end of the windows, which reach back 1, 7 and 30 days from here
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize
+}
+
+impl ActiveUsers {
+    pub fn at(events: &[SignInEvent], at: u64) -> Self {
+        ActiveUsers {
+            at,
+            daily: active_users(events, at.saturating_sub(DAY), at),
+            weekly: active_users(events, at.saturating_sub(WEEK), at),
+            monthly: active_users(events, at.saturating_sub(MONTH), at)
+        }
+    }
+
+    // DAU / MAU, how much of the monthly audience comes back on a given day
+    pub fn stickiness(&self) -> f64 {
+        if self.monthly == 0 { 0.0 } else { self.daily as f64 / self.monthly as f64 }
+    }
+}
+
+// one row per UTC day from the day `from` falls in up to the day before `to`, each measured at the end of its day
+pub fn daily_activity(events: &[SignInEvent], from: u64, to: u64) -> Vec<ActiveUsers> {
+    let mut rows = Vec::new();
+    let mut day = day_start(from);
+    while day < to {
+        rows.push(ActiveUsers::at(events, day + DAY));
+        day += DAY;
+    }
+    rows
+}
+
+pub fn activity_csv(rows: &[ActiveUsers]) -> String {
+    let mut out = String::from("date,dau,wau,mau,stickiness\r\n");
+    for row in rows {
+        // a row is measured at midnight, the end of the day it reports on
+        out.push_str(&format!("{},{},{},{},{:.3}\r\n", date(row.at.saturating_sub(1)), row.daily, row.weekly, row.monthly, row.stickiness()));
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cohort {
+    pub week_start: u64,
+    pub users: Vec<String>,  // who signed up that week
+    pub retained: Vec<usize>  // retained[k] of them signed in during week k after signing up, weeks that have not begun are left out
+}
+
+impl Cohort {
+    pub fn rate(&self, week: usize) -> Option<f64> {
+        let retained = *self.retained.get(week)?;
+        Some(if self.users.is_empty() { 0.0 } else { retained as f64 / self.users.len() as f64 })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionTable {
+    pub weeks: usize,  // columns in the table, week 0 to weeks - 1
+    pub cohorts: Vec<Cohort>  // oldest first
+}
+
+// cohorts by signup week for users created at or after `since`, as of `now`
+// accounts from before created_at was recorded (created_at 0) have no signup week and are left out
+pub fn retention(store: &dyn UserStore, events: &[SignInEvent], since: u64, now: u64, weeks: usize) -> RetentionTable {
+    let mut signups: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for user in store.list() {
+        let created = user.lifecycle.created_at();
+        if created > 0 && created >= since && created < now {
+            signups.entry(week_start(created)).or_default().push(user.username.clone());
+        }
+    }
+
+    let mut active_weeks: HashMap<&str, BTreeSet<u64>> = HashMap::new();
+    for event in events.iter().filter(|e| e.at < now) {
+        active_weeks.entry(event.username.as_str()).or_default().insert(week_start(event.at));
+    }
+
+    let cohorts = signups.into_iter().map(|(start, users)| {
+        let begun = ((week_start(now) - start) / WEEK) as usize + 1;
+        let retained = (0..weeks.min(begun)).map(|k| {
+            let week = start + k as u64 * WEEK;
+            users.iter().filter(|u| active_weeks.get(u.as_str()).is_some_and(|w| w.contains(&week))).count()
+        }).collect();
+        Cohort { week_start: start, users, retained }
+    });
+    RetentionTable { weeks, cohorts: cohorts.collect() }
+}
+
+// one row per cohort, each week as the percentage of the cohort that signed in, empty for weeks still to come
+pub fn retention_csv(table: &RetentionTable) -> String {
+    let mut out = String::from("cohort,users");
+    for k in 0..table.weeks {
+        out.push_str(&format!(",week_{k}"));
+    }
+    out.push_str("\r\n");
+    for cohort in &table.cohorts {
+        out.push_str(&format!("{},{}", date(cohort.week_start), cohort.users.len()));
+        for k in 0..table.weeks {
+            match cohort.rate(k) {
+                Some(rate) => out.push_str(&format!(",{:.1}", rate * 100.0)),
+                None => out.push(',')
+            }
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Churned {
+    pub username: String,
+    pub last_sign_in: u64,
+    pub days_inactive: u64
+}
+
+// active accounts that signed in at least once but not in the `inactive_for` seconds before `now`, longest gone first
+// the last sign-in is taken from the events, or from the account itself for sign-ins from before the log
+pub fn churned(store: &dyn UserStore, events: &[SignInEvent], now: u64, inactive_for: u64) -> Vec<Churned> {
+    let mut last: HashMap<&str, u64> = HashMap::new();
+    for event in events.iter().filter(|e| e.at < now) {
+        let seen = last.entry(event.username.as_str()).or_insert(event.at);
+        *seen = (*seen).max(event.at);
+    }
+    let mut churned: Vec<Churned> = store.list().into_iter().filter(|u| *u.state() == AccountState::Active).filter_map(|user| {
+        let from_events = last.get(user.username.as_str()).copied();
+        let last_sign_in = from_events.max(user.credentials().last_sign_in().filter(|t| *t < now))?;
+        (now - last_sign_in >= inactive_for).then(|| Churned {
+            username: user.username.clone(),
+            last_sign_in,
+            days_inactive: (now - last_sign_in) / DAY
+        })
+    }).collect();
+    churned.sort_by(|a, b| a.last_sign_in.cmp(&b.last_sign_in).then_with(|| a.username.cmp(&b.username)));
+    churned
+}
+
+// of the users active in the period before the last one, the share that did not come back in the last one
+pub fn churn_rate(events: &[SignInEvent], now: u64, period: u64) -> f64 {
+    let users_between = |from: u64, to: u64| -> BTreeSet<&str> {
+        events.iter().filter(|e| (from..to).contains(&e.at)).map(|e| e.username.as_str()).collect()
+    };
+    let before = users_between(now.saturating_sub(2 * period), now.saturating_sub(period));
+    if before.is_empty() {
+        return 0.0;
+    }
+    let recent = users_between(now.saturating_sub(period), now);
+    before.difference(&recent).count() as f64 / before.len() as f64
+}
+
+pub fn churn_csv(rows: &[Churned]) -> String {
+    let mut out = String::from("username,last_sign_in,days_inactive\r\n");
+    for row in rows {
+        out.push_str(&format!("{},{},{}\r\n", csv_escape(&row.username), date(row.last_sign_in), row.days_inactive));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::lifecycle::Lifecycle;
+    use crate::users::store::MemoryUserStore;
+
+    const MONDAY: u64 = 1_704_067_200;  // 2024-01-01 00:00 UTC
+
+    fn event(username: &str, at: u64) -> SignInEvent {
+        SignInEvent { username: username.to_string(), at }
+    }
+
+    fn user_created(username: &str, at: u64) -> User {
+        let mut user = crate::build_user(format!("{username}@example.com"), username.to_string()).unwrap();
+        user.lifecycle = Lifecycle::new(AccountState::Active, at);
+        user
+    }
+
+    #[test]
+    fn weeks_start_on_monday_at_midnight_utc() {
+        assert_eq!(date(MONDAY), "2024-01-01");
+        assert_eq!(week_start(MONDAY), MONDAY);
+        assert_eq!(week_start(MONDAY + WEEK - 1), MONDAY);  // the Sunday just before midnight
+        assert_eq!(week_start(MONDAY - 1), MONDAY - WEEK);
+        assert_eq!(date(week_start(MONDAY - 1)), "2023-12-25");
+        assert_eq!(day_start(MONDAY + DAY + 5), MONDAY + DAY);
+        assert_eq!(date(951_782_400), "2000-02-29");
+        assert_eq!(week_start(0), 0);  // 1 January 1970 was a Thursday, there is no Monday before it to go back to
+    }
+
+    #[test]
+    fn retention_has_a_column_for_each_week_that_has_begun() {
+        let mut store = MemoryUserStore::new();
+        store.insert(user_created("ann", MONDAY + DAY)).unwrap();
+        store.insert(user_created("bob", MONDAY + 6 * DAY)).unwrap();
+        store.insert(user_created("cat", MONDAY + WEEK)).unwrap();
+        // ann comes back in week 1 only, bob in week 0 only, on the last second of the Sunday
+        let events = [event("ann", MONDAY + DAY), event("ann", MONDAY + WEEK), event("bob", MONDAY + WEEK - 1), event("cat", MONDAY + WEEK + 1)];
+        let now = MONDAY + WEEK + 3 * DAY;
+
+        let table = retention(&store, &events, MONDAY, now, 4);
+        assert_eq!(table.cohorts.len(), 2);
+        let (first, second) = (&table.cohorts[0], &table.cohorts[1]);
+        assert_eq!((first.week_start, first.users.len(), first.retained.clone()), (MONDAY, 2, vec![2, 1]));
+        assert_eq!((second.week_start, second.users.len(), second.retained.clone()), (MONDAY + WEEK, 1, vec![1]));
+        assert_eq!(first.rate(2), None);
+
+        let csv = retention_csv(&table);
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines[0], "cohort,users,week_0,week_1,week_2,week_3");
+        assert_eq!(lines[1], "2024-01-01,2,100.0,50.0,,");
+        assert_eq!(lines[2], "2024-01-08,1,100.0,,,");
+    }
+
+    #[test]
+    fn daily_activity_rows_are_dated_by_the_day_they_cover() {
+        let events = [event("ann", MONDAY + 10), event("bob", MONDAY + DAY + 10), event("ann", MONDAY + DAY + 20)];
+        let rows = daily_activity(&events, MONDAY, MONDAY + 2 * DAY);
+        assert_eq!(rows.iter().map(|r| (r.daily, r.weekly)).collect::<Vec<_>>(), vec![(1, 1), (2, 2)]);
+        let csv = activity_csv(&rows);
+        assert!(csv.starts_with("date,dau,wau,mau,stickiness\r\n2024-01-01,1,1,1,1.000\r\n2024-01-02,2,2,2,1.000\r\n"), "{csv}");
+        // a row measured at the epoch itself has no day before it
+        assert_eq!(activity_csv(&[ActiveUsers::at(&events, 0)]), "date,dau,wau,mau,stickiness\r\n1970-01-01,0,0,0,0.000\r\n");
+    }
+
+    #[test]
+    fn churn_counts_users_who_did_not_come_back() {
+        let mut store = MemoryUserStore::new();
+        for username in ["ann", "bob"] {
+            store.insert(user_created(username, MONDAY)).unwrap();
+        }
+        let events = [event("ann", MONDAY), event("bob", MONDAY), event("ann", MONDAY + 40 * DAY)];
+        let now = MONDAY + 45 * DAY;
+        let gone = churned(&store, &events, now, MONTH);
+        assert_eq!(gone, vec![Churned { username: "bob".to_string(), last_sign_in: MONDAY, days_inactive: 45 }]);
+        assert_eq!(churn_csv(&gone), "username,last_sign_in,days_inactive\r\nbob,2024-01-01,45\r\n");
+        assert_eq!(churn_rate(&events, now, MONTH), 0.5);
+    }
+}
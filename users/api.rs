@@ -0,0 +1,838 @@
+// a REST API for managing users, served from a local TcpListener with no outside services
+//
+//     GET    /users?page=1&per_page=20   list users, optionally filtered with &email= or &state=
+//     POST   /users                      create {"username", "email", "password"?}
+//     GET    /users/{username}           look one user up
+//     PATCH  /users/{username}           change {"username"?, "email"?, "sign_in_count"?}, the count only by an admin
+//     DELETE /users/{username}           remove a user
+//     POST   /users/{username}/verification   email a new verification token
+//     POST   /verifications              confirm an email address {"token"}
+//     POST   /sessions                   sign in {"username", "password", "code"?, "organisation"?}, returns a session token
+//     GET    /sessions/current           the session for "Authorization: Bearer <token>"
+//     DELETE /sessions/current           sign that session out
+//
+// everything under /users needs a signed-in session, users only see themselves and the other members of their
+// organisations (see users/orgs.rs), an admin sees everyone; anyone may add an account, but changing,
+// deleting or re-mailing one is for that user or a session with the admin role; the one exception is the very
+// first account, which can be created without a session, so that someone can sign in at all, and is an admin
+// new accounts are mailed a verification token and cannot sign in until it has been posted back
+// renaming or deleting an account signs it out everywhere, a token naming a username that has been given up
+// must not work for whoever takes that name next; deleting one also takes it out of its organisations, and is
+// refused with 409 while it is the last owner of one
+// an account that belongs to an organisation is "slug:name" in paths, or signs in with its name and "organisation"
+// bodies are JSON both ways, password hashes and TOTP secrets are never sent back
+// each connection is handled on its own thread, the store sits behind a Mutex so they take turns with it
+// past max_connections at once a client is answered 503 straight away rather than given another thread
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::users::analytics::SignInLog;
+use crate::users::auth::{AuthError, Authenticator, LockoutPolicy, DEFAULT_ITERATIONS};
+use crate::users::clock::Clock;
+use crate::users::http::{read_request, Request, Response};
+use crate::users::json::Json;
+use crate::users::lifecycle::AccountState;
+use crate::users::mail::Mailer;
+use crate::users::orgs::{rescope, scoped_username, OrgError, Organisations};
+use crate::users::patch::{patch_user, AuditLog, UserPatch};
+use crate::users::session::{Session, SessionError, SessionManager};
+use crate::users::store::{StoreError, UserStore};
+use crate::users::verification::{EmailVerifier, VerificationError};
+use crate::User;
+
+pub const DEFAULT_PER_PAGE: usize = 20;
+pub const MAX_PER_PAGE: usize = 100;
+pub const ADMIN_ROLE: &str = "admin";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+pub struct ApiState {
+    store: Mutex<Box<dyn UserStore + Send>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+    audit: Mutex<AuditLog>,
+    verifier: Mutex<EmailVerifier>,
+    sessions: Mutex<SessionManager>,
+    sign_ins: Mutex<SignInLog>,
+    mailer: Mutex<Box<dyn Mailer + Send>>,
+    orgs: Mutex<Organisations>,
+    pub lockout: LockoutPolicy,
+    pub password_iterations: u32,
+    pub max_connections: usize
+}
+
+// a panic on another connection's thread poisons the mutex, what it guards is still consistent so carry on
+// when more than one is needed they are locked in the order store, audit, verifier, sign_ins, sessions, mailer, orgs
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub struct ApiServer {
+    listener: TcpListener,
+    state: ApiState
+}
+
+// a server running on a background thread, dropping it stops the server
+pub struct RunningServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl ApiServer {
+    // "127.0.0.1:0" picks a free port, local_addr says which one
+    // tokens are signed with a random key unless with_verifier gives one, so by default they do not survive a restart
+    // the same goes for session tokens and with_sessions
+    pub fn bind(
+        addr: &str,
+        store: impl UserStore + Send + 'static,
+        clock: impl Clock + Send + Sync + 'static,
+        mailer: impl Mailer + Send + 'static
+    ) -> io::Result<Self> {
+        let clock: Arc<dyn Clock + Send + Sync> = Arc::new(clock);
+        Ok(ApiServer {
+            listener: TcpListener::bind(addr)?,
+            state: ApiState {
+                store: Mutex::new(Box::new(store)),
+                sessions: Mutex::new(SessionManager::generate(Arc::clone(&clock))),
+                sign_ins: Mutex::new(SignInLog::new()),
+                clock,
+                audit: Mutex::new(AuditLog::new()),
+                verifier: Mutex::new(EmailVerifier::generate()),
+                mailer: Mutex::new(Box::new(mailer)),
+                orgs: Mutex::new(Organisations::new()),
+                lockout: LockoutPolicy::default(),
+                password_iterations: DEFAULT_ITERATIONS,
+                max_connections: DEFAULT_MAX_CONNECTIONS
+            }
+        })
+    }
+
+    pub fn with_lockout(mut self, lockout: LockoutPolicy) -> Self {
+        self.state.lockout = lockout;
+        self
+    }
+
+    pub fn with_verifier(mut self, verifier: EmailVerifier) -> Self {
+        self.state.verifier = Mutex::new(verifier);
+        self
+    }
+
+    pub fn with_sessions(mut self, sessions: SessionManager) -> Self {
+        self.state.sessions = Mutex::new(sessions);
+        self
+    }
+
+    // successful sign-ins are recorded here, by default only in memory
+    pub fn with_sign_in_log(mut self, log: SignInLog) -> Self {
+        self.state.sign_ins = Mutex::new(log);
+        self
+    }
+
+    // every change made through the API is recorded here, by default only in memory
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.state.audit = Mutex::new(log);
+        self
+    }
+
+    // who can see whom, without any organisations a user only sees themselves
+    // organisations opened from a file are saved to it whenever an account is renamed or deleted
+    pub fn with_organisations(mut self, orgs: Organisations) -> Self {
+        self.state.orgs = Mutex::new(orgs);
+        self
+    }
+
+    pub fn with_password_iterations(mut self, iterations: u32) -> Self {
+        self.state.password_iterations = iterations;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.state.max_connections = max;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // serves until the process is stopped
+    pub fn run(self) -> io::Result<()> {
+        serve(self.listener, Arc::new(self.state), Arc::new(AtomicBool::new(false)));
+        Ok(())
+    }
+
+    pub fn spawn(self) -> io::Result<RunningServer> {
+        let addr = self.listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        let state = Arc::new(self.state);
+        let listener = self.listener;
+        let handle = thread::spawn(move || serve(listener, state, flag));
+        Ok(RunningServer { addr, stop, handle: Some(handle) })
+    }
+}
+
+impl RunningServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.addr);  // accept() is blocking, a connection wakes it up to see the flag
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// one connection being handled, the count goes down again however the thread ends
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve(listener: TcpListener, state: Arc<ApiState>, stop: Arc<AtomicBool>) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(mut stream) = stream else { continue };
+        let slot = Slot(Arc::clone(&open));
+        if open.fetch_add(1, Ordering::SeqCst) >= state.max_connections {
+            drop(slot);
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = Response::error(503, "the server is busy, try again shortly").with_header("Retry-After", "1").write_to(&mut stream);
+            continue;
+        }
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let _slot = slot;
+            handle_connection(stream, &state);
+        });
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, state: &ApiState) {
+    let response = match read_request(&stream) {
+        Ok(request) => route(state, &request),
+        Err(response) => response
+    };
+    let _ = response.write_to(&mut stream);
+}
+
+pub fn route(state: &ApiState, request: &Request) -> Response {
+    let method = request.method.as_str();
+    let result = match request.segments().as_slice() {
+        ["users"] => match method {
+            "GET" => authenticate(state, request).and_then(|session| list_users(state, request, &session)),
+            "POST" => create_user(state, request),
+            _ => Err(not_allowed("GET, POST"))
+        },
+        ["users", username] => match method {
+            "GET" => authenticate(state, request).and_then(|session| get_user(state, username, &session)),
+            "PATCH" => authorise(state, request, username).and_then(|session| update_user(state, request, username, &session)),
+            "DELETE" => authorise(state, request, username).and_then(|_| delete_user(state, username)),
+            _ => Err(not_allowed("GET, PATCH, DELETE"))
+        },
+        ["users", username, "verification"] => match method {
+            "POST" => authorise(state, request, username).and_then(|_| resend_verification(state, username)),
+            _ => Err(not_allowed("POST"))
+        },
+        ["verifications"] => match method {
+            "POST" => verify_email(state, request),
+            _ => Err(not_allowed("POST"))
+        },
+        ["sessions"] => match method {
+            "POST" => sign_in(state, request),
+            _ => Err(not_allowed("POST"))
+        },
+        ["sessions", "current"] => match method {
+            "GET" => current_session(state, request),
+            "DELETE" => sign_out(state, request),
+            _ => Err(not_allowed("GET, DELETE"))
+        },
+        _ => Err(Response::error(404, "no such endpoint"))
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+fn not_allowed(allow: &str) -> Response {
+    Response::error(405, "method not allowed").with_header("Allow", allow)
+}
+
+// what the API shows of a user, the credentials stay on the server
+pub fn public_json(user: &User) -> Json {
+    Json::object(vec![
+        ("username", Json::str(&user.username)),
+        ("email", Json::str(&user.email)),
+        ("state", Json::str(user.state().name())),
+        ("sign_in_count", Json::uint(user.sign_in_count)),
+        ("roles", Json::Array(user.roles.iter().map(|r| Json::str(r)).collect())),
+        ("created_at", Json::uint(user.lifecycle.created_at())),
+        ("last_sign_in", user.credentials.last_sign_in().map_or(Json::Null, Json::uint)),
+        ("two_factor", Json::Bool(user.has_totp()))
+    ])
+}
+
+fn store_error(err: StoreError) -> Response {
+    match err {
+        StoreError::Duplicate(_) => Response::error(409, &err.to_string()),
+        StoreError::NotFound(_) => Response::error(404, &err.to_string()),
+        StoreError::Invalid(_) => Response::error(422, &err.to_string()),
+        StoreError::Corrupt { .. } | StoreError::Io(_) => Response::error(500, &err.to_string())
+    }
+}
+
+fn auth_error(err: AuthError) -> Response {
+    match err {
+        AuthError::InvalidCredentials | AuthError::InvalidSecondFactor => Response::error(401, &err.to_string()),
+        AuthError::SecondFactorRequired => {
+            let mut body = Json::object(vec![("error", Json::str(&err.to_string()))]);
+            body.set("second_factor_required", Json::Bool(true));
+            Response::json(401, body)
+        }
+        AuthError::Locked { until } => Response::error(423, &err.to_string()).with_header("Retry-After", &until.to_string()),
+        AuthError::NotActive(_) => Response::error(403, &err.to_string()),
+        AuthError::PasswordTooShort { .. } => Response::error(422, &err.to_string()),
+        AuthError::Store(err) => store_error(err)
+    }
+}
+
+fn verification_error(err: VerificationError) -> Response {
+    match err {
+        VerificationError::Invalid => Response::error(400, &err.to_string()),
+        VerificationError::Expired { .. } | VerificationError::Revoked => Response::error(410, &err.to_string()),
+        VerificationError::UnknownUser(_) => Response::error(404, &err.to_string()),
+        VerificationError::EmailChanged | VerificationError::NotPending(_) | VerificationError::Lifecycle(_) => Response::error(409, &err.to_string()),
+        VerificationError::Throttled { retry_at } => Response::error(429, &err.to_string()).with_header("Retry-After", &retry_at.to_string()),
+        VerificationError::Mail(_) => Response::error(502, &err.to_string()),
+        VerificationError::Store(err) => store_error(err)
+    }
+}
+
+fn org_error(err: OrgError) -> Response {
+    let status = match err {
+        OrgError::NotFound(_) | OrgError::NotMember | OrgError::TeamNotFound(_) => 404,
+        OrgError::Forbidden(_) => 403,
+        OrgError::Exists(_) | OrgError::AlreadyMember | OrgError::NameTaken(_) | OrgError::LastOwner | OrgError::TeamExists(_) => 409,
+        OrgError::InvalidSlug(_) | OrgError::InvalidName(_) | OrgError::InvitationInvalid | OrgError::InvitationExpired { .. } | OrgError::WrongEmail => 422,
+        OrgError::Store(_) => 500
+    };
+    Response::error(status, &err.to_string())
+}
+
+fn session_error(err: SessionError) -> Response {
+    match err {
+        SessionError::InvalidKeyId(_) | SessionError::NoSigningKey => Response::error(500, &err.to_string()),
+        _ => Response::error(401, &err.to_string()).with_header("WWW-Authenticate", "Bearer")
+    }
+}
+
+fn bearer_token(request: &Request) -> Result<&str, Response> {
+    let header = request.header("authorization").ok_or_else(|| {
+        Response::error(401, "an Authorization: Bearer header is required").with_header("WWW-Authenticate", "Bearer")
+    })?;
+    match header.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(token.trim()),
+        _ => Err(Response::error(401, "the Authorization header must use the Bearer scheme").with_header("WWW-Authenticate", "Bearer"))
+    }
+}
+
+// the session behind the request's bearer token, signed out and expired sessions do not count
+fn authenticate(state: &ApiState, request: &Request) -> Result<Session, Response> {
+    lock(&state.sessions).verify(bearer_token(request)?).map_err(session_error)
+}
+
+// a session allowed to change username's account, their own or an admin's
+fn authorise(state: &ApiState, request: &Request, username: &str) -> Result<Session, Response> {
+    let session = authenticate(state, request)?;
+    if !session.username.eq_ignore_ascii_case(username) && !session.has_role(ADMIN_ROLE) {
+        return Err(Response::error(403, &format!("only {username} or an admin can change this account")));
+    }
+    Ok(session)
+}
+
+fn required_str<'a>(body: &'a Json, key: &str) -> Result<&'a str, Response> {
+    body.get(key).and_then(Json::as_str).ok_or_else(|| Response::error(400, &format!("'{key}' is required and must be a string")))
+}
+
+fn optional_str<'a>(body: &'a Json, key: &str) -> Result<Option<&'a str>, Response> {
+    match body.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(value) => value.as_str().map(Some).ok_or_else(|| Response::error(400, &format!("'{key}' must be a string")))
+    }
+}
+
+fn query_number(request: &Request, name: &str, default: usize) -> Result<usize, Response> {
+    match request.query_param(name) {
+        None => Ok(default),
+        Some(text) => text.parse().map_err(|_| Response::error(400, &format!("'{name}' must be a whole number")))
+    }
+}
+
+// the users the session may see, in username order
+fn visible_users<'a>(state: &ApiState, store: &'a dyn UserStore, session: &Session) -> Vec<&'a User> {
+    if session.has_role(ADMIN_ROLE) {
+        return store.list();
+    }
+    lock(&state.orgs).visible_users(store, &session.username)
+}
+
+// pages start at 1, the response says how many users matched so clients can draw page links
+fn list_users(state: &ApiState, request: &Request, session: &Session) -> Result<Response, Response> {
+    let page = query_number(request, "page", 1)?.max(1);
+    let per_page = query_number(request, "per_page", DEFAULT_PER_PAGE)?.clamp(1, MAX_PER_PAGE);
+    let email = request.query_param("email").map(str::to_lowercase);
+    let wanted_state = match request.query_param("state") {
+        Some(name) => Some(AccountState::from_name(name).ok_or_else(|| Response::error(400, &format!("unknown state '{name}'")))?),
+        None => None
+    };
+
+    let store = lock(&state.store);
+    let matching: Vec<&User> = visible_users(state, &**store, session)
+        .into_iter()
+        .filter(|u| email.as_ref().is_none_or(|e| *e == u.email.to_lowercase()))
+        .filter(|u| wanted_state.as_ref().is_none_or(|s| s.name() == u.state().name()))
+        .collect();
+    let total = matching.len();
+    let users: Vec<Json> = matching.into_iter().skip((page - 1) * per_page).take(per_page).map(public_json).collect();
+    let has_next = page * per_page < total;
+    Ok(Response::json(200, Json::object(vec![
+        ("users", Json::Array(users)),
+        ("page", Json::uint(page as u64)),
+        ("per_page", Json::uint(per_page as u64)),
+        ("total", Json::uint(total as u64)),
+        ("next_page", if has_next { Json::uint(page as u64 + 1) } else { Json::Null })
+    ])))
+}
+
+fn create_user(state: &ApiState, request: &Request) -> Result<Response, Response> {
+    let body = request.json()?;
+    let username = required_str(&body, "username")?;
+    let email = required_str(&body, "email")?;
+    let mut user = crate::build_user(email.to_string(), username.to_string()).map_err(|e| Response::error(422, &e.to_string()))?;
+    if let Some(password) = optional_str(&body, "password")? {
+        user.set_password_with_iterations(password, state.password_iterations).map_err(auth_error)?;
+    }
+    let mut store = lock(&state.store);
+    if store.list().is_empty() {
+        user.grant_role(ADMIN_ROLE).map_err(|e| Response::error(500, &e))?;
+    } else {
+        // checked with the store held, so two requests cannot both create the first account
+        authenticate(state, request)?;
+    }
+    let json = public_json(&user);
+    let username = user.username.clone();
+    let location = format!("/users/{username}");
+    store.insert(user).map_err(store_error)?;
+    // the account exists either way, if the email did not go out the client can ask for it again
+    let sent = send_verification(state, &**store, &username);
+    let mut response = Response::json(201, json).with_header("Location", &location);
+    if let (Some(body), Err(err)) = (response.body.as_mut(), sent) {
+        body.set("verification_error", Json::str(&err.to_string()));
+    }
+    Ok(response)
+}
+
+// someone the session cannot see is as good as not there, so accounts cannot be probed for
+fn get_user(state: &ApiState, username: &str, session: &Session) -> Result<Response, Response> {
+    let store = lock(&state.store);
+    let visible = visible_users(state, &**store, session);
+    let user = visible.into_iter().find(|u| u.username.eq_ignore_ascii_case(username)).ok_or_else(|| store_error(StoreError::NotFound(username.to_string())))?;
+    Ok(Response::json(200, public_json(user)))
+}
+
+fn update_user(state: &ApiState, request: &Request, username: &str, session: &Session) -> Result<Response, Response> {
+    let body = request.json()?;
+    let sign_in_count = match body.get("sign_in_count") {
+        None | Some(Json::Null) => None,
+        Some(value) => Some(value.as_u64().ok_or_else(|| Response::error(400, "'sign_in_count' must be a whole number"))?)
+    };
+    if sign_in_count.is_some() && !session.has_role(ADMIN_ROLE) {
+        return Err(Response::error(403, "only an admin can change sign_in_count"));
+    }
+    let patch = UserPatch {
+        username: optional_str(&body, "username")?.map(String::from),
+        email: optional_str(&body, "email")?.map(String::from),
+        sign_in_count
+    };
+    let mut store = lock(&state.store);
+    let mut audit = lock(&state.audit);
+    // the path can spell the name in any case, everything else is kept under the stored spelling
+    let username = store.get(username).map(|u| u.username.clone()).ok_or_else(|| store_error(StoreError::NotFound(username.to_string())))?;
+    if let Some(name) = &patch.username {
+        // an account that belongs to an organisation cannot take a name another member there goes by
+        let to = rescope(&username, name.trim());
+        lock(&state.orgs).check_rename(&username, &to).map_err(org_error)?;
+    }
+    let diff = patch_user(&mut **store, &mut audit, &username, &patch, &session.username, &*state.clock).map_err(store_error)?;
+    let current = diff.after.username.as_deref().unwrap_or(&username);
+    if current != username {
+        // the rename has happened, failing to write the sign-ins or organisations only leaves the old name in their files
+        let _ = lock(&state.sign_ins).rename(&username, current);
+        lock(&state.sessions).revoke_all(&username);
+        let mut orgs = lock(&state.orgs);
+        orgs.rename_account(&username, current);
+        let _ = orgs.save();
+    }
+    let user = store.get(current).ok_or_else(|| store_error(StoreError::NotFound(current.to_string())))?;
+    Ok(Response::json(200, public_json(user)))
+}
+
+fn delete_user(state: &ApiState, username: &str) -> Result<Response, Response> {
+    let mut store = lock(&state.store);
+    let username = store.get(username).map(|u| u.username.clone()).ok_or_else(|| store_error(StoreError::NotFound(username.to_string())))?;
+    // an organisation cannot be left without an owner, so this goes first and stops the delete when it has to
+    // only an i/o error in the store can then leave the account in place without its memberships
+    let mut orgs = lock(&state.orgs);
+    orgs.remove_account(&username).map_err(org_error)?;
+    orgs.save().map_err(|e| Response::error(500, &format!("could not save the organisations: {e}")))?;
+    drop(orgs);
+    store.delete(&username).map_err(store_error)?;
+    // the account is gone, so are its sessions
+    lock(&state.sessions).revoke_all(&username);
+    Ok(Response::empty(204))
+}
+
+fn send_verification(state: &ApiState, store: &dyn UserStore, username: &str) -> Result<String, VerificationError> {
+    let mut verifier = lock(&state.verifier);
+    let mut mailer = lock(&state.mailer);
+    verifier.resend(store, &mut **mailer, username, &*state.clock)
+}
+
+fn resend_verification(state: &ApiState, username: &str) -> Result<Response, Response> {
+    let store = lock(&state.store);
+    send_verification(state, &**store, username).map_err(verification_error)?;
+    Ok(Response::empty(202))
+}
+
+fn verify_email(state: &ApiState, request: &Request) -> Result<Response, Response> {
+    let body = request.json()?;
+    let token = required_str(&body, "token")?;
+    let mut store = lock(&state.store);
+    let username = lock(&state.verifier).verify(&mut **store, token, &*state.clock).map_err(verification_error)?;
+    let user = store.get(&username).ok_or_else(|| store_error(StoreError::NotFound(username.clone())))?;
+    Ok(Response::json(200, public_json(user)))
+}
+
+fn sign_in(state: &ApiState, request: &Request) -> Result<Response, Response> {
+    let body = request.json()?;
+    let username = match optional_str(&body, "organisation")? {
+        Some(slug) => scoped_username(slug, required_str(&body, "username")?),
+        None => required_str(&body, "username")?.to_string()
+    };
+    let username = username.as_str();
+    let password = required_str(&body, "password")?;
+    let code = optional_str(&body, "code")?;
+    // the password hash takes a while, other requests can use the store in the meantime
+    let user = lock(&state.store).get(username).cloned();
+    let attempt = Authenticator::new(state.lockout, &*state.clock).check(user.as_ref(), password, code);
+    let mut store = lock(&state.store);
+    let mut sign_ins = lock(&state.sign_ins);
+    Authenticator::new(state.lockout, &*state.clock).with_sign_in_log(&mut sign_ins).apply(&mut **store, attempt).map_err(auth_error)?;
+    let user = store.get(username).ok_or_else(|| store_error(StoreError::NotFound(username.to_string())))?;
+    let (token, session) = lock(&state.sessions).issue(user).map_err(session_error)?;
+    let mut body = public_json(user);
+    body.set("token", Json::str(&token));
+    body.set("expires_at", Json::uint(session.expires_at));
+    Ok(Response::json(200, body))
+}
+
+fn current_session(state: &ApiState, request: &Request) -> Result<Response, Response> {
+    let session = lock(&state.sessions).verify(bearer_token(request)?).map_err(session_error)?;
+    Ok(Response::json(200, session.to_json()))
+}
+
+// only a session that is still valid can sign itself out
+fn sign_out(state: &ApiState, request: &Request) -> Result<Response, Response> {
+    let token = bearer_token(request)?;
+    let mut sessions = lock(&state.sessions);
+    sessions.verify(token).map_err(session_error)?;
+    sessions.revoke(token).map_err(session_error)?;
+    Ok(Response::empty(204))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::users::clock::SystemClock;
+    use crate::users::mail::MemoryMailer;
+    use crate::users::store::MemoryUserStore;
+
+    const PASSWORD: &str = "correct horse battery";
+
+    fn start() -> (RunningServer, MemoryMailer) {
+        let mailer = MemoryMailer::new();
+        let server = ApiServer::bind("127.0.0.1:0", MemoryUserStore::new(), SystemClock, mailer.clone())
+            .unwrap()
+            .with_password_iterations(1_000)
+            .spawn()
+            .unwrap();
+        (server, mailer)
+    }
+
+    // the status and the JSON body, Null when there is none
+    fn send(server: &RunningServer, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, Json) {
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        let auth = token.map_or(String::new(), |t| format!("Authorization: Bearer {t}\r\n"));
+        write!(stream, "{method} {path} HTTP/1.1\r\nHost: localhost\r\n{auth}Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, if body.is_empty() { Json::Null } else { Json::parse(body).unwrap() })
+    }
+
+    // creates the account, confirms its email and signs in, returning the session token
+    fn sign_up(server: &RunningServer, mailer: &MemoryMailer, username: &str, token: Option<&str>) -> String {
+        let email = format!("{username}@example.com");
+        let body = format!(r#"{{"username": "{username}", "email": "{email}", "password": "{PASSWORD}"}}"#);
+        assert_eq!(send(server, "POST", "/users", token, &body).0, 201);
+        let mail = mailer.last_to(&email).unwrap();
+        let code = mail.body.lines().nth(4).unwrap();
+        assert_eq!(send(server, "POST", "/verifications", None, &format!(r#"{{"token": "{code}"}}"#)).0, 200);
+        let (status, session) = send(server, "POST", "/sessions", None, &format!(r#"{{"username": "{username}", "password": "{PASSWORD}"}}"#));
+        assert_eq!(status, 200);
+        session.get("token").and_then(Json::as_str).unwrap().to_string()
+    }
+
+    #[test]
+    fn only_the_first_account_can_be_created_without_a_session() {
+        let (server, mailer) = start();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        let (_, session) = send(&server, "GET", "/sessions/current", Some(&admin), "");
+        assert_eq!(session.get("roles"), Some(&Json::Array(vec![Json::str(ADMIN_ROLE)])));
+
+        let body = r#"{"username": "mallory", "email": "mallory@example.com"}"#;
+        assert_eq!(send(&server, "POST", "/users", None, body).0, 401);
+        assert_eq!(send(&server, "POST", "/users", Some("not.a-token"), body).0, 401);
+        assert_eq!(send(&server, "POST", "/users", Some(&admin), body).0, 201);
+    }
+
+    #[test]
+    fn reading_and_changing_users_needs_a_session() {
+        let (server, mailer) = start();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        for (method, path) in [("GET", "/users"), ("GET", "/users/alice"), ("PATCH", "/users/alice"), ("DELETE", "/users/alice"), ("POST", "/users/alice/verification")] {
+            let (status, _) = send(&server, method, path, None, "{}");
+            assert_eq!(status, 401, "{method} {path}");
+        }
+        let (status, list) = send(&server, "GET", "/users", Some(&admin), "");
+        assert_eq!(status, 200);
+        assert_eq!(list.get("total").and_then(Json::as_u64), Some(1));
+    }
+
+    #[test]
+    fn users_change_their_own_account_and_admins_any() {
+        let (server, mailer) = start();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        let bob = sign_up(&server, &mailer, "bob", Some(&admin));
+
+        assert_eq!(send(&server, "PATCH", "/users/alice", Some(&bob), r#"{"email": "bob@example.org"}"#).0, 403);
+        assert_eq!(send(&server, "DELETE", "/users/alice", Some(&bob), "").0, 403);
+        let (status, user) = send(&server, "PATCH", "/users/bob", Some(&bob), r#"{"email": "bob@example.org"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(user.get("email").and_then(Json::as_str), Some("bob@example.org"));
+
+        assert_eq!(send(&server, "DELETE", "/users/bob", Some(&admin), "").0, 204);
+        // the deleted account's sessions went with it
+        assert_eq!(send(&server, "GET", "/sessions/current", Some(&bob), "").0, 401);
+    }
+
+    #[test]
+    fn changes_are_audited_under_the_signed_in_user_not_a_header() {
+        let mailer = MemoryMailer::new();
+        let server = ApiServer::bind("127.0.0.1:0", MemoryUserStore::new(), SystemClock, mailer).unwrap().with_password_iterations(1_000);
+        let mut user = crate::build_user("alice@example.com".to_string(), "alice".to_string()).unwrap();
+        user.grant_role(ADMIN_ROLE).unwrap();
+        let (token, _) = lock(&server.state.sessions).issue(&user).unwrap();
+        lock(&server.state.store).insert(user).unwrap();
+
+        let request = Request {
+            method: "PATCH".to_string(),
+            path: "/users/alice".to_string(),
+            query: Vec::new(),
+            headers: vec![("authorization".to_string(), format!("Bearer {token}")), ("x-actor".to_string(), "someone-else".to_string())],
+            body: br#"{"email": "alice@example.org"}"#.to_vec()
+        };
+        assert_eq!(route(&server.state, &request).status, 200);
+        let audit = lock(&server.state.audit);
+        assert_eq!(audit.entries().len(), 1);
+        assert_eq!(audit.entries()[0].actor, "alice");
+    }
+
+    #[test]
+    fn users_only_see_the_members_of_their_organisations() {
+        let clock = SystemClock;
+        let user = |name: &str| crate::build_user(format!("{name}@example.com"), name.to_string()).unwrap();
+        let mut orgs = Organisations::new();
+        orgs.create("basics", "Basics Cafe", &user("bob"), &clock).unwrap();
+        let code = orgs.invite("basics", "bob", "carol@example.com", crate::users::orgs::OrgRole::Member, &clock).unwrap();
+        orgs.accept(&code, &user("carol"), None, &clock).unwrap();
+
+        let mailer = MemoryMailer::new();
+        let server = ApiServer::bind("127.0.0.1:0", MemoryUserStore::new(), SystemClock, mailer.clone())
+            .unwrap()
+            .with_password_iterations(1_000)
+            .with_organisations(orgs)
+            .spawn()
+            .unwrap();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        let bob = sign_up(&server, &mailer, "bob", Some(&admin));
+        sign_up(&server, &mailer, "carol", Some(&admin));
+        let dave = sign_up(&server, &mailer, "dave", Some(&admin));
+
+        let listed = |token: &str| -> Vec<String> {
+            let (_, list) = send(&server, "GET", "/users", Some(token), "");
+            list.get("users").and_then(Json::as_array).unwrap().iter().filter_map(|u| u.get("username")?.as_str().map(String::from)).collect()
+        };
+        assert_eq!(listed(&bob), vec!["bob", "carol"]);
+        assert_eq!(listed(&dave), vec!["dave"]);
+        assert_eq!(listed(&admin), vec!["alice", "bob", "carol", "dave"]);
+        assert_eq!(send(&server, "GET", "/users/carol", Some(&bob), "").0, 200);
+        assert_eq!(send(&server, "GET", "/users/carol", Some(&dave), "").0, 404);
+    }
+
+    #[test]
+    fn accounts_of_an_organisation_go_by_it_and_its_last_owner_cannot_be_deleted() {
+        let clock = SystemClock;
+        let mut store = MemoryUserStore::new();
+        let mut orgs = Organisations::new();
+        let mut bob = crate::build_user("bob@example.com".to_string(), "bob".to_string()).unwrap();
+        bob.activate(&clock).unwrap();
+        bob.set_password_with_iterations(PASSWORD, 1_000).unwrap();
+        store.insert(bob.clone()).unwrap();
+        for slug in ["acme", "globex"] {
+            orgs.create(slug, slug, &bob, &clock).unwrap();
+            let code = orgs.invite(slug, "bob", &format!("alex@{slug}.example"), crate::users::orgs::OrgRole::Member, &clock).unwrap();
+            let account = orgs.join(&mut store, &code, "alex", &clock).unwrap();
+            let mut alex = store.get(&account).unwrap().clone();
+            alex.set_password_with_iterations(PASSWORD, 1_000).unwrap();
+            store.update(alex).unwrap();
+        }
+        let server = ApiServer::bind("127.0.0.1:0", store, SystemClock, MemoryMailer::new())
+            .unwrap()
+            .with_organisations(orgs)
+            .spawn()
+            .unwrap();
+        let sign_in = |username: &str, organisation: &str| {
+            let body = format!(r#"{{"username": "{username}", "organisation": {organisation}, "password": "{PASSWORD}"}}"#);
+            send(&server, "POST", "/sessions", None, &body)
+        };
+
+        // two accounts called alex, one in each organisation, and no alex outside them
+        let (status, acme) = sign_in("alex", r#""acme""#);
+        assert_eq!(status, 200);
+        assert_eq!(acme.get("username").and_then(Json::as_str), Some("acme:alex"));
+        let (_, globex) = sign_in("alex", r#""globex""#);
+        assert_eq!(globex.get("email").and_then(Json::as_str), Some("alex@globex.example"));
+        assert_eq!(sign_in("alex", "null").0, 401);
+        let globex = globex.get("token").and_then(Json::as_str).unwrap().to_string();
+
+        // a rename stays inside the organisation
+        let (status, renamed) = send(&server, "PATCH", "/users/globex:alex", Some(&globex), r#"{"username": "alexa"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(renamed.get("username").and_then(Json::as_str), Some("globex:alexa"));
+        let (status, _) = sign_in("globex:alexa", "null");
+        assert_eq!(status, 200);
+
+        let (_, bob) = sign_in("bob", "null");
+        let bob = bob.get("token").and_then(Json::as_str).unwrap().to_string();
+        assert_eq!(send(&server, "DELETE", "/users/bob", Some(&bob), "").0, 409);
+        assert_eq!(send(&server, "DELETE", "/users/acme:alex", Some(&bob), "").0, 403);
+        let acme = acme.get("token").and_then(Json::as_str).unwrap();
+        assert_eq!(send(&server, "DELETE", "/users/acme:alex", Some(acme), "").0, 204);
+        let (_, list) = send(&server, "GET", "/users", Some(&bob), "");
+        let listed: Vec<&str> = list.get("users").and_then(Json::as_array).unwrap().iter().filter_map(|u| u.get("username")?.as_str()).collect();
+        assert_eq!(listed, vec!["bob", "globex:alexa"]);
+    }
+
+    #[test]
+    fn failed_sign_ins_lock_the_account() {
+        let mailer = MemoryMailer::new();
+        let server = ApiServer::bind("127.0.0.1:0", MemoryUserStore::new(), SystemClock, mailer.clone())
+            .unwrap()
+            .with_password_iterations(1_000)
+            .with_lockout(LockoutPolicy { max_failures: 3, window: 60, lockout: 60 })
+            .spawn()
+            .unwrap();
+        sign_up(&server, &mailer, "alice", None);
+        let wrong = r#"{"username": "alice", "password": "wrong password"}"#;
+        assert_eq!(send(&server, "POST", "/sessions", None, wrong).0, 401);
+        assert_eq!(send(&server, "POST", "/sessions", None, wrong).0, 401);
+        assert_eq!(send(&server, "POST", "/sessions", None, wrong).0, 423);
+        let right = format!(r#"{{"username": "alice", "password": "{PASSWORD}"}}"#);
+        assert_eq!(send(&server, "POST", "/sessions", None, &right).0, 423);
+    }
+
+    #[test]
+    fn a_header_line_without_an_end_is_cut_off_at_the_limit() {
+        let (server, _) = start();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        // more than the header limit and no newline, the server answers without waiting for the rest
+        let _ = stream.write_all(format!("GET /{} HTTP/1.1", "a".repeat(20 * 1024)).as_bytes());
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_turned_away() {
+        let mailer = MemoryMailer::new();
+        let server = ApiServer::bind("127.0.0.1:0", MemoryUserStore::new(), SystemClock, mailer).unwrap().with_max_connections(1).spawn().unwrap();
+        let idle = TcpStream::connect(server.addr()).unwrap();
+        // the answer comes without reading the request, had one been sent, closing on it unread would reset the connection
+        let mut turned_away = String::new();
+        TcpStream::connect(server.addr()).unwrap().read_to_string(&mut turned_away).unwrap();
+        assert!(turned_away.starts_with("HTTP/1.1 503"), "{turned_away}");
+        // once the idle connection goes its place is free again
+        drop(idle);
+        let mut status = 503;
+        for _ in 0..100 {
+            status = send(&server, "GET", "/sessions/current", None, "").0;
+            if status != 503 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status, 401);
+    }
+
+    #[test]
+    fn a_token_from_before_a_rename_does_not_reach_whoever_takes_the_old_name() {
+        let (server, mailer) = start();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        let bob = sign_up(&server, &mailer, "bob", Some(&admin));
+        assert_eq!(send(&server, "PATCH", "/users/bob", Some(&bob), r#"{"username": "robert"}"#).0, 200);
+        assert_eq!(send(&server, "GET", "/sessions/current", Some(&bob), "").0, 401);
+
+        sign_up(&server, &mailer, "bob", Some(&admin));
+        let (status, _) = send(&server, "PATCH", "/users/bob", Some(&bob), r#"{"email": "mallory@example.com"}"#);
+        assert!(status == 401 || status == 403, "{status}");
+        assert_eq!(send(&server, "DELETE", "/users/bob", Some(&bob), "").0, 401);
+    }
+
+    #[test]
+    fn only_an_admin_can_change_a_sign_in_count() {
+        let (server, mailer) = start();
+        let admin = sign_up(&server, &mailer, "alice", None);
+        let bob = sign_up(&server, &mailer, "bob", Some(&admin));
+        assert_eq!(send(&server, "PATCH", "/users/bob", Some(&bob), r#"{"sign_in_count": 1000}"#).0, 403);
+        let (status, user) = send(&server, "PATCH", "/users/BOB", Some(&admin), r#"{"sign_in_count": 1000}"#);
+        assert_eq!(status, 200);
+        assert_eq!((user.get("username").and_then(Json::as_str), user.get("sign_in_count").and_then(Json::as_u64)), (Some("bob"), Some(1000)));
+    }
+}
@@ -0,0 +1,398 @@
+// password sign-in for users kept in a UserStore
+// passwords are stored as salted PBKDF2-HMAC-SHA256 hashes, never as the text the user typed
+// repeated failures inside a time window lock the account for a while, the limits come from a LockoutPolicy
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::users::analytics::SignInLog;
+use crate::users::clock::Clock;
+use crate::users::crypto::{constant_time_eq, from_hex, pbkdf2_hmac_sha256, random_bytes, to_hex};
+use crate::users::json::Json;
+use crate::users::lifecycle::AccountState;
+use crate::users::store::{StoreError, UserStore};
+use crate::users::totp::Totp;
+use crate::User;
+
+pub const DEFAULT_ITERATIONS: u32 = 600_000;  // the OWASP recommendation for PBKDF2-HMAC-SHA256
+pub const PASSWORD_MIN: usize = 8;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32]
+}
+
+impl PasswordHash {
+    // every hash gets its own random salt, so two users with the same password end up with different hashes
+    pub fn create(password: &str, iterations: u32) -> Self {
+        let salt = random_bytes(SALT_LEN);
+        let hash = pbkdf2_hmac_sha256(password.as_bytes(), &salt, iterations);
+        PasswordHash { iterations, salt, hash }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let candidate = pbkdf2_hmac_sha256(password.as_bytes(), &self.salt, self.iterations);
+        constant_time_eq(&candidate, &self.hash)
+    }
+
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    // "pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>", the parameters travel with the hash so they can be raised later
+    pub fn encode(&self) -> String {
+        format!("pbkdf2-sha256${}${}${}", self.iterations, to_hex(&self.salt), to_hex(&self.hash))
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut parts = text.split('$');
+        if parts.next()? != "pbkdf2-sha256" {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok()?;
+        let salt = from_hex(parts.next()?)?;
+        let hash = from_hex(parts.next()?)?.try_into().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PasswordHash { iterations, salt, hash })
+    }
+}
+
+// everything sign-in needs to remember about a user
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Credentials {
+    password: Option<PasswordHash>,
+    last_sign_in: Option<u64>,
+    failed_sign_ins: Vec<u64>,  // times of recent failures, only those inside the policy window are kept
+    locked_until: Option<u64>,
+    totp: Option<Totp>  // the optional second factor (see users/totp.rs)
+}
+
+impl Credentials {
+    pub fn last_sign_in(&self) -> Option<u64> {
+        self.last_sign_in
+    }
+
+    pub fn locked_until(&self) -> Option<u64> {
+        self.locked_until
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn totp(&self) -> Option<&Totp> {
+        self.totp.as_ref()
+    }
+
+    pub fn totp_mut(&mut self) -> Option<&mut Totp> {
+        self.totp.as_mut()
+    }
+
+    pub fn set_totp(&mut self, totp: Option<Totp>) {
+        self.totp = totp;
+    }
+
+    pub fn failed_sign_ins(&self) -> &[u64] {
+        &self.failed_sign_ins
+    }
+
+    // drops the password, second factor and failure tracking, only when the user last signed in is kept
+    pub fn erase_secrets(&mut self) {
+        *self = Credentials { last_sign_in: self.last_sign_in, ..Credentials::default() };
+    }
+
+    fn record_failure(&mut self, now: u64, policy: &LockoutPolicy) {
+        self.failed_sign_ins.retain(|t| now.saturating_sub(*t) < policy.window);
+        self.failed_sign_ins.push(now);
+        if self.failed_sign_ins.len() >= policy.max_failures {
+            self.locked_until = Some(now + policy.lockout);
+            self.failed_sign_ins.clear();
+        }
+    }
+
+    fn record_success(&mut self, now: u64) {
+        self.last_sign_in = Some(now);
+        self.failed_sign_ins.clear();
+        self.locked_until = None;
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("password", self.password.as_ref().map_or(Json::Null, |p| Json::String(p.encode()))),
+            ("last_sign_in", self.last_sign_in.map_or(Json::Null, Json::uint)),
+            ("failed_sign_ins", Json::Array(self.failed_sign_ins.iter().map(|t| Json::uint(*t)).collect())),
+            ("locked_until", self.locked_until.map_or(Json::Null, Json::uint)),
+            ("totp", self.totp.as_ref().map_or(Json::Null, Totp::to_json))
+        ])
+    }
+
+    pub fn from_json(value: &Json) -> Result<Self, String> {
+        let password = match value.get("password") {
+            None | Some(Json::Null) => None,
+            Some(encoded) => Some(encoded.as_str().and_then(PasswordHash::decode).ok_or("invalid password hash")?)
+        };
+        let failed = value.get("failed_sign_ins").and_then(Json::as_array).map_or(&[][..], |v| v);
+        Ok(Credentials {
+            password,
+            last_sign_in: value.get("last_sign_in").and_then(Json::as_u64),
+            failed_sign_ins: failed.iter().filter_map(Json::as_u64).collect(),
+            locked_until: value.get("locked_until").and_then(Json::as_u64),
+            totp: match value.get("totp") {
+                None | Some(Json::Null) => None,
+                Some(totp) => Some(Totp::from_json(totp)?)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockoutPolicy {
+    pub max_failures: usize,  // this many failures inside the window locks the account
+    pub window: u64,  // seconds
+    pub lockout: u64  // seconds the account stays locked
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy { max_failures: 5, window: 15 * 60, lockout: 15 * 60 }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,  // unknown username and wrong password look the same, so neither can be probed for
+    Locked { until: u64 },
+    NotActive(AccountState),
+    SecondFactorRequired,  // the password was right, now a TOTP or recovery code is needed
+    InvalidSecondFactor,
+    PasswordTooShort { min: usize },
+    Store(StoreError)
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "username or password is incorrect"),
+            AuthError::Locked { until } => write!(f, "too many failed sign-ins, the account is locked until {until}"),
+            AuthError::NotActive(state) => write!(f, "the account is {state}"),
+            AuthError::SecondFactorRequired => write!(f, "enter the code from your authenticator app"),
+            AuthError::InvalidSecondFactor => write!(f, "the authentication code is incorrect"),
+            AuthError::PasswordTooShort { min } => write!(f, "password must be at least {min} characters"),
+            AuthError::Store(err) => write!(f, "{err}")
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<StoreError> for AuthError {
+    fn from(err: StoreError) -> Self {
+        AuthError::Store(err)
+    }
+}
+
+impl User {
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<(), AuthError> {
+        self.set_password_with_iterations(password, DEFAULT_ITERATIONS)
+    }
+
+    // fewer iterations are only for tests and development servers, where hashing speed matters more than strength
+    pub fn set_password_with_iterations(&mut self, password: &str, iterations: u32) -> Result<(), AuthError> {
+        if password.chars().count() < PASSWORD_MIN {
+            return Err(AuthError::PasswordTooShort { min: PASSWORD_MIN });
+        }
+        self.set_password_hash(PasswordHash::create(password, iterations));
+        Ok(())
+    }
+
+    // for a hash made elsewhere, e.g. with fewer iterations in tests
+    pub fn set_password_hash(&mut self, hash: PasswordHash) {
+        self.credentials.password = Some(hash);
+    }
+}
+
+// what Authenticator::check made of a sign-in, for Authenticator::apply to save
+#[derive(Debug)]
+pub enum Attempt {
+    Rejected(AuthError),  // nothing about the user changes
+    Failed { username: String, at: u64, err: AuthError },  // counts towards the lockout
+    Succeeded { username: String, at: u64, totp: Option<Totp> }
+}
+
+pub struct Authenticator<'a> {
+    pub policy: LockoutPolicy,
+    clock: &'a dyn Clock,
+    sign_ins: Option<RefCell<&'a mut SignInLog>>  // sign_in takes &self, the log still has to be written to
+}
+
+impl<'a> Authenticator<'a> {
+    pub fn new(policy: LockoutPolicy, clock: &'a dyn Clock) -> Self {
+        Authenticator { policy, clock, sign_ins: None }
+    }
+
+    // records every successful sign-in as an event in the log too (see users/analytics.rs)
+    pub fn with_sign_in_log(mut self, log: &'a mut SignInLog) -> Self {
+        self.sign_ins = Some(RefCell::new(log));
+        self
+    }
+
+    // checks the password and, on success, bumps sign_in_count and records the time
+    // failures are written back to the store too, so the lockout survives a restart of a FileUserStore
+    pub fn sign_in(&self, store: &mut dyn UserStore, username: &str, password: &str) -> Result<(), AuthError> {
+        self.sign_in_with_code(store, username, password, None)
+    }
+
+    // the same as sign_in, for users with a confirmed TOTP secret the code is also checked
+    // code can be the current authenticator code or one of the unused recovery codes
+    pub fn sign_in_with_code(&self, store: &mut dyn UserStore, username: &str, password: &str, code: Option<&str>) -> Result<(), AuthError> {
+        let attempt = self.check(store.get(username), password, code);
+        self.apply(store, attempt)
+    }
+
+    // the slow half of a sign-in, checking the password against a copy of the user, which needs no store
+    // so whatever guards the store need not be held while the password is hashed; apply saves what it found
+    pub fn check(&self, user: Option<&User>, password: &str, code: Option<&str>) -> Attempt {
+        let now = self.clock.now();
+        let Some(user) = user else {
+            // hash anyway so an unknown username takes as long to reject as a wrong password
+            PasswordHash { iterations: DEFAULT_ITERATIONS, salt: vec![0; SALT_LEN], hash: [0; 32] }.verify(password);
+            return Attempt::Rejected(AuthError::InvalidCredentials);
+        };
+        let mut user = user.clone();
+        let username = user.username.clone();
+        let failed = |err| Attempt::Failed { username: username.clone(), at: now, err };
+
+        if let Some(until) = user.credentials.locked_until.filter(|until| now < *until) {
+            return Attempt::Rejected(AuthError::Locked { until });
+        }
+
+        let matches = user.credentials.password.as_ref().is_some_and(|hash| hash.verify(password));
+        if !matches {
+            return failed(AuthError::InvalidCredentials);
+        }
+
+        // the state is only revealed to someone who knows the password
+        user.lift_expired_suspension(self.clock);
+        if !user.is_active(self.clock) {
+            return Attempt::Rejected(AuthError::NotActive(user.state().clone()));
+        }
+
+        if let Some(totp) = user.credentials.totp.as_mut().filter(|t| t.is_confirmed()) {
+            let Some(code) = code else {
+                return Attempt::Rejected(AuthError::SecondFactorRequired);
+            };
+            if !totp.verify(code, now) && !totp.use_recovery_code(code) {
+                return failed(AuthError::InvalidSecondFactor);
+            }
+        }
+        Attempt::Succeeded { username, at: now, totp: user.credentials.totp }
+    }
+
+    // saves what check found to the user as the store has them now, failures are counted against the stored
+    // credentials so attempts checked at the same time all count towards the lockout
+    pub fn apply(&self, store: &mut dyn UserStore, attempt: Attempt) -> Result<(), AuthError> {
+        let (username, now, totp) = match attempt {
+            Attempt::Rejected(err) => return Err(err),
+            Attempt::Failed { username, at, err } => {
+                let user = store.get(&username).ok_or(AuthError::InvalidCredentials)?.clone();
+                return Err(self.fail(store, user, at, err));
+            }
+            Attempt::Succeeded { username, at, totp } => (username, at, totp)
+        };
+        let mut user = store.get(&username).ok_or(AuthError::InvalidCredentials)?.clone();
+        // failures saved since the check may have locked the account
+        if let Some(until) = user.credentials.locked_until.filter(|until| now < *until) {
+            return Err(AuthError::Locked { until });
+        }
+        user.lift_expired_suspension(self.clock);
+        user.credentials.totp = totp;  // a recovery code, once used, is gone
+        user.credentials.record_success(now);
+        user.sign_in_count += 1;
+        store.update(user)?;
+        if let Some(log) = &self.sign_ins {
+            // the user is signed in by now, a lost analytics event is better than turning them away
+            let _ = log.borrow_mut().record(&username, now);
+        }
+        Ok(())
+    }
+
+    // counts a failed attempt, which may lock the account, and saves it
+    fn fail(&self, store: &mut dyn UserStore, mut user: User, now: u64, err: AuthError) -> AuthError {
+        user.credentials.record_failure(now, &self.policy);
+        let locked = user.credentials.locked_until;
+        if let Err(store_err) = store.update(user) {
+            return AuthError::Store(store_err);
+        }
+        locked.map_or(err, |until| AuthError::Locked { until })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::store::MemoryUserStore;
+
+    const POLICY: LockoutPolicy = LockoutPolicy { max_failures: 3, window: 60, lockout: 300 };
+
+    fn store_with_ann(clock: &ManualClock) -> MemoryUserStore {
+        let mut user = crate::build_user("ann@example.com".to_string(), "ann".to_string()).unwrap();
+        user.set_password_with_iterations("correct horse", 1_000).unwrap();
+        user.activate(clock).unwrap();
+        let mut store = MemoryUserStore::new();
+        store.insert(user).unwrap();
+        store
+    }
+
+    #[test]
+    fn failures_inside_the_window_lock_the_account() {
+        let clock = ManualClock::new(1_000);
+        let mut store = store_with_ann(&clock);
+        let auth = Authenticator::new(POLICY, &clock);
+        for _ in 0..2 {
+            assert!(matches!(auth.sign_in(&mut store, "ann", "wrong"), Err(AuthError::InvalidCredentials)));
+            clock.advance(20);
+        }
+        assert!(matches!(auth.sign_in(&mut store, "ann", "wrong"), Err(AuthError::Locked { until: 1_340 })));
+        // while locked even the right password is turned away
+        assert!(matches!(auth.sign_in(&mut store, "ann", "correct horse"), Err(AuthError::Locked { until: 1_340 })));
+    }
+
+    #[test]
+    fn failures_spread_wider_than_the_window_do_not_lock() {
+        let clock = ManualClock::new(1_000);
+        let mut store = store_with_ann(&clock);
+        let auth = Authenticator::new(POLICY, &clock);
+        for _ in 0..6 {
+            assert!(matches!(auth.sign_in(&mut store, "ann", "wrong"), Err(AuthError::InvalidCredentials)));
+            clock.advance(POLICY.window / 2 + 1);
+        }
+        assert_eq!(store.get("ann").unwrap().credentials().locked_until(), None);
+        auth.sign_in(&mut store, "ann", "correct horse").unwrap();
+    }
+
+    #[test]
+    fn the_lock_lifts_after_the_lockout() {
+        let clock = ManualClock::new(1_000);
+        let mut store = store_with_ann(&clock);
+        let auth = Authenticator::new(POLICY, &clock);
+        for _ in 0..3 {
+            let _ = auth.sign_in(&mut store, "ann", "wrong");
+        }
+        clock.advance(POLICY.lockout - 1);
+        assert!(matches!(auth.sign_in(&mut store, "ann", "correct horse"), Err(AuthError::Locked { .. })));
+        clock.advance(1);
+        auth.sign_in(&mut store, "ann", "correct horse").unwrap();
+        let ann = store.get("ann").unwrap();
+        assert_eq!((ann.credentials().locked_until(), ann.credentials().last_sign_in()), (None, Some(1_300)));
+    }
+}
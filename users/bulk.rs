@@ -0,0 +1,487 @@
+// bulk import and export of users as CSV or JSON, for onboarding accounts from spreadsheets
+//
+// the columns (or JSON keys) are:
+//     username, email          required on import
+//     state                    an AccountState name such as active or suspended
+//     active                   true/false, yes/no or 1/0, used when there is no state column
+//                              with neither column a new account waits for email verification, like any other
+//     sign_in_count            a whole number
+//     roles                    role names separated by ';'
+//     created_at, last_sign_in exported for reference, ignored on import
+//
+// spreadsheets rarely use these exact headers, so ImportOptions::header_map renames columns before they are read
+// every row is checked on its own, a bad row is reported with its line number and the rest still go in
+
+use std::collections::BTreeMap;
+
+use crate::users::clock::Clock;
+use crate::users::json::Json;
+use crate::users::lifecycle::{AccountState, Lifecycle};
+use crate::users::patch::UserPatch;
+use crate::users::rbac::check_name;
+use crate::users::store::UserStore;
+use crate::User;
+
+pub const EXPORT_COLUMNS: [&str; 8] = ["username", "email", "state", "active", "sign_in_count", "roles", "created_at", "last_sign_in"];
+const IMPORT_COLUMNS: [&str; 6] = ["username", "email", "state", "active", "sign_in_count", "roles"];
+const FORMULA_STARTS: [char; 4] = ['=', '+', '-', '@'];  // what spreadsheets take as the start of a formula
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    pub dry_run: bool,  // check and count every row without writing anything to the store
+    pub update_existing: bool,  // rows for usernames already in the store update them, otherwise they are skipped
+    pub header_map: BTreeMap<String, String>  // source column name -> one of the columns above, compared case-insensitively
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub line: usize,
+    pub message: String
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,  // rows with errors, plus existing users that were left alone
+    pub errors: Vec<RowError>,
+    pub ignored_columns: Vec<String>  // columns that did not map onto any user field
+}
+
+// ---- export ----
+
+pub fn export_csv(users: &[&User]) -> String {
+    let mut out = EXPORT_COLUMNS.join(",");
+    out.push_str("\r\n");
+    for user in users {
+        let row: Vec<String> = export_row(user).iter().map(|v| csv_escape(v)).collect();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+pub fn export_json(users: &[&User]) -> String {
+    let records = users.iter().map(|user| {
+        Json::object(vec![
+            ("username", Json::str(&user.username)),
+            ("email", Json::str(&user.email)),
+            ("state", Json::str(user.state().name())),
+            ("active", Json::Bool(user.state() == &AccountState::Active)),
+            ("sign_in_count", Json::uint(user.sign_in_count)),
+            ("roles", Json::Array(user.roles.iter().map(|r| Json::str(r)).collect())),
+            ("created_at", Json::uint(user.lifecycle.created_at())),
+            ("last_sign_in", user.credentials.last_sign_in().map_or(Json::Null, Json::uint))
+        ])
+    });
+    Json::Array(records.collect()).pretty()
+}
+
+fn export_row(user: &User) -> Vec<String> {
+    vec![
+        user.username.clone(),
+        user.email.clone(),
+        user.state().name().to_string(),
+        (user.state() == &AccountState::Active).to_string(),
+        user.sign_in_count.to_string(),
+        user.roles.join(";"),
+        user.lifecycle.created_at().to_string(),
+        user.credentials.last_sign_in().map_or(String::new(), |t| t.to_string())
+    ]
+}
+
+// quotes a field when it holds a comma, quote or line break, doubling any quotes inside (RFC 4180)
+// a field a spreadsheet would run as a formula gets a ' in front, which spreadsheets show as plain text
+// and import_csv takes off again
+pub fn csv_escape(value: &str) -> String {
+    let value = if value.starts_with(FORMULA_STARTS) { format!("'{value}") } else { value.to_string() };
+    if value.contains([',', '"', '\n', '\r']) || value.starts_with(' ') || value.ends_with(' ') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+// undoes the ' csv_escape puts in front of a would-be formula
+fn csv_unescape(value: &str) -> String {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_STARTS) => rest.to_string(),
+        _ => value.to_string()
+    }
+}
+
+// ---- import ----
+
+// one input record, with the line it started on and its fields keyed by canonical column name
+struct Row {
+    line: usize,
+    fields: BTreeMap<String, String>
+}
+
+pub fn import_csv(store: &mut dyn UserStore, text: &str, options: &ImportOptions, clock: &dyn Clock) -> ImportSummary {
+    let mut summary = ImportSummary::default();
+    let records = match parse_csv(text) {
+        Ok(records) => records,
+        Err(err) => {
+            summary.errors.push(err);
+            return summary;
+        }
+    };
+    let Some((_, header)) = records.first() else {
+        return summary;
+    };
+    let columns = map_headers(header, options, &mut summary);
+    let rows = records[1..].iter().filter(|(_, values)| !(values.len() == 1 && values[0].trim().is_empty())).map(|(line, values)| {
+        let mut fields = BTreeMap::new();
+        for (column, value) in columns.iter().zip(values) {
+            if let Some(column) = column {
+                fields.insert(column.clone(), csv_unescape(value));
+            }
+        }
+        (Row { line: *line, fields }, values.len())
+    });
+    let mut rows_checked = Vec::new();
+    for (row, width) in rows {
+        if width != columns.len() {
+            summary.skipped += 1;
+            summary.errors.push(RowError { line: row.line, message: format!("expected {} fields, found {width}", columns.len()) });
+        } else {
+            rows_checked.push(row);
+        }
+    }
+    import_rows(store, rows_checked, options, clock, &mut summary);
+    summary
+}
+
+// accepts a JSON array of objects, or JSON lines with one object per line
+pub fn import_json(store: &mut dyn UserStore, text: &str, options: &ImportOptions, clock: &dyn Clock) -> ImportSummary {
+    let mut summary = ImportSummary::default();
+    let line_of = |offset: usize| text[..offset].matches('\n').count() + 1;
+    let items: Vec<(usize, Result<Json, String>)> = if text.trim_start().starts_with('[') {
+        match Json::parse_array_with_offsets(text) {
+            Ok(items) => items.into_iter().map(|(offset, value)| (line_of(offset), Ok(value))).collect(),
+            Err(err) => {
+                summary.errors.push(RowError { line: line_of(err.position.min(text.len())), message: err.message });
+                return summary;
+            }
+        }
+    } else {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| (index + 1, Json::parse(line).map_err(|e| e.to_string())))
+            .collect()
+    };
+
+    let mut rows = Vec::new();
+    let mut ignored = Vec::new();
+    for (line, item) in items {
+        let fields = match item {
+            Ok(Json::Object(fields)) => fields,
+            Ok(_) => {
+                summary.skipped += 1;
+                summary.errors.push(RowError { line, message: "expected a JSON object".to_string() });
+                continue;
+            }
+            Err(message) => {
+                summary.skipped += 1;
+                summary.errors.push(RowError { line, message });
+                continue;
+            }
+        };
+        let mut row = Row { line, fields: BTreeMap::new() };
+        for (key, value) in fields {
+            let Some(column) = canonical_column(&key, options) else {
+                if !ignored.contains(&key) {
+                    ignored.push(key);
+                }
+                continue;
+            };
+            let text = match value {
+                Json::Null => continue,
+                Json::String(s) => s,
+                Json::Array(items) => items.iter().map(|i| i.as_str().map_or(i.to_string(), String::from)).collect::<Vec<_>>().join(";"),
+                other => other.to_string()
+            };
+            row.fields.insert(column, text);
+        }
+        rows.push(row);
+    }
+    summary.ignored_columns = ignored;
+    import_rows(store, rows, options, clock, &mut summary);
+    summary
+}
+
+fn canonical_column(name: &str, options: &ImportOptions) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    let mapped = options.header_map.iter().find(|(from, _)| from.trim().to_lowercase() == name).map(|(_, to)| to.to_lowercase());
+    let column = mapped.unwrap_or(name);
+    IMPORT_COLUMNS.contains(&column.as_str()).then_some(column)
+}
+
+fn map_headers(header: &[String], options: &ImportOptions, summary: &mut ImportSummary) -> Vec<Option<String>> {
+    header
+        .iter()
+        .map(|name| {
+            let column = canonical_column(name, options);
+            if column.is_none() {
+                summary.ignored_columns.push(name.clone());
+            }
+            column
+        })
+        .collect()
+}
+
+fn import_rows(store: &mut dyn UserStore, rows: Vec<Row>, options: &ImportOptions, clock: &dyn Clock, summary: &mut ImportSummary) {
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    for row in rows {
+        match import_row(store, &row, options, clock, &mut seen) {
+            Ok(Outcome::Created) => summary.created += 1,
+            Ok(Outcome::Updated) => summary.updated += 1,
+            Ok(Outcome::Skipped) => summary.skipped += 1,
+            Err(message) => {
+                summary.skipped += 1;
+                summary.errors.push(RowError { line: row.line, message });
+            }
+        }
+    }
+    // rows rejected while reading the file were reported first, put everything back in file order
+    summary.errors.sort_by_key(|e| e.line);
+}
+
+enum Outcome {
+    Created,
+    Updated,
+    Skipped
+}
+
+fn import_row(store: &mut dyn UserStore, row: &Row, options: &ImportOptions, clock: &dyn Clock, seen: &mut BTreeMap<String, usize>) -> Result<Outcome, String> {
+    let field = |name: &str| row.fields.get(name).map(|v| v.trim()).filter(|v| !v.is_empty());
+    let username = field("username").ok_or("username is missing")?;
+    let email = field("email").ok_or("email is missing")?;
+    let mut user = crate::build_user(email.to_string(), username.to_string()).map_err(|e| e.to_string())?;
+
+    // a name seen earlier in the same file, in any case, is an error in both modes, so a dry run reports what a
+    // real run would do, the store would refuse "BOB" after "bob" too
+    let folded = user.username.to_ascii_lowercase();
+    if let Some(line) = seen.get(&folded) {
+        return Err(format!("username '{}' already appears on line {line}", user.username));
+    }
+    seen.insert(folded, row.line);
+
+    let state = match (field("state"), field("active")) {
+        (Some(name), _) => Some(AccountState::from_name(&name.to_lowercase()).ok_or(format!("unknown state '{name}'"))?),
+        (None, Some(active)) => Some(if parse_bool(active)? { AccountState::Active } else { AccountState::Deactivated }),
+        (None, None) => None
+    };
+    let sign_in_count = field("sign_in_count").map(|n| n.parse::<u64>().map_err(|_| format!("sign_in_count '{n}' is not a whole number"))).transpose()?;
+    let roles: Vec<&str> = field("roles").map_or(Vec::new(), |r| r.split(';').map(str::trim).filter(|r| !r.is_empty()).collect());
+    for role in &roles {
+        check_name(role)?;
+    }
+
+    let Some(existing) = store.get(&user.username) else {
+        if let Some(state) = state {
+            user.lifecycle = Lifecycle::new(state, clock.now());
+        }
+        if let Some(count) = sign_in_count {
+            user.sign_in_count = count;
+        }
+        for role in roles {
+            user.grant_role(role)?;
+        }
+        if !options.dry_run {
+            store.insert(user).map_err(|e| e.to_string())?;
+        }
+        return Ok(Outcome::Created);
+    };
+
+    if !options.update_existing {
+        return Ok(Outcome::Skipped);
+    }
+    let mut existing = existing.clone();
+    let patch = UserPatch { email: Some(user.email), sign_in_count, ..UserPatch::default() };
+    let mut changed = !patch.apply(&mut existing).map_err(|e| e.to_string())?.is_empty();
+    if let Some(state) = state.filter(|s| s.name() != existing.state().name()) {
+        existing.lifecycle.transition(state, clock.now()).map_err(|e| e.to_string())?;
+        changed = true;
+    }
+    for role in roles {
+        changed |= existing.grant_role(role)?;
+    }
+    if !changed {
+        return Ok(Outcome::Skipped);
+    }
+    if !options.dry_run {
+        store.update(existing).map_err(|e| e.to_string())?;
+    }
+    Ok(Outcome::Updated)
+}
+
+fn parse_bool(text: &str) -> Result<bool, String> {
+    match text.to_lowercase().as_str() {
+        "true" | "yes" | "y" | "1" => Ok(true),
+        "false" | "no" | "n" | "0" => Ok(false),
+        _ => Err(format!("active '{text}' is not true or false"))
+    }
+}
+
+// splits CSV text into records, each with the line number it starts on
+// quoted fields may contain commas, doubled quotes and line breaks
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, RowError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut in_quotes = false;
+    let mut quote_line = 0;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();  // Excel puts a byte order mark at the start
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                '\n' => {
+                    line += 1;
+                    field.push('\n');
+                }
+                c => field.push(c)
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                quote_line = line;
+            }
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                records.push((record_line, std::mem::take(&mut record)));
+                line += 1;
+                record_line = line;
+            }
+            c => field.push(c)
+        }
+    }
+    if in_quotes {
+        return Err(RowError { line: quote_line, message: "a quoted field is never closed".to_string() });
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::store::MemoryUserStore;
+
+    // line 3's note runs over two lines, so the rows after it start a line later than their position
+    const SHEET: &str = "\u{feff}User Name,E-mail,Active,sign_in_count,roles,notes\r\n\
+        ann,ann@example.com,no,4,server;host,\"hi, there\"\n\
+        bob,bob@example.com,yes,,,\"two\nlines\"\n\
+        cat,not-an-email,yes,,,\n\
+        bob,bob2@example.com,yes,,,\n\
+        dan,dan@example.com,maybe,,,\n\
+        eve,eve@example.com\n";
+
+    fn options() -> ImportOptions {
+        let mut options = ImportOptions::default();
+        options.header_map.insert("User Name".to_string(), "username".to_string());
+        options.header_map.insert("e-mail".to_string(), "email".to_string());
+        options
+    }
+
+    #[test]
+    fn bad_rows_are_reported_by_line_and_the_rest_go_in() {
+        let (mut store, clock) = (MemoryUserStore::new(), ManualClock::new(1_000));
+        let summary = import_csv(&mut store, SHEET, &options(), &clock);
+        assert_eq!((summary.created, summary.updated, summary.skipped), (2, 0, 4), "{summary:?}");
+        let errors: Vec<(usize, &str)> = summary.errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(errors[0].0, 5);  // cat's email
+        assert_eq!(errors[1], (6, "username 'bob' already appears on line 3"));
+        assert_eq!(errors[2], (7, "active 'maybe' is not true or false"));
+        assert_eq!(errors[3], (8, "expected 6 fields, found 2"));
+        assert_eq!(summary.ignored_columns, vec!["notes".to_string()]);
+        let ann = store.get("ann").unwrap();
+        assert_eq!((ann.state().name(), ann.sign_in_count, ann.roles.len()), ("deactivated", 4, 2));
+    }
+
+    #[test]
+    fn a_dry_run_reports_the_same_and_writes_nothing() {
+        let (mut store, clock) = (MemoryUserStore::new(), ManualClock::new(1_000));
+        let dry = import_csv(&mut store, SHEET, &ImportOptions { dry_run: true, ..options() }, &clock);
+        assert!(store.list().is_empty());
+        let real = import_csv(&mut store, SHEET, &options(), &clock);
+        assert_eq!(dry, real);
+
+        // with update_existing a second run changes what differs and leaves the rest, whatever case the names are in
+        let sheet = "username,email,sign_in_count\nANN,ann@example.org,4\nBob,bob@example.com,\n";
+        let update = ImportOptions { dry_run: true, update_existing: true, ..ImportOptions::default() };
+        let summary = import_csv(&mut store, sheet, &update, &clock);
+        assert_eq!((summary.created, summary.updated, summary.skipped), (0, 1, 1), "{summary:?}");
+        assert_eq!(store.get("ann").unwrap().email, "ann@example.com");
+        let real = import_csv(&mut store, sheet, &ImportOptions { dry_run: false, ..update }, &clock);
+        assert_eq!((real.created, real.updated, real.skipped, real.errors.len()), (0, 1, 1, 0));
+        let ann = store.get("ann").unwrap();
+        assert_eq!((ann.username.as_str(), ann.email.as_str()), ("ann", "ann@example.org"));
+
+        // two spellings of one name in a file are one account, a dry run and a real run agree on that
+        let sheet = "username,email\ncarl,carl@example.com\nCARL,carl2@example.com\n";
+        let dry = import_csv(&mut store, sheet, &ImportOptions { dry_run: true, ..ImportOptions::default() }, &clock);
+        let real = import_csv(&mut store, sheet, &ImportOptions::default(), &clock);
+        assert_eq!(dry, real);
+        assert_eq!((real.created, real.errors[0].line), (1, 3));
+        assert_eq!(real.errors[0].message, "username 'CARL' already appears on line 2");
+    }
+
+    #[test]
+    fn fields_that_look_like_formulas_are_exported_as_text() {
+        assert_eq!(csv_escape("=HYPERLINK(\"http://evil\")"), "\"'=HYPERLINK(\"\"http://evil\"\")\"");
+        assert_eq!(csv_escape("+1"), "'+1");
+        assert_eq!(csv_escape("-2+3"), "'-2+3");
+        assert_eq!(csv_escape("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_escape("a=b"), "a=b");
+        assert_eq!(csv_escape("'quoted"), "'quoted");
+    }
+
+    #[test]
+    fn an_export_imports_back_as_it_was() {
+        let (mut store, clock) = (MemoryUserStore::new(), ManualClock::new(1_000));
+        import_csv(&mut store, SHEET, &options(), &clock);
+        let mut csv_copy = MemoryUserStore::new();
+        let summary = import_csv(&mut csv_copy, &export_csv(&store.list()), &ImportOptions::default(), &clock);
+        assert_eq!((summary.created, summary.errors.len()), (2, 0));
+        let mut json_copy = MemoryUserStore::new();
+        let summary = import_json(&mut json_copy, &export_json(&store.list()), &ImportOptions::default(), &clock);
+        assert_eq!((summary.created, summary.errors.len()), (2, 0));
+        for copy in [&csv_copy, &json_copy] {
+            let ann = copy.get("ann").unwrap();
+            assert_eq!((ann.email.as_str(), ann.state().name(), ann.roles.clone()), ("ann@example.com", "deactivated", vec!["host".to_string(), "server".to_string()]));
+        }
+        // a quoted formula from a spreadsheet comes back without its '
+        let summary = import_csv(&mut csv_copy, "username,email,roles\nzed,zed@example.com,'-x\n", &ImportOptions::default(), &clock);
+        assert_eq!(summary.created, 1);
+        assert_eq!(csv_copy.get("zed").unwrap().roles, vec!["-x".to_string()]);
+    }
+
+    #[test]
+    fn json_errors_point_at_the_line_of_the_record() {
+        let (mut store, clock) = (MemoryUserStore::new(), ManualClock::new(1_000));
+        let summary = import_json(&mut store, "[\n{\"username\": \"zed\", \"email\": \"zed@example.com\"},\n{\"username\": \"yan\"}\n]", &ImportOptions::default(), &clock);
+        assert_eq!((summary.created, summary.errors[0].line), (1, 3));
+        let summary = import_json(&mut store, "{\"username\": \"xia\", \"email\": \"xia@example.com\", \"extra\": 1}\n\nnot json\n", &ImportOptions::default(), &clock);
+        assert_eq!((summary.created, summary.errors[0].line), (1, 3));
+        assert_eq!(summary.ignored_columns, vec!["extra".to_string()]);
+    }
+}
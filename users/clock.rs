@@ -0,0 +1,50 @@
+// anything that records when something happened asks a Clock rather than the system directly
+// tests hand in a ManualClock so they can move time forward without sleeping
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock {
+    fn now(&self) -> u64;  // seconds since the unix epoch
+}
+
+// lets one clock be shared by several owners, such as a server and its session manager
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+// a clock that only moves when told to, an atomic so it can be shared between threads by reference
+pub struct ManualClock {
+    now: AtomicU64
+}
+
+impl ManualClock {
+    pub fn new(start: u64) -> Self {
+        ManualClock { now: AtomicU64::new(start) }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,547 @@
+// the same person registered twice, and folding the two accounts back into one
+//
+// find_duplicates scores pairs of users on these signals, each a rough chance that the pair is one person:
+//     SameEmail         the same address once case is ignored                          0.95
+//     EmailAlias        the same mailbox written differently, a.hus+shop@gmail.com     0.85
+//     SameMailboxName   the same name before the @ at different providers              0.35
+//     UsernameVariant   usernames that differ only in case or punctuation              0.60
+//     SimilarUsername   usernames one or two edits apart                               0.45 / 0.25
+//     UsernameInEmail   one account's username is the other's mailbox name             0.30
+// signals combine as independent chances, so two weak ones add up to more than either alone
+//
+// merge_users keeps one account and removes the other, the MergeRecord it returns holds both accounts
+// as they were, which is what undo_merge puts back
+// given the Linked logs, the absorbed account's sign-ins and organisation memberships go to the survivor and its
+// sessions are signed out; the record says what moved, so undoing hands it back
+// the record is meant to be kept wherever the support tool that made the merge keeps its history, so it holds
+// neither account's password hash nor second factor: an account brought back by undo_merge has no password and
+// its owner sets a new one, the survivor keeps the credentials it has now
+// it does name both accounts and their emails, so whatever keeps records has to take part in erasure too
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::users::analytics::SignInLog;
+use crate::users::clock::Clock;
+use crate::users::json::Json;
+use crate::users::lifecycle::Lifecycle;
+use crate::users::orgs::{MovedMembership, Organisations};
+use crate::users::search::{default_max_distance, edit_distance, Field, MatchKind, SearchIndex};
+use crate::users::session::SessionManager;
+use crate::users::store::{StoreError, UserStore};
+use crate::User;
+
+pub const DEFAULT_THRESHOLD: f64 = 0.5;
+
+// providers that deliver name+anything to name, elsewhere a + can be part of a different mailbox
+const PLUS_ADDRESSING_DOMAINS: [&str; 9] =
+    ["gmail.com", "googlemail.com", "outlook.com", "hotmail.com", "live.com", "icloud.com", "me.com", "fastmail.com", "proton.me"];
+// providers that ignore dots in the mailbox name, googlemail.com is an old name for gmail.com
+const DOTLESS_DOMAINS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+// how many neighbours each user is scored against within a group, and among its fuzzy username matches
+// a mailbox name such as info or admin can be shared by thousands of unrelated users, scoring every pair of
+// them is quadratic, so a group is sorted by canonical email and each member only meets the next few
+const WINDOW: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    SameEmail,
+    EmailAlias,
+    SameMailboxName,
+    UsernameVariant,
+    SimilarUsername { distance: usize },
+    UsernameInEmail
+}
+
+impl Signal {
+    pub fn weight(&self) -> f64 {
+        match self {
+            Signal::SameEmail => 0.95,
+            Signal::EmailAlias => 0.85,
+            Signal::SameMailboxName => 0.35,
+            Signal::UsernameVariant => 0.6,
+            Signal::SimilarUsername { distance: 1 } => 0.45,
+            Signal::SimilarUsername { .. } => 0.25,
+            Signal::UsernameInEmail => 0.3
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Signal::SameEmail => write!(f, "same email address"),
+            Signal::EmailAlias => write!(f, "email addresses reach the same mailbox"),
+            Signal::SameMailboxName => write!(f, "same mailbox name at different providers"),
+            Signal::UsernameVariant => write!(f, "usernames differ only in case or punctuation"),
+            Signal::SimilarUsername { distance } => write!(f, "usernames are {distance} edit(s) apart"),
+            Signal::UsernameInEmail => write!(f, "one username is the other's mailbox name")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateCandidate {
+    pub first: String,  // usernames, first < second
+    pub second: String,
+    pub score: f64,  // 0 to 1
+    pub signals: Vec<Signal>
+}
+
+// lowercased, with the +tag dropped from the mailbox name at providers that support one and, for Gmail,
+// the dots as well
+pub fn canonical_email(email: &str) -> String {
+    let email = email.trim().to_lowercase();
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return email;
+    };
+    let local = if PLUS_ADDRESSING_DOMAINS.contains(&domain) { local.split('+').next().unwrap_or(local) } else { local };
+    if DOTLESS_DOMAINS.contains(&domain) {
+        format!("{}@gmail.com", local.replace('.', ""))
+    } else {
+        format!("{local}@{domain}")
+    }
+}
+
+fn mailbox_name(canonical: &str) -> &str {
+    canonical.rsplit_once('@').map_or(canonical, |(local, _)| local)
+}
+
+// lowercase letters and digits only, so "A.Hus" and "ahus" compare equal
+fn username_key(username: &str) -> String {
+    username.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+// the signals between two users, strongest first
+pub fn compare(a: &User, b: &User) -> Vec<Signal> {
+    let mut signals = Vec::new();
+    let (email_a, email_b) = (canonical_email(&a.email), canonical_email(&b.email));
+    if email_a == email_b {
+        signals.push(if a.email.eq_ignore_ascii_case(&b.email) { Signal::SameEmail } else { Signal::EmailAlias });
+    } else if mailbox_name(&email_a) == mailbox_name(&email_b) {
+        signals.push(Signal::SameMailboxName);
+    }
+    let (name_a, name_b) = (username_key(&a.username), username_key(&b.username));
+    if name_a == name_b {
+        signals.push(Signal::UsernameVariant);
+    } else {
+        let distance = edit_distance(&name_a, &name_b);
+        if distance > 0 && distance <= default_max_distance(&name_a).min(default_max_distance(&name_b)) {
+            signals.push(Signal::SimilarUsername { distance });
+        }
+    }
+    if name_a == username_key(mailbox_name(&email_b)) || name_b == username_key(mailbox_name(&email_a)) {
+        signals.push(Signal::UsernameInEmail);
+    }
+    signals.sort_by(|x, y| y.weight().total_cmp(&x.weight()));
+    signals
+}
+
+pub fn score(signals: &[Signal]) -> f64 {
+    1.0 - signals.iter().map(|s| 1.0 - s.weight()).product::<f64>()
+}
+
+fn ordered<'a>(a: &'a str, b: &'a str) -> (&'a str, &'a str) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+// every pair scoring at least threshold, best first
+// only pairs that share a canonical email, a mailbox name or a near-identical username are scored,
+// found through lookup tables and the search index rather than by comparing every user with every other
+pub fn find_duplicates(store: &dyn UserStore, threshold: f64) -> Vec<DuplicateCandidate> {
+    let pairs = candidate_pairs(store);
+    let mut candidates: Vec<DuplicateCandidate> = pairs
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let signals = compare(store.get(a)?, store.get(b)?);
+            let score = score(&signals);
+            (score >= threshold).then(|| DuplicateCandidate { first: a.to_string(), second: b.to_string(), score, signals })
+        })
+        .collect();
+    candidates.sort_by(|x, y| y.score.total_cmp(&x.score).then_with(|| x.first.cmp(&y.first)));
+    candidates
+}
+
+// at most WINDOW pairs for each user in each group and WINDOW more from its nearest fuzzy matches
+fn candidate_pairs(store: &dyn UserStore) -> BTreeSet<(&str, &str)> {
+    let users = store.list();
+    // (canonical email, username), sorted so the same address and then the same provider sit side by side
+    let mut groups: BTreeMap<String, Vec<(String, &str)>> = BTreeMap::new();
+    for user in &users {
+        let email = canonical_email(&user.email);
+        let name = username_key(&user.username);
+        let member = || (email.clone(), user.username.as_str());
+        groups.entry(format!("mailbox:{}", mailbox_name(&email))).or_default().push(member());
+        groups.entry(format!("name:{name}")).or_default().push(member());
+        if name != mailbox_name(&email) {
+            // a username that is someone's mailbox name lands in the same group as that mailbox
+            groups.entry(format!("mailbox:{name}")).or_default().push(member());
+        }
+    }
+
+    let mut pairs = BTreeSet::new();
+    for members in groups.values_mut() {
+        members.sort();
+        for (i, (_, a)) in members.iter().enumerate() {
+            for (_, b) in members[i + 1..].iter().take(WINDOW) {
+                pairs.insert(ordered(a, b));
+            }
+        }
+    }
+    let index = SearchIndex::build(store);
+    for user in &users {
+        let mut found = index.fuzzy(&user.username, default_max_distance(&user.username));
+        found.retain(|f| f.field == Field::Username && matches!(f.kind, MatchKind::Fuzzy { .. }) && f.username != user.username);
+        found.sort();
+        for other in found.iter().take(WINDOW).filter_map(|f| store.get(&f.username)) {
+            pairs.insert(ordered(&user.username, &other.username));
+        }
+    }
+    pairs
+}
+
+#[derive(Debug)]
+pub enum MergeError {
+    SameUser,
+    Conflict(String),  // the survivor changed after the merge, undoing would throw those changes away
+    Corrupt(String),  // a MergeRecord that could not be read back
+    Store(StoreError),
+    SignIns(String)  // the sign-in log could not be written, the accounts themselves are merged or split already
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeError::SameUser => write!(f, "an account cannot be merged into itself"),
+            MergeError::Conflict(name) => write!(f, "'{name}' has changed since the merge, it cannot be undone automatically"),
+            MergeError::Corrupt(message) => write!(f, "unreadable merge record: {message}"),
+            MergeError::Store(err) => write!(f, "{err}"),
+            MergeError::SignIns(message) => write!(f, "could not move the sign-ins: {message}")
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl From<StoreError> for MergeError {
+    fn from(err: StoreError) -> Self {
+        MergeError::Store(err)
+    }
+}
+
+// everything else that knows accounts by username, each one merge_users is given follows the merge
+// fill in what there is: Linked { sign_ins: Some(&mut log), ..Linked::default() }
+#[derive(Default)]
+pub struct Linked<'a> {
+    pub sign_ins: Option<&'a mut SignInLog>,
+    pub orgs: Option<&'a mut Organisations>,
+    pub sessions: Option<&'a mut SessionManager>
+}
+
+// users are kept as their stored JSON without secrets, so a record can be written out and undone after a restart
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeRecord {
+    pub survivor: String,
+    pub absorbed: String,
+    pub actor: String,
+    pub at: u64,
+    survivor_before: Json,
+    absorbed_before: Json,
+    survivor_after: Json,
+    pub sign_ins: Vec<u64>,  // the times of the absorbed account's sign-ins, now the survivor's
+    pub memberships: Vec<MovedMembership>
+}
+
+impl MergeRecord {
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("survivor", Json::str(&self.survivor)),
+            ("absorbed", Json::str(&self.absorbed)),
+            ("actor", Json::str(&self.actor)),
+            ("at", Json::uint(self.at)),
+            ("survivor_before", self.survivor_before.clone()),
+            ("absorbed_before", self.absorbed_before.clone()),
+            ("survivor_after", self.survivor_after.clone()),
+            ("sign_ins", Json::Array(self.sign_ins.iter().map(|t| Json::uint(*t)).collect())),
+            ("memberships", Json::Array(self.memberships.iter().map(MovedMembership::to_json).collect()))
+        ])
+    }
+
+    pub fn from_json(value: &Json) -> Result<Self, String> {
+        let text = |key: &str| value.get(key).and_then(Json::as_str).map(String::from).ok_or(format!("'{key}' is missing"));
+        let user = |key: &str| {
+            let json = value.get(key).ok_or(format!("'{key}' is missing"))?;
+            User::from_json(json)?;  // checked now rather than when the merge is undone
+            Ok::<Json, String>(json.clone())
+        };
+        Ok(MergeRecord {
+            survivor: text("survivor")?,
+            absorbed: text("absorbed")?,
+            actor: text("actor")?,
+            at: value.get("at").and_then(Json::as_u64).ok_or("'at' is missing")?,
+            survivor_before: user("survivor_before")?,
+            absorbed_before: user("absorbed_before")?,
+            survivor_after: user("survivor_after")?,
+            // records from before sign-ins and memberships moved have neither
+            sign_ins: value.get("sign_ins").and_then(Json::as_array).map_or(Vec::new(), |t| t.iter().filter_map(Json::as_u64).collect()),
+            memberships: match value.get("memberships").and_then(Json::as_array) {
+                Some(list) => list.iter().map(MovedMembership::from_json).collect::<Result<_, _>>()?,
+                None => Vec::new()
+            }
+        })
+    }
+}
+
+// survivor keeps its username, email, state and credentials
+// sign-in counts are added, roles are combined, the state histories are interleaved by time
+// and the account counts as created when the older of the two was
+fn combine(survivor: &User, absorbed: &User) -> User {
+    let mut merged = survivor.clone();
+    merged.sign_in_count = survivor.sign_in_count.saturating_add(absorbed.sign_in_count);
+    for role in &absorbed.roles {
+        if !merged.roles.contains(role) {
+            merged.roles.push(role.clone());
+        }
+    }
+    let mut history = survivor.lifecycle.history().to_vec();
+    history.extend_from_slice(absorbed.lifecycle.history());
+    history.sort_by_key(|t| t.at);  // stable, so equal times keep the survivor's entries first
+    let created_at = survivor.lifecycle.created_at().min(absorbed.lifecycle.created_at());
+    merged.lifecycle = Lifecycle::restore(survivor.state().clone(), created_at, history);
+    merged
+}
+
+// the user as a record keeps it, with the password hash and second factor left out
+fn without_secrets(user: &User) -> Json {
+    let mut user = user.clone();
+    user.credentials.erase_secrets();
+    user.to_json()
+}
+
+pub fn merge_users(
+    store: &mut dyn UserStore,
+    linked: Linked,
+    survivor: &str,
+    absorbed: &str,
+    actor: &str,
+    clock: &dyn Clock
+) -> Result<MergeRecord, MergeError> {
+    let keep = store.get(survivor).ok_or_else(|| StoreError::NotFound(survivor.to_string()))?.clone();
+    let gone = store.get(absorbed).ok_or_else(|| StoreError::NotFound(absorbed.to_string()))?.clone();
+    if keep.username == gone.username {
+        return Err(MergeError::SameUser);
+    }
+    let (survivor, absorbed) = (keep.username.as_str(), gone.username.as_str());  // as stored, for the logs
+    let merged = combine(&keep, &gone);
+    let mut record = MergeRecord {
+        survivor: survivor.to_string(),
+        absorbed: absorbed.to_string(),
+        actor: actor.to_string(),
+        at: clock.now(),
+        survivor_before: without_secrets(&keep),
+        absorbed_before: without_secrets(&gone),
+        survivor_after: without_secrets(&merged),
+        sign_ins: Vec::new(),
+        memberships: Vec::new()
+    };
+    store.update(merged)?;
+    if let Err(err) = store.delete(absorbed) {
+        store.update(keep)?;  // leave both accounts as they were
+        return Err(err.into());
+    }
+    if let Some(sessions) = linked.sessions {
+        sessions.revoke_all(absorbed);
+    }
+    if let Some(orgs) = linked.orgs {
+        record.memberships = orgs.merge_accounts(survivor, absorbed);
+        let _ = orgs.save();  // like a rename, a failed write only leaves the old name in the file
+    }
+    if let Some(sign_ins) = linked.sign_ins {
+        record.sign_ins = sign_ins.events().iter().filter(|e| e.username == absorbed).map(|e| e.at).collect();
+        sign_ins.rename(absorbed, survivor).map_err(|e| MergeError::SignIns(e.to_string()))?;
+    }
+    Ok(record)
+}
+
+// puts both accounts back as they were before the merge, with what moved in the linked logs
+// refused if the survivor has changed since, or if the absorbed username has been taken again
+// the survivor keeps its current credentials, the absorbed account comes back without a password or second factor
+pub fn undo_merge(store: &mut dyn UserStore, linked: Linked, record: &MergeRecord) -> Result<(), MergeError> {
+    let current = store.get(&record.survivor).ok_or_else(|| StoreError::NotFound(record.survivor.clone()))?;
+    if without_secrets(current) != record.survivor_after {
+        return Err(MergeError::Conflict(record.survivor.clone()));
+    }
+    if store.get(&record.absorbed).is_some() {
+        return Err(StoreError::Duplicate(record.absorbed.clone()).into());
+    }
+    let mut survivor = User::from_json(&record.survivor_before).map_err(MergeError::Corrupt)?;
+    survivor.credentials = current.credentials.clone();
+    let absorbed = User::from_json(&record.absorbed_before).map_err(MergeError::Corrupt)?;
+    store.insert(absorbed)?;
+    store.update(survivor)?;
+    if let Some(orgs) = linked.orgs {
+        orgs.split_accounts(&record.survivor, &record.memberships);
+        let _ = orgs.save();
+    }
+    if let Some(sign_ins) = linked.sign_ins {
+        sign_ins.reassign(&record.survivor, &record.absorbed, &record.sign_ins).map_err(|e| MergeError::SignIns(e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::orgs::OrgRole;
+    use crate::users::store::MemoryUserStore;
+    use crate::users::totp::TotpConfig;
+
+    fn store_of(users: &[(&str, &str)]) -> MemoryUserStore {
+        let clock = ManualClock::new(100);
+        let mut store = MemoryUserStore::new();
+        for (email, username) in users {
+            let mut user = crate::build_user(email.to_string(), username.to_string()).unwrap();
+            user.activate(&clock).unwrap();
+            store.insert(user).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn tags_and_dots_are_only_folded_where_the_provider_ignores_them() {
+        assert_eq!(canonical_email("A.Hus+shop@GoogleMail.com"), "ahus@gmail.com");
+        assert_eq!(canonical_email("a.hus+news@outlook.com"), "a.hus@outlook.com");
+        assert_eq!(canonical_email("a.hus+x@yahoo.com"), "a.hus+x@yahoo.com");
+        assert_eq!(canonical_email("sales+eu@example.com"), "sales+eu@example.com");
+        assert_eq!(canonical_email("not an email"), "not an email");
+    }
+
+    #[test]
+    fn likely_pairs_are_found_and_strangers_left_alone() {
+        let store = store_of(&[
+            ("a.hus@gmail.com", "AHus"),
+            ("ahus+spam@gmail.com", "AHus2"),
+            ("ahus@yahoo.com", "A_Hus"),
+            ("zed@x.io", "zed"),
+            ("bob@x.io", "robert"),
+            ("bob@y.io", "roberto"),
+            ("sales+eu@example.com", "emea"),
+            ("sales+us@example.com", "americas")
+        ]);
+        let found = find_duplicates(&store, DEFAULT_THRESHOLD);
+        assert_eq!((found[0].first.as_str(), found[0].second.as_str()), ("AHus", "AHus2"));
+        assert_eq!(found[0].signals[0], Signal::EmailAlias);
+        assert!(found.iter().any(|c| c.first == "robert" && c.second == "roberto"));
+        assert!(!found.iter().any(|c| [&c.first, &c.second].iter().any(|n| ["zed", "emea", "americas"].contains(&n.as_str()))));
+    }
+
+    // usernames eight letters long and far apart, so only the shared mailbox name ties them together
+    fn stranger(i: usize) -> String {
+        let mut seed = i as u64 * 2_654_435_761 + 1;
+        (0..8)
+            .map(|_| {
+                seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                (b'a' + (seed >> 59) as u8 % 26) as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_crowded_mailbox_name_is_scored_in_a_window_not_pair_by_pair() {
+        let users: Vec<(String, String)> = (0..400).map(|i| (format!("info@company{i:03}.example"), stranger(i))).collect();
+        let mut users: Vec<(&str, &str)> = users.iter().map(|(e, u)| (e.as_str(), u.as_str())).collect();
+        users.push(("info@company200.example", "second_login"));
+        let store = store_of(&users);
+        let pairs = candidate_pairs(&store);
+        assert!(pairs.len() <= 2 * 401 * WINDOW, "{} pairs", pairs.len());
+        // the one real duplicate sorts next to its twin, so the window still reaches it
+        let found = find_duplicates(&store, DEFAULT_THRESHOLD);
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert_eq!((found[0].second.as_str(), found[0].signals[0]), ("second_login", Signal::SameEmail));
+    }
+
+    #[test]
+    fn a_merge_can_be_undone_until_the_survivor_changes() {
+        let clock = ManualClock::new(200);
+        let mut store = store_of(&[("a.hus@gmail.com", "AHus"), ("ahus+spam@gmail.com", "AHus2")]);
+        let mut first = store.get("AHus").unwrap().clone();
+        first.grant_role("admin").unwrap();
+        first.sign_in_count = 3;
+        store.update(first).unwrap();
+        let mut second = store.get("AHus2").unwrap().clone();
+        second.grant_role("server").unwrap();
+        second.sign_in_count = 4;
+        store.update(second).unwrap();
+        let before = store.get("AHus").unwrap().to_json();
+
+        let record = merge_users(&mut store, Linked::default(), "AHus", "AHus2", "support", &clock).unwrap();
+        let merged = store.get("AHus").unwrap();
+        assert_eq!((merged.sign_in_count, merged.roles.clone()), (7, vec!["admin".to_string(), "server".to_string()]));
+        assert!(store.get("AHus2").is_none());
+        let record = MergeRecord::from_json(&Json::parse(&record.to_json().to_string()).unwrap()).unwrap();
+        undo_merge(&mut store, Linked::default(), &record).unwrap();
+        assert_eq!(store.get("AHus").unwrap().to_json(), before);
+        assert_eq!(store.get("AHus2").unwrap().sign_in_count, 4);
+
+        let record = merge_users(&mut store, Linked::default(), "AHus", "AHus2", "support", &clock).unwrap();
+        let mut changed = store.get("AHus").unwrap().clone();
+        changed.sign_in_count += 1;
+        store.update(changed).unwrap();
+        assert!(matches!(undo_merge(&mut store, Linked::default(), &record), Err(MergeError::Conflict(_))));
+        assert!(matches!(merge_users(&mut store, Linked::default(), "AHus", "AHus", "support", &clock), Err(MergeError::SameUser)));
+    }
+
+    #[test]
+    fn sign_ins_memberships_and_sessions_follow_a_merge_and_come_back_on_undo() {
+        let clock = ManualClock::new(200);
+        let mut store = store_of(&[("a.hus@gmail.com", "AHus"), ("ahus+spam@gmail.com", "AHus2"), ("bea@x.io", "bea")]);
+        let mut second = store.get("AHus2").unwrap().clone();
+        second.set_password_with_iterations("correct horse", 1).unwrap();
+        second.enrol_totp("basics", TotpConfig::default()).unwrap();
+        store.update(second).unwrap();
+
+        let mut sign_ins = SignInLog::new();
+        for (username, at) in [("AHus", 10), ("AHus2", 20), ("bea", 30), ("AHus2", 40)] {
+            sign_ins.record(username, at).unwrap();
+        }
+        let mut orgs = Organisations::new();
+        orgs.create("basics", "Basics", store.get("bea").unwrap(), &clock).unwrap();
+        orgs.create("acme", "Acme", store.get("AHus2").unwrap(), &clock).unwrap();
+        let code = orgs.invite("basics", "bea", "a.hus@gmail.com", OrgRole::Member, &clock).unwrap();
+        orgs.accept(&code, store.get("AHus").unwrap(), None, &clock).unwrap();
+        let code = orgs.invite("basics", "bea", "ahus+spam@gmail.com", OrgRole::Admin, &clock).unwrap();
+        orgs.accept(&code, store.get("AHus2").unwrap(), None, &clock).unwrap();
+        orgs.create_team("basics", "bea", "kitchen").unwrap();
+        orgs.add_to_team("basics", "bea", "kitchen", "AHus2").unwrap();
+        let mut sessions = SessionManager::new("k1", b"session key", ManualClock::new(200)).unwrap();
+        let (token, _) = sessions.issue(store.get("AHus2").unwrap()).unwrap();
+
+        let linked = Linked { sign_ins: Some(&mut sign_ins), orgs: Some(&mut orgs), sessions: Some(&mut sessions) };
+        let record = merge_users(&mut store, linked, "AHus", "AHus2", "support", &clock).unwrap();
+        let times: Vec<u64> = sign_ins.events().iter().filter(|e| e.username == "AHus").map(|e| e.at).collect();
+        assert_eq!(times, [10, 20, 40]);
+        assert_eq!(orgs.get("acme", "AHus").unwrap().role_of("AHus"), Some(OrgRole::Owner));
+        assert_eq!(orgs.get("basics", "AHus").unwrap().role_of("AHus"), Some(OrgRole::Admin));  // the higher of the two
+        assert_eq!(orgs.team_members("basics", "AHus", "kitchen").unwrap()[0].account, "AHus");
+        assert!(sessions.verify(&token).is_err());
+
+        // the record can be written out without the absorbed account's secrets
+        let text = record.to_json().to_string();
+        assert!(!text.contains("pbkdf2") && !text.contains("\"secret\""), "{text}");
+        let record = MergeRecord::from_json(&Json::parse(&text).unwrap()).unwrap();
+
+        let linked = Linked { sign_ins: Some(&mut sign_ins), orgs: Some(&mut orgs), ..Linked::default() };
+        undo_merge(&mut store, linked, &record).unwrap();
+        let times: Vec<u64> = sign_ins.events().iter().filter(|e| e.username == "AHus2").map(|e| e.at).collect();
+        assert_eq!(times, [20, 40]);
+        assert_eq!(orgs.get("acme", "AHus2").unwrap().role_of("AHus2"), Some(OrgRole::Owner));
+        assert!(orgs.get("acme", "AHus").is_err());
+        let basics = orgs.get("basics", "bea").unwrap();
+        assert_eq!((basics.role_of("AHus"), basics.role_of("AHus2")), (Some(OrgRole::Member), Some(OrgRole::Admin)));
+        assert_eq!(orgs.team_members("basics", "bea", "kitchen").unwrap()[0].account, "AHus2");
+        let back = store.get("AHus2").unwrap();
+        assert!(!back.credentials.has_password() && !back.has_totp());  // its owner sets a new password
+    }
+}
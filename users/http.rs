@@ -0,0 +1,226 @@
+// just enough HTTP/1.1 to serve JSON on localhost: read one request from a TcpStream, write one response back
+// every response closes the connection, which keeps the server simple and is allowed by HTTP/1.1
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::users::json::Json;
+
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);  // for the whole request, not each read
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,  // percent-decoded, without the query string
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,  // names lowercased
+    pub body: Vec<u8>
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    // the path split on '/', so "/users/AHus" gives ["users", "AHus"]
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+
+    pub fn json(&self) -> Result<Json, Response> {
+        let text = std::str::from_utf8(&self.body).map_err(|_| Response::error(400, "request body is not UTF-8"))?;
+        Json::parse(text).map_err(|e| Response::error(400, &format!("invalid JSON: {e}")))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Option<Json>,
+    pub headers: Vec<(String, String)>
+}
+
+impl Response {
+    pub fn json(status: u16, body: Json) -> Self {
+        Response { status, body: Some(body), headers: Vec::new() }
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Response { status, body: None, headers: Vec::new() }
+    }
+
+    // errors always have the same shape, {"error": "..."}, so clients can show the message as it is
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, Json::object(vec![("error", Json::str(message))]))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> io::Result<()> {
+        let body = self.body.as_ref().map_or(String::new(), |b| b.to_string());
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if self.body.is_some() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        423 => "Locked",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown"
+    }
+}
+
+// a socket's read timeout starts again with every byte, so a client sending one a second would hold the
+// connection for ever, this gives each read only what is left of REQUEST_TIMEOUT
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the request took too long to arrive"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+fn read_error(err: io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Response::error(408, "the request took too long to arrive"),
+        _ => Response::error(400, "could not read the request")
+    }
+}
+
+// reads one request, a Response comes back as the error when the request itself is malformed
+pub fn read_request(stream: &TcpStream) -> Result<Request, Response> {
+    let mut reader = BufReader::new(Deadline { stream, until: Instant::now() + REQUEST_TIMEOUT });
+    let mut header_bytes = 0;
+    let mut next_line = |reader: &mut BufReader<Deadline>| -> Result<String, Response> {
+        let mut line = String::new();
+        // read_line would buffer a line without end, so it is only ever offered one byte past the limit
+        let limit = (MAX_HEADER_BYTES - header_bytes + 1) as u64;
+        let read = reader.by_ref().take(limit).read_line(&mut line).map_err(read_error)?;
+        header_bytes += read;
+        if header_bytes > MAX_HEADER_BYTES {
+            return Err(Response::error(431, "request headers are too large"));
+        }
+        if read == 0 {
+            return Err(Response::error(400, "connection closed before the request was complete"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    };
+
+    let request_line = next_line(&mut reader)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(Response::error(400, "malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Response::error(400, "only HTTP/1.x is supported"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = next_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| Response::error(400, "malformed header"))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+
+    let length = match headers.iter().find(|(n, _)| n == "content-length") {
+        Some((_, value)) => value.parse::<usize>().map_err(|_| Response::error(400, "invalid Content-Length"))?,
+        None if headers.iter().any(|(n, _)| n == "transfer-encoding") => return Err(Response::error(411, "send a Content-Length, chunked bodies are not supported")),
+        None => 0
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(Response::error(413, "request body is too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => Response::error(400, "request body is shorter than Content-Length"),
+        _ => read_error(err)
+    })?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Request {
+        method: method.to_string(),
+        path: percent_decode(path).ok_or_else(|| Response::error(400, "invalid percent-encoding in path"))?,
+        query: parse_query(query).ok_or_else(|| Response::error(400, "invalid percent-encoding in query"))?,
+        headers,
+        body
+    })
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(&name.replace('+', " "))?, percent_decode(&value.replace('+', " "))?))
+        })
+        .collect()
+}
+
+pub fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
@@ -0,0 +1,179 @@
+// a default avatar for every user, drawn from a hash of the username so the same name always gets the same picture
+//
+// the SHA-256 of the username gives a hue for the foreground Colour and 15 bits for the pattern:
+// the left three columns of a 5x5 grid, mirrored onto the right two so the shape is symmetric
+//
+//     # . # . #
+//     . # # # .        bit set -> foreground, bit clear -> background
+//     # # . # #
+//
+// the grid is drawn with a half-cell margin, as SVG for the web and as binary PPM for anything else
+
+use std::fmt::{self, Write as _};
+
+use crate::colour::spaces::Hsl;
+use crate::users::crypto::sha256;
+use crate::Colour;
+use crate::User;
+
+pub const GRID: usize = 5;
+// the widest PPM to_ppm will draw, 4096 pixels across is already 48 MiB of pixels
+pub const MAX_SIZE: usize = 4096;
+const BACKGROUND: Colour = Colour(240, 240, 240);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdenticonError {
+    TooLarge { size: usize, max: usize }
+}
+
+impl fmt::Display for IdenticonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdenticonError::TooLarge { size, max } => write!(f, "an identicon {size} pixels across is too large, the most is {max}")
+        }
+    }
+}
+
+impl std::error::Error for IdenticonError {}
+
+pub struct Identicon {
+    pub foreground: Colour,
+    pub background: Colour,
+    pub cells: [[bool; GRID]; GRID]  // rows, then columns
+}
+
+impl Identicon {
+    pub fn from_text(text: &str) -> Self {
+        let hash = sha256(text.as_bytes());
+        let mut cells = [[false; GRID]; GRID];
+        let half = GRID.div_ceil(2);
+        for (row, cells) in cells.iter_mut().enumerate() {
+            for column in 0..half {
+                let bit = row * half + column;
+                let on = hash[bit / 8] >> (bit % 8) & 1 == 1;
+                cells[column] = on;
+                cells[GRID - 1 - column] = on;
+            }
+        }
+        // bytes 30 and 31 are not used by the pattern, so the colour does not follow the shape
+        let hue = u16::from_be_bytes([hash[30], hash[31]]) as f64 / 65536.0 * 360.0;
+        Identicon { foreground: Colour::from_hsl(Hsl { h: hue, s: 0.65, l: 0.45 }), background: BACKGROUND, cells }
+    }
+
+    // the cell a pixel falls in, None in the margin, for an image size pixels across
+    fn cell_at(&self, x: usize, y: usize, size: usize) -> Option<(usize, usize)> {
+        let units = GRID as f64 + 1.0;  // five cells plus half a cell each side
+        let to_cell = |p: usize| (p as f64 + 0.5) / size as f64 * units - 0.5;
+        let (column, row) = (to_cell(x), to_cell(y));
+        let inside = |c: f64| (0.0..GRID as f64).contains(&c);
+        (inside(column) && inside(row)).then_some((row as usize, column as usize))
+    }
+
+    pub fn colour_at(&self, x: usize, y: usize, size: usize) -> &Colour {
+        match self.cell_at(x, y, size) {
+            Some((row, column)) if self.cells[row][column] => &self.foreground,
+            _ => &self.background
+        }
+    }
+
+    pub fn to_svg(&self, size: usize) -> String {
+        let cell = size as f64 / (GRID as f64 + 1.0);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" viewBox=\"0 0 {size} {size}\">\n  <rect width=\"{size}\" height=\"{size}\" fill=\"{}\"/>\n",
+            self.background
+        );
+        for (row, cells) in self.cells.iter().enumerate() {
+            for (column, on) in cells.iter().enumerate() {
+                if *on {
+                    let (x, y) = (cell * (column as f64 + 0.5), cell * (row as f64 + 0.5));
+                    // writing to a String cannot fail
+                    let _ = writeln!(svg, "  <rect x=\"{x:.2}\" y=\"{y:.2}\" width=\"{cell:.2}\" height=\"{cell:.2}\" fill=\"{}\"/>", self.foreground);
+                }
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    // binary PPM (P6): a short text header, then three bytes per pixel, row by row
+    // size is bounded by MAX_SIZE because the image is built in memory and size * size * 3 would overflow long before
+    pub fn to_ppm(&self, size: usize) -> Result<Vec<u8>, IdenticonError> {
+        if size > MAX_SIZE {
+            return Err(IdenticonError::TooLarge { size, max: MAX_SIZE });
+        }
+        let mut ppm = format!("P6\n{size} {size}\n255\n").into_bytes();
+        ppm.reserve(size * size * 3);
+        for y in 0..size {
+            for x in 0..size {
+                ppm.extend(self.colour_at(x, y, size).channels());
+            }
+        }
+        Ok(ppm)
+    }
+}
+
+impl User {
+    pub fn identicon(&self) -> Identicon {
+        Identicon::from_text(&self.username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_name_always_gets_the_same_picture() {
+        let (first, again, other) = (Identicon::from_text("AHus"), Identicon::from_text("AHus"), Identicon::from_text("ahus"));
+        assert_eq!((first.cells, first.foreground), (again.cells, again.foreground));
+        assert_eq!(first.to_svg(120), again.to_svg(120));
+        assert_ne!((first.cells, first.foreground), (other.cells, other.foreground));
+        let user = crate::build_user("ahus@yahoo.com".to_string(), "AHus".to_string()).unwrap();
+        assert_eq!(user.identicon().cells, first.cells);
+    }
+
+    #[test]
+    fn every_pattern_is_mirrored_left_to_right() {
+        for name in ["AHus", "sam", "bea", "", "a much longer name than most"] {
+            let icon = Identicon::from_text(name);
+            for row in icon.cells {
+                assert_eq!(row, [row[4], row[3], row[2], row[1], row[0]], "{name}");
+            }
+            let ppm = icon.to_ppm(60).unwrap();
+            let pixels = &ppm[ppm.len() - 60 * 60 * 3..];
+            for y in 0..60 {
+                for x in 0..60 {
+                    let (left, right) = ((y * 60 + x) * 3, (y * 60 + 59 - x) * 3);
+                    assert_eq!(pixels[left..left + 3], pixels[right..right + 3], "{name} at {x}, {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ppm_is_a_header_then_three_bytes_a_pixel() {
+        let icon = Identicon::from_text("AHus");
+        for size in [0, 1, 7, 64] {
+            let ppm = icon.to_ppm(size).unwrap();
+            let header = format!("P6\n{size} {size}\n255\n");
+            assert!(ppm.starts_with(header.as_bytes()));
+            assert_eq!(ppm.len(), header.len() + size * size * 3);
+        }
+        // the corner is always margin
+        assert_eq!(icon.to_ppm(64).unwrap()[13..16], BACKGROUND.channels());
+        assert_eq!(icon.to_ppm(MAX_SIZE + 1), Err(IdenticonError::TooLarge { size: MAX_SIZE + 1, max: MAX_SIZE }));
+        assert!(icon.to_ppm(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn svg_draws_one_rect_per_cell_that_is_on() {
+        for name in ["AHus", "sam", "bea"] {
+            let icon = Identicon::from_text(name);
+            let on = icon.cells.iter().flatten().filter(|c| **c).count();
+            let svg = icon.to_svg(120);
+            assert_eq!(svg.matches("<rect").count(), on + 1, "{name}");  // and the background
+            assert_eq!(svg.matches(&format!("fill=\"{}\"", icon.foreground)).count(), on);
+            assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        }
+    }
+}
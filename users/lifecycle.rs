@@ -0,0 +1,277 @@
+// the states an account moves through, replacing the old active: bool on User
+// every change goes through transition(), which rejects moves the table below does not allow
+//
+//     PendingVerification -> Active, Deleted
+//     Active              -> Suspended, Deactivated, Deleted
+//     Suspended           -> Active, Deactivated, Deleted
+//     Deactivated         -> Active, Deleted
+//     Deleted             -> nothing, it is final
+
+use std::fmt;
+
+use crate::users::clock::Clock;
+use crate::User;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountState {
+    PendingVerification,
+    Active,
+    Suspended { reason: String, until: Option<u64> },  // until None means suspended until someone reinstates the account
+    Deactivated,
+    Deleted
+}
+
+impl AccountState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountState::PendingVerification => "pending_verification",
+            AccountState::Active => "active",
+            AccountState::Suspended { .. } => "suspended",
+            AccountState::Deactivated => "deactivated",
+            AccountState::Deleted => "deleted"
+        }
+    }
+
+    // the inverse of name(), a suspension named this way has no reason and no end time
+    pub fn from_name(name: &str) -> Option<AccountState> {
+        match name {
+            "pending_verification" => Some(AccountState::PendingVerification),
+            "active" => Some(AccountState::Active),
+            "suspended" => Some(AccountState::Suspended { reason: String::new(), until: None }),
+            "deactivated" => Some(AccountState::Deactivated),
+            "deleted" => Some(AccountState::Deleted),
+            _ => None
+        }
+    }
+
+    fn can_become(&self, next: &AccountState) -> bool {
+        use AccountState::*;
+        matches!(
+            (self, next),
+            (PendingVerification, Active | Deleted)
+                | (Active, Suspended { .. } | Deactivated | Deleted)
+                | (Suspended { .. }, Active | Deactivated | Deleted)
+                | (Deactivated, Active | Deleted)
+        )
+    }
+}
+
+impl fmt::Display for AccountState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: AccountState,
+    pub to: AccountState,
+    pub at: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lifecycle {
+    state: AccountState,
+    created_at: u64,
+    history: Vec<Transition>  // oldest first
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LifecycleError {
+    IllegalTransition { from: &'static str, to: &'static str }
+}
+
+impl fmt::Display for LifecycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LifecycleError::IllegalTransition { from, to } => write!(f, "an account cannot go from {from} to {to}")
+        }
+    }
+}
+
+impl std::error::Error for LifecycleError {}
+
+impl Lifecycle {
+    pub fn new(state: AccountState, created_at: u64) -> Self {
+        Lifecycle { state, created_at, history: Vec::new() }
+    }
+
+    // used when reading a stored account back, the history is trusted as it was written by transition()
+    pub fn restore(state: AccountState, created_at: u64, history: Vec<Transition>) -> Self {
+        Lifecycle { state, created_at, history }
+    }
+
+    pub fn state(&self) -> &AccountState {
+        &self.state
+    }
+
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub fn history(&self) -> &[Transition] {
+        &self.history
+    }
+
+    // when the current state began, the creation time if nothing has happened since
+    pub fn since(&self) -> u64 {
+        self.history.last().map_or(self.created_at, |t| t.at)
+    }
+
+    pub fn transition(&mut self, next: AccountState, at: u64) -> Result<(), LifecycleError> {
+        if !self.state.can_become(&next) {
+            return Err(LifecycleError::IllegalTransition { from: self.state.name(), to: next.name() });
+        }
+        let from = std::mem::replace(&mut self.state, next.clone());
+        self.history.push(Transition { from, to: next, at });
+        Ok(())
+    }
+}
+
+// the lifecycle calls are methods on User so existing code reads u1.deactivate(..) instead of u1.active = false
+impl User {
+    pub fn state(&self) -> &AccountState {
+        self.lifecycle.state()
+    }
+
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    // a suspension that has run out counts as active, even before lift_expired_suspension records it
+    pub fn is_active(&self, clock: &dyn Clock) -> bool {
+        match self.lifecycle.state() {
+            AccountState::Active => true,
+            AccountState::Suspended { until: Some(until), .. } => clock.now() >= *until,
+            _ => false
+        }
+    }
+
+    pub fn activate(&mut self, clock: &dyn Clock) -> Result<(), LifecycleError> {
+        self.lifecycle.transition(AccountState::Active, clock.now())
+    }
+
+    pub fn suspend(&mut self, reason: &str, until: Option<u64>, clock: &dyn Clock) -> Result<(), LifecycleError> {
+        self.lifecycle.transition(AccountState::Suspended { reason: reason.to_string(), until }, clock.now())
+    }
+
+    pub fn deactivate(&mut self, clock: &dyn Clock) -> Result<(), LifecycleError> {
+        self.lifecycle.transition(AccountState::Deactivated, clock.now())
+    }
+
+    pub fn delete(&mut self, clock: &dyn Clock) -> Result<(), LifecycleError> {
+        self.lifecycle.transition(AccountState::Deleted, clock.now())
+    }
+
+    // moves a suspended account whose end time has passed back to Active, returns whether it did
+    pub fn lift_expired_suspension(&mut self, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        match self.lifecycle.state() {
+            AccountState::Suspended { until: Some(until), .. } if now >= *until => {
+                // lifting a suspension is always a legal transition, so the result can be ignored
+                self.lifecycle.transition(AccountState::Active, now).is_ok()
+            }
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+
+    fn suspended(until: Option<u64>) -> AccountState {
+        AccountState::Suspended { reason: "spam".to_string(), until }
+    }
+
+    fn all_states() -> Vec<AccountState> {
+        vec![AccountState::PendingVerification, AccountState::Active, suspended(None), AccountState::Deactivated, AccountState::Deleted]
+    }
+
+    #[test]
+    fn only_the_moves_in_the_table_are_allowed() {
+        let allowed = [
+            ("pending_verification", vec!["active", "deleted"]),
+            ("active", vec!["suspended", "deactivated", "deleted"]),
+            ("suspended", vec!["active", "deactivated", "deleted"]),
+            ("deactivated", vec!["active", "deleted"]),
+            ("deleted", vec![])
+        ];
+        for (from, to) in &allowed {
+            for next in all_states() {
+                let state = AccountState::from_name(from).unwrap();
+                let mut lifecycle = Lifecycle::new(state.clone(), 0);
+                let result = lifecycle.transition(next.clone(), 5);
+                if to.contains(&next.name()) {
+                    assert_eq!(result, Ok(()), "{from} -> {next}");
+                    assert_eq!(lifecycle.state(), &next);
+                } else {
+                    assert_eq!(result, Err(LifecycleError::IllegalTransition { from: state.name(), to: next.name() }), "{from} -> {next}");
+                    assert_eq!((lifecycle.state(), lifecycle.history().len()), (&state, 0));  // nothing changed
+                }
+            }
+        }
+        let err = Lifecycle::new(AccountState::Deleted, 0).transition(AccountState::Active, 1).unwrap_err();
+        assert_eq!(err.to_string(), "an account cannot go from deleted to active");
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for state in all_states() {
+            assert_eq!(AccountState::from_name(state.name()).map(|s| s.name()), Some(state.name()));
+        }
+        assert_eq!(AccountState::from_name("banned"), None);
+    }
+
+    #[test]
+    fn history_keeps_each_move_with_its_time() {
+        let clock = ManualClock::new(100);
+        let mut user = crate::build_user("sam@x.io".to_string(), "sam".to_string()).unwrap();
+        user.lifecycle = Lifecycle::new(AccountState::PendingVerification, 50);
+        assert_eq!(user.lifecycle().since(), 50);
+        user.activate(&clock).unwrap();
+        clock.advance(10);
+        user.suspend("spam", None, &clock).unwrap();
+        clock.advance(10);
+        user.deactivate(&clock).unwrap();
+        clock.advance(10);
+        user.delete(&clock).unwrap();
+        assert!(user.activate(&clock).is_err());
+
+        let moves: Vec<(&str, &str, u64)> = user.lifecycle().history().iter().map(|t| (t.from.name(), t.to.name(), t.at)).collect();
+        assert_eq!(
+            moves,
+            [("pending_verification", "active", 100), ("active", "suspended", 110), ("suspended", "deactivated", 120), ("deactivated", "deleted", 130)]
+        );
+        assert_eq!((user.lifecycle().created_at(), user.lifecycle().since()), (50, 130));
+    }
+
+    #[test]
+    fn a_suspension_with_an_end_runs_out() {
+        let clock = ManualClock::new(100);
+        let mut user = crate::build_user("sam@x.io".to_string(), "sam".to_string()).unwrap();
+        user.activate(&clock).unwrap();
+        user.suspend("spam", Some(200), &clock).unwrap();
+        assert!(!user.is_active(&clock));
+        assert!(!user.lift_expired_suspension(&clock));
+
+        clock.set(199);
+        assert!(!user.is_active(&clock));
+        clock.set(200);
+        assert!(user.is_active(&clock));  // counts as active before it is lifted
+        assert_eq!(user.state().name(), "suspended");
+        assert!(user.lift_expired_suspension(&clock));
+        assert_eq!(user.state(), &AccountState::Active);
+        assert_eq!(user.lifecycle().since(), 200);
+        assert!(!user.lift_expired_suspension(&clock));  // nothing left to lift
+
+        // without an end it lasts until someone reinstates the account
+        user.suspend("spam", None, &clock).unwrap();
+        clock.advance(1_000_000);
+        assert!(!user.is_active(&clock));
+        assert!(!user.lift_expired_suspension(&clock));
+        user.activate(&clock).unwrap();
+        assert!(user.is_active(&clock));
+    }
+}
@@ -0,0 +1,265 @@
+// structured logging that will not write personal data in the clear
+// every line is a JSON object: {"at":..., "level":"info", "message":"signed in", ...fields}
+//
+// a field is either plain (numbers, flags, state names) or a Sensitive value, which is written masked
+// the logger refuses the whole event when a plain field carries something it should not:
+//     a field named like personal data (email, username, password, token, ...) given as a plain value, which goes
+//     by each word of the name, so user_email, newUsername and owner are caught as well as email
+//     a plain string that contains an email address
+// messages are &'static str so user data cannot be formatted into them, it has to go in a field
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::users::clock::Clock;
+use crate::users::json::Json;
+use crate::users::redact::Sensitive;
+
+// words that make a field name personal, the field is then only accepted as a Sensitive value
+// owner, actor, account and member name a user in this crate as often as username does
+pub const SENSITIVE_FIELDS: [&str; 14] =
+    ["email", "username", "user", "name", "password", "token", "secret", "code", "ip", "address", "owner", "actor", "account", "member"];
+
+// splits a field name into lowercase words at '_', '-', '.' and lower-to-upper case changes,
+// then checks each word and its singular against SENSITIVE_FIELDS: user_email, newUsername and owners all match,
+// while sign_in_count and zip do not
+pub fn is_sensitive_field(name: &str) -> bool {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if !c.is_alphanumeric() || (c.is_uppercase() && previous_lower) {
+            words.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        }
+        previous_lower = c.is_lowercase() || c.is_ascii_digit();
+    }
+    words.push(word);
+    words.iter().filter(|w| !w.is_empty()).any(|w| {
+        let singular = w.strip_suffix('s').unwrap_or(w);
+        SENSITIVE_FIELDS.iter().any(|s| *s == w || *s == singular)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error"
+        }
+    }
+}
+
+// a field value as the logger sees it, masked values are already safe text
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogValue {
+    Plain(Json),
+    Masked(String)
+}
+
+pub trait ToLogValue {
+    fn to_log_value(&self) -> LogValue;
+}
+
+impl ToLogValue for &str {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Plain(Json::str(self))
+    }
+}
+
+impl ToLogValue for String {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Plain(Json::str(self))
+    }
+}
+
+impl ToLogValue for u64 {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Plain(Json::uint(*self))
+    }
+}
+
+impl ToLogValue for usize {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Plain(Json::uint(*self as u64))
+    }
+}
+
+impl ToLogValue for bool {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Plain(Json::Bool(*self))
+    }
+}
+
+impl<T: fmt::Display> ToLogValue for Sensitive<T> {
+    fn to_log_value(&self) -> LogValue {
+        LogValue::Masked(self.masked())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub level: Level,
+    pub message: &'static str,
+    pub fields: Vec<(&'static str, LogValue)>
+}
+
+impl Event {
+    pub fn new(level: Level, message: &'static str) -> Self {
+        Event { level, message, fields: Vec::new() }
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl ToLogValue) -> Self {
+        self.fields.push((name, value.to_log_value()));
+        self
+    }
+
+    // the first field that would leak personal data if written
+    pub fn check(&self) -> Result<(), LogError> {
+        for (name, value) in &self.fields {
+            let LogValue::Plain(value) = value else { continue };
+            if is_sensitive_field(name) {
+                return Err(LogError::Unredacted(name));
+            }
+            if value.as_str().is_some_and(contains_email) {
+                return Err(LogError::LooksPersonal(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json(&self, at: u64) -> Json {
+        let mut fields = vec![("at", Json::uint(at)), ("level", Json::str(self.level.name())), ("message", Json::str(self.message))];
+        for (name, value) in &self.fields {
+            fields.push((name, match value {
+                LogValue::Plain(json) => json.clone(),
+                LogValue::Masked(text) => Json::str(text)
+            }));
+        }
+        Json::object(fields)
+    }
+}
+
+// an @ with something on both sides and a dot after it, loose enough to catch addresses inside longer text
+fn contains_email(text: &str) -> bool {
+    text.match_indices('@').any(|(i, _)| {
+        let before = text[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || "._%+-".contains(c));
+        let domain: String = text[i + 1..].chars().take_while(|c| c.is_alphanumeric() || *c == '.' || *c == '-').collect();
+        before && domain.trim_matches('.').contains('.')
+    })
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    Unredacted(&'static str),  // a personal field given as a plain value
+    LooksPersonal(&'static str),  // a plain value that contains an email address
+    Io(io::Error)
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Unredacted(name) => write!(f, "field '{name}' holds personal data and must be wrapped in Sensitive"),
+            LogError::LooksPersonal(name) => write!(f, "field '{name}' contains an email address, wrap it in Sensitive"),
+            LogError::Io(err) => write!(f, "could not write the log: {err}")
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<io::Error> for LogError {
+    fn from(err: io::Error) -> Self {
+        LogError::Io(err)
+    }
+}
+
+pub struct Logger<'a, W: Write> {
+    out: W,
+    clock: &'a dyn Clock,
+    pub min_level: Level
+}
+
+impl<'a, W: Write> Logger<'a, W> {
+    pub fn new(out: W, clock: &'a dyn Clock) -> Self {
+        Logger { out, clock, min_level: Level::Info }
+    }
+
+    // writes the event as one line, or nothing at all if any field fails the check
+    pub fn log(&mut self, event: Event) -> Result<(), LogError> {
+        event.check()?;
+        if event.level < self.min_level {
+            return Ok(());
+        }
+        writeln!(self.out, "{}", event.to_json(self.clock.now()))?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::redact::Reveal;
+
+    fn written(event: Event) -> Result<String, LogError> {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut logger = Logger::new(Vec::new(), &clock);
+        logger.log(event)?;
+        Ok(String::from_utf8(logger.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn field_names_are_checked_word_by_word() {
+        for name in ["email", "EMAIL", "user_email", "new_username", "newUsername", "owner", "owners", "actor", "ip_address", "reset-token", "member.name"] {
+            assert!(is_sensitive_field(name), "{name}");
+        }
+        for name in ["count", "sign_in_count", "state", "roles", "zip", "level", "barcode", "duration_ms"] {
+            assert!(!is_sensitive_field(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn plain_personal_fields_are_refused_and_nothing_is_written() {
+        let event = Event::new(Level::Info, "signed in").field("count", 3u64).field("new_username", "ahus");
+        assert!(matches!(written(event), Err(LogError::Unredacted("new_username"))));
+        let event = Event::new(Level::Info, "org created").field("owner", String::from("AHus"));
+        assert!(matches!(written(event), Err(LogError::Unredacted("owner"))));
+        let event = Event::new(Level::Warn, "bounced").field("reason", "mailbox ahus@yahoo.com is full");
+        let err = written(event).unwrap_err();
+        assert_eq!(err.to_string(), "field 'reason' contains an email address, wrap it in Sensitive");
+        // refused even below the level that would be written, so a debug line cannot hide a leak until it is turned on
+        assert!(written(Event::new(Level::Debug, "x").field("user_email", "a@b.io")).is_err());
+        assert!(!contains_email("ten @ once") && !contains_email("@home.com") && contains_email("write to a.b@x.co.uk today"));
+    }
+
+    #[test]
+    fn sensitive_fields_are_written_masked() {
+        let event = Event::new(Level::Info, "signed in")
+            .field("user_email", Sensitive::new("ahus@yahoo.com", Reveal::EmailDomain))
+            .field("owner", Sensitive::hidden("AHus"))
+            .field("sign_in_count", 3u64)
+            .field("locked", false);
+        assert_eq!(
+            written(event).unwrap(),
+            "{\"at\":1700000000,\"level\":\"info\",\"message\":\"signed in\",\"user_email\":\"***@yahoo.com\",\"owner\":\"[redacted]\",\"sign_in_count\":3,\"locked\":false}\n"
+        );
+        assert_eq!(written(Event::new(Level::Debug, "skipped")).unwrap(), "");
+    }
+}
@@ -0,0 +1,120 @@
+// outgoing email goes through a Mailer, so nothing in the account code talks to an SMTP server directly
+// FileMailer writes each message into an outbox directory and MemoryMailer keeps them in a list,
+// which is enough to develop and test offline, a real relay only has to implement send
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+impl Email {
+    // the message in RFC 5322 form, which mail clients can open straight from the outbox
+    pub fn to_rfc5322(&self, from: &str) -> String {
+        format!("From: {from}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n", self.to, self.subject, self.body.replace('\n', "\r\n"))
+    }
+}
+
+pub trait Mailer {
+    fn send(&mut self, email: &Email) -> io::Result<()>;
+}
+
+// writes every message to <dir>/<number>.eml, numbered in the order they were sent
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+    next: usize
+}
+
+impl FileMailer {
+    pub fn new(dir: impl AsRef<Path>, from: &str) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        // carry on after the messages already there rather than overwriting them
+        let next = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok()?.path().file_stem()?.to_str()?.parse::<usize>().ok())
+            .max()
+            .map_or(1, |n| n + 1);
+        Ok(FileMailer { dir, from: from.to_string(), next })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&mut self, email: &Email) -> io::Result<()> {
+        let path = self.dir.join(format!("{:06}.eml", self.next));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(email.to_rfc5322(&self.from).as_bytes())?;
+        self.next += 1;
+        Ok(())
+    }
+}
+
+// clones share the same outbox, so a test can keep one clone and hand the other to a server thread
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>
+}
+
+impl MemoryMailer {
+    pub fn new() -> Self {
+        MemoryMailer::default()
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    pub fn last_to(&self, address: &str) -> Option<Email> {
+        self.sent().into_iter().rev().find(|e| e.to == address)
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&mut self, email: &Email) -> io::Result<()> {
+        self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str, body: &str) -> Email {
+        Email { to: to.to_string(), subject: String::from("Hello"), body: body.to_string() }
+    }
+
+    #[test]
+    fn memory_mailer_clones_share_one_outbox() {
+        let outbox = MemoryMailer::new();
+        let mut sender = outbox.clone();
+        sender.send(&email("ann@example.com", "first")).unwrap();
+        sender.send(&email("bob@example.com", "second")).unwrap();
+        sender.send(&email("ann@example.com", "third")).unwrap();
+        assert_eq!(outbox.sent().len(), 3);
+        assert_eq!(outbox.last_to("ann@example.com").map(|e| e.body), Some(String::from("third")));
+        assert_eq!(outbox.last_to("eve@example.com"), None);
+    }
+
+    #[test]
+    fn file_mailer_numbers_messages_and_carries_on_after_existing_ones() {
+        let dir = std::env::temp_dir().join(format!("outbox-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileMailer::new(&dir, "accounts@localhost").unwrap().send(&email("ann@example.com", "line one\nline two")).unwrap();
+        FileMailer::new(&dir, "accounts@localhost").unwrap().send(&email("bob@example.com", "hi")).unwrap();
+        let first = fs::read_to_string(dir.join("000001.eml")).unwrap();
+        assert_eq!(first, "From: accounts@localhost\r\nTo: ann@example.com\r\nSubject: Hello\r\n\r\nline one\r\nline two\r\n");
+        assert!(fs::read_to_string(dir.join("000002.eml")).unwrap().contains("To: bob@example.com"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+// schema versions for stored User records, and the migrations between them
+//
+//     v1  username, email, sign_in_count and an active flag
+//     v2  the lifecycle replaces the flag: state, created_at and history
+//     v3  credentials: password hash, sign-in times, lockout and second factor
+//     v4  roles
+//
+// every record is written with a "schema" field, records from before it existed are recognised by their shape
+// User::from_json upgrades whatever it is given to SCHEMA_VERSION, so old records load without a rewrite
+//
+// a down-migration refuses a record when the older version would make the account behave differently,
+// such as a suspended user (v1 has no suspension) or one with a password (v2 has no credentials)
+// bookkeeping the older version has nowhere to keep, like state history and sign-in times, is dropped
+//
+// migrate_file rewrites a whole FileUserStore log to one version, keeping a copy of the original alongside it
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::users::auth::Credentials;
+use crate::users::json::Json;
+
+pub const SCHEMA_VERSION: u64 = 4;
+
+type Step = fn(&mut Json) -> Result<(), String>;
+
+pub struct Migration {
+    pub from: u64,  // up goes from this version to the next one
+    pub description: &'static str,
+    pub up: Step,
+    pub down: Option<Step>  // None when there is no way back at all
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Invalid(String),  // not a user record, or a schema field that is not a version
+    TooNew { found: u64, latest: u64 },  // written by a newer build than this one
+    UnknownVersion(u64),  // a target version the registry does not reach
+    NoWayDown { from: u64 },  // the migration out of this version has no down step
+    Refused { from: u64, to: u64, message: String },  // a step could not convert this record
+    OutOfOrder { expected: u64, found: u64 },  // a migration registered that does not start at the latest version
+    Corrupt { line: usize, message: String },
+    Io(io::Error)
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Invalid(message) => write!(f, "not a user record: {message}"),
+            MigrationError::TooNew { found, latest } => write!(f, "record is schema v{found} but this build only knows up to v{latest}"),
+            MigrationError::UnknownVersion(version) => write!(f, "there is no schema v{version}"),
+            MigrationError::NoWayDown { from } => write!(f, "there is no migration down from schema v{}", from + 1),
+            MigrationError::Refused { from, to, message } => write!(f, "cannot migrate from v{from} to v{to}: {message}"),
+            MigrationError::OutOfOrder { expected, found } => write!(f, "the next migration must start at v{expected}, not v{found}"),
+            MigrationError::Corrupt { line, message } => write!(f, "corrupt store record on line {line}: {message}"),
+            MigrationError::Io(err) => write!(f, "migration i/o error: {err}")
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<io::Error> for MigrationError {
+    fn from(err: io::Error) -> Self {
+        MigrationError::Io(err)
+    }
+}
+
+pub struct Migrations {
+    steps: Vec<Migration>  // steps[i] goes from v(i + 1) to v(i + 2)
+}
+
+impl Default for Migrations {
+    fn default() -> Self {
+        Migrations::standard()
+    }
+}
+
+impl Migrations {
+    // a registry at v1 with nothing registered, for building up a different history
+    pub fn new() -> Self {
+        Migrations { steps: Vec::new() }
+    }
+
+    // the history of the User record, ending at SCHEMA_VERSION
+    pub fn standard() -> Self {
+        Migrations {
+            steps: vec![
+                Migration { from: 1, description: "replace the active flag with the account lifecycle", up: flag_to_lifecycle, down: Some(lifecycle_to_flag) },
+                Migration { from: 2, description: "add credentials", up: add_credentials, down: Some(drop_credentials) },
+                Migration { from: 3, description: "add roles", up: add_roles, down: Some(drop_roles) }
+            ]
+        }
+    }
+
+    pub fn register(&mut self, migration: Migration) -> Result<(), MigrationError> {
+        if migration.from != self.latest() {
+            return Err(MigrationError::OutOfOrder { expected: self.latest(), found: migration.from });
+        }
+        self.steps.push(migration);
+        Ok(())
+    }
+
+    pub fn latest(&self) -> u64 {
+        self.steps.len() as u64 + 1
+    }
+
+    pub fn steps(&self) -> &[Migration] {
+        &self.steps
+    }
+
+    // the version a record was written at, records without a schema field are placed by the fields they have
+    pub fn version_of(&self, record: &Json) -> Result<u64, MigrationError> {
+        if !matches!(record, Json::Object(_)) {
+            return Err(MigrationError::Invalid(String::from("expected an object")));
+        }
+        let version = match record.get("schema") {
+            Some(schema) => schema.as_u64().filter(|v| *v >= 1).ok_or(MigrationError::Invalid(String::from("schema must be a version number")))?,
+            None if record.get("active").is_some() => 1,
+            None if record.get("roles").is_some() => 4,
+            None if record.get("credentials").is_some() => 3,
+            None => 2
+        };
+        if version > self.latest() {
+            return Err(MigrationError::TooNew { found: version, latest: self.latest() });
+        }
+        Ok(version)
+    }
+
+    // the record converted to version `to`, up or down, one step at a time
+    pub fn migrate(&self, record: &Json, to: u64) -> Result<Json, MigrationError> {
+        if to == 0 || to > self.latest() {
+            return Err(MigrationError::UnknownVersion(to));
+        }
+        let mut version = self.version_of(record)?;
+        let mut record = record.clone();
+        while version != to {
+            let (step, next) = if version < to {
+                (self.steps[version as usize - 1].up, version + 1)
+            } else {
+                let migration = &self.steps[version as usize - 2];
+                (migration.down.ok_or(MigrationError::NoWayDown { from: migration.from })?, version - 1)
+            };
+            step(&mut record).map_err(|message| MigrationError::Refused { from: version, to: next, message })?;
+            version = next;
+        }
+        record.set("schema", Json::uint(version));
+        Ok(record)
+    }
+
+    pub fn upgrade(&self, record: &Json) -> Result<Json, MigrationError> {
+        self.migrate(record, self.latest())
+    }
+}
+
+fn flag_to_lifecycle(record: &mut Json) -> Result<(), String> {
+    let active = record.remove("active").and_then(|a| a.as_bool()).ok_or("the active flag is not a boolean")?;
+    // created_at was never recorded, 0 stands for "before records began"
+    record.set("state", Json::object(vec![("name", Json::str(if active { "active" } else { "deactivated" }))]));
+    record.set("created_at", Json::uint(0));
+    record.set("history", Json::Array(Vec::new()));
+    Ok(())
+}
+
+fn lifecycle_to_flag(record: &mut Json) -> Result<(), String> {
+    let state = record.get("state").and_then(|s| s.get("name")).and_then(Json::as_str).unwrap_or_default().to_string();
+    let active = match state.as_str() {
+        "active" => true,
+        "deactivated" => false,
+        other => return Err(format!("v1 has no '{other}' state"))
+    };
+    record.remove("state");
+    record.remove("created_at");
+    record.remove("history");
+    record.set("active", Json::Bool(active));
+    Ok(())
+}
+
+fn add_credentials(record: &mut Json) -> Result<(), String> {
+    if record.get("credentials").is_none() {
+        record.set("credentials", Credentials::default().to_json());
+    }
+    Ok(())
+}
+
+fn drop_credentials(record: &mut Json) -> Result<(), String> {
+    if let Some(credentials) = record.get("credentials") {
+        if credentials.get("password").is_some_and(|p| !p.is_null()) {
+            return Err(String::from("the user has a password, which v2 cannot keep"));
+        }
+        if credentials.get("totp").is_some_and(|t| !t.is_null()) {
+            return Err(String::from("the user has two-factor turned on, which v2 cannot keep"));
+        }
+    }
+    record.remove("credentials");
+    Ok(())
+}
+
+fn add_roles(record: &mut Json) -> Result<(), String> {
+    if record.get("roles").is_none() {
+        record.set("roles", Json::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+fn drop_roles(record: &mut Json) -> Result<(), String> {
+    if record.get("roles").and_then(Json::as_array).is_some_and(|r| !r.is_empty()) {
+        return Err(String::from("the user has roles, which v3 cannot keep"));
+    }
+    record.remove("roles");
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub backup: PathBuf,  // the untouched original
+    pub to: u64,
+    pub records: usize,  // user records in the log, delete records are copied across as they are
+    pub changed: usize,  // records that were not already at `to`
+    pub found: BTreeMap<u64, usize>  // how many records were at each version beforehand
+}
+
+// rewrites every user record in a FileUserStore log to version `to`
+// nothing is written unless every record converts, and the original is first copied to <file>.bak
+// (or .bak.1, .bak.2, ... when that is taken) so the migration can be undone by hand
+// the store must not be open while this runs
+pub fn migrate_file(path: impl AsRef<Path>, to: u64, migrations: &Migrations) -> Result<MigrationReport, MigrationError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let mut lines = Vec::new();
+    let mut records = 0;
+    let mut changed = 0;
+    let mut found = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let corrupt = |message: String| MigrationError::Corrupt { line: index + 1, message };
+        let mut record = Json::parse(line).map_err(|e| corrupt(e.to_string()))?;
+        if record.get("op").and_then(Json::as_str) == Some("put") {
+            let user = record.get("user").ok_or_else(|| corrupt(String::from("put record without a user")))?;
+            let version = migrations.version_of(user).map_err(|e| corrupt(e.to_string()))?;
+            *found.entry(version).or_insert(0) += 1;
+            records += 1;
+            if version != to {
+                changed += 1;
+            }
+            let user = migrations.migrate(user, to).map_err(|e| corrupt(e.to_string()))?;
+            record.set("user", user);
+        }
+        lines.push(record.to_string());
+    }
+
+    let backup = backup_path(path);
+    fs::copy(path, &backup)?;
+    let tmp = path.with_extension("migrate");
+    {
+        let mut out = File::create(&tmp)?;
+        for line in &lines {
+            writeln!(out, "{line}")?;
+        }
+        out.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(MigrationReport { backup, to, records, changed, found })
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let first = PathBuf::from(format!("{}.bak", path.display()));
+    let mut candidate = first.clone();
+    let mut n = 1;
+    while candidate.exists() {
+        candidate = PathBuf::from(format!("{}.{n}", first.display()));
+        n += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::store::{FileUserStore, UserStore};
+    use crate::User;
+
+    fn user(username: &str) -> User {
+        let mut user = crate::build_user(format!("{}@example.com", username.to_lowercase()), username.to_string()).unwrap();
+        user.activate(&ManualClock::new(100)).unwrap();
+        user
+    }
+
+    fn without_schema(record: &Json) -> Json {
+        let mut record = record.clone();
+        record.remove("schema");
+        record
+    }
+
+    fn refused(record: &Json, to: u64) -> (u64, u64) {
+        match Migrations::standard().migrate(record, to) {
+            Err(MigrationError::Refused { from, to, .. }) => (from, to),
+            other => panic!("{other:?}")
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("migrate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("users.jsonl")
+    }
+
+    #[test]
+    fn every_version_goes_down_and_back_up() {
+        let migrations = Migrations::standard();
+        let current = user("AHus").to_json();
+        let keys = |record: &Json| ["active", "state", "credentials", "roles"].map(|key| record.get(key).is_some());
+        let shapes = [(1, [true, false, false, false]), (2, [false, true, false, false]), (3, [false, true, true, false]), (4, [false, true, true, true])];
+        for (version, shape) in shapes {
+            let old = migrations.migrate(&current, version).unwrap();
+            assert_eq!((old.get("schema").and_then(Json::as_u64), keys(&old)), (Some(version), shape), "v{version}");
+            // records from before the schema field are told apart by their shape
+            assert_eq!(migrations.version_of(&without_schema(&old)).unwrap(), version);
+            let back = User::from_json(&old).unwrap();
+            assert_eq!((back.username.as_str(), back.state()), ("AHus", &crate::users::lifecycle::AccountState::Active));
+            for to in 1..=SCHEMA_VERSION {
+                assert_eq!(migrations.migrate(&old, to).unwrap().get("schema").and_then(Json::as_u64), Some(to), "v{version} to v{to}");
+            }
+        }
+        // down to v2 and back keeps everything but the history, which v1 drops
+        let round_trip = migrations.upgrade(&migrations.migrate(&current, 2).unwrap()).unwrap();
+        assert_eq!(User::from_json(&round_trip).unwrap().to_json(), current);
+        let from_v1 = User::from_json(&migrations.migrate(&current, 1).unwrap()).unwrap();
+        assert_eq!((from_v1.lifecycle().created_at(), from_v1.lifecycle().history().len()), (0, 0));
+        let deactivated = Json::parse(r#"{"username":"old","email":"old@x.io","sign_in_count":2,"active":false}"#).unwrap();
+        assert_eq!(User::from_json(&deactivated).unwrap().state().name(), "deactivated");
+    }
+
+    #[test]
+    fn accounts_the_older_version_cannot_hold_are_refused() {
+        let mut suspended = user("sam");
+        suspended.suspend("spam", Some(500), &ManualClock::new(200)).unwrap();
+        assert_eq!(refused(&suspended.to_json(), 1), (2, 1));
+        assert_eq!(Migrations::standard().migrate(&suspended.to_json(), 2).unwrap().get("state").unwrap().get("name").and_then(Json::as_str), Some("suspended"));
+
+        let mut with_password = user("sam");
+        with_password.set_password_with_iterations("correct horse", 1).unwrap();
+        assert_eq!(refused(&with_password.to_json(), 2), (3, 2));
+        assert_eq!(refused(&with_password.to_json(), 1), (3, 2));
+        let mut with_role = user("sam");
+        with_role.grant_role("admin").unwrap();
+        let err = Migrations::standard().migrate(&with_role.to_json(), 3).unwrap_err();
+        assert_eq!(err.to_string(), "cannot migrate from v4 to v3: the user has roles, which v3 cannot keep");
+    }
+
+    #[test]
+    fn versions_outside_the_registry_are_errors() {
+        let migrations = Migrations::standard();
+        let record = user("sam").to_json();
+        assert!(matches!(migrations.migrate(&record, 0), Err(MigrationError::UnknownVersion(0))));
+        assert!(matches!(migrations.migrate(&record, 5), Err(MigrationError::UnknownVersion(5))));
+        let mut newer = record.clone();
+        newer.set("schema", Json::uint(9));
+        assert!(matches!(migrations.version_of(&newer), Err(MigrationError::TooNew { found: 9, latest: 4 })));
+        newer.set("schema", Json::str("4"));
+        assert!(matches!(migrations.version_of(&newer), Err(MigrationError::Invalid(_))));
+        assert!(matches!(migrations.version_of(&Json::Array(Vec::new())), Err(MigrationError::Invalid(_))));
+
+        let mut one_way = Migrations::new();
+        one_way.register(Migration { from: 1, description: "no way back", up: add_roles, down: None }).unwrap();
+        let v2 = one_way.upgrade(&Json::parse(r#"{"active":true}"#).unwrap()).unwrap();
+        assert!(matches!(one_way.migrate(&v2, 1), Err(MigrationError::NoWayDown { from: 1 })));
+        let late = Migration { from: 1, description: "out of order", up: add_roles, down: None };
+        assert!(matches!(one_way.register(late), Err(MigrationError::OutOfOrder { expected: 2, found: 1 })));
+    }
+
+    #[test]
+    fn migrate_file_keeps_a_backup_each_time() {
+        let path = temp_path("backup");
+        {
+            let mut store = FileUserStore::open(&path).unwrap();
+            store.insert(user("ann")).unwrap();
+            store.insert(user("bob")).unwrap();
+            store.delete("bob").unwrap();
+        }
+        let original = fs::read_to_string(&path).unwrap();
+
+        let report = migrate_file(&path, 2, &Migrations::standard()).unwrap();
+        assert_eq!(report.backup, PathBuf::from(format!("{}.bak", path.display())));
+        assert_eq!((report.to, report.records, report.changed), (2, 2, 2));
+        assert_eq!(report.found, BTreeMap::from([(4, 2)]));
+        assert_eq!(fs::read_to_string(&report.backup).unwrap(), original);
+        assert!(fs::read_to_string(&path).unwrap().lines().all(|line| !line.contains("\"credentials\"")));
+        // the older records still load, brought up to date as they are read
+        assert_eq!(FileUserStore::open(&path).unwrap().list().len(), 1);
+
+        let again = migrate_file(&path, 4, &Migrations::standard()).unwrap();
+        assert_eq!(again.backup, PathBuf::from(format!("{}.bak.1", path.display())));
+        assert_eq!(again.found, BTreeMap::from([(2, 2)]));
+        assert_eq!(migrate_file(&path, 4, &Migrations::standard()).unwrap().backup, PathBuf::from(format!("{}.bak.2", path.display())));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_failed_migration_leaves_the_file_alone() {
+        let path = temp_path("refused");
+        {
+            let mut store = FileUserStore::open(&path).unwrap();
+            store.insert(user("ann")).unwrap();
+            let mut bob = user("bob");
+            bob.set_password_with_iterations("correct horse", 1).unwrap();
+            store.insert(bob).unwrap();
+        }
+        let original = fs::read_to_string(&path).unwrap();
+        let err = migrate_file(&path, 2, &Migrations::standard()).unwrap_err();
+        assert!(matches!(err, MigrationError::Corrupt { line: 2, .. }), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        let names: Vec<String> = fs::read_dir(path.parent().unwrap()).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(names, ["users.jsonl"]);  // no backup and no temporary file
+
+        fs::write(&path, "{\"op\":\"put\"}\n").unwrap();
+        assert!(matches!(migrate_file(&path, 3, &Migrations::standard()), Err(MigrationError::Corrupt { line: 1, .. })));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
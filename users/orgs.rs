@@ -0,0 +1,967 @@
+// organisations and the teams inside them
+//
+// usernames only have to be unique inside an organisation: someone invited to one can join with an account that
+// belongs to it, stored as "slug:name", so acme:alex and globex:alex are two accounts that are both alex
+// ':' is never part of a plain username, so those cannot clash with each other or with accounts outside any
+// organisation; such an account signs in as "slug:name", or as its name with the organisation given separately
+// an existing account can join too, under a name of its own there, again unique only inside the organisation
+// members point at the User in the store by its username; look people up inside an organisation with member_named
+// the API lists users through visible_users, so outside of admins nobody sees accounts beyond their organisations
+// deleting an account takes it out of every organisation first, see remove_account
+//
+// each member has a role in the organisation:
+//     owner    everything, including making other owners and removing admins, there is always at least one
+//     admin    invites people, removes members and runs the teams
+//     member   sees the other members and the teams
+// nobody outside an organisation can list its members or teams, or look one of them up
+//
+// people join by invitation: an admin invites an email address and mails them the code, the code works once,
+// only for an account with that email, and only until it expires
+// codes are kept as SHA-256 hashes, so a copy of the organisations file cannot be used to join anything
+// Organisations::open reads that file, a single JSON document, and save writes it back whole after changes
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::users::clock::Clock;
+use crate::users::crypto::{random_bytes, sha256, to_hex};
+use crate::users::json::Json;
+use crate::users::privacy::PersonalData;
+use crate::users::rbac::check_name;
+use crate::users::store::{StoreError, UserStore};
+use crate::users::validation::{validate_username, UserError};
+use crate::User;
+
+const CODE_LEN: usize = 12;
+pub const SCOPE_SEPARATOR: char = ':';
+
+// the username of an account that belongs to the organisation
+pub fn scoped_username(slug: &str, name: &str) -> String {
+    format!("{slug}{SCOPE_SEPARATOR}{name}")
+}
+
+// the organisation and the name inside it, None for an account that belongs to no organisation
+pub fn split_scoped(username: &str) -> Option<(&str, &str)> {
+    username.split_once(SCOPE_SEPARATOR)
+}
+
+// a new name for an account keeps the organisation the account belongs to, if any
+pub fn rescope(username: &str, name: &str) -> String {
+    match split_scoped(username) {
+        Some((slug, _)) => scoped_username(slug, name),
+        None => name.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OrgRole {
+    Member,
+    Admin,
+    Owner
+}
+
+impl OrgRole {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OrgRole> {
+        match name {
+            "member" => Some(OrgRole::Member),
+            "admin" => Some(OrgRole::Admin),
+            "owner" => Some(OrgRole::Owner),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for OrgRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub account: String,  // the username of the User in the store
+    pub name: String,  // what the member is called inside this organisation
+    pub role: OrgRole,
+    pub joined_at: u64
+}
+
+impl Member {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("account", Json::str(&self.account)),
+            ("name", Json::str(&self.name)),
+            ("role", Json::str(self.role.name())),
+            ("joined_at", Json::uint(self.joined_at))
+        ])
+    }
+
+    fn from_json(value: &Json) -> Result<Member, String> {
+        let text = |key: &str| value.get(key).and_then(Json::as_str).map(String::from).ok_or(format!("missing or invalid field '{key}'"));
+        Ok(Member {
+            account: text("account")?,
+            name: text("name")?,
+            role: value.get("role").and_then(Json::as_str).and_then(OrgRole::from_name).ok_or("missing or invalid field 'role'")?,
+            joined_at: value.get("joined_at").and_then(Json::as_u64).ok_or("missing or invalid field 'joined_at'")?
+        })
+    }
+}
+
+// one organisation's part in merging two accounts, what split_accounts needs to put it back
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovedMembership {
+    pub org: String,
+    pub member: Member,  // the absorbed account's membership as it was
+    pub teams: Vec<String>,  // the teams the absorbed account was in
+    pub survivor_role: Option<OrgRole>,  // the survivor's role before, None if it was not a member
+    pub added_teams: Vec<String>  // the teams the survivor was put in because the absorbed account was
+}
+
+impl MovedMembership {
+    pub fn to_json(&self) -> Json {
+        let names = |teams: &[String]| Json::Array(teams.iter().map(|t| Json::str(t)).collect());
+        Json::object(vec![
+            ("organisation", Json::str(&self.org)),
+            ("member", self.member.to_json()),
+            ("teams", names(&self.teams)),
+            ("survivor_role", self.survivor_role.map_or(Json::Null, |r| Json::str(r.name()))),
+            ("added_teams", names(&self.added_teams))
+        ])
+    }
+
+    pub fn from_json(value: &Json) -> Result<MovedMembership, String> {
+        let names = |key: &str| -> Vec<String> {
+            value.get(key).and_then(Json::as_array).map_or(Vec::new(), |t| t.iter().filter_map(Json::as_str).map(String::from).collect())
+        };
+        let survivor_role = match value.get("survivor_role") {
+            None | Some(Json::Null) => None,
+            Some(role) => Some(role.as_str().and_then(OrgRole::from_name).ok_or("invalid field 'survivor_role'")?)
+        };
+        Ok(MovedMembership {
+            org: value.get("organisation").and_then(Json::as_str).ok_or("missing or invalid field 'organisation'")?.to_string(),
+            member: Member::from_json(value.get("member").ok_or("missing field 'member'")?)?,
+            teams: names("teams"),
+            survivor_role,
+            added_teams: names("added_teams")
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invitation {
+    code_hash: String,
+    pub email: String,
+    pub role: OrgRole,
+    pub invited_by: String,  // account
+    pub expires_at: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Organisation {
+    pub slug: String,  // short lowercase id, e.g. "acme"
+    pub name: String,
+    pub created_at: u64,
+    members: Vec<Member>,
+    teams: BTreeMap<String, BTreeSet<String>>,  // team name -> accounts
+    invitations: Vec<Invitation>
+}
+
+impl Organisation {
+    fn member(&self, account: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.account == account)
+    }
+
+    fn member_mut(&mut self, account: &str) -> Option<&mut Member> {
+        self.members.iter_mut().find(|m| m.account == account)
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.members.iter().any(|m| m.name.eq_ignore_ascii_case(name))
+    }
+
+    fn owners(&self) -> usize {
+        self.members.iter().filter(|m| m.role == OrgRole::Owner).count()
+    }
+
+    pub fn is_member(&self, account: &str) -> bool {
+        self.member(account).is_some()
+    }
+
+    pub fn role_of(&self, account: &str) -> Option<OrgRole> {
+        self.member(account).map(|m| m.role)
+    }
+
+    // the actor's membership, as long as their role is at least `min`
+    fn require(&self, actor: &str, min: OrgRole) -> Result<&Member, OrgError> {
+        let member = self.member(actor).ok_or(OrgError::NotMember)?;
+        if member.role < min {
+            return Err(OrgError::Forbidden(format!("this needs the {min} role, you are a {}", member.role)));
+        }
+        Ok(member)
+    }
+
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("slug", Json::str(&self.slug)),
+            ("name", Json::str(&self.name)),
+            ("created_at", Json::uint(self.created_at)),
+            ("members", Json::Array(self.members.iter().map(Member::to_json).collect())),
+            ("teams", Json::Object(self.teams.iter().map(|(team, accounts)| {
+                (team.clone(), Json::Array(accounts.iter().map(|a| Json::str(a)).collect()))
+            }).collect())),
+            ("invitations", Json::Array(self.invitations.iter().map(|i| Json::object(vec![
+                ("code_hash", Json::str(&i.code_hash)),
+                ("email", Json::str(&i.email)),
+                ("role", Json::str(i.role.name())),
+                ("invited_by", Json::str(&i.invited_by)),
+                ("expires_at", Json::uint(i.expires_at))
+            ])).collect()))
+        ])
+    }
+
+    fn from_json(value: &Json) -> Result<Organisation, String> {
+        let text = |v: &Json, key: &str| v.get(key).and_then(Json::as_str).map(String::from).ok_or(format!("missing or invalid field '{key}'"));
+        let number = |v: &Json, key: &str| v.get(key).and_then(Json::as_u64).ok_or(format!("missing or invalid field '{key}'"));
+        let role = |v: &Json| v.get("role").and_then(Json::as_str).and_then(OrgRole::from_name).ok_or("missing or invalid field 'role'");
+        let list = |key: &str| value.get(key).and_then(Json::as_array).map_or(&[][..], |v| v);
+        let members = list("members").iter().map(Member::from_json).collect::<Result<Vec<Member>, String>>()?;
+        let mut invitations = Vec::new();
+        for i in list("invitations") {
+            invitations.push(Invitation {
+                code_hash: text(i, "code_hash")?,
+                email: text(i, "email")?,
+                role: role(i)?,
+                invited_by: text(i, "invited_by")?,
+                expires_at: number(i, "expires_at")?
+            });
+        }
+        let mut teams = BTreeMap::new();
+        if let Some(Json::Object(fields)) = value.get("teams") {
+            for (team, accounts) in fields {
+                let accounts = accounts.as_array().ok_or("a team must be a list of accounts")?;
+                teams.insert(team.clone(), accounts.iter().filter_map(Json::as_str).map(String::from).collect());
+            }
+        }
+        Ok(Organisation { slug: text(value, "slug")?, name: text(value, "name")?, created_at: number(value, "created_at")?, members, teams, invitations })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrgError {
+    NotFound(String),  // no organisation with this slug
+    Exists(String),
+    InvalidSlug(String),
+    NotMember,  // the actor, or the account acted on, is not in the organisation
+    Forbidden(String),
+    AlreadyMember,
+    NameTaken(String),  // another member already uses this name in the organisation
+    InvalidName(UserError),
+    InvitationInvalid,  // no such code, or it has been used or withdrawn
+    InvitationExpired { at: u64 },
+    WrongEmail,  // the invitation was for a different email address
+    LastOwner,  // the change would leave the organisation without an owner
+    TeamNotFound(String),
+    TeamExists(String),
+    Store(String)  // the store could not take the new account
+}
+
+impl fmt::Display for OrgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrgError::NotFound(slug) => write!(f, "no organisation '{slug}'"),
+            OrgError::Exists(slug) => write!(f, "organisation '{slug}' already exists"),
+            OrgError::InvalidSlug(message) => write!(f, "{message}"),
+            OrgError::NotMember => write!(f, "not a member of the organisation"),
+            OrgError::Forbidden(message) => write!(f, "not allowed: {message}"),
+            OrgError::AlreadyMember => write!(f, "already a member of the organisation"),
+            OrgError::NameTaken(name) => write!(f, "the name '{name}' is already used in the organisation"),
+            OrgError::InvalidName(err) => write!(f, "{err}"),
+            OrgError::InvitationInvalid => write!(f, "the invitation code is not valid"),
+            OrgError::InvitationExpired { at } => write!(f, "the invitation expired at {at}"),
+            OrgError::WrongEmail => write!(f, "the invitation was sent to a different email address"),
+            OrgError::LastOwner => write!(f, "an organisation must keep at least one owner"),
+            OrgError::TeamNotFound(team) => write!(f, "no team '{team}'"),
+            OrgError::TeamExists(team) => write!(f, "team '{team}' already exists"),
+            OrgError::Store(message) => write!(f, "{message}")
+        }
+    }
+}
+
+impl std::error::Error for OrgError {}
+
+impl From<UserError> for OrgError {
+    fn from(err: UserError) -> Self {
+        OrgError::InvalidName(err)
+    }
+}
+
+pub struct Organisations {
+    orgs: BTreeMap<String, Organisation>,
+    pub invitation_ttl: u64,  // seconds an invitation code stays valid
+    file: Option<PathBuf>  // where save writes to, None for organisations kept in memory only
+}
+
+impl Default for Organisations {
+    fn default() -> Self {
+        Organisations::new()
+    }
+}
+
+impl Organisations {
+    pub fn new() -> Self {
+        Organisations { orgs: BTreeMap::new(), invitation_ttl: 7 * 24 * 60 * 60, file: None }
+    }
+
+    // the organisations saved in the file, none if it does not exist yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut orgs = if path.exists() {
+            let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {message}", path.display()));
+            let json = Json::parse(&fs::read_to_string(&path)?).map_err(|e| invalid(e.to_string()))?;
+            Organisations::from_json(&json).map_err(invalid)?
+        } else {
+            Organisations::new()
+        };
+        orgs.file = Some(path);
+        Ok(orgs)
+    }
+
+    // writes everything to the file open read from, through a temporary file so a crash leaves the old one whole
+    // does nothing for organisations kept in memory
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.file else { return Ok(()) };
+        let tmp = path.with_extension("rewrite");
+        {
+            let mut out = fs::File::create(&tmp)?;
+            writeln!(out, "{}", self.to_json().pretty())?;
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    fn org(&self, slug: &str) -> Result<&Organisation, OrgError> {
+        self.orgs.get(slug).ok_or_else(|| OrgError::NotFound(slug.to_string()))
+    }
+
+    fn org_mut(&mut self, slug: &str) -> Result<&mut Organisation, OrgError> {
+        self.orgs.get_mut(slug).ok_or_else(|| OrgError::NotFound(slug.to_string()))
+    }
+
+    // the founder becomes the first owner, known inside by their username
+    pub fn create(&mut self, slug: &str, name: &str, founder: &User, clock: &dyn Clock) -> Result<(), OrgError> {
+        check_name(slug).map_err(OrgError::InvalidSlug)?;
+        if self.orgs.contains_key(slug) {
+            return Err(OrgError::Exists(slug.to_string()));
+        }
+        let now = clock.now();
+        let owner = Member { account: founder.username.clone(), name: founder.username.clone(), role: OrgRole::Owner, joined_at: now };
+        self.orgs.insert(slug.to_string(), Organisation {
+            slug: slug.to_string(),
+            name: name.to_string(),
+            created_at: now,
+            members: vec![owner],
+            teams: BTreeMap::new(),
+            invitations: Vec::new()
+        });
+        Ok(())
+    }
+
+    // only an owner can close an organisation
+    pub fn delete(&mut self, slug: &str, actor: &str) -> Result<Organisation, OrgError> {
+        self.org(slug)?.require(actor, OrgRole::Owner)?;
+        Ok(self.orgs.remove(slug).expect("checked above"))
+    }
+
+    // the organisation's details, for its members only
+    pub fn get(&self, slug: &str, viewer: &str) -> Result<&Organisation, OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Member)?;
+        Ok(org)
+    }
+
+    // the organisations an account belongs to, with its role in each
+    pub fn for_account(&self, account: &str) -> Vec<(&Organisation, OrgRole)> {
+        self.orgs.values().filter_map(|org| org.role_of(account).map(|role| (org, role))).collect()
+    }
+
+    // returns the code to send to the invitee, inviting the same address again replaces the earlier code
+    // admins can invite members and admins, only owners can invite owners
+    pub fn invite(&mut self, slug: &str, actor: &str, email: &str, role: OrgRole, clock: &dyn Clock) -> Result<String, OrgError> {
+        let ttl = self.invitation_ttl;
+        let org = self.org_mut(slug)?;
+        let inviter = org.require(actor, OrgRole::Admin)?;
+        if role > inviter.role {
+            return Err(OrgError::Forbidden(format!("an {} cannot invite an {role}", inviter.role)));
+        }
+        let email = email.trim().to_lowercase();
+        let code = to_hex(&random_bytes(CODE_LEN));
+        org.invitations.retain(|i| i.email != email);
+        org.invitations.push(Invitation { code_hash: hash_code(&code), email, role, invited_by: actor.to_string(), expires_at: clock.now() + ttl });
+        Ok(code)
+    }
+
+    pub fn revoke_invitation(&mut self, slug: &str, actor: &str, email: &str) -> Result<bool, OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, OrgRole::Admin)?;
+        let email = email.trim().to_lowercase();
+        let before = org.invitations.len();
+        org.invitations.retain(|i| i.email != email);
+        Ok(org.invitations.len() != before)
+    }
+
+    pub fn invitations(&self, slug: &str, viewer: &str) -> Result<&[Invitation], OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Admin)?;
+        Ok(&org.invitations)
+    }
+
+    // joins the organisation the code was made for, under `name` or else the account's username
+    // returns the organisation's slug
+    pub fn accept(&mut self, code: &str, user: &User, name: Option<&str>, clock: &dyn Clock) -> Result<String, OrgError> {
+        let now = clock.now();
+        let (org, index) = self.invitation(code, now)?;
+        let invitation = &org.invitations[index];
+        if !invitation.email.eq_ignore_ascii_case(&user.email) {
+            return Err(OrgError::WrongEmail);
+        }
+        if org.is_member(&user.username) {
+            return Err(OrgError::AlreadyMember);
+        }
+        let name = validate_username(name.unwrap_or(&user.username))?;
+        if org.name_taken(&name) {
+            return Err(OrgError::NameTaken(name));
+        }
+        let invitation = org.invitations.remove(index);
+        org.members.push(Member { account: user.username.clone(), name, role: invitation.role, joined_at: now });
+        Ok(org.slug.clone())
+    }
+
+    // joins the organisation the code was made for with a new account that belongs to it, named slug:name
+    // the account gets the invited email, which counts as confirmed since the code was mailed there
+    // it has no password yet, like any new account; returns the account's username
+    pub fn join(&mut self, store: &mut dyn UserStore, code: &str, name: &str, clock: &dyn Clock) -> Result<String, OrgError> {
+        let now = clock.now();
+        let (org, index) = self.invitation(code, now)?;
+        let name = validate_username(name)?;
+        if org.name_taken(&name) {
+            return Err(OrgError::NameTaken(name));
+        }
+        let mut user = crate::build_user(org.invitations[index].email.clone(), name.clone())?;
+        user.username = scoped_username(&org.slug, &name);
+        user.activate(clock).map_err(|e| OrgError::Store(e.to_string()))?;
+        let account = user.username.clone();
+        match store.insert(user) {
+            Ok(()) => {}
+            // an account left behind by a member who has since been removed
+            Err(StoreError::Duplicate(_)) => return Err(OrgError::NameTaken(name)),
+            Err(err) => return Err(OrgError::Store(err.to_string()))
+        }
+        let invitation = org.invitations.remove(index);
+        org.members.push(Member { account: account.clone(), name, role: invitation.role, joined_at: now });
+        Ok(account)
+    }
+
+    // the organisation holding a code and where the invitation is in it, as long as it has not expired
+    fn invitation(&mut self, code: &str, now: u64) -> Result<(&mut Organisation, usize), OrgError> {
+        let hash = hash_code(code.trim());
+        let org = self.orgs.values_mut().find(|o| o.invitations.iter().any(|i| i.code_hash == hash)).ok_or(OrgError::InvitationInvalid)?;
+        let index = org.invitations.iter().position(|i| i.code_hash == hash).expect("found above");
+        let expires_at = org.invitations[index].expires_at;
+        if now >= expires_at {
+            return Err(OrgError::InvitationExpired { at: expires_at });
+        }
+        Ok((org, index))
+    }
+
+    // owners can change anyone's role, admins only between member and admin and never an owner's
+    pub fn set_role(&mut self, slug: &str, actor: &str, account: &str, role: OrgRole) -> Result<(), OrgError> {
+        let org = self.org_mut(slug)?;
+        let actor_role = org.require(actor, OrgRole::Admin)?.role;
+        let current = org.role_of(account).ok_or(OrgError::NotMember)?;
+        if actor_role < OrgRole::Owner && (current == OrgRole::Owner || role == OrgRole::Owner) {
+            return Err(OrgError::Forbidden(String::from("only an owner can make or unmake owners")));
+        }
+        if current == OrgRole::Owner && role != OrgRole::Owner && org.owners() == 1 {
+            return Err(OrgError::LastOwner);
+        }
+        org.member_mut(account).expect("checked above").role = role;
+        Ok(())
+    }
+
+    // members can always leave, otherwise admins remove members and owners remove anyone
+    pub fn remove(&mut self, slug: &str, actor: &str, account: &str) -> Result<Member, OrgError> {
+        let org = self.org_mut(slug)?;
+        let target = org.role_of(account).ok_or(OrgError::NotMember)?;
+        if actor != account {
+            let actor_role = org.require(actor, OrgRole::Admin)?.role;
+            if actor_role < OrgRole::Owner && target > OrgRole::Member {
+                return Err(OrgError::Forbidden(format!("only an owner can remove an {target}")));
+            }
+        }
+        if target == OrgRole::Owner && org.owners() == 1 {
+            return Err(OrgError::LastOwner);
+        }
+        for accounts in org.teams.values_mut() {
+            accounts.remove(account);
+        }
+        let index = org.members.iter().position(|m| m.account == account).expect("checked above");
+        Ok(org.members.remove(index))
+    }
+
+    // members may rename themselves, admins may rename anyone
+    // an account that belongs to the organisation is called by its username there, renaming the account renames it
+    pub fn rename_member(&mut self, slug: &str, actor: &str, account: &str, name: &str) -> Result<(), OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, if actor == account { OrgRole::Member } else { OrgRole::Admin })?;
+        org.member(account).ok_or(OrgError::NotMember)?;
+        if split_scoped(account).is_some_and(|(scope, _)| scope == slug) {
+            return Err(OrgError::Forbidden(String::from("this account belongs to the organisation, rename the account instead")));
+        }
+        let name = validate_username(name)?;
+        if org.members.iter().any(|m| m.account != account && m.name.eq_ignore_ascii_case(&name)) {
+            return Err(OrgError::NameTaken(name));
+        }
+        org.member_mut(account).expect("checked above").name = name;
+        Ok(())
+    }
+
+    // every member, ordered by name, for someone in the organisation
+    pub fn members(&self, slug: &str, viewer: &str) -> Result<Vec<&Member>, OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Member)?;
+        let mut members: Vec<&Member> = org.members.iter().collect();
+        members.sort_by_key(|m| m.name.to_lowercase());
+        Ok(members)
+    }
+
+    // looks a member up by their name inside the organisation, names are compared ignoring case
+    pub fn member_named(&self, slug: &str, viewer: &str, name: &str) -> Result<&Member, OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Member)?;
+        org.members.iter().find(|m| m.name.eq_ignore_ascii_case(name)).ok_or(OrgError::NotMember)
+    }
+
+    pub fn create_team(&mut self, slug: &str, actor: &str, team: &str) -> Result<(), OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, OrgRole::Admin)?;
+        check_name(team).map_err(OrgError::InvalidSlug)?;
+        if org.teams.contains_key(team) {
+            return Err(OrgError::TeamExists(team.to_string()));
+        }
+        org.teams.insert(team.to_string(), BTreeSet::new());
+        Ok(())
+    }
+
+    pub fn delete_team(&mut self, slug: &str, actor: &str, team: &str) -> Result<(), OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, OrgRole::Admin)?;
+        org.teams.remove(team).map(|_| ()).ok_or_else(|| OrgError::TeamNotFound(team.to_string()))
+    }
+
+    // only members of the organisation can be put in one of its teams, returns false if they were already in it
+    pub fn add_to_team(&mut self, slug: &str, actor: &str, team: &str, account: &str) -> Result<bool, OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, OrgRole::Admin)?;
+        if !org.is_member(account) {
+            return Err(OrgError::NotMember);
+        }
+        let accounts = org.teams.get_mut(team).ok_or_else(|| OrgError::TeamNotFound(team.to_string()))?;
+        Ok(accounts.insert(account.to_string()))
+    }
+
+    pub fn remove_from_team(&mut self, slug: &str, actor: &str, team: &str, account: &str) -> Result<bool, OrgError> {
+        let org = self.org_mut(slug)?;
+        org.require(actor, OrgRole::Admin)?;
+        let accounts = org.teams.get_mut(team).ok_or_else(|| OrgError::TeamNotFound(team.to_string()))?;
+        Ok(accounts.remove(account))
+    }
+
+    pub fn teams(&self, slug: &str, viewer: &str) -> Result<Vec<&str>, OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Member)?;
+        Ok(org.teams.keys().map(String::as_str).collect())
+    }
+
+    pub fn team_members(&self, slug: &str, viewer: &str, team: &str) -> Result<Vec<&Member>, OrgError> {
+        let org = self.org(slug)?;
+        org.require(viewer, OrgRole::Member)?;
+        let accounts = org.teams.get(team).ok_or_else(|| OrgError::TeamNotFound(team.to_string()))?;
+        Ok(org.members.iter().filter(|m| accounts.contains(&m.account)).collect())
+    }
+
+    // the accounts in the store that share at least one organisation with the viewer, the viewer included
+    pub fn visible_users<'a>(&self, store: &'a dyn UserStore, viewer: &str) -> Vec<&'a User> {
+        let mut accounts: BTreeSet<&str> = BTreeSet::from([viewer]);
+        for org in self.orgs.values().filter(|o| o.is_member(viewer)) {
+            accounts.extend(org.members.iter().map(|m| m.account.as_str()));
+        }
+        store.list().into_iter().filter(|u| accounts.contains(u.username.as_str())).collect()
+    }
+
+    // whether an account can take the username `to`, an account that belongs to an organisation needs a name no
+    // other member there uses; call it before renaming, the store only knows that the username itself is free
+    pub fn check_rename(&self, from: &str, to: &str) -> Result<(), OrgError> {
+        let Some((slug, name)) = split_scoped(to) else {
+            return Ok(());
+        };
+        let org = self.org(slug)?;
+        if org.members.iter().any(|m| m.account != from && m.name.eq_ignore_ascii_case(name)) {
+            return Err(OrgError::NameTaken(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // follows an account to its new username, call it whenever a user is renamed
+    // a member whose account belongs to the organisation takes the new name there as well
+    pub fn rename_account(&mut self, from: &str, to: &str) -> usize {
+        let mut changed = 0;
+        for org in self.orgs.values_mut() {
+            let own_name = split_scoped(to).filter(|(scope, _)| *scope == org.slug).map(|(_, name)| name.to_string());
+            for member in org.members.iter_mut().filter(|m| m.account == from) {
+                member.account = to.to_string();
+                if let Some(name) = &own_name {
+                    member.name = name.clone();
+                }
+                changed += 1;
+            }
+            for invitation in org.invitations.iter_mut().filter(|i| i.invited_by == from) {
+                invitation.invited_by = to.to_string();
+                changed += 1;
+            }
+            for accounts in org.teams.values_mut() {
+                if accounts.remove(from) {
+                    accounts.insert(to.to_string());
+                }
+            }
+        }
+        changed
+    }
+
+    // takes an account that is being deleted out of every organisation and team, and withdraws the invitations it
+    // sent, which nobody could answer for any more; nothing changes if it is the last owner of any of them
+    // returns the organisations it left
+    pub fn remove_account(&mut self, account: &str) -> Result<Vec<String>, OrgError> {
+        if self.orgs.values().any(|o| o.role_of(account) == Some(OrgRole::Owner) && o.owners() == 1) {
+            return Err(OrgError::LastOwner);
+        }
+        let mut left = Vec::new();
+        for org in self.orgs.values_mut() {
+            org.invitations.retain(|i| i.invited_by != account);
+            for accounts in org.teams.values_mut() {
+                accounts.remove(account);
+            }
+            if org.is_member(account) {
+                org.members.retain(|m| m.account != account);
+                left.push(org.slug.clone());
+            }
+        }
+        Ok(left)
+    }
+
+    // hands the absorbed account's memberships to the survivor, for merge_users (see users/duplicates.rs)
+    // where both are members the survivor keeps its name there and the higher of the two roles, and joins the
+    // absorbed account's teams
+    pub fn merge_accounts(&mut self, survivor: &str, absorbed: &str) -> Vec<MovedMembership> {
+        let mut moved = Vec::new();
+        for org in self.orgs.values_mut() {
+            let Some(index) = org.members.iter().position(|m| m.account == absorbed) else { continue };
+            let member = org.members.remove(index);
+            let survivor_role = org.role_of(survivor);
+            let mut teams = Vec::new();
+            let mut added_teams = Vec::new();
+            for (team, accounts) in org.teams.iter_mut() {
+                if accounts.remove(absorbed) {
+                    teams.push(team.clone());
+                    if accounts.insert(survivor.to_string()) {
+                        added_teams.push(team.clone());
+                    }
+                }
+            }
+            match org.member_mut(survivor) {
+                Some(existing) => existing.role = existing.role.max(member.role),
+                None => org.members.push(Member { account: survivor.to_string(), ..member.clone() })
+            }
+            moved.push(MovedMembership { org: org.slug.clone(), member, teams, survivor_role, added_teams });
+        }
+        moved
+    }
+
+    // undoes merge_accounts, for organisations that still exist
+    pub fn split_accounts(&mut self, survivor: &str, moved: &[MovedMembership]) {
+        for entry in moved {
+            let Some(org) = self.orgs.get_mut(&entry.org) else { continue };
+            match entry.survivor_role {
+                Some(role) => {
+                    if let Some(member) = org.member_mut(survivor) {
+                        member.role = role;
+                    }
+                }
+                None => org.members.retain(|m| m.account != survivor)
+            }
+            for team in &entry.added_teams {
+                if let Some(accounts) = org.teams.get_mut(team) {
+                    accounts.remove(survivor);
+                }
+            }
+            for team in &entry.teams {
+                if let Some(accounts) = org.teams.get_mut(team) {
+                    accounts.insert(entry.member.account.clone());
+                }
+            }
+            if !org.is_member(&entry.member.account) {
+                org.members.push(entry.member.clone());
+            }
+        }
+    }
+
+    // drops invitations nobody used in time, call it now and then
+    pub fn prune(&mut self, clock: &dyn Clock) {
+        let now = clock.now();
+        for org in self.orgs.values_mut() {
+            org.invitations.retain(|i| now < i.expires_at);
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Array(self.orgs.values().map(Organisation::to_json).collect())
+    }
+
+    pub fn from_json(value: &Json) -> Result<Organisations, String> {
+        let mut orgs = Organisations::new();
+        for org in value.as_array().ok_or("expected a list of organisations")? {
+            let org = Organisation::from_json(org)?;
+            orgs.orgs.insert(org.slug.clone(), org);
+        }
+        Ok(orgs)
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    to_hex(&sha256(code.as_bytes()))
+}
+
+impl PersonalData for Organisations {
+    fn name(&self) -> &str {
+        "organisations"
+    }
+
+    // memberships of the account, and invitations still waiting for the user's email, which they may not know about
+    fn export(&self, subject: &User) -> Json {
+        let username = subject.username.as_str();
+        let memberships: Vec<Json> = self.for_account(username).into_iter().map(|(org, role)| {
+            let member = org.member(username).expect("for_account only returns memberships");
+            let teams = org.teams.iter().filter(|(_, accounts)| accounts.contains(username)).map(|(team, _)| Json::str(team));
+            Json::object(vec![
+                ("organisation", Json::str(&org.slug)),
+                ("name", Json::str(&member.name)),
+                ("role", Json::str(role.name())),
+                ("joined_at", Json::uint(member.joined_at)),
+                ("teams", Json::Array(teams.collect()))
+            ])
+        }).collect();
+        let invitations: Vec<Json> = self.orgs.values().flat_map(|org| {
+            org.invitations.iter().filter(|i| i.email.eq_ignore_ascii_case(&subject.email)).map(|i| Json::object(vec![
+                ("organisation", Json::str(&org.slug)),
+                ("email", Json::str(&i.email)),
+                ("role", Json::str(i.role.name())),
+                ("invited_by", Json::str(&i.invited_by)),
+                ("expires_at", Json::uint(i.expires_at))
+            ]))
+        }).collect();
+        if memberships.is_empty() && invitations.is_empty() {
+            return Json::Null;
+        }
+        Json::object(vec![("memberships", Json::Array(memberships)), ("invitations", Json::Array(invitations))])
+    }
+
+    // the account keeps its memberships under the pseudonym, and each organisation gets a name for it nobody chose
+    // the old names are not searched for afterwards, another member elsewhere may well use the same one
+    // invitations to the user's email are dropped, there is nobody left at that address to accept them
+    // organisations opened from a file are saved straight away, so the old names do not stay on disk
+    fn erase(&mut self, subject: &User, pseudonym: &str, _email: &str) -> Result<usize, String> {
+        let mut dropped = 0;
+        for org in self.orgs.values_mut() {
+            if let Some(member) = org.members.iter_mut().find(|m| m.account == subject.username) {
+                member.name = pseudonym.to_string();
+            }
+            let before = org.invitations.len();
+            org.invitations.retain(|i| !i.email.eq_ignore_ascii_case(&subject.email));
+            dropped += before - org.invitations.len();
+        }
+        let changed = self.rename_account(&subject.username, pseudonym) + dropped;
+        self.save().map_err(|e| e.to_string())?;
+        Ok(changed)
+    }
+
+    fn all_identifiers(&self) -> Vec<String> {
+        let mut found = Vec::new();
+        for org in self.orgs.values() {
+            found.extend(org.members.iter().map(|m| m.account.clone()));
+            found.extend(org.teams.values().flatten().cloned());
+            for invitation in &org.invitations {
+                found.push(invitation.email.clone());
+                found.push(invitation.invited_by.clone());
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::privacy::{erase_subject, export_subject};
+    use crate::users::store::MemoryUserStore;
+
+    fn user(name: &str) -> User {
+        crate::build_user(format!("{name}@example.com"), name.to_string()).unwrap()
+    }
+
+    // basics with ann as its owner, bea an admin and cal a member
+    fn basics(clock: &ManualClock) -> Organisations {
+        let mut orgs = Organisations::new();
+        orgs.create("basics", "Basics", &user("ann"), clock).unwrap();
+        for (name, role) in [("bea", OrgRole::Admin), ("cal", OrgRole::Member)] {
+            let code = orgs.invite("basics", "ann", &format!("{name}@example.com"), role, clock).unwrap();
+            orgs.accept(&code, &user(name), None, clock).unwrap();
+        }
+        orgs
+    }
+
+    #[test]
+    fn invitations_to_a_user_are_exported_and_go_when_they_are_erased() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut store = MemoryUserStore::new();
+        for username in ["sam", "ahus"] {
+            store.insert(crate::build_user(format!("{username}@example.com"), username.to_string()).unwrap()).unwrap();
+        }
+        let mut orgs = Organisations::new();
+        orgs.create("basics", "Basics", store.get("sam").unwrap(), &clock).unwrap();
+        orgs.invite("basics", "sam", "AHus@Example.com", OrgRole::Member, &clock).unwrap();
+        orgs.invite("basics", "sam", "someone@example.com", OrgRole::Member, &clock).unwrap();
+
+        let export = export_subject(&store, &[&orgs], "ahus", &clock).unwrap();
+        let invitations = export.get("sources").and_then(|s| s.get("organisations")).and_then(|o| o.get("invitations")).unwrap();
+        assert_eq!(invitations.as_array().map(Vec::len), Some(1));
+        assert_eq!(invitations.as_array().unwrap()[0].get("invited_by").and_then(Json::as_str), Some("sam"));
+
+        let report = erase_subject(&mut store, &mut [&mut orgs], "ahus", &clock).unwrap();
+        assert!(report.is_complete(), "{:?}", report.remaining);
+        let left: Vec<&str> = orgs.invitations("basics", "sam").unwrap().iter().map(|i| i.email.as_str()).collect();
+        assert_eq!(left, vec!["someone@example.com"]);
+    }
+
+    #[test]
+    fn owners_change_any_role_and_admins_only_below_owner() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut orgs = basics(&clock);
+        assert!(matches!(orgs.set_role("basics", "cal", "cal", OrgRole::Admin), Err(OrgError::Forbidden(_))));
+        assert!(matches!(orgs.set_role("basics", "bea", "cal", OrgRole::Owner), Err(OrgError::Forbidden(_))));
+        assert!(matches!(orgs.set_role("basics", "bea", "ann", OrgRole::Member), Err(OrgError::Forbidden(_))));
+        orgs.set_role("basics", "bea", "cal", OrgRole::Admin).unwrap();
+        assert_eq!(orgs.get("basics", "cal").unwrap().role_of("cal"), Some(OrgRole::Admin));
+        orgs.set_role("basics", "ann", "bea", OrgRole::Owner).unwrap();
+        orgs.set_role("basics", "bea", "ann", OrgRole::Member).unwrap();
+        assert_eq!(orgs.for_account("ann")[0].1, OrgRole::Member);
+        assert_eq!(orgs.set_role("basics", "bea", "dan", OrgRole::Member), Err(OrgError::NotMember));
+        assert_eq!(orgs.set_role("nowhere", "bea", "ann", OrgRole::Member), Err(OrgError::NotFound("nowhere".to_string())));
+    }
+
+    #[test]
+    fn the_last_owner_cannot_step_down_leave_or_be_deleted() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut orgs = basics(&clock);
+        assert_eq!(orgs.set_role("basics", "ann", "ann", OrgRole::Admin), Err(OrgError::LastOwner));
+        assert_eq!(orgs.remove("basics", "ann", "ann").err(), Some(OrgError::LastOwner));
+        assert_eq!(orgs.remove_account("ann"), Err(OrgError::LastOwner));
+        assert!(orgs.get("basics", "ann").unwrap().is_member("ann"));
+
+        orgs.set_role("basics", "ann", "bea", OrgRole::Owner).unwrap();
+        assert_eq!(orgs.remove_account("ann"), Ok(vec!["basics".to_string()]));
+        assert_eq!(orgs.remove_account("bea"), Err(OrgError::LastOwner));
+    }
+
+    #[test]
+    fn a_deleted_account_leaves_its_teams_and_its_invitations_are_withdrawn() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut orgs = basics(&clock);
+        orgs.create_team("basics", "bea", "kitchen").unwrap();
+        orgs.add_to_team("basics", "bea", "kitchen", "bea").unwrap();
+        orgs.add_to_team("basics", "bea", "kitchen", "cal").unwrap();
+        orgs.invite("basics", "bea", "dan@example.com", OrgRole::Member, &clock).unwrap();
+        orgs.invite("basics", "ann", "eve@example.com", OrgRole::Member, &clock).unwrap();
+
+        assert_eq!(orgs.remove_account("bea"), Ok(vec!["basics".to_string()]));
+        assert!(orgs.for_account("bea").is_empty());
+        let team: Vec<&str> = orgs.team_members("basics", "ann", "kitchen").unwrap().iter().map(|m| m.account.as_str()).collect();
+        assert_eq!(team, vec!["cal"]);
+        let invited: Vec<&str> = orgs.invitations("basics", "ann").unwrap().iter().map(|i| i.email.as_str()).collect();
+        assert_eq!(invited, vec!["eve@example.com"]);
+        assert!(!orgs.all_identifiers().iter().any(|id| id == "bea"));
+        assert_eq!(orgs.remove_account("bea"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn invitations_expire_and_only_work_for_their_email_once() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut orgs = basics(&clock);
+        let code = orgs.invite("basics", "bea", "dan@example.com", OrgRole::Member, &clock).unwrap();
+        assert!(matches!(orgs.invite("basics", "bea", "dan@example.com", OrgRole::Owner, &clock), Err(OrgError::Forbidden(_))));
+        assert!(matches!(orgs.invite("basics", "cal", "dan@example.com", OrgRole::Member, &clock), Err(OrgError::Forbidden(_))));
+
+        assert_eq!(orgs.accept(&code, &user("eve"), None, &clock), Err(OrgError::WrongEmail));
+        clock.advance(orgs.invitation_ttl);
+        let expires_at = 1_700_000_000 + orgs.invitation_ttl;
+        assert_eq!(orgs.accept(&code, &user("dan"), None, &clock), Err(OrgError::InvitationExpired { at: expires_at }));
+        orgs.prune(&clock);
+        assert_eq!(orgs.accept(&code, &user("dan"), None, &clock), Err(OrgError::InvitationInvalid));
+
+        let code = orgs.invite("basics", "bea", "DAN@example.com", OrgRole::Member, &clock).unwrap();
+        clock.advance(orgs.invitation_ttl - 1);
+        assert_eq!(orgs.accept(&format!(" {code} "), &user("dan"), Some("Danny"), &clock), Ok("basics".to_string()));
+        assert_eq!(orgs.member_named("basics", "cal", "danny").unwrap().account, "dan");
+        assert_eq!(orgs.accept(&code, &user("dan"), None, &clock), Err(OrgError::InvitationInvalid));
+    }
+
+    #[test]
+    fn accounts_of_different_organisations_can_share_a_name() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut store = MemoryUserStore::new();
+        let mut orgs = basics(&clock);
+        orgs.create("acme", "Acme", &user("zed"), &clock).unwrap();
+        let basics_code = orgs.invite("basics", "ann", "alex@basics.example", OrgRole::Member, &clock).unwrap();
+        let acme_code = orgs.invite("acme", "zed", "alex@acme.example", OrgRole::Admin, &clock).unwrap();
+        assert_eq!(orgs.join(&mut store, &basics_code, "alex", &clock), Ok("basics:alex".to_string()));
+        assert_eq!(orgs.join(&mut store, &acme_code, "Alex", &clock), Ok("acme:Alex".to_string()));
+
+        let alex = store.get("basics:alex").unwrap();
+        assert_eq!(alex.email, "alex@basics.example");
+        assert!(alex.is_active(&clock));
+        assert_eq!(store.get("acme:alex").unwrap().email, "alex@acme.example");
+        assert_eq!(orgs.member_named("acme", "zed", "alex").unwrap().role, OrgRole::Admin);
+        assert_eq!(orgs.member_named("basics", "cal", "alex").unwrap().account, "basics:alex");
+
+        // a name is still unique inside one organisation, and a name of its own there is the account's own
+        let code = orgs.invite("basics", "ann", "other@example.com", OrgRole::Member, &clock).unwrap();
+        assert_eq!(orgs.join(&mut store, &code, "CAL", &clock), Err(OrgError::NameTaken("CAL".to_string())));
+        assert!(matches!(orgs.join(&mut store, &code, "a:b", &clock), Err(OrgError::InvalidName(_))));
+        assert!(matches!(orgs.rename_member("basics", "basics:alex", "basics:alex", "al"), Err(OrgError::Forbidden(_))));
+        assert_eq!(orgs.check_rename("basics:alex", "basics:cal"), Err(OrgError::NameTaken("cal".to_string())));
+        assert_eq!(orgs.check_rename("basics:alex", "basics:Alex"), Ok(()));
+        orgs.rename_account("basics:alex", "basics:al");
+        assert_eq!(orgs.member_named("basics", "ann", "al").unwrap().account, "basics:al");
+        assert_eq!(rescope("basics:al", "alf"), "basics:alf");
+        assert_eq!(rescope("ann", "anna"), "anna");
+    }
+}
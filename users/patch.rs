@@ -0,0 +1,361 @@
+// partial updates to a User without moving anything out of it
+// struct update syntax like User { email, ..u2 } moves u2's Strings into the new value and leaves u2 unusable
+// a UserPatch only holds the fields being changed and is applied through &mut User, so the user stays whole:
+//
+//     let patch = UserPatch { email: Some(String::from("ahus@hotmail.com")), ..UserPatch::default() };
+//     let diff = patch.apply(&mut u2)?;  // u2 is still fully usable
+//     diff.revert(&mut u2);  // and back again
+//
+// applying a patch returns a UserDiff with the old and new value of every field that actually changed
+// patch_user records each change in an AuditLog, kept in memory or appended to a file of JSON lines
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::users::clock::Clock;
+use crate::users::json::Json;
+use crate::users::orgs::rescope;
+use crate::users::store::{StoreError, UserStore};
+use crate::users::validation::{validate_email, validate_username, UserError};
+use crate::User;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UserPatch {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub sign_in_count: Option<u64>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String
+}
+
+// before holds the old values of the changed fields, after the new ones, so reverting is applying before
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UserDiff {
+    pub before: UserPatch,
+    pub after: UserPatch
+}
+
+impl UserPatch {
+    pub fn is_empty(&self) -> bool {
+        self.username.is_none() && self.email.is_none() && self.sign_in_count.is_none()
+    }
+
+    // every field is validated before any is written, so a failed patch leaves the user untouched
+    // an account that belongs to an organisation keeps it, the new username is the name inside it (see users/orgs.rs)
+    pub fn apply(&self, user: &mut User) -> Result<UserDiff, UserError> {
+        let checked = UserPatch {
+            username: self.username.as_deref().map(validate_username).transpose()?.map(|name| rescope(&user.username, &name)),
+            email: self.email.as_deref().map(validate_email).transpose()?,
+            sign_in_count: self.sign_in_count
+        };
+        Ok(checked.apply_unchecked(user))
+    }
+
+    // writes the fields as they are, only for values the user already held such as a diff's before side
+    fn apply_unchecked(&self, user: &mut User) -> UserDiff {
+        let mut diff = UserDiff::default();
+        if let Some(username) = self.username.clone().filter(|u| *u != user.username) {
+            diff.before.username = Some(std::mem::replace(&mut user.username, username.clone()));
+            diff.after.username = Some(username);
+        }
+        if let Some(email) = self.email.clone().filter(|e| *e != user.email) {
+            diff.before.email = Some(std::mem::replace(&mut user.email, email.clone()));
+            diff.after.email = Some(email);
+        }
+        if let Some(count) = self.sign_in_count.filter(|c| *c != user.sign_in_count) {
+            diff.before.sign_in_count = Some(std::mem::replace(&mut user.sign_in_count, count));
+            diff.after.sign_in_count = Some(count);
+        }
+        diff
+    }
+}
+
+impl UserDiff {
+    pub fn is_empty(&self) -> bool {
+        self.after.is_empty()
+    }
+
+    // one entry per changed field, in the order the fields appear on User
+    pub fn changes(&self) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        let mut push = |field, old: Option<String>, new: Option<String>| {
+            if let (Some(old), Some(new)) = (old, new) {
+                changes.push(FieldChange { field, old, new });
+            }
+        };
+        push("username", self.before.username.clone(), self.after.username.clone());
+        push("email", self.before.email.clone(), self.after.email.clone());
+        push("sign_in_count", self.before.sign_in_count.map(|c| c.to_string()), self.after.sign_in_count.map(|c| c.to_string()));
+        changes
+    }
+
+    // puts the old values back without validating them again, the returned diff undoes the revert
+    pub fn revert(&self, user: &mut User) -> UserDiff {
+        self.before.apply_unchecked(user)
+    }
+}
+
+// who changed what and when, for the admin audit trail
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub username: String,  // the user's username after the change
+    pub at: u64,
+    pub diff: UserDiff
+}
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    entries: Vec<AuditEntry>,  // oldest first
+    file: Option<(PathBuf, File)>
+}
+
+impl AuditLog {
+    // a log that lives as long as the value does
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reads the entries already in the file and appends new ones to it
+    // a half-written last line, left by a crash, is dropped
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = Vec::new();
+        let mut torn_tail = false;
+        if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = contents.lines().collect();
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match Json::parse(line).ok().as_ref().and_then(AuditEntry::from_json) {
+                    Some(entry) => entries.push(entry),
+                    None if index + 1 == lines.len() && !contents.ends_with('\n') => torn_tail = true,
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: bad audit entry on line {}", path.display(), index + 1)))
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut log = AuditLog { entries, file: Some((path, file)) };
+        if torn_tail {
+            log.rewrite()?;
+        }
+        Ok(log)
+    }
+
+    // the entry is kept in memory even if it could not be written to the file
+    pub fn record(&mut self, entry: AuditEntry) -> io::Result<()> {
+        let written = match &mut self.file {
+            Some((_, file)) => writeln!(file, "{}", entry.to_json()),
+            None => Ok(())
+        };
+        self.entries.push(entry);
+        written
+    }
+
+    // writes every entry out again through a temporary file, so replaced identifiers do not stay on disk
+    fn rewrite(&mut self) -> io::Result<()> {
+        let Some((path, _)) = &self.file else { return Ok(()) };
+        let path = path.clone();
+        let tmp = path.with_extension("rewrite");
+        {
+            let mut out = File::create(&tmp)?;
+            for entry in &self.entries {
+                writeln!(out, "{}", entry.to_json())?;
+            }
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        self.file = Some((path, file));
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[AuditEntry] {
+        &self.entries
+    }
+
+    // the history of one account, following it through any renames
+    pub fn for_user(&self, username: &str) -> Vec<&AuditEntry> {
+        let positions = self.positions_for(username);
+        positions.into_iter().map(|i| &self.entries[i]).collect()
+    }
+
+    // indexes of the entries for_user returns, oldest first
+    fn positions_for(&self, username: &str) -> Vec<usize> {
+        let mut names = vec![username.to_string()];
+        let mut found = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate().rev() {
+            if names.contains(&entry.username) {
+                if let Some(old) = &entry.diff.before.username {
+                    names.push(old.clone());
+                }
+                found.push(i);
+            }
+        }
+        found.reverse();
+        found
+    }
+
+    // replaces every username and email the account has had with the stand-ins given, for erasure requests
+    // the times and sign-in counts stay, so the trail still shows that changes happened
+    pub fn pseudonymise(&mut self, username: &str, pseudonym: &str, email: &str) -> io::Result<usize> {
+        let positions = self.positions_for(username);
+        let mut names: Vec<String> = vec![username.to_string()];
+        for &i in &positions {
+            names.extend(self.entries[i].diff.before.username.clone());
+        }
+        let mut changed = positions.clone();
+        // the user may also have been the one making changes, to their own account or to others
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            if names.contains(&entry.actor) {
+                entry.actor = pseudonym.to_string();
+                changed.push(i);
+            }
+        }
+        changed.sort_unstable();
+        changed.dedup();
+        for &i in &positions {
+            let entry = &mut self.entries[i];
+            entry.username = pseudonym.to_string();
+            for side in [&mut entry.diff.before, &mut entry.diff.after] {
+                if side.username.is_some() {
+                    side.username = Some(pseudonym.to_string());
+                }
+                if side.email.is_some() {
+                    side.email = Some(email.to_string());
+                }
+            }
+        }
+        if !changed.is_empty() {
+            self.rewrite()?;
+        }
+        Ok(changed.len())
+    }
+}
+
+impl UserPatch {
+    pub fn to_json(&self) -> Json {
+        let mut fields = Vec::new();
+        if let Some(username) = &self.username {
+            fields.push(("username", Json::str(username)));
+        }
+        if let Some(email) = &self.email {
+            fields.push(("email", Json::str(email)));
+        }
+        if let Some(count) = self.sign_in_count {
+            fields.push(("sign_in_count", Json::uint(count)));
+        }
+        Json::object(fields)
+    }
+
+    fn from_json(value: &Json) -> Option<UserPatch> {
+        let text = |key| match value.get(key) {
+            None => Some(None),
+            Some(v) => v.as_str().map(|s| Some(s.to_string()))
+        };
+        let sign_in_count = match value.get("sign_in_count") {
+            None => None,
+            Some(v) => Some(v.as_u64()?)
+        };
+        Some(UserPatch { username: text("username")?, email: text("email")?, sign_in_count })
+    }
+}
+
+impl AuditEntry {
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("actor", Json::str(&self.actor)),
+            ("username", Json::str(&self.username)),
+            ("at", Json::uint(self.at)),
+            ("before", self.diff.before.to_json()),
+            ("after", self.diff.after.to_json())
+        ])
+    }
+
+    fn from_json(value: &Json) -> Option<AuditEntry> {
+        Some(AuditEntry {
+            actor: value.get("actor")?.as_str()?.to_string(),
+            username: value.get("username")?.as_str()?.to_string(),
+            at: value.get("at")?.as_u64()?,
+            diff: UserDiff { before: UserPatch::from_json(value.get("before")?)?, after: UserPatch::from_json(value.get("after")?)? }
+        })
+    }
+}
+
+// applies a patch to a stored user and records it in the audit log
+// a username change moves the user to its new key, failing with Duplicate if the name is taken
+pub fn patch_user(
+    store: &mut dyn UserStore,
+    log: &mut AuditLog,
+    username: &str,
+    patch: &UserPatch,
+    actor: &str,
+    clock: &dyn Clock
+) -> Result<UserDiff, StoreError> {
+    let mut user = store.get(username).ok_or_else(|| StoreError::NotFound(username.to_string()))?.clone();
+    let stored = user.username.clone();  // the store finds "ann" as "Ann", the rest goes by the stored spelling
+    let username = stored.as_str();
+    let diff = patch.apply(&mut user)?;
+    if diff.is_empty() {
+        return Ok(diff);
+    }
+    if user.username == username {
+        store.update(user)?;
+    } else if user.username.eq_ignore_ascii_case(username) {
+        // only the case changes, the store would see the new name as taken by the old one
+        let old = store.delete(username)?;
+        if let Err(err) = store.insert(user) {
+            let _ = store.insert(old);
+            return Err(err);
+        }
+    } else {
+        if store.get(&user.username).is_some() {
+            return Err(StoreError::Duplicate(user.username));
+        }
+        let new_name = user.username.clone();
+        store.insert(user)?;
+        if let Err(err) = store.delete(username) {
+            // keep the store with one copy of the user if the old record could not be removed
+            let _ = store.delete(&new_name);
+            return Err(err);
+        }
+    }
+    // the change has been made by now and the entry is kept in memory either way, like a sign-in event
+    // failing the call here would have callers skip what follows a rename, such as signing the old name out
+    let _ = log.record(AuditEntry {
+        actor: actor.to_string(),
+        username: diff.after.username.clone().unwrap_or_else(|| username.to_string()),
+        at: clock.now(),
+        diff: diff.clone()
+    });
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::store::MemoryUserStore;
+
+    #[test]
+    fn a_user_can_change_the_case_of_their_own_username_but_not_take_another() {
+        let (mut store, mut log, clock) = (MemoryUserStore::new(), AuditLog::new(), ManualClock::new(0));
+        store.insert(crate::build_user("ahus@example.com".to_string(), "AHus".to_string()).unwrap()).unwrap();
+        store.insert(crate::build_user("bob@example.com".to_string(), "bob".to_string()).unwrap()).unwrap();
+        let rename = |to: &str| UserPatch { username: Some(to.to_string()), email: None, sign_in_count: None };
+
+        patch_user(&mut store, &mut log, "AHus", &rename("ahus"), "ahus", &clock).unwrap();
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(store.get("AHus").unwrap().username, "ahus");
+        assert!(matches!(patch_user(&mut store, &mut log, "bob", &rename("AHUS"), "bob", &clock), Err(StoreError::Duplicate(_))));
+        assert!(store.get("bob").is_some());
+    }
+}
@@ -0,0 +1,346 @@
+// data subject requests: a copy of everything held about a user, and erasure of it
+//
+// the user record itself always comes from the UserStore, anything else that mentions users (the audit log,
+// orders, ...) takes part by implementing PersonalData and being passed in alongside the store
+// pass the SessionManager as well, erasing from it signs the user out everywhere
+//
+// erasure replaces the username and email with a random stand-in and throws away the password and second factor
+// the counts and times analytics rely on stay: sign_in_count, created_at, the state history and roles
+// afterwards every source is searched again for the old identifiers, and the report says where any were found
+// stores that keep old values around, like the log behind FileUserStore, are purged as part of the erasure
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::users::clock::Clock;
+use crate::users::crypto::{random_bytes, to_hex};
+use crate::users::duplicates::canonical_email;
+use crate::users::json::Json;
+use crate::users::lifecycle::AccountState;
+use crate::users::patch::AuditLog;
+use crate::users::store::{StoreError, UserStore};
+use crate::User;
+
+pub const EXPORT_VERSION: u64 = 1;
+const ERASED_DOMAIN: &str = "erased.invalid";  // .invalid can never be a real domain (RFC 2606)
+
+pub trait PersonalData {
+    fn name(&self) -> &str;
+    // what this source holds about the user, Null for nothing
+    fn export(&self, subject: &User) -> Json;
+    // other usernames and emails this source knows the user by, such as names from before a rename
+    fn identifiers(&self, _subject: &User) -> Vec<String> {
+        Vec::new()
+    }
+    // replaces the user's identifiers with the stand-ins, returns how many records changed
+    fn erase(&mut self, subject: &User, pseudonym: &str, email: &str) -> Result<usize, String>;
+    // every username and email the source holds, for anyone, checked to confirm an erasure left nothing behind
+    fn all_identifiers(&self) -> Vec<String>;
+}
+
+impl PersonalData for AuditLog {
+    fn name(&self) -> &str {
+        "audit_log"
+    }
+
+    fn export(&self, subject: &User) -> Json {
+        let entries = self.for_user(&subject.username);
+        if entries.is_empty() {
+            return Json::Null;
+        }
+        Json::Array(entries.into_iter().map(|e| e.to_json()).collect())
+    }
+
+    fn identifiers(&self, subject: &User) -> Vec<String> {
+        let mut found = Vec::new();
+        for entry in self.for_user(&subject.username) {
+            for side in [&entry.diff.before, &entry.diff.after] {
+                found.extend(side.username.clone());
+                found.extend(side.email.clone());
+            }
+        }
+        found
+    }
+
+    fn erase(&mut self, subject: &User, pseudonym: &str, email: &str) -> Result<usize, String> {
+        self.pseudonymise(&subject.username, pseudonym, email).map_err(|e| e.to_string())
+    }
+
+    fn all_identifiers(&self) -> Vec<String> {
+        let mut found = Vec::new();
+        for entry in self.entries() {
+            found.push(entry.actor.clone());
+            found.push(entry.username.clone());
+            for side in [&entry.diff.before, &entry.diff.after] {
+                found.extend(side.username.clone());
+                found.extend(side.email.clone());
+            }
+        }
+        found
+    }
+}
+
+#[derive(Debug)]
+pub enum PrivacyError {
+    Store(StoreError),
+    Source { name: String, message: String }
+}
+
+impl fmt::Display for PrivacyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrivacyError::Store(err) => write!(f, "{err}"),
+            PrivacyError::Source { name, message } => write!(f, "could not erase from {name}: {message}")
+        }
+    }
+}
+
+impl std::error::Error for PrivacyError {}
+
+impl From<StoreError> for PrivacyError {
+    fn from(err: StoreError) -> Self {
+        PrivacyError::Store(err)
+    }
+}
+
+// the profile as the user would recognise it, with secrets described rather than included
+fn profile_json(user: &User) -> Json {
+    let credentials = user.credentials();
+    Json::object(vec![
+        ("username", Json::str(&user.username)),
+        ("email", Json::str(&user.email)),
+        ("state", Json::str(user.state().name())),
+        ("created_at", Json::uint(user.lifecycle.created_at())),
+        ("sign_in_count", Json::uint(user.sign_in_count)),
+        ("roles", Json::Array(user.roles.iter().map(|r| Json::str(r)).collect())),
+        ("security", Json::object(vec![
+            ("has_password", Json::Bool(credentials.has_password())),
+            ("two_factor", Json::Bool(user.has_totp())),
+            ("last_sign_in", credentials.last_sign_in().map_or(Json::Null, Json::uint)),
+            ("failed_sign_ins", Json::Array(credentials.failed_sign_ins().iter().map(|t| Json::uint(*t)).collect())),
+            ("locked_until", credentials.locked_until().map_or(Json::Null, Json::uint))
+        ])),
+        ("history", Json::Array(user.lifecycle.history().iter().map(|t| {
+            Json::object(vec![("from", Json::str(t.from.name())), ("to", Json::str(t.to.name())), ("at", Json::uint(t.at))])
+        }).collect()))
+    ])
+}
+
+// one JSON document with the profile and whatever each source holds, write it out with pretty()
+pub fn export_subject(store: &dyn UserStore, sources: &[&dyn PersonalData], username: &str, clock: &dyn Clock) -> Result<Json, PrivacyError> {
+    let user = store.get(username).ok_or_else(|| StoreError::NotFound(username.to_string()))?;
+    let held = sources.iter().map(|s| (s.name().to_string(), s.export(user))).filter(|(_, json)| !json.is_null()).collect();
+    Ok(Json::object(vec![
+        ("format", Json::str("subject-access-export")),
+        ("version", Json::uint(EXPORT_VERSION)),
+        ("generated_at", Json::uint(clock.now())),
+        ("profile", profile_json(user)),
+        ("sources", Json::Object(held))
+    ]))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErasureReport {
+    pub pseudonym: String,  // what the account is called now
+    pub at: u64,
+    pub touched: Vec<(String, usize)>,  // source name and how many of its records changed, the store is "users"
+    pub remaining: Vec<String>  // sources where one of the old identifiers could still be found, empty when the erasure is complete
+}
+
+impl ErasureReport {
+    pub fn is_complete(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    // the report never names the person, it would otherwise be personal data itself
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("pseudonym", Json::str(&self.pseudonym)),
+            ("at", Json::uint(self.at)),
+            ("touched", Json::Array(self.touched.iter().map(|(name, count)| {
+                Json::object(vec![("source", Json::str(name)), ("records", Json::uint(*count as u64))])
+            }).collect())),
+            ("remaining", Json::Array(self.remaining.iter().map(|n| Json::str(n)).collect())),
+            ("complete", Json::Bool(self.is_complete()))
+        ])
+    }
+}
+
+pub fn erase_subject(store: &mut dyn UserStore, sources: &mut [&mut dyn PersonalData], username: &str, clock: &dyn Clock) -> Result<ErasureReport, PrivacyError> {
+    let mut user = store.get(username).ok_or_else(|| StoreError::NotFound(username.to_string()))?.clone();
+    let mut identifiers = vec![user.username.clone(), user.email.clone(), canonical_email(&user.email)];
+    for source in sources.iter() {
+        identifiers.extend(source.identifiers(&user));
+    }
+
+    let pseudonym = loop {
+        let candidate = format!("erased-{}", to_hex(&random_bytes(8)));
+        if store.get(&candidate).is_none() {
+            break candidate;
+        }
+    };
+    let email = format!("{pseudonym}@{ERASED_DOMAIN}");
+
+    // the other sources go first, while the account can still be found by its name if one of them fails
+    let mut touched = Vec::new();
+    for source in sources.iter_mut() {
+        let count = source.erase(&user, &pseudonym, &email).map_err(|message| PrivacyError::Source { name: source.name().to_string(), message })?;
+        touched.push((source.name().to_string(), count));
+    }
+
+    user.username = pseudonym.clone();
+    user.email = email;
+    user.credentials.erase_secrets();
+    if *user.state() != AccountState::Deleted {
+        // every state but Deleted itself can move to Deleted
+        let _ = user.delete(clock);
+    }
+    store.insert(user)?;
+    store.delete(username)?;
+    store.purge_history()?;
+    touched.insert(0, (String::from("users"), 1));
+
+    let views: Vec<&dyn PersonalData> = sources.iter().map(|s| &**s).collect();
+    Ok(ErasureReport { pseudonym, at: clock.now(), touched, remaining: find_identifiers(store, &views, &identifiers) })
+}
+
+// names of the places where any of the identifiers still appear, "users" being the store
+// only the fields that hold usernames and emails are compared, and whole values at that, so erasing "ann" is
+// not undone by a user called "anne", nor one called "active" by every account in that state
+pub fn find_identifiers(store: &dyn UserStore, sources: &[&dyn PersonalData], identifiers: &[String]) -> Vec<String> {
+    let needles: HashSet<String> = identifiers.iter().map(|i| i.to_lowercase()).filter(|i| !i.is_empty()).collect();
+    let found = |held: &String| {
+        let held = held.to_lowercase();
+        needles.contains(&held) || (held.contains('@') && needles.contains(&canonical_email(&held)))
+    };
+    let mut remaining = Vec::new();
+    if store.list().iter().any(|u| found(&u.username) || found(&u.email)) {
+        remaining.push(String::from("users"));
+    }
+    for source in sources {
+        if source.all_identifiers().iter().any(found) {
+            remaining.push(source.name().to_string());
+        }
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::users::analytics::SignInLog;
+    use crate::users::clock::ManualClock;
+    use crate::users::orgs::{OrgRole, Organisations};
+    use crate::users::patch::{patch_user, UserPatch};
+    use crate::users::session::{SessionError, SessionManager};
+    use crate::users::store::{FileUserStore, MemoryUserStore};
+
+    fn add(store: &mut dyn UserStore, username: &str, clock: &ManualClock) {
+        let mut user = crate::build_user(format!("{username}@example.com"), username.to_string()).unwrap();
+        user.set_password_with_iterations("correct horse", 1_000).unwrap();
+        user.activate(clock).unwrap();
+        store.insert(user).unwrap();
+    }
+
+    #[test]
+    fn erasure_leaves_no_username_or_email_behind() {
+        let clock = ManualClock::new(1_700_000_000);
+        let path = std::env::temp_dir().join(format!("privacy-erase-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = FileUserStore::open(&path).unwrap();
+        let (mut audit, mut sign_ins) = (AuditLog::new(), SignInLog::new());
+        // "active" is also what every active account's state is called, "ann" is part of "anne"
+        for username in ["ann", "anne", "active"] {
+            add(&mut store, username, &clock);
+            sign_ins.record(username, clock.now()).unwrap();
+        }
+        let new_email = UserPatch { email: Some("ann.new@example.com".to_string()), ..UserPatch::default() };
+        patch_user(&mut store, &mut audit, "ann", &new_email, "ann", &clock).unwrap();
+        let rename = UserPatch { username: Some("annie".to_string()), ..UserPatch::default() };
+        patch_user(&mut store, &mut audit, "ann", &rename, "anne", &clock).unwrap();
+        sign_ins.rename("ann", "annie").unwrap();
+
+        let export = export_subject(&store, &[&audit, &sign_ins], "annie", &clock).unwrap().to_string();
+        assert!(export.contains("ann@example.com") && export.contains("ann.new@example.com"));
+        assert!(!export.contains("pbkdf2"));
+
+        for username in ["annie", "active"] {
+            let report = erase_subject(&mut store, &mut [&mut audit, &mut sign_ins], username, &clock).unwrap();
+            assert!(report.is_complete(), "{username}: {:?}", report.remaining);
+            assert!(store.get(&report.pseudonym).is_some_and(|u| !u.credentials().has_password()));
+        }
+
+        let gone = ["annie", "ann@example.com", "ann.new@example.com", "active", "active@example.com"];
+        let held: Vec<String> = store.list().iter().flat_map(|u| [u.username.clone(), u.email.clone()])
+            .chain(audit.all_identifiers())
+            .chain(sign_ins.all_identifiers())
+            .collect();
+        for identifier in gone {
+            assert!(!held.iter().any(|h| h == identifier), "{identifier} is still held");
+        }
+        // the other account, mentioned in the audit log as the one who renamed annie, stays as it was
+        assert!(held.iter().any(|h| h == "anne"));
+        let disk = fs::read_to_string(&path).unwrap();
+        assert!(!disk.contains("\"annie\"") && !disk.contains("ann@example.com") && !disk.contains("ann.new@"), "{disk}");
+        assert!(disk.contains("anne@example.com"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn erasure_signs_the_user_out_everywhere() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut store = MemoryUserStore::new();
+        add(&mut store, "ann", &clock);
+        add(&mut store, "bob", &clock);
+        let mut sessions = SessionManager::new("k1", b"session key", ManualClock::new(1_700_000_000)).unwrap();
+        let (ann, _) = sessions.issue(store.get("ann").unwrap()).unwrap();
+        let (bob, _) = sessions.issue(store.get("bob").unwrap()).unwrap();
+
+        let report = erase_subject(&mut store, &mut [&mut sessions], "ann", &clock).unwrap();
+        assert!(report.is_complete(), "{:?}", report.remaining);
+        assert!(report.touched.contains(&("sessions".to_string(), 1)));
+        assert_eq!(sessions.verify(&ann), Err(SessionError::Revoked));
+        assert!(sessions.verify(&bob).is_ok());
+    }
+
+    #[test]
+    fn erasure_rewrites_the_audit_and_organisation_files() {
+        let clock = ManualClock::new(1_700_000_000);
+        let dir = std::env::temp_dir().join(format!("privacy-files-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut store = MemoryUserStore::new();
+        add(&mut store, "ann", &clock);
+        add(&mut store, "bob", &clock);
+        {
+            let mut audit = AuditLog::open(dir.join("audit.jsonl")).unwrap();
+            let new_email = UserPatch { email: Some("ann.new@example.com".to_string()), ..UserPatch::default() };
+            patch_user(&mut store, &mut audit, "ann", &new_email, "ann", &clock).unwrap();
+            let mut orgs = Organisations::open(dir.join("organisations.json")).unwrap();
+            orgs.create("basics", "Basics", store.get("bob").unwrap(), &clock).unwrap();
+            let code = orgs.invite("basics", "bob", "ann.new@example.com", OrgRole::Member, &clock).unwrap();
+            orgs.accept(&code, store.get("ann").unwrap(), None, &clock).unwrap();
+            orgs.save().unwrap();
+        }
+
+        // both come back from their files, and erasing from them rewrites the files
+        let mut audit = AuditLog::open(dir.join("audit.jsonl")).unwrap();
+        let mut orgs = Organisations::open(dir.join("organisations.json")).unwrap();
+        assert_eq!(audit.for_user("ann").len(), 1);
+        assert!(orgs.get("basics", "ann").unwrap().is_member("ann"));
+        let report = erase_subject(&mut store, &mut [&mut audit, &mut orgs], "ann", &clock).unwrap();
+        assert!(report.is_complete(), "{:?}", report.remaining);
+
+        for file in ["audit.jsonl", "organisations.json"] {
+            let disk = fs::read_to_string(dir.join(file)).unwrap();
+            assert!(!disk.contains("\"ann\"") && !disk.contains("ann@example.com") && !disk.contains("ann.new@"), "{file}: {disk}");
+            assert!(disk.contains(&report.pseudonym), "{file}: {disk}");
+        }
+        let audit = AuditLog::open(dir.join("audit.jsonl")).unwrap();
+        assert_eq!(audit.for_user(&report.pseudonym).len(), 1);
+        assert!(Organisations::open(dir.join("organisations.json")).unwrap().get("basics", &report.pseudonym).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
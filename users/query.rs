@@ -0,0 +1,722 @@
+// a small query language for picking out users, so a cohort can be selected without writing Rust
+//
+//     active = false AND sign_in_count > 5 AND email ENDS WITH "@yahoo.com"
+//     state IN ("suspended", "deactivated") OR NOT roles HAS "server"
+//
+// fields and the operators each one takes:
+//     username, email            text      = != CONTAINS  STARTS WITH  ENDS WITH  IN
+//     state                      state     = != IN, compared with a state name such as "suspended"
+//     active, two_factor         boolean   = !=
+//     sign_in_count, created_at  number    = != < <= > >= IN
+//     last_sign_in               number    as above, never matches for a user who has not signed in
+//     roles                      list      HAS
+// keywords and field names ignore case, text comparisons ignore case too
+// AND binds tighter than OR, NOT tighter than both, and parentheses group as usual
+//
+// Query::parse reads the text into an Expr (the syntax tree) and then type checks it, each error carries
+// the byte position it was found at so it can be pointed to under the query
+// NOT and parentheses nest at most MAX_DEPTH deep and a query holds at most MAX_CONDITIONS comparisons,
+// parsing, checking and evaluating all recurse through the tree and must not run out of stack
+
+use std::fmt;
+
+use crate::users::clock::Clock;
+use crate::users::lifecycle::AccountState;
+use crate::User;
+
+pub const MAX_DEPTH: usize = 64;
+pub const MAX_CONDITIONS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Username,
+    Email,
+    State,
+    Active,
+    TwoFactor,
+    SignInCount,
+    CreatedAt,
+    LastSignIn,
+    Roles
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Text,
+    State,
+    Boolean,
+    Number,
+    List
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Text => "text",
+            Type::State => "a state",
+            Type::Boolean => "a boolean",
+            Type::Number => "a number",
+            Type::List => "a list"
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Field {
+    pub const ALL: [Field; 9] = [
+        Field::Username,
+        Field::Email,
+        Field::State,
+        Field::Active,
+        Field::TwoFactor,
+        Field::SignInCount,
+        Field::CreatedAt,
+        Field::LastSignIn,
+        Field::Roles
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Username => "username",
+            Field::Email => "email",
+            Field::State => "state",
+            Field::Active => "active",
+            Field::TwoFactor => "two_factor",
+            Field::SignInCount => "sign_in_count",
+            Field::CreatedAt => "created_at",
+            Field::LastSignIn => "last_sign_in",
+            Field::Roles => "roles"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL.into_iter().find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn field_type(&self) -> Type {
+        match self {
+            Field::Username | Field::Email => Type::Text,
+            Field::State => Type::State,
+            Field::Active | Field::TwoFactor => Type::Boolean,
+            Field::SignInCount | Field::CreatedAt | Field::LastSignIn => Type::Number,
+            Field::Roles => Type::List
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    StartsWith,
+    EndsWith,
+    In,
+    Has
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Contains => "CONTAINS",
+            Op::StartsWith => "STARTS WITH",
+            Op::EndsWith => "ENDS WITH",
+            Op::In => "IN",
+            Op::Has => "HAS"
+        }
+    }
+
+    fn allowed_on(&self, field_type: Type) -> bool {
+        match field_type {
+            Type::Text => matches!(self, Op::Eq | Op::Ne | Op::Contains | Op::StartsWith | Op::EndsWith | Op::In),
+            Type::State => matches!(self, Op::Eq | Op::Ne | Op::In),
+            Type::Boolean => matches!(self, Op::Eq | Op::Ne),
+            Type::Number => matches!(self, Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::In),
+            Type::List => matches!(self, Op::Has)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(u64),
+    Boolean(bool),
+    List(Vec<Value>)
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Text(_) => "text",
+            Value::Number(_) => "a number",
+            Value::Boolean(_) => "a boolean",
+            Value::List(_) => "a list"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare { field: String, op: Op, value: Value, position: usize }  // position of the field name in the query
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryErrorKind {
+    Syntax,
+    Type
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub kind: QueryErrorKind,
+    pub position: usize,  // byte offset into the query
+    pub message: String
+}
+
+impl QueryError {
+    fn syntax(position: usize, message: impl Into<String>) -> Self {
+        QueryError { kind: QueryErrorKind::Syntax, position, message: message.into() }
+    }
+
+    fn type_error(position: usize, message: impl Into<String>) -> Self {
+        QueryError { kind: QueryErrorKind::Type, position, message: message.into() }
+    }
+
+    // the query with a ^ under the place the error was found
+    pub fn pointer(&self, query: &str) -> String {
+        let column = query[..self.position.min(query.len())].chars().count();
+        format!("{query}\n{}^ {}", " ".repeat(column), self.message)
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            QueryErrorKind::Syntax => "syntax error",
+            QueryErrorKind::Type => "type error"
+        };
+        write!(f, "{kind} at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(u64),
+    Symbol(&'static str)
+}
+
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_ascii_alphanumeric() || *c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push((start, Token::Word(word)));
+        } else if c.is_ascii_digit() {
+            let mut digits = String::new();
+            while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit()) {
+                digits.push(c);
+                chars.next();
+            }
+            let number = digits.parse().map_err(|_| QueryError::syntax(start, "number is too large"))?;
+            tokens.push((start, Token::Number(number)));
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err(QueryError::syntax(start, "text is missing its closing quote")),
+                    Some((_, '"')) => break,
+                    Some((at, '\\')) => match chars.next() {
+                        Some((_, escaped @ ('"' | '\\'))) => text.push(escaped),
+                        _ => return Err(QueryError::syntax(at, "only \\\" and \\\\ can be escaped"))
+                    },
+                    Some((_, c)) => text.push(c)
+                }
+            }
+            tokens.push((start, Token::Text(text)));
+        } else {
+            let rest = &query[start..];
+            let symbol = ["!=", "<>", "<=", ">=", "=", "<", ">", "(", ")", ","].into_iter().find(|s| rest.starts_with(s));
+            let Some(symbol) = symbol else {
+                return Err(QueryError::syntax(start, format!("unexpected '{c}'")));
+            };
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((start, Token::Symbol(if symbol == "<>" { "!=" } else { symbol })));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,  // the query length, where errors about running out of input point
+    depth: usize,  // NOT and parentheses currently open
+    conditions: usize  // comparisons read so far
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.next).map_or(self.end, |(p, _)| *p)
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.at_keyword(keyword);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn eat_symbol(&mut self, symbol: &'static str) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &'static str) -> Result<(), QueryError> {
+        if self.eat_symbol(symbol) { Ok(()) } else { Err(QueryError::syntax(self.position(), format!("expected '{symbol}'"))) }
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, QueryError> {
+        let position = self.position();
+        if self.eat_keyword("not") {
+            return self.nested(position, |p| Ok(Expr::Not(Box::new(p.not()?))));
+        }
+        if self.eat_symbol("(") {
+            return self.nested(position, |p| {
+                let inner = p.or()?;
+                p.expect_symbol(")")?;
+                Ok(inner)
+            });
+        }
+        self.comparison()
+    }
+
+    // position is where the NOT or opening parenthesis is, that is what an error points to
+    fn nested(&mut self, position: usize, parse: fn(&mut Self) -> Result<Expr, QueryError>) -> Result<Expr, QueryError> {
+        if self.depth == MAX_DEPTH {
+            return Err(QueryError::syntax(position, format!("NOT and parentheses nested more than {MAX_DEPTH} deep")));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn comparison(&mut self) -> Result<Expr, QueryError> {
+        let position = self.position();
+        if self.conditions == MAX_CONDITIONS {
+            return Err(QueryError::syntax(position, format!("a query can hold at most {MAX_CONDITIONS} comparisons")));
+        }
+        self.conditions += 1;
+        let field = match self.peek() {
+            Some(Token::Word(word)) if !is_keyword(word) => word.clone(),
+            _ => return Err(QueryError::syntax(position, "expected a field name")),
+        };
+        self.next += 1;
+        let op = self.operator()?;
+        let value = if op == Op::In { self.list()? } else { self.value()? };
+        Ok(Expr::Compare { field, op, value, position })
+    }
+
+    fn operator(&mut self) -> Result<Op, QueryError> {
+        let position = self.position();
+        let op = match self.peek().cloned() {
+            Some(Token::Symbol(symbol)) => match symbol {
+                "=" => Op::Eq,
+                "!=" => Op::Ne,
+                "<" => Op::Lt,
+                "<=" => Op::Le,
+                ">" => Op::Gt,
+                ">=" => Op::Ge,
+                _ => return Err(QueryError::syntax(position, "expected an operator"))
+            },
+            Some(Token::Word(word)) => match word.to_ascii_lowercase().as_str() {
+                "contains" => Op::Contains,
+                "in" => Op::In,
+                "has" => Op::Has,
+                "starts" | "ends" => {
+                    let op = if word.eq_ignore_ascii_case("starts") { Op::StartsWith } else { Op::EndsWith };
+                    self.next += 1;
+                    if !self.at_keyword("with") {
+                        return Err(QueryError::syntax(self.position(), format!("expected WITH after {}", word.to_ascii_uppercase())));
+                    }
+                    op
+                }
+                _ => return Err(QueryError::syntax(position, "expected an operator"))
+            },
+            _ => return Err(QueryError::syntax(position, "expected an operator"))
+        };
+        self.next += 1;
+        Ok(op)
+    }
+
+    fn value(&mut self) -> Result<Value, QueryError> {
+        let position = self.position();
+        let value = match self.peek() {
+            Some(Token::Text(text)) => Value::Text(text.clone()),
+            Some(Token::Number(n)) => Value::Number(*n),
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("true") => Value::Boolean(true),
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("false") => Value::Boolean(false),
+            Some(Token::Word(w)) => return Err(QueryError::syntax(position, format!("expected a value, text needs quotes: \"{w}\""))),
+            _ => return Err(QueryError::syntax(position, "expected a value"))
+        };
+        self.next += 1;
+        Ok(value)
+    }
+
+    fn list(&mut self) -> Result<Value, QueryError> {
+        self.expect_symbol("(")?;
+        let mut items = vec![self.value()?];
+        while self.eat_symbol(",") {
+            items.push(self.value()?);
+        }
+        self.expect_symbol(")")?;
+        Ok(Value::List(items))
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    ["and", "or", "not", "true", "false"].iter().any(|k| k.eq_ignore_ascii_case(word))
+}
+
+pub fn parse(query: &str) -> Result<Expr, QueryError> {
+    let mut parser = Parser { tokens: tokenize(query)?, next: 0, end: query.len(), depth: 0, conditions: 0 };
+    if parser.tokens.is_empty() {
+        return Err(QueryError::syntax(0, "the query is empty"));
+    }
+    let expr = parser.or()?;
+    if parser.next < parser.tokens.len() {
+        return Err(QueryError::syntax(parser.position(), "expected AND, OR or the end of the query"));
+    }
+    Ok(expr)
+}
+
+// makes sure every field exists and every operator and value suit the field they are used with
+pub fn check(expr: &Expr) -> Result<(), QueryError> {
+    match expr {
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            check(left)?;
+            check(right)
+        }
+        Expr::Not(inner) => check(inner),
+        Expr::Compare { field, op, value, position } => {
+            let position = *position;
+            let known = Field::ALL.map(|f| f.name()).join(", ");
+            let field = Field::from_name(field).ok_or_else(|| QueryError::type_error(position, format!("unknown field '{field}', expected one of {known}")))?;
+            let field_type = field.field_type();
+            if !op.allowed_on(field_type) {
+                return Err(QueryError::type_error(position, format!("{} cannot be used with {}, which is {field_type}", op.name(), field.name())));
+            }
+            let items = match value {
+                Value::List(items) => items.as_slice(),
+                single => std::slice::from_ref(single)
+            };
+            for item in items {
+                check_value(field, item, position)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check_value(field: Field, value: &Value, position: usize) -> Result<(), QueryError> {
+    let expected = match field.field_type() {
+        Type::Text | Type::List => matches!(value, Value::Text(_)),
+        Type::Number => matches!(value, Value::Number(_)),
+        Type::Boolean => matches!(value, Value::Boolean(_)),
+        Type::State => match value {
+            Value::Text(name) => {
+                if AccountState::from_name(&name.to_lowercase()).is_none() {
+                    return Err(QueryError::type_error(position, format!("'{name}' is not an account state")));
+                }
+                true
+            }
+            _ => false
+        }
+    };
+    if expected {
+        return Ok(());
+    }
+    let wanted = if field.field_type() == Type::List { Type::Text } else { field.field_type() };
+    Err(QueryError::type_error(position, format!("{} is compared with {wanted}, not {}", field.name(), value.describe())))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    text: String,
+    expr: Expr
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, QueryError> {
+        let expr = parse(text)?;
+        check(&expr)?;
+        Ok(Query { text: text.to_string(), expr })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    pub fn matches(&self, user: &User, clock: &dyn Clock) -> bool {
+        evaluate(&self.expr, user, clock)
+    }
+
+    // the users the query selects, in the order given
+    pub fn filter<'a>(&self, users: impl IntoIterator<Item = &'a User>, clock: &dyn Clock) -> Vec<&'a User> {
+        users.into_iter().filter(|u| self.matches(u, clock)).collect()
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn evaluate(expr: &Expr, user: &User, clock: &dyn Clock) -> bool {
+    match expr {
+        Expr::And(left, right) => evaluate(left, user, clock) && evaluate(right, user, clock),
+        Expr::Or(left, right) => evaluate(left, user, clock) || evaluate(right, user, clock),
+        Expr::Not(inner) => !evaluate(inner, user, clock),
+        Expr::Compare { field, op, value, .. } => {
+            // check() has already turned away unknown fields
+            let Some(field) = Field::from_name(field) else { return false };
+            match field {
+                Field::Username => compare_text(&user.username, *op, value),
+                Field::Email => compare_text(&user.email, *op, value),
+                Field::State => compare_text(user.state().name(), *op, value),
+                Field::Active => compare_bool(user.is_active(clock), *op, value),
+                Field::TwoFactor => compare_bool(user.has_totp(), *op, value),
+                Field::SignInCount => compare_number(Some(user.sign_in_count), *op, value),
+                Field::CreatedAt => compare_number(Some(user.lifecycle.created_at()), *op, value),
+                Field::LastSignIn => compare_number(user.credentials().last_sign_in(), *op, value),
+                Field::Roles => matches!(value, Value::Text(role) if user.roles.iter().any(|r| r.eq_ignore_ascii_case(role)))
+            }
+        }
+    }
+}
+
+fn compare_text(actual: &str, op: Op, value: &Value) -> bool {
+    let actual = actual.to_lowercase();
+    let text = |v: &Value| match v {
+        Value::Text(t) => t.to_lowercase(),
+        _ => String::new()
+    };
+    match (op, value) {
+        (Op::In, Value::List(items)) => items.iter().any(|v| actual == text(v)),
+        (Op::Eq, v) => actual == text(v),
+        (Op::Ne, v) => actual != text(v),
+        (Op::Contains, v) => actual.contains(&text(v)),
+        (Op::StartsWith, v) => actual.starts_with(&text(v)),
+        (Op::EndsWith, v) => actual.ends_with(&text(v)),
+        _ => false
+    }
+}
+
+fn compare_bool(actual: bool, op: Op, value: &Value) -> bool {
+    match (op, value) {
+        (Op::Eq, Value::Boolean(b)) => actual == *b,
+        (Op::Ne, Value::Boolean(b)) => actual != *b,
+        _ => false
+    }
+}
+
+fn compare_number(actual: Option<u64>, op: Op, value: &Value) -> bool {
+    let Some(actual) = actual else { return false };
+    match (op, value) {
+        (Op::In, Value::List(items)) => items.contains(&Value::Number(actual)),
+        (Op::Eq, Value::Number(n)) => actual == *n,
+        (Op::Ne, Value::Number(n)) => actual != *n,
+        (Op::Lt, Value::Number(n)) => actual < *n,
+        (Op::Le, Value::Number(n)) => actual <= *n,
+        (Op::Gt, Value::Number(n)) => actual > *n,
+        (Op::Ge, Value::Number(n)) => actual >= *n,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::auth::Credentials;
+    use crate::users::clock::ManualClock;
+    use crate::users::json::Json;
+
+    fn user(username: &str, sign_in_count: u64, last_sign_in: Option<u64>) -> User {
+        let mut user = crate::build_user(format!("{username}@example.com"), username.to_string()).unwrap();
+        user.sign_in_count = sign_in_count;
+        let credentials = Json::object(vec![("last_sign_in", last_sign_in.map_or(Json::Null, Json::uint))]);
+        user.credentials = Credentials::from_json(&credentials).unwrap();
+        user
+    }
+
+    fn selected(query: &str, users: &[User]) -> Vec<String> {
+        let clock = ManualClock::new(1_700_000_000);
+        Query::parse(query).unwrap().filter(users, &clock).into_iter().map(|u| u.username.clone()).collect()
+    }
+
+    fn error(query: &str) -> QueryError {
+        Query::parse(query).unwrap_err()
+    }
+
+    // positions zeroed, so trees can be compared with ones built by hand
+    fn strip(expr: Expr) -> Expr {
+        match expr {
+            Expr::And(l, r) => Expr::And(Box::new(strip(*l)), Box::new(strip(*r))),
+            Expr::Or(l, r) => Expr::Or(Box::new(strip(*l)), Box::new(strip(*r))),
+            Expr::Not(inner) => Expr::Not(Box::new(strip(*inner))),
+            Expr::Compare { field, op, value, .. } => Expr::Compare { field, op, value, position: 0 }
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_tighter_than_both() {
+        let compare = |field: &str, value: u64| Expr::Compare { field: field.to_string(), op: Op::Eq, value: Value::Number(value), position: 0 };
+        let (a, b, c) = (|| compare("a", 1), || compare("b", 2), || compare("c", 3));
+        assert_eq!(strip(parse("a = 1 OR b = 2 AND c = 3").unwrap()), Expr::Or(Box::new(a()), Box::new(Expr::And(Box::new(b()), Box::new(c())))));
+        assert_eq!(strip(parse("(a = 1 OR b = 2) and c = 3").unwrap()), Expr::And(Box::new(Expr::Or(Box::new(a()), Box::new(b()))), Box::new(c())));
+        assert_eq!(strip(parse("NOT a = 1 AND b = 2").unwrap()), Expr::And(Box::new(Expr::Not(Box::new(a()))), Box::new(b())));
+        assert_eq!(strip(parse("not (a = 1 or b = 2)").unwrap()), Expr::Not(Box::new(Expr::Or(Box::new(a()), Box::new(b())))));
+
+        let users = [user("ann", 1, None), user("bob", 7, None), user("cat", 7, None)];
+        assert_eq!(selected("username = \"ann\" OR sign_in_count = 7 AND username = \"bob\"", &users), ["ann", "bob"]);
+        assert_eq!(selected("(username = \"ann\" OR sign_in_count = 7) AND NOT username = \"bob\"", &users), ["ann", "cat"]);
+    }
+
+    #[test]
+    fn type_errors_point_at_the_field_they_are_about() {
+        let cases = [
+            ("active = true AND colour = \"red\"", 18, "unknown field 'colour'"),
+            ("email > \"a\"", 0, "> cannot be used with email, which is text"),
+            ("active = true AND roles = \"x\"", 18, "= cannot be used with roles, which is a list"),
+            ("username = \"a\" OR sign_in_count > \"5\"", 18, "sign_in_count is compared with a number, not text"),
+            ("two_factor = 1", 0, "two_factor is compared with a boolean, not a number"),
+            ("state = \"sleeping\"", 0, "'sleeping' is not an account state"),
+            ("state IN (\"active\", 3)", 0, "state is compared with a state, not a number"),
+            ("roles HAS 3", 0, "roles is compared with text, not a number")
+        ];
+        for (query, position, message) in cases {
+            let err = error(query);
+            assert_eq!((err.kind.clone(), err.position), (QueryErrorKind::Type, position), "{query}");
+            assert!(err.message.starts_with(message), "{query}: {}", err.message);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_pointed_at() {
+        let err = error("active = ");
+        assert_eq!((err.kind.clone(), err.position), (QueryErrorKind::Syntax, 9));
+        assert_eq!(error("username = ann").message, "expected a value, text needs quotes: \"ann\"");
+        assert_eq!(error("email STARTS \"a\"").position, 13);
+        assert_eq!(error("(active = true").message, "expected ')'");
+        assert_eq!(error("active = true active = false").position, 14);
+        assert_eq!(error("   ").message, "the query is empty");
+        assert_eq!(error("username = \"ann").message, "text is missing its closing quote");
+
+        // the caret goes under the character, counting characters rather than bytes
+        let query = "username = \"\u{e9}\u{e9}\" AND x = 1";
+        let err = error(query);
+        assert_eq!(err.position, 22);
+        assert_eq!(err.pointer(query), format!("{query}\n{}^ {}", " ".repeat(20), err.message));
+        assert_eq!(err.to_string(), format!("type error at position 22: {}", err.message));
+    }
+
+    #[test]
+    fn in_lists_match_any_of_their_items() {
+        let users = [user("ann", 1, None), user("bob", 2, None), user("cat", 3, None)];
+        assert_eq!(selected("sign_in_count IN (1, 3)", &users), ["ann", "cat"]);
+        assert_eq!(selected("username in (\"BOB\", \"dan\")", &users), ["bob"]);
+        assert_eq!(selected("state IN (\"pending_verification\")", &users).len(), 3);
+        assert_eq!(selected("NOT sign_in_count IN (2)", &users), ["ann", "cat"]);
+        assert_eq!(error("sign_in_count IN ()").message, "expected a value");
+        assert_eq!(error("sign_in_count IN (1,)").message, "expected a value");
+        assert_eq!(error("sign_in_count IN 1").message, "expected '('");
+    }
+
+    #[test]
+    fn last_sign_in_never_matches_someone_who_has_not_signed_in() {
+        let users = [user("ann", 1, Some(1_000)), user("bob", 0, None)];
+        assert_eq!(selected("last_sign_in > 0", &users), ["ann"]);
+        assert_eq!(selected("last_sign_in < 5000", &users), ["ann"]);
+        assert_eq!(selected("last_sign_in != 1000", &users), Vec::<String>::new());
+        // NOT turns never matching into always matching
+        assert_eq!(selected("NOT last_sign_in > 0", &users), ["bob"]);
+    }
+
+    #[test]
+    fn deep_nesting_and_long_queries_are_refused() {
+        let fits = format!("{}active = true{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert!(Query::parse(&fits).is_ok());
+        let deep = format!("{}active = true{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+        let err = error(&deep);
+        assert_eq!((err.kind.clone(), err.position), (QueryErrorKind::Syntax, MAX_DEPTH));
+        assert!(Query::parse(&format!("{}active = true", "NOT ".repeat(MAX_DEPTH))).is_ok());
+        assert!(error(&"NOT ".repeat(100_000)).message.contains("nested more than"));
+
+        let long = vec!["active = true"; MAX_CONDITIONS].join(" OR ");
+        assert!(Query::parse(&long).is_ok());
+        let err = error(&format!("{long} OR active = false"));
+        assert_eq!(err.position, long.len() + 4);
+        assert!(err.message.contains("at most"));
+    }
+}
@@ -0,0 +1,355 @@
+// roles, permissions and the check that decides whether someone may do something
+// this file does not use anything else from the crate, so Basics4.rs can include it with #[path] as well
+//
+// a policy file lists roles, what each one allows, and which roles it inherits from:
+//
+//     # lines starting with # are comments
+//     role guest
+//         allow read menu
+//
+//     role server inherits guest
+//         allow take_order orders
+//         allow read tables/*
+//
+//     role manager inherits server
+//         allow * *
+//
+// an action or resource of * matches anything, and a resource ending in /* matches everything under it
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// anything that holds roles can be checked against a policy, User in Basics3.rs and Staff in Basics4.rs both do
+pub trait Principal {
+    fn name(&self) -> &str;
+    fn roles(&self) -> &[String];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Permission {
+    pub action: String,
+    pub resource: String
+}
+
+impl Permission {
+    pub fn new(action: &str, resource: &str) -> Self {
+        Permission { action: action.to_string(), resource: resource.to_string() }
+    }
+
+    pub fn allows(&self, action: &str, resource: &str) -> bool {
+        let action_matches = self.action == "*" || self.action == action;
+        let resource_matches = self.resource == "*"
+            || self.resource == resource
+            || self.resource.strip_suffix("/*").is_some_and(|prefix| resource.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/')));
+        action_matches && resource_matches
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    pub name: String,
+    pub inherits: Vec<String>,
+    pub permissions: Vec<Permission>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyError {
+    pub line: usize,  // 0 when the problem is not tied to one line, such as an inheritance cycle
+    pub message: String
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "policy error: {}", self.message)
+        } else {
+            write!(f, "policy error on line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessDenied {
+    pub who: String,
+    pub action: String,
+    pub resource: String
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} may not {} {}", self.who, self.action, self.resource)
+    }
+}
+
+impl std::error::Error for AccessDenied {}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    roles: BTreeMap<String, Role>
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Policy, PolicyError> {
+        let text = fs::read_to_string(path.as_ref())
+            .map_err(|e| PolicyError { line: 0, message: format!("cannot read {}: {e}", path.as_ref().display()) })?;
+        Policy::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Policy, PolicyError> {
+        let mut policy = Policy::new();
+        let mut current: Option<String> = None;
+        for (index, raw) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let error = |message: &str| PolicyError { line: line_no, message: message.to_string() };
+            let line = raw.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[0] {
+                "role" => {
+                    let name = *words.get(1).ok_or_else(|| error("expected a role name after 'role'"))?;
+                    check_name(name).map_err(|m| error(&m))?;
+                    if policy.roles.contains_key(name) {
+                        return Err(error(&format!("role '{name}' is defined twice")));
+                    }
+                    let inherits = match words.get(2) {
+                        None => Vec::new(),
+                        Some(&"inherits") => {
+                            let list = words[3..].join(" ");
+                            let parents: Vec<String> = list.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+                            if parents.is_empty() {
+                                return Err(error("expected role names after 'inherits'"));
+                            }
+                            parents
+                        }
+                        Some(other) => return Err(error(&format!("unexpected '{other}', expected 'inherits'")))
+                    };
+                    policy.roles.insert(name.to_string(), Role { name: name.to_string(), inherits, permissions: Vec::new() });
+                    current = Some(name.to_string());
+                }
+                "allow" => {
+                    let role = current.as_ref().ok_or_else(|| error("'allow' must come after a 'role' line"))?;
+                    if words.len() != 3 {
+                        return Err(error("expected 'allow <action> <resource>'"));
+                    }
+                    let role = policy.roles.get_mut(role).expect("current role was inserted above");
+                    role.permissions.push(Permission::new(words[1], words[2]));
+                }
+                other => return Err(error(&format!("unknown keyword '{other}'")))
+            }
+        }
+        policy.check()?;
+        Ok(policy)
+    }
+
+    // adds or replaces a role, the policy is checked again so unknown parents and cycles are caught here too
+    pub fn add_role(&mut self, role: Role) -> Result<(), PolicyError> {
+        check_name(&role.name).map_err(|message| PolicyError { line: 0, message })?;
+        let previous = self.roles.insert(role.name.clone(), role.clone());
+        if let Err(err) = self.check() {
+            match previous {
+                Some(previous) => self.roles.insert(role.name, previous),
+                None => self.roles.remove(&role.name)
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn role(&self, name: &str) -> Option<&Role> {
+        self.roles.get(name)
+    }
+
+    pub fn roles(&self) -> impl Iterator<Item = &Role> {
+        self.roles.values()
+    }
+
+    // every parent must exist and following the parents must never lead back to where it started
+    fn check(&self) -> Result<(), PolicyError> {
+        for role in self.roles.values() {
+            for parent in &role.inherits {
+                if !self.roles.contains_key(parent) {
+                    return Err(PolicyError { line: 0, message: format!("role '{}' inherits unknown role '{parent}'", role.name) });
+                }
+            }
+        }
+        let mut done = BTreeSet::new();
+        for name in self.roles.keys() {
+            self.visit(name, &mut Vec::new(), &mut done)?;
+        }
+        Ok(())
+    }
+
+    // done holds roles whose ancestors are already known to be free of cycles, so a role reached along
+    // many paths, as in a diamond of roles inheriting from one another, is only followed up once
+    fn visit<'a>(&'a self, name: &'a str, path: &mut Vec<&'a str>, done: &mut BTreeSet<&'a str>) -> Result<(), PolicyError> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if path.contains(&name) {
+            path.push(name);
+            return Err(PolicyError { line: 0, message: format!("roles inherit from each other in a cycle: {}", path.join(" -> ")) });
+        }
+        path.push(name);
+        for parent in self.roles.get(name).map_or(&[][..], |r| &r.inherits) {
+            self.visit(parent, path, done)?;
+        }
+        path.pop();
+        done.insert(name);
+        Ok(())
+    }
+
+    // the role itself and every role it inherits from, directly or further up
+    pub fn expand(&self, role: &str) -> BTreeSet<String> {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![role.to_string()];
+        while let Some(name) = pending.pop() {
+            if let Some(role) = self.roles.get(&name) {
+                if seen.insert(name) {
+                    pending.extend(role.inherits.iter().cloned());
+                }
+            }
+        }
+        seen
+    }
+
+    pub fn can(&self, who: &dyn Principal, action: &str, resource: &str) -> bool {
+        who.roles().iter().flat_map(|r| self.expand(r)).any(|name| {
+            self.roles[&name].permissions.iter().any(|p| p.allows(action, resource))
+        })
+    }
+
+    // the same check as can, as a Result so callers can use ? to stop early
+    pub fn require(&self, who: &dyn Principal, action: &str, resource: &str) -> Result<(), AccessDenied> {
+        if self.can(who, action, resource) {
+            Ok(())
+        } else {
+            Err(AccessDenied { who: who.name().to_string(), action: action.to_string(), resource: resource.to_string() })
+        }
+    }
+}
+
+// role names are lowercase words so they read the same in policy files, stored users and error messages
+pub fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if valid { Ok(()) } else { Err(format!("'{name}' is not a valid role name, use lowercase letters, digits, '_' and '-'")) }
+}
+
+// can(user, action, resource) reads closer to how people describe the rule than policy.can(..)
+pub fn can(policy: &Policy, who: &dyn Principal, action: &str, resource: &str) -> bool {
+    policy.can(who, action, resource)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESTAURANT: &str = include_str!("../restaurant.policy");
+
+    struct Staff(&'static str, Vec<String>);
+
+    impl Principal for Staff {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn roles(&self) -> &[String] {
+            &self.1
+        }
+    }
+
+    fn staff(name: &'static str, roles: &[&str]) -> Staff {
+        Staff(name, roles.iter().map(|r| r.to_string()).collect())
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        let err = Policy::parse(text).unwrap_err();
+        (err.line, err.message)
+    }
+
+    #[test]
+    fn parse_errors_name_their_line() {
+        assert_eq!(error("# roles\n\nallow read menu"), (3, "'allow' must come after a 'role' line".to_string()));
+        assert_eq!(error("role guest\n  allow read"), (2, "expected 'allow <action> <resource>'".to_string()));
+        assert_eq!(error("role guest\nrole guest"), (2, "role 'guest' is defined twice".to_string()));
+        assert_eq!(error("role Guest").0, 1);
+        assert_eq!(error("role\n").1, "expected a role name after 'role'");
+        assert_eq!(error("role a\nrole b inherits").1, "expected role names after 'inherits'");
+        assert_eq!(error("role a\nrole b extends a"), (2, "unexpected 'extends', expected 'inherits'".to_string()));
+        assert_eq!(error("role a\n  deny * *"), (2, "unknown keyword 'deny'".to_string()));
+        assert_eq!(error("role a inherits nobody"), (0, "role 'a' inherits unknown role 'nobody'".to_string()));
+        assert_eq!(Policy::parse("role a\n  allow\tread  menu   # trailing comment").unwrap().role("a").unwrap().permissions, [Permission::new("read", "menu")]);
+        let err = Policy::parse("role a\nrole a").unwrap_err();
+        assert_eq!(err.to_string(), "policy error on line 2: role 'a' is defined twice");
+    }
+
+    #[test]
+    fn roles_expand_through_every_ancestor() {
+        let policy = Policy::parse(RESTAURANT).unwrap();
+        let names = |role: &str| policy.expand(role).into_iter().collect::<Vec<_>>();
+        assert_eq!(names("manager"), ["host", "manager", "server"]);
+        assert_eq!(names("host"), ["host"]);
+        assert!(names("chef").is_empty());
+
+        let diamond = Policy::parse("role base\nrole left inherits base\nrole right inherits base\nrole top inherits left, right").unwrap();
+        assert_eq!(diamond.expand("top").into_iter().collect::<Vec<_>>(), ["base", "left", "right", "top"]);
+    }
+
+    #[test]
+    fn cycles_are_rejected_when_parsed_or_added() {
+        let err = Policy::parse("role a inherits c\nrole b inherits a\nrole c inherits b").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (0, "roles inherit from each other in a cycle: a -> c -> b -> a"));
+        assert!(Policy::parse("role a inherits a").is_err());
+
+        let mut policy = Policy::parse(RESTAURANT).unwrap();
+        let before = policy.clone();
+        let looped = Role { name: "host".to_string(), inherits: vec!["manager".to_string()], permissions: Vec::new() };
+        assert!(policy.add_role(looped).is_err());
+        assert_eq!(policy, before);  // the old host is put back
+        let unknown = Role { name: "chef".to_string(), inherits: vec!["cook".to_string()], permissions: Vec::new() };
+        assert!(policy.add_role(unknown).is_err());
+        assert!(policy.role("chef").is_none());
+    }
+
+    #[test]
+    fn a_deep_diamond_is_checked_quickly() {
+        // each layer inherits both roles of the layer below, 2^60 paths lead down to the bottom
+        let mut text = String::from("role l0a\nrole l0b\n");
+        for i in 1..=60 {
+            text += &format!("role l{i}a inherits l{0}a, l{0}b\nrole l{i}b inherits l{0}a, l{0}b\n", i - 1);
+        }
+        let policy = Policy::parse(&text).unwrap();
+        assert_eq!(policy.expand("l60a").len(), 121);
+    }
+
+    #[test]
+    fn can_follows_wildcards_and_inheritance() {
+        let policy = Policy::parse(RESTAURANT).unwrap();
+        let (host, server, manager) = (staff("Alex", &["host"]), staff("Sam", &["server"]), staff("Kim", &["manager"]));
+        assert!(can(&policy, &host, "seat_at_table", "tables/4"));
+        assert!(!can(&policy, &host, "seat_at_table", "tables"));  // tables/* only covers what is under tables
+        assert!(!can(&policy, &host, "seat_at_table", "tablesides/4"));
+        assert!(can(&policy, &server, "read", "menu"));  // from host
+        assert!(can(&policy, &manager, "refund", "orders/12"));
+        assert!(!can(&policy, &staff("Nobody", &[]), "read", "menu"));
+        assert!(!can(&policy, &staff("Chef", &["chef"]), "read", "menu"));  // a role the policy does not know allows nothing
+    }
+
+    #[test]
+    fn take_order_needs_the_server_role() {
+        let policy = Policy::parse(RESTAURANT).unwrap();
+        assert_eq!(policy.require(&staff("Sam", &["server"]), "take_order", "orders"), Ok(()));
+        assert_eq!(policy.require(&staff("Kim", &["host", "manager"]), "take_order", "orders"), Ok(()));
+        let err = policy.require(&staff("Alex", &["host"]), "take_order", "orders").unwrap_err();
+        assert_eq!(err.to_string(), "Alex may not take_order orders");
+    }
+}
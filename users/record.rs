@@ -0,0 +1,84 @@
+// converts a User to and from the JSON object the stores write to disk
+// records carry their schema version, older ones are brought up to date by users::migrate as they are read
+
+use crate::users::auth::Credentials;
+use crate::users::json::Json;
+use crate::users::lifecycle::{AccountState, Lifecycle, Transition};
+use crate::users::migrate::{Migrations, SCHEMA_VERSION};
+use crate::User;
+
+impl User {
+    pub fn to_json(&self) -> Json {
+        let history = self.lifecycle.history().iter().map(|t| {
+            Json::object(vec![("from", state_to_json(&t.from)), ("to", state_to_json(&t.to)), ("at", Json::uint(t.at))])
+        });
+        Json::object(vec![
+            ("schema", Json::uint(SCHEMA_VERSION)),
+            ("state", state_to_json(self.lifecycle.state())),
+            ("created_at", Json::uint(self.lifecycle.created_at())),
+            ("history", Json::Array(history.collect())),
+            ("username", Json::str(&self.username)),
+            ("email", Json::str(&self.email)),
+            ("sign_in_count", Json::uint(self.sign_in_count)),
+            ("credentials", self.credentials.to_json()),
+            ("roles", Json::Array(self.roles.iter().map(|r| Json::str(r)).collect()))
+        ])
+    }
+
+    pub fn from_json(value: &Json) -> Result<User, String> {
+        User::from_json_with(value, &Migrations::standard())
+    }
+
+    // the same, with a registry the caller built once, as a store does for every record it loads
+    pub fn from_json_with(value: &Json, migrations: &Migrations) -> Result<User, String> {
+        let value = &migrations.upgrade(value).map_err(|e| e.to_string())?;
+        let text = |key: &str| {
+            value.get(key).and_then(Json::as_str).map(String::from).ok_or(format!("missing or invalid field '{key}'"))
+        };
+        Ok(User {
+            lifecycle: lifecycle_from_json(value)?,
+            username: text("username")?,
+            email: text("email")?,
+            sign_in_count: value.get("sign_in_count").and_then(Json::as_u64).ok_or("missing or invalid field 'sign_in_count'")?,
+            credentials: value.get("credentials").map_or(Ok(Credentials::default()), Credentials::from_json)?,
+            roles: value.get("roles").and_then(Json::as_array).map_or(&[][..], |v| v).iter().filter_map(Json::as_str).map(String::from).collect()
+        })
+    }
+}
+
+fn lifecycle_from_json(value: &Json) -> Result<Lifecycle, String> {
+    let state = state_from_json(value.get("state").ok_or("missing field 'state'")?)?;
+    let created_at = value.get("created_at").and_then(Json::as_u64).unwrap_or(0);
+    let mut history = Vec::new();
+    for entry in value.get("history").and_then(Json::as_array).map_or(&[][..], |v| v) {
+        history.push(Transition {
+            from: state_from_json(entry.get("from").ok_or("history entry without 'from'")?)?,
+            to: state_from_json(entry.get("to").ok_or("history entry without 'to'")?)?,
+            at: entry.get("at").and_then(Json::as_u64).ok_or("history entry without 'at'")?
+        });
+    }
+    Ok(Lifecycle::restore(state, created_at, history))
+}
+
+pub fn state_to_json(state: &AccountState) -> Json {
+    let mut json = Json::object(vec![("name", Json::str(state.name()))]);
+    if let AccountState::Suspended { reason, until } = state {
+        json.set("reason", Json::str(reason));
+        json.set("until", until.map_or(Json::Null, Json::uint));
+    }
+    json
+}
+
+pub fn state_from_json(value: &Json) -> Result<AccountState, String> {
+    match value.get("name").and_then(Json::as_str) {
+        Some("pending_verification") => Ok(AccountState::PendingVerification),
+        Some("active") => Ok(AccountState::Active),
+        Some("suspended") => Ok(AccountState::Suspended {
+            reason: value.get("reason").and_then(Json::as_str).unwrap_or_default().to_string(),
+            until: value.get("until").and_then(Json::as_u64)
+        }),
+        Some("deactivated") => Ok(AccountState::Deactivated),
+        Some("deleted") => Ok(AccountState::Deleted),
+        _ => Err("unknown account state".to_string())
+    }
+}
@@ -0,0 +1,200 @@
+// keeping emails and usernames out of logs
+// Sensitive<T> wraps a value so that both {} and {:?} print a masked form, the real value has to be asked for
+// by name with reveal(), which is easy to search for in review
+//
+//     let email = Sensitive::new("ahus@yahoo.com", Reveal::EmailDomain);
+//     println!("{email}");  // ***@yahoo.com
+//
+// User's Debug output goes through the same masks, so {:?} on a user is safe to log
+
+use std::fmt;
+
+use crate::users::crypto::{sha256, to_hex};
+use crate::User;
+
+const MASK: &str = "***";
+
+// how much of a value a mask lets through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reveal {
+    Nothing,  // [redacted]
+    EmailDomain,  // ***@yahoo.com, and [redacted] for anything that is not an email
+    Initial,  // A***
+    Length,  // [redacted, 4 chars]
+    // #5d41402a, the same value always gives the same fingerprint so log lines can be matched up
+    // short values such as usernames can be guessed from it by trying names, so prefer Nothing for those
+    Fingerprint
+}
+
+impl Reveal {
+    pub fn mask(&self, value: &str) -> String {
+        match self {
+            Reveal::Nothing => String::from("[redacted]"),
+            Reveal::EmailDomain => match value.rsplit_once('@') {
+                Some((_, domain)) if !domain.is_empty() => format!("{MASK}@{domain}"),
+                _ => String::from("[redacted]")
+            },
+            Reveal::Initial => match value.chars().next() {
+                Some(first) => format!("{first}{MASK}"),
+                None => String::from(MASK)
+            },
+            Reveal::Length => format!("[redacted, {} chars]", value.chars().count()),
+            Reveal::Fingerprint => format!("#{}", &to_hex(&sha256(value.as_bytes()))[..8])
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Sensitive<T> {
+    value: T,
+    reveal: Reveal
+}
+
+impl<T> Sensitive<T> {
+    pub fn new(value: T, reveal: Reveal) -> Self {
+        Sensitive { value, reveal }
+    }
+
+    pub fn hidden(value: T) -> Self {
+        Sensitive::new(value, Reveal::Nothing)
+    }
+
+    // the unmasked value, every call is a place where personal data leaves the wrapper
+    pub fn reveal(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    pub fn policy(&self) -> Reveal {
+        self.reveal
+    }
+}
+
+impl<T: fmt::Display> Sensitive<T> {
+    pub fn masked(&self) -> String {
+        self.reveal.mask(&self.value.to_string())
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.masked())
+    }
+}
+
+// Debug masks too, so a Sensitive field inside a derived Debug struct is still safe
+impl<T: fmt::Display> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sensitive({})", self.masked())
+    }
+}
+
+// which mask each kind of personal field gets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RedactionPolicy {
+    pub email: Reveal,
+    pub username: Reveal
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        RedactionPolicy { email: Reveal::EmailDomain, username: Reveal::Initial }
+    }
+}
+
+impl RedactionPolicy {
+    // a policy that lets nothing through, for logs that leave the building
+    pub fn strict() -> Self {
+        RedactionPolicy { email: Reveal::Nothing, username: Reveal::Nothing }
+    }
+
+    pub fn email<'a>(&self, user: &'a User) -> Sensitive<&'a str> {
+        Sensitive::new(&user.email, self.email)
+    }
+
+    pub fn username<'a>(&self, user: &'a User) -> Sensitive<&'a str> {
+        Sensitive::new(&user.username, self.username)
+    }
+}
+
+impl User {
+    // the user's Debug output under a chosen policy, {:?} uses the default one
+    pub fn redacted(&self, policy: RedactionPolicy) -> RedactedUser<'_> {
+        RedactedUser { user: self, policy }
+    }
+}
+
+pub struct RedactedUser<'a> {
+    user: &'a User,
+    policy: RedactionPolicy
+}
+
+impl fmt::Debug for RedactedUser<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let user = self.user;
+        f.debug_struct("User")
+            .field("username", &self.policy.username(user))
+            .field("email", &self.policy.email(user))
+            .field("state", &user.state().name())
+            .field("sign_in_count", &user.sign_in_count)
+            .field("roles", &user.roles)
+            .field("credentials", &format_args!("[redacted]"))
+            .finish()
+    }
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.redacted(RedactionPolicy::default()), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+
+    #[test]
+    fn each_reveal_lets_through_only_its_part() {
+        assert_eq!(Reveal::Nothing.mask("ahus@yahoo.com"), "[redacted]");
+        assert_eq!(Reveal::EmailDomain.mask("ahus@yahoo.com"), "***@yahoo.com");
+        assert_eq!(Reveal::EmailDomain.mask("a@b@yahoo.com"), "***@yahoo.com");
+        assert_eq!(Reveal::EmailDomain.mask("ahus"), "[redacted]");
+        assert_eq!(Reveal::EmailDomain.mask("ahus@"), "[redacted]");
+        assert_eq!(Reveal::Initial.mask("Ünal"), "Ü***");
+        assert_eq!(Reveal::Initial.mask(""), "***");
+        assert_eq!(Reveal::Length.mask("Ünal"), "[redacted, 4 chars]");
+        let fingerprint = Reveal::Fingerprint.mask("AHus");
+        assert_eq!((fingerprint.len(), fingerprint.starts_with('#')), (9, true));
+        assert_eq!(fingerprint, Reveal::Fingerprint.mask("AHus"));
+        assert_ne!(fingerprint, Reveal::Fingerprint.mask("ahus"));
+    }
+
+    #[test]
+    fn sensitive_values_are_masked_however_they_are_printed() {
+        let email = Sensitive::new("ahus@yahoo.com", Reveal::EmailDomain);
+        assert_eq!(format!("{email}"), "***@yahoo.com");
+        assert_eq!(format!("{email:?}"), "Sensitive(***@yahoo.com)");
+        assert_eq!(*email.reveal(), "ahus@yahoo.com");
+    }
+
+    #[test]
+    fn debug_on_a_user_never_shows_the_email_or_the_username() {
+        let clock = ManualClock::new(100);
+        let mut user = crate::build_user("ahus.private@yahoo.com".to_string(), "AHusband".to_string()).unwrap();
+        user.activate(&clock).unwrap();
+        user.set_password_with_iterations("correct horse", 1).unwrap();
+        for text in [format!("{user:?}"), format!("{user:#?}"), format!("{:?}", vec![&user]), format!("{:?}", user.redacted(RedactionPolicy::strict()))] {
+            assert!(!text.contains("ahus.private"), "{text}");
+            assert!(!text.contains("AHusband"), "{text}");
+            assert!(!text.contains("pbkdf2"), "{text}");
+        }
+        assert_eq!(
+            format!("{user:?}"),
+            "User { username: Sensitive(A***), email: Sensitive(***@yahoo.com), state: \"active\", sign_in_count: 1, roles: [], credentials: [redacted] }"
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// gives User the roles that users/rbac.rs checks permissions against
+
+use crate::users::rbac::{check_name, Principal};
+use crate::User;
+
+impl Principal for User {
+    fn name(&self) -> &str {
+        &self.username
+    }
+
+    fn roles(&self) -> &[String] {
+        &self.roles
+    }
+}
+
+impl User {
+    // returns false if the user already had the role
+    pub fn grant_role(&mut self, role: &str) -> Result<bool, String> {
+        check_name(role)?;
+        if self.has_role(role) {
+            return Ok(false);
+        }
+        self.roles.push(role.to_string());
+        self.roles.sort();
+        Ok(true)
+    }
+
+    // returns false if the user did not have the role
+    pub fn revoke_role(&mut self, role: &str) -> bool {
+        let before = self.roles.len();
+        self.roles.retain(|r| r != role);
+        self.roles.len() != before
+    }
+
+    // only the roles granted directly, a policy decides what they inherit
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
@@ -0,0 +1,474 @@
+// finding users by what support staff type, which is often part of a name or a misspelling of one
+// SearchIndex keeps a trie of lowercased usernames and another of emails, answering
+//     exact               the text exactly as stored
+//     case-insensitive    the same text in any case
+//     prefix              the start of a username or email, shortest completions first
+//     fuzzy               within an edit distance, counting a swap of two neighbouring letters as one edit
+// suggest runs all four and ranks the results in that order
+// IndexedUserStore wraps any UserStore and updates its index on every insert, update and delete
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use crate::users::store::{StoreError, UserStore};
+use crate::User;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    Username,
+    Email
+}
+
+// listed from best to worst, the derived Ord ranks them that way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchKind {
+    Exact,
+    CaseInsensitive,
+    Prefix,
+    Fuzzy { distance: usize },  // the whole username or email is this many edits away
+    FuzzyPrefix { distance: usize }  // the start of it is this many edits away
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub username: String,
+    pub field: Field,
+    pub matched: String,  // the username or email that matched, as stored
+    pub kind: MatchKind
+}
+
+impl Ord for Suggestion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.kind
+            .cmp(&other.kind)
+            .then(self.field.cmp(&other.field))
+            .then(self.matched.len().cmp(&other.matched.len()))
+            .then(self.username.cmp(&other.username))
+    }
+}
+
+impl PartialOrd for Suggestion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: BTreeMap<char, TrieNode>,
+    owners: BTreeMap<String, String>  // username -> the text as stored, for every user whose lowercased text ends here
+}
+
+impl TrieNode {
+    fn find(&self, key: &str) -> Option<&TrieNode> {
+        key.chars().try_fold(self, |node, c| node.children.get(&c))
+    }
+
+    fn insert(&mut self, key: &str, username: &str, text: &str) {
+        let node = key.chars().fold(self, |node, c| node.children.entry(c).or_default());
+        node.owners.insert(username.to_string(), text.to_string());
+    }
+
+    // removes the entry and any nodes left without owners or children, returns whether this node is now empty
+    fn remove(&mut self, mut key: std::str::Chars, username: &str) -> bool {
+        match key.next() {
+            None => {
+                self.owners.remove(username);
+            }
+            Some(c) => {
+                if self.children.get_mut(&c).is_some_and(|child| child.remove(key, username)) {
+                    self.children.remove(&c);
+                }
+            }
+        }
+        self.owners.is_empty() && self.children.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    usernames: TrieNode,
+    emails: TrieNode,
+    len: usize
+}
+
+// the distances worth trying for a query of this length, a one letter query cannot be misspelled usefully
+pub fn default_max_distance(query: &str) -> usize {
+    match query.chars().count() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2
+    }
+}
+
+// the same optimal string alignment distance the fuzzy lookup uses, for comparing two strings directly
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            row[j] = (rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1])).min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        SearchIndex::default()
+    }
+
+    pub fn build(store: &dyn UserStore) -> Self {
+        let mut index = SearchIndex::new();
+        for user in store.list() {
+            index.insert(user);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, user: &User) {
+        self.usernames.insert(&user.username.to_lowercase(), &user.username, &user.username);
+        self.emails.insert(&user.email.to_lowercase(), &user.username, &user.email);
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, user: &User) {
+        self.usernames.remove(user.username.to_lowercase().chars(), &user.username);
+        self.emails.remove(user.email.to_lowercase().chars(), &user.username);
+        self.len = self.len.saturating_sub(1);
+    }
+
+    fn trie(&self, field: Field) -> &TrieNode {
+        match field {
+            Field::Username => &self.usernames,
+            Field::Email => &self.emails
+        }
+    }
+
+    // usernames whose username or email is exactly the query
+    pub fn exact(&self, query: &str) -> Vec<Suggestion> {
+        let mut found = self.case_insensitive(query);
+        found.retain(|s| s.matched == query);
+        for s in &mut found {
+            s.kind = MatchKind::Exact;
+        }
+        found
+    }
+
+    pub fn case_insensitive(&self, query: &str) -> Vec<Suggestion> {
+        let key = query.to_lowercase();
+        let mut found = Vec::new();
+        for field in [Field::Username, Field::Email] {
+            if let Some(node) = self.trie(field).find(&key) {
+                found.extend(node.owners.iter().map(|(username, text)| Suggestion {
+                    username: username.clone(),
+                    field,
+                    matched: text.clone(),
+                    kind: MatchKind::CaseInsensitive
+                }));
+            }
+        }
+        found
+    }
+
+    // case-insensitive, breadth first so the shortest completions come back first, at most limit per field
+    pub fn prefix(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        let key = query.to_lowercase();
+        let mut found = Vec::new();
+        for field in [Field::Username, Field::Email] {
+            let Some(start) = self.trie(field).find(&key) else { continue };
+            let mut taken = 0;
+            let mut queue = VecDeque::from([start]);
+            'walk: while let Some(node) = queue.pop_front() {
+                for (username, text) in &node.owners {
+                    if taken == limit {
+                        break 'walk;
+                    }
+                    found.push(Suggestion { username: username.clone(), field, matched: text.clone(), kind: MatchKind::Prefix });
+                    taken += 1;
+                }
+                queue.extend(node.children.values());
+            }
+        }
+        found
+    }
+
+    // every username or email within max_distance edits of the query, whole or as a prefix
+    // the trie is walked with one row of the edit distance table per letter, and a branch is left
+    // as soon as every entry in its row is over the limit, so the cost depends on max_distance more than on the user count
+    pub fn fuzzy(&self, query: &str, max_distance: usize) -> Vec<Suggestion> {
+        let query: Vec<char> = query.to_lowercase().chars().collect();
+        let first_row: Vec<usize> = (0..=query.len()).collect();
+        let mut found = Vec::new();
+        for field in [Field::Username, Field::Email] {
+            let mut walk = FuzzyWalk { query: &query, max: max_distance, field, found: &mut found };
+            for (c, child) in &self.trie(field).children {
+                walk.step(child, *c, None, &first_row, &[], first_row[query.len()]);
+            }
+        }
+        found
+    }
+
+    // everything above ranked best first, each user listed once under its best match
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let mut all = self.exact(query);
+        all.extend(self.case_insensitive(query));
+        all.extend(self.prefix(query, limit));
+        all.extend(self.fuzzy(query, default_max_distance(query)));
+        all.sort();
+        let mut seen = HashSet::new();
+        all.retain(|s| seen.insert(s.username.clone()));
+        all.truncate(limit);
+        all
+    }
+}
+
+struct FuzzyWalk<'a> {
+    query: &'a [char],
+    max: usize,
+    field: Field,
+    found: &'a mut Vec<Suggestion>
+}
+
+impl FuzzyWalk<'_> {
+    // prefix_best is the smallest distance between the whole query and any prefix on the path so far
+    fn step(&mut self, node: &TrieNode, c: char, previous_char: Option<char>, previous_row: &[usize], before_previous_row: &[usize], prefix_best: usize) {
+        let n = self.query.len();
+        let mut row = vec![previous_row[0] + 1; n + 1];
+        for j in 1..=n {
+            let substitution = previous_row[j - 1] + usize::from(self.query[j - 1] != c);
+            row[j] = substitution.min(previous_row[j] + 1).min(row[j - 1] + 1);
+            // optimal string alignment: two neighbouring letters swapped count as a single edit
+            if j > 1 && previous_char == Some(self.query[j - 1]) && self.query[j - 2] == c {
+                row[j] = row[j].min(before_previous_row[j - 2] + 1);
+            }
+        }
+        let prefix_best = prefix_best.min(row[n]);
+
+        for (username, text) in &node.owners {
+            let kind = if row[n] <= self.max {
+                MatchKind::Fuzzy { distance: row[n] }
+            } else if prefix_best <= self.max {
+                MatchKind::FuzzyPrefix { distance: prefix_best }
+            } else {
+                continue;
+            };
+            self.found.push(Suggestion { username: username.clone(), field: self.field, matched: text.clone(), kind });
+        }
+
+        if row.iter().min().is_some_and(|&m| m <= self.max) {
+            for (next, child) in &node.children {
+                self.step(child, *next, Some(c), &row, previous_row, prefix_best);
+            }
+        } else if prefix_best <= self.max {
+            // nothing further down can come closer as a whole, but all of it still starts with a near match
+            self.collect(node, prefix_best);
+        }
+    }
+
+    fn collect(&mut self, node: &TrieNode, distance: usize) {
+        for child in node.children.values() {
+            for (username, text) in &child.owners {
+                self.found.push(Suggestion { username: username.clone(), field: self.field, matched: text.clone(), kind: MatchKind::FuzzyPrefix { distance } });
+            }
+            self.collect(child, distance);
+        }
+    }
+}
+
+// a UserStore that keeps a SearchIndex of the users in another store
+pub struct IndexedUserStore<S: UserStore> {
+    inner: S,
+    index: SearchIndex
+}
+
+impl<S: UserStore> IndexedUserStore<S> {
+    pub fn new(inner: S) -> Self {
+        let index = SearchIndex::build(&inner);
+        IndexedUserStore { inner, index }
+    }
+
+    pub fn index(&self) -> &SearchIndex {
+        &self.index
+    }
+
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        self.index.suggest(query, limit)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: UserStore> UserStore for IndexedUserStore<S> {
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        let copy = user.clone();
+        self.inner.insert(user)?;
+        self.index.insert(&copy);
+        Ok(())
+    }
+
+    fn get(&self, username: &str) -> Option<&User> {
+        self.inner.get(username)
+    }
+
+    // the store keeps its own spelling of the username, so the index takes the user back from it
+    fn update(&mut self, user: User) -> Result<User, StoreError> {
+        let old = self.inner.update(user)?;
+        self.index.remove(&old);
+        if let Some(new) = self.inner.get(&old.username) {
+            self.index.insert(new);
+        }
+        Ok(old)
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        let old = self.inner.delete(username)?;
+        self.index.remove(&old);
+        Ok(old)
+    }
+
+    fn list(&self) -> Vec<&User> {
+        self.inner.list()
+    }
+
+    fn purge_history(&mut self) -> Result<(), StoreError> {
+        self.inner.purge_history()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::patch::{patch_user, AuditLog, UserPatch};
+    use crate::users::store::MemoryUserStore;
+
+    fn user(username: &str, email: &str) -> User {
+        crate::build_user(email.to_string(), username.to_string()).unwrap()
+    }
+
+    fn store(users: &[(&str, &str)]) -> IndexedUserStore<MemoryUserStore> {
+        let mut store = IndexedUserStore::new(MemoryUserStore::new());
+        for (username, email) in users {
+            store.insert(user(username, email)).unwrap();
+        }
+        store
+    }
+
+    fn usernames(found: &[Suggestion]) -> Vec<&str> {
+        found.iter().map(|s| s.username.as_str()).collect()
+    }
+
+    #[test]
+    fn the_index_follows_inserts_renames_and_deletes() {
+        let mut store = store(&[("AHus", "ahus@yahoo.com"), ("bob", "bob@example.com")]);
+        assert_eq!(store.index().len(), 2);
+        assert_eq!(usernames(&store.index().exact("AHus")), ["AHus"]);
+
+        let (mut audit, clock) = (AuditLog::new(), ManualClock::new(0));
+        let rename = UserPatch { username: Some("Ahmed".to_string()), email: Some("ahmed@yahoo.com".to_string()), ..UserPatch::default() };
+        patch_user(&mut store, &mut audit, "ahus", &rename, "support", &clock).unwrap();
+        assert!(store.index().case_insensitive("ahus").is_empty());
+        assert!(store.index().case_insensitive("ahus@yahoo.com").is_empty());
+        assert_eq!(usernames(&store.index().exact("ahmed@yahoo.com")), ["Ahmed"]);
+
+        // update keeps the stored spelling, so that is what the index holds too
+        let mut bob = user("BOB", "robert@example.com");
+        bob.sign_in_count = 3;
+        store.update(bob).unwrap();
+        let found = store.index().case_insensitive("bob");
+        assert_eq!((found.len(), found[0].matched.as_str()), (1, "bob"));
+        assert!(store.index().case_insensitive("bob@example.com").is_empty());
+        assert_eq!(usernames(&store.index().exact("robert@example.com")), ["bob"]);
+
+        store.delete("BOB").unwrap();
+        assert_eq!(store.index().len(), 1);
+        assert!(store.index().prefix("b", 10).is_empty());
+        assert!(!store.index().usernames.children.contains_key(&'b'), "empty branches are pruned");
+    }
+
+    #[test]
+    fn exact_matches_keep_case_and_case_insensitive_ones_do_not() {
+        let store = store(&[("AHus", "AHus@Yahoo.com"), ("ahus2", "x@example.com")]);
+        assert!(store.index().exact("ahus").is_empty());
+        assert_eq!(usernames(&store.index().exact("AHus")), ["AHus"]);
+        let found = store.index().case_insensitive("aHUS");
+        assert_eq!((usernames(&found), found[0].field, found[0].matched.as_str()), (vec!["AHus"], Field::Username, "AHus"));
+        let found = store.index().case_insensitive("AHUS@yahoo.COM");
+        assert_eq!((usernames(&found), found[0].field, found[0].matched.as_str()), (vec!["AHus"], Field::Email, "ahus@yahoo.com"));
+    }
+
+    #[test]
+    fn prefixes_come_back_shortest_first_up_to_the_limit() {
+        let store = store(&[("annabel", "a1@example.com"), ("ann", "a2@example.com"), ("anne", "a3@example.com"), ("bob", "ann.b@example.com")]);
+        let found: Vec<Suggestion> = store.index().prefix("AN", 10).into_iter().filter(|s| s.field == Field::Username).collect();
+        assert_eq!(usernames(&found), ["ann", "anne", "annabel"]);
+        assert_eq!(usernames(&store.index().prefix("an", 2)), ["ann", "anne", "bob"]);  // two per field
+        assert!(store.index().prefix("anx", 10).is_empty());
+    }
+
+    #[test]
+    fn fuzzy_matches_stay_within_the_distance_and_count_a_swap_as_one() {
+        let store = store(&[("ahus", "h@example.com"), ("hamish", "m@example.com"), ("ahmed", "d@example.com")]);
+        let fuzzy = |query: &str, max: usize| -> Vec<(String, MatchKind)> {
+            let mut found: Vec<(String, MatchKind)> = store.index().fuzzy(query, max).into_iter().filter(|s| s.field == Field::Username).map(|s| (s.username, s.kind)).collect();
+            found.sort();
+            found
+        };
+        assert_eq!(fuzzy("ahsu", 1), [("ahus".to_string(), MatchKind::Fuzzy { distance: 1 })]);
+        assert_eq!(fuzzy("ahsu", 0), []);
+        assert_eq!(fuzzy("ahme", 1), [("ahmed".to_string(), MatchKind::Fuzzy { distance: 1 })]);
+        assert_eq!(fuzzy("hami", 0), [("hamish".to_string(), MatchKind::FuzzyPrefix { distance: 0 })]);
+
+        // every distance the walk reports is the real one, and nothing within reach is missed
+        for query in ["ahsu", "ahm", "hamsih", "amhed", "xyz"] {
+            for max in 0..=2 {
+                let reported: Vec<(String, MatchKind)> = fuzzy(query, max).into_iter().filter(|(_, k)| matches!(k, MatchKind::Fuzzy { .. })).collect();
+                let expected: Vec<(String, MatchKind)> = ["ahmed", "ahus", "hamish"]
+                    .iter()
+                    .map(|u| (u.to_string(), edit_distance(query, u)))
+                    .filter(|(_, d)| *d <= max)
+                    .map(|(u, distance)| (u, MatchKind::Fuzzy { distance }))
+                    .collect();
+                assert_eq!(reported, expected, "{query} within {max}");
+            }
+        }
+        assert_eq!(edit_distance("ca", "abc"), 3);  // optimal string alignment does not edit a swapped pair again
+    }
+
+    #[test]
+    fn suggestions_rank_exact_then_case_then_prefix_then_fuzzy() {
+        let store = store(&[("Ann", "x@example.com"), ("Ann2", "a2@example.com"), ("anna", "a3@example.com"), ("amn", "y@example.com")]);
+        let ranked = |query: &str| -> Vec<(String, MatchKind)> { store.suggest(query, 10).into_iter().map(|s| (s.username, s.kind)).collect() };
+        let expected = |first: MatchKind| vec![
+            ("Ann".to_string(), first),
+            ("Ann2".to_string(), MatchKind::Prefix),  // the same length, so they go by username
+            ("anna".to_string(), MatchKind::Prefix),
+            ("amn".to_string(), MatchKind::Fuzzy { distance: 1 })
+        ];
+        assert_eq!(ranked("Ann"), expected(MatchKind::Exact));
+        assert_eq!(ranked("ann"), expected(MatchKind::CaseInsensitive));
+        assert_eq!(store.suggest("ann", 2).len(), 2);
+        assert!(store.suggest("  ", 10).is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+// stateless session tokens, handed out after a successful sign-in and sent back with every request
+//
+// a token is key_id "." base64url(payload) "." base64url(HMAC-SHA256(key, payload)), the payload being
+//     session_id \n username \n issued_at \n expires_at \n roles joined with ',' \n generation
+// every part is URL-safe, so a token can go in a header, a cookie or a query string as it is
+//
+// several keys can check tokens at once, each known by its key id, and new tokens are signed with the newest
+// rotating adds a key and signs with it from then on, tokens signed with the older keys keep working until
+// those keys are retired
+//
+// the server remembers revoked sessions, only until the token would have expired anyway
+// revoke_all goes by the second, so a token also carries how many times its username had been signed out
+// everywhere within the second it was issued, that way an account that takes over a deleted or renamed
+// username and signs in the same second is not signed out along with the old one
+// a token's roles are the ones the user had when it was issued, revoke_all the user's sessions after changing them
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::users::clock::Clock;
+use crate::users::crypto::{base64url_decode, base64url_encode, constant_time_eq, hmac_sha256, random_bytes, to_hex};
+use crate::users::json::Json;
+use crate::users::privacy::PersonalData;
+use crate::User;
+
+const KEY_LEN: usize = 32;
+const ID_LEN: usize = 16;
+const MAX_KEY_ID_LEN: usize = 32;
+const PURPOSE: &[u8] = b"session\n";  // keeps a session token from passing as any other token signed with the same key
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub roles: Vec<String>,
+    pub key_id: String,  // the key that signed the token
+    pub generation: u64  // revoke_all calls for the username in the second it was issued, before it was
+}
+
+impl Session {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("id", Json::str(&self.id)),
+            ("username", Json::str(&self.username)),
+            ("issued_at", Json::uint(self.issued_at)),
+            ("expires_at", Json::uint(self.expires_at)),
+            ("roles", Json::Array(self.roles.iter().map(|r| Json::str(r)).collect()))
+        ])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    Malformed,  // not shaped like a session token
+    UnknownKey(String),  // signed with a key that has been retired, or never existed
+    BadSignature,  // altered, or signed by someone else
+    Expired { at: u64 },
+    NotYetValid { at: u64 },  // issued further in the future than the allowed clock skew
+    Revoked,
+    InvalidKeyId(String),  // a key id that cannot go in a token
+    NoSigningKey  // every key has been retired
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Malformed => write!(f, "the session token is malformed"),
+            SessionError::UnknownKey(id) => write!(f, "the session token was signed with unknown key '{id}'"),
+            SessionError::BadSignature => write!(f, "the session token signature does not match"),
+            SessionError::Expired { at } => write!(f, "the session expired at {at}"),
+            SessionError::NotYetValid { at } => write!(f, "the session is not valid until {at}"),
+            SessionError::Revoked => write!(f, "the session has been signed out"),
+            SessionError::InvalidKeyId(id) => write!(f, "'{id}' is not a valid key id, use up to {MAX_KEY_ID_LEN} letters, digits, '_' and '-'"),
+            SessionError::NoSigningKey => write!(f, "there is no key to sign sessions with")
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+pub struct SessionManager {
+    keys: Vec<(String, Vec<u8>)>,  // key id and secret, oldest first, the last one signs
+    clock: Arc<dyn Clock + Send + Sync>,
+    pub ttl: u64,  // seconds a session lasts
+    pub leeway: u64,  // seconds of clock skew allowed between the servers sharing the keys
+    revoked: HashMap<String, u64>,  // session id -> expiry
+    revoked_before: HashMap<String, (u64, u64)>  // username -> sessions issued before this time and generation are revoked
+}
+
+impl SessionManager {
+    // keys have to be the same on every server and across restarts, or signed-in users are signed out
+    pub fn new(key_id: &str, key: &[u8], clock: impl Clock + Send + Sync + 'static) -> Result<Self, SessionError> {
+        SessionManager::with_shared_clock(key_id, key, Arc::new(clock))
+    }
+
+    // for callers that already share their clock, such as the API server
+    pub fn with_shared_clock(key_id: &str, key: &[u8], clock: Arc<dyn Clock + Send + Sync>) -> Result<Self, SessionError> {
+        check_key_id(key_id)?;
+        Ok(SessionManager {
+            keys: vec![(key_id.to_string(), key.to_vec())],
+            clock,
+            ttl: 12 * 60 * 60,
+            leeway: 60,
+            revoked: HashMap::new(),
+            revoked_before: HashMap::new()
+        })
+    }
+
+    // a manager with one random key, its sessions end when the process does
+    pub fn generate(clock: Arc<dyn Clock + Send + Sync>) -> Self {
+        SessionManager::with_shared_clock("k1", &random_bytes(KEY_LEN), clock).expect("k1 is a valid key id")
+    }
+
+    pub fn with_ttl(mut self, ttl: u64) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // adds a key that checks tokens and signs new ones, replacing a key with the same id
+    pub fn rotate(&mut self, key_id: &str, key: &[u8]) -> Result<(), SessionError> {
+        check_key_id(key_id)?;
+        self.keys.retain(|(id, _)| id != key_id);
+        self.keys.push((key_id.to_string(), key.to_vec()));
+        Ok(())
+    }
+
+    // adds a key that only checks tokens, for a key another server has started signing with
+    pub fn accept(&mut self, key_id: &str, key: &[u8]) -> Result<(), SessionError> {
+        check_key_id(key_id)?;
+        if !self.keys.iter().any(|(id, _)| id == key_id) {
+            self.keys.insert(0, (key_id.to_string(), key.to_vec()));
+        }
+        Ok(())
+    }
+
+    // drops a key, every token it signed stops working, returns false if there was no such key
+    pub fn retire(&mut self, key_id: &str) -> bool {
+        let before = self.keys.len();
+        self.keys.retain(|(id, _)| id != key_id);
+        self.keys.len() != before
+    }
+
+    pub fn key_ids(&self) -> Vec<&str> {
+        self.keys.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    pub fn signing_key_id(&self) -> Option<&str> {
+        self.keys.last().map(|(id, _)| id.as_str())
+    }
+
+    pub fn issue(&self, user: &User) -> Result<(String, Session), SessionError> {
+        let (key_id, key) = self.keys.last().ok_or(SessionError::NoSigningKey)?;
+        let now = self.clock.now();
+        let generation = match self.revoked_before.get(&user.username) {
+            Some(&(at, generation)) if at == now => generation,
+            _ => 0
+        };
+        let session = Session {
+            id: to_hex(&random_bytes(ID_LEN)),
+            username: user.username.clone(),
+            issued_at: now,
+            expires_at: now + self.ttl,
+            roles: user.roles.clone(),
+            key_id: key_id.clone(),
+            generation
+        };
+        let payload = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            session.id,
+            session.username,
+            session.issued_at,
+            session.expires_at,
+            session.roles.join(","),
+            session.generation
+        );
+        let mac = sign(key_id, key, payload.as_bytes());
+        Ok((format!("{key_id}.{}.{}", base64url_encode(payload.as_bytes()), base64url_encode(&mac)), session))
+    }
+
+    // checks the signature and reads the session, without looking at the time or the revocation list
+    pub fn decode(&self, token: &str) -> Result<Session, SessionError> {
+        let mut parts = token.trim().split('.');
+        let (Some(key_id), Some(payload), Some(mac), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(SessionError::Malformed);
+        };
+        let (_, key) = self.keys.iter().find(|(id, _)| id == key_id).ok_or_else(|| SessionError::UnknownKey(key_id.to_string()))?;
+        let payload = base64url_decode(payload).ok_or(SessionError::Malformed)?;
+        let mac = base64url_decode(mac).ok_or(SessionError::Malformed)?;
+        if !constant_time_eq(&mac, &sign(key_id, key, &payload)) {
+            return Err(SessionError::BadSignature);
+        }
+        let payload = String::from_utf8(payload).map_err(|_| SessionError::Malformed)?;
+        let fields: Vec<&str> = payload.split('\n').collect();
+        // tokens from before the generation was added have none, they count as generation 0
+        let (id, username, issued_at, expires_at, roles, generation) = match fields.as_slice() {
+            [id, username, issued_at, expires_at, roles] => (id, username, issued_at, expires_at, roles, "0"),
+            [id, username, issued_at, expires_at, roles, generation] => (id, username, issued_at, expires_at, roles, *generation),
+            _ => return Err(SessionError::Malformed)
+        };
+        Ok(Session {
+            id: id.to_string(),
+            username: username.to_string(),
+            issued_at: issued_at.parse().map_err(|_| SessionError::Malformed)?,
+            expires_at: expires_at.parse().map_err(|_| SessionError::Malformed)?,
+            roles: roles.split(',').filter(|r| !r.is_empty()).map(String::from).collect(),
+            key_id: key_id.to_string(),
+            generation: generation.parse().map_err(|_| SessionError::Malformed)?
+        })
+    }
+
+    // the session a token stands for, as long as it is genuine, current and not revoked
+    pub fn verify(&self, token: &str) -> Result<Session, SessionError> {
+        let session = self.decode(token)?;
+        let now = self.clock.now();
+        if now >= session.expires_at {
+            return Err(SessionError::Expired { at: session.expires_at });
+        }
+        if session.issued_at > now + self.leeway {
+            return Err(SessionError::NotYetValid { at: session.issued_at });
+        }
+        if self.is_revoked(&session) {
+            return Err(SessionError::Revoked);
+        }
+        Ok(session)
+    }
+
+    // signs one session out, the token has to be genuine but may already have expired
+    pub fn revoke(&mut self, token: &str) -> Result<Session, SessionError> {
+        let session = self.decode(token)?;
+        self.revoked.insert(session.id.clone(), session.expires_at);
+        Ok(session)
+    }
+
+    // signs the user out everywhere, including sessions issued earlier in the same second but not later ones
+    pub fn revoke_all(&mut self, username: &str) {
+        let now = self.clock.now();
+        let entry = self.revoked_before.entry(username.to_string()).or_insert((now, 0));
+        *entry = if entry.0 == now { (now, entry.1 + 1) } else { (now, 1) };
+    }
+
+    pub fn is_revoked(&self, session: &Session) -> bool {
+        self.revoked.contains_key(&session.id)
+            || self.revoked_before.get(&session.username).is_some_and(|&before| (session.issued_at, session.generation) < before)
+    }
+
+    // forgets revocations for sessions that have expired anyway, call it now and then
+    pub fn prune(&mut self) {
+        let now = self.clock.now();
+        self.revoked.retain(|_, expires_at| now < *expires_at);
+        let ttl = self.ttl;
+        self.revoked_before.retain(|_, (t, _)| now < *t + ttl);
+    }
+}
+
+// pass the manager to erase_subject alongside the other sources so an erased user is signed out everywhere
+// tokens are not kept, so there is nothing to export
+impl PersonalData for SessionManager {
+    fn name(&self) -> &str {
+        "sessions"
+    }
+
+    fn export(&self, _subject: &User) -> Json {
+        Json::Null
+    }
+
+    fn erase(&mut self, subject: &User, _pseudonym: &str, _email: &str) -> Result<usize, String> {
+        self.revoke_all(&subject.username);
+        Ok(1)
+    }
+
+    // revocations name the old username until every token carrying it has expired and prune drops them,
+    // forgetting it any sooner would let those tokens back in, so they are not counted as left behind
+    fn all_identifiers(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// the key id is signed too, so a token cannot be moved over to a different key
+fn sign(key_id: &str, key: &[u8], payload: &[u8]) -> [u8; 32] {
+    hmac_sha256(key, &[PURPOSE, key_id.as_bytes(), b"\n", payload].concat())
+}
+
+fn check_key_id(key_id: &str) -> Result<(), SessionError> {
+    let valid = !key_id.is_empty() && key_id.len() <= MAX_KEY_ID_LEN && key_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid { Ok(()) } else { Err(SessionError::InvalidKeyId(key_id.to_string())) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+
+    fn manager(start: u64) -> (SessionManager, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(start));
+        let manager = SessionManager::with_shared_clock("k1", b"first key", clock.clone()).unwrap().with_ttl(3600);
+        (manager, clock)
+    }
+
+    fn user(name: &str) -> User {
+        let mut user = crate::build_user(format!("{name}@example.com"), name.to_string()).unwrap();
+        user.roles = vec!["admin".to_string(), "server".to_string()];
+        user
+    }
+
+    // swaps one base64url character of the given part for another that decodes differently
+    fn tamper(token: &str, part: usize) -> String {
+        let mut parts: Vec<String> = token.split('.').map(String::from).collect();
+        let first = parts[part].remove(0);
+        parts[part].insert(0, if first == 'A' { 'B' } else { 'A' });
+        parts.join(".")
+    }
+
+    #[test]
+    fn a_token_reads_back_as_the_session_it_was_issued_for() {
+        let (manager, _) = manager(1000);
+        let (token, issued) = manager.issue(&user("ann")).unwrap();
+        let session = manager.verify(&token).unwrap();
+        assert_eq!(session, issued);
+        assert_eq!(session.username, "ann");
+        assert_eq!((session.issued_at, session.expires_at), (1000, 4600));
+        assert!(session.has_role("server") && !session.has_role("owner"));
+        assert_eq!(session.key_id, "k1");
+        assert!(token.starts_with("k1."));
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let (manager, _) = manager(1000);
+        let (token, _) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(manager.verify(&tamper(&token, 1)), Err(SessionError::BadSignature));
+        assert_eq!(manager.verify(&tamper(&token, 2)), Err(SessionError::BadSignature));
+        assert_eq!(manager.verify(&token.replacen("k1", "k2", 1)), Err(SessionError::UnknownKey("k2".to_string())));
+        assert_eq!(manager.verify("k1.abc"), Err(SessionError::Malformed));
+        assert_eq!(manager.verify(&format!("{token}.x")), Err(SessionError::Malformed));
+        assert_eq!(manager.verify("k1.!!.!!"), Err(SessionError::Malformed));
+
+        // the same payload signed with another key does not pass either
+        let other = SessionManager::new("k1", b"someone else's key", ManualClock::new(1000)).unwrap();
+        let (forged, _) = other.issue(&user("ann")).unwrap();
+        assert_eq!(manager.verify(&forged), Err(SessionError::BadSignature));
+    }
+
+    #[test]
+    fn tokens_without_a_generation_still_verify() {
+        let (manager, _) = manager(1000);
+        let payload = "0123\nann\n1000\n4600\nadmin";
+        let key = &manager.keys[0].1;
+        let token = format!("k1.{}.{}", base64url_encode(payload.as_bytes()), base64url_encode(&sign("k1", key, payload.as_bytes())));
+        let session = manager.verify(&token).unwrap();
+        assert_eq!((session.username.as_str(), session.generation), ("ann", 0));
+    }
+
+    #[test]
+    fn a_session_expires_after_its_ttl() {
+        let (manager, clock) = manager(1000);
+        let (token, _) = manager.issue(&user("ann")).unwrap();
+        clock.set(4599);
+        assert!(manager.verify(&token).is_ok());
+        clock.set(4600);
+        assert_eq!(manager.verify(&token), Err(SessionError::Expired { at: 4600 }));
+    }
+
+    #[test]
+    fn a_token_from_the_future_is_only_accepted_within_the_leeway() {
+        let (manager, clock) = manager(1000);
+        let (token, _) = manager.issue(&user("ann")).unwrap();
+        clock.set(940);
+        assert!(manager.verify(&token).is_ok());
+        clock.set(939);
+        assert_eq!(manager.verify(&token), Err(SessionError::NotYetValid { at: 1000 }));
+    }
+
+    #[test]
+    fn rotated_keys_sign_and_retired_keys_stop_checking() {
+        let (mut manager, _) = manager(1000);
+        let (old, _) = manager.issue(&user("ann")).unwrap();
+        manager.rotate("k2", b"second key").unwrap();
+        assert_eq!(manager.signing_key_id(), Some("k2"));
+        let (new, session) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(session.key_id, "k2");
+        assert!(manager.verify(&old).is_ok() && manager.verify(&new).is_ok());
+
+        // a key accepted from another server checks tokens but does not sign
+        manager.accept("k0", b"other server").unwrap();
+        assert_eq!(manager.key_ids(), ["k0", "k1", "k2"]);
+        assert_eq!(manager.signing_key_id(), Some("k2"));
+
+        assert!(manager.retire("k1"));
+        assert!(!manager.retire("k1"));
+        assert_eq!(manager.verify(&old), Err(SessionError::UnknownKey("k1".to_string())));
+        assert!(manager.verify(&new).is_ok());
+
+        assert!(manager.retire("k0") && manager.retire("k2"));
+        assert_eq!(manager.issue(&user("ann")).err(), Some(SessionError::NoSigningKey));
+        assert_eq!(manager.rotate("not a key id", b"x"), Err(SessionError::InvalidKeyId("not a key id".to_string())));
+        assert!(manager.accept(&"k".repeat(MAX_KEY_ID_LEN + 1), b"x").is_err());
+    }
+
+    #[test]
+    fn revoke_signs_out_one_session() {
+        let (mut manager, _) = manager(1000);
+        let (first, _) = manager.issue(&user("ann")).unwrap();
+        let (second, _) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(manager.revoke(&first).unwrap().username, "ann");
+        assert_eq!(manager.verify(&first), Err(SessionError::Revoked));
+        assert!(manager.verify(&second).is_ok());
+        assert_eq!(manager.revoke(&tamper(&second, 2)), Err(SessionError::BadSignature));
+    }
+
+    #[test]
+    fn revoke_all_signs_out_every_earlier_session_of_that_user() {
+        let (mut manager, clock) = manager(1000);
+        let (earlier, _) = manager.issue(&user("ann")).unwrap();
+        clock.advance(5);
+        let (same_second, _) = manager.issue(&user("ann")).unwrap();
+        let (bob, _) = manager.issue(&user("bob")).unwrap();
+        manager.revoke_all("ann");
+        assert_eq!(manager.verify(&earlier), Err(SessionError::Revoked));
+        assert_eq!(manager.verify(&same_second), Err(SessionError::Revoked));
+        assert!(manager.verify(&bob).is_ok());
+
+        // signing in again afterwards works, within the same second and later
+        let (again, session) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(session.generation, 1);
+        assert!(manager.verify(&again).is_ok());
+        clock.advance(1);
+        let (later, session) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(session.generation, 0);
+        assert!(manager.verify(&later).is_ok());
+
+        // a second revoke_all in the same second takes the sessions issued between the two with it
+        manager.revoke_all("ann");
+        let (between, _) = manager.issue(&user("ann")).unwrap();
+        manager.revoke_all("ann");
+        assert_eq!(manager.verify(&between), Err(SessionError::Revoked));
+        assert!(manager.verify(&manager.issue(&user("ann")).unwrap().0).is_ok());
+    }
+
+    #[test]
+    fn a_new_account_reusing_a_name_in_the_same_second_is_not_signed_out() {
+        let (mut manager, _) = manager(1000);
+        let (old, _) = manager.issue(&user("ann")).unwrap();
+        // the account is renamed or deleted, then someone else signs up as ann and signs in straight away
+        manager.revoke_all("ann");
+        let (new, _) = manager.issue(&user("ann")).unwrap();
+        assert_eq!(manager.verify(&old), Err(SessionError::Revoked));
+        assert!(manager.verify(&new).is_ok());
+    }
+
+    #[test]
+    fn prune_forgets_only_revocations_that_no_longer_matter() {
+        let (mut manager, clock) = manager(1000);
+        let (token, _) = manager.issue(&user("ann")).unwrap();
+        manager.revoke(&token).unwrap();
+        manager.revoke_all("bob");
+        clock.set(4599);
+        manager.prune();
+        assert_eq!((manager.revoked.len(), manager.revoked_before.len()), (1, 1));
+        assert_eq!(manager.verify(&token), Err(SessionError::Revoked));
+        clock.set(4600);
+        manager.prune();
+        assert!(manager.revoked.is_empty() && manager.revoked_before.is_empty());
+    }
+}
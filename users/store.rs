@@ -0,0 +1,457 @@
+// a UserStore keeps User values alive beyond the stack frame that built them
+// the trait lets callers work with either store without caring where the users live
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::users::json::Json;
+use crate::users::migrate::Migrations;
+use crate::users::validation::UserError;
+use crate::User;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Duplicate(String),  // a user with this username already exists
+    NotFound(String),  // no user has this username
+    Invalid(UserError),  // the user failed validation before it reached the store
+    Corrupt { line: usize, message: String },  // a log line that could not be read back
+    Io(io::Error)
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Duplicate(name) => write!(f, "username '{name}' is already taken"),
+            StoreError::NotFound(name) => write!(f, "no user named '{name}'"),
+            StoreError::Invalid(err) => write!(f, "{err}"),
+            StoreError::Corrupt { line, message } => write!(f, "corrupt store record on line {line}: {message}"),
+            StoreError::Io(err) => write!(f, "store i/o error: {err}")
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<UserError> for StoreError {
+    fn from(err: UserError) -> Self {
+        StoreError::Invalid(err)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+pub trait UserStore {
+    fn insert(&mut self, user: User) -> Result<(), StoreError>;  // fails with Duplicate if the username is taken in any case
+    fn get(&self, username: &str) -> Option<&User>;  // in any case, "ann" finds "Ann"
+    // replaces the user with the same username in any case, returns the old value
+    // the stored spelling is kept, patch_user is how the case of a username changes
+    fn update(&mut self, user: User) -> Result<User, StoreError>;
+    fn delete(&mut self, username: &str) -> Result<User, StoreError>;  // in any case, returns the removed user
+    fn list(&self) -> Vec<&User>;  // every user, ordered by username
+    // drops any copies of old values the store still keeps, such as superseded lines in a log file
+    // erasure calls this so removed personal data does not linger on disk
+    fn purge_history(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+// "AHus" and "ahus" would be two accounts that look like one, so a username is taken, and found, whatever its case
+// usernames are ASCII (see validate_username), each store maps the lowercased ones to the spelling it holds, a log
+// written before this was checked can hold two, and then the exact spelling wins
+#[derive(Debug, Default)]
+struct FoldedNames(BTreeMap<String, BTreeSet<String>>);
+
+impl FoldedNames {
+    fn of<'a>(usernames: impl Iterator<Item = &'a String>) -> Self {
+        let mut folded = FoldedNames::default();
+        usernames.for_each(|username| folded.add(username));
+        folded
+    }
+
+    fn contains(&self, username: &str) -> bool {
+        self.0.contains_key(&username.to_ascii_lowercase())
+    }
+
+    // the stored spelling of username
+    fn resolve(&self, username: &str) -> Option<&str> {
+        let spellings = self.0.get(&username.to_ascii_lowercase())?;
+        spellings.get(username).or_else(|| spellings.first()).map(String::as_str)
+    }
+
+    fn add(&mut self, username: &str) {
+        self.0.entry(username.to_ascii_lowercase()).or_default().insert(username.to_string());
+    }
+
+    fn remove(&mut self, username: &str) {
+        let folded = username.to_ascii_lowercase();
+        if let Some(spellings) = self.0.get_mut(&folded) {
+            spellings.remove(username);
+            if spellings.is_empty() {
+                self.0.remove(&folded);
+            }
+        }
+    }
+}
+
+// keeps everything in a BTreeMap, nothing survives the process
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: BTreeMap<String, User>,
+    folded: FoldedNames
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        if self.folded.contains(&user.username) {
+            return Err(StoreError::Duplicate(user.username));
+        }
+        self.folded.add(&user.username);
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    fn get(&self, username: &str) -> Option<&User> {
+        self.users.get(self.folded.resolve(username)?)
+    }
+
+    fn update(&mut self, mut user: User) -> Result<User, StoreError> {
+        let Some(stored) = self.folded.resolve(&user.username) else {
+            return Err(StoreError::NotFound(user.username));
+        };
+        user.username = stored.to_string();
+        let existing = self.users.get_mut(stored).expect("folded names follow the map");
+        Ok(std::mem::replace(existing, user))
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        let stored = self.folded.resolve(username).ok_or_else(|| StoreError::NotFound(username.to_string()))?.to_string();
+        self.folded.remove(&stored);
+        Ok(self.users.remove(&stored).expect("folded names follow the map"))
+    }
+
+    fn list(&self) -> Vec<&User> {
+        self.users.values().collect()
+    }
+}
+
+// once the log holds this many superseded records, and more of them than live users, it gets rewritten
+const COMPACT_THRESHOLD: usize = 1000;
+
+// an append-only JSON-lines log, one record per line:
+//     {"op":"put","user":{...}}        written by insert and update
+//     {"op":"delete","username":"..."} written by delete
+// opening the store replays the log into memory, so reads never touch the disk
+// compaction rewrites the log with a single put per live user
+pub struct FileUserStore {
+    path: PathBuf,
+    log: File,
+    users: BTreeMap<String, User>,
+    folded: FoldedNames,
+    stale: usize  // records in the log that a later record has superseded
+}
+
+impl FileUserStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let mut users = BTreeMap::new();
+        let mut stale = 0;
+        let mut torn_tail = false;
+
+        if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            let ends_with_newline = contents.is_empty() || contents.ends_with('\n');
+            let lines: Vec<&str> = contents.lines().collect();
+            let migrations = Migrations::standard();
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match apply_record(&mut users, line, &migrations) {
+                    Ok(superseded) => stale += superseded,
+                    // a crash part way through an append leaves a half-written final line, which we drop
+                    Err(_) if index + 1 == lines.len() && !ends_with_newline => torn_tail = true,
+                    Err(message) => return Err(StoreError::Corrupt { line: index + 1, message })
+                }
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        let folded = FoldedNames::of(users.keys());
+        let mut store = FileUserStore { path, log, users, folded, stale };
+        if torn_tail {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // rewrites the log to a temporary file and renames it over the original, so a crash mid-way leaves the old log intact
+    pub fn compact(&mut self) -> Result<(), StoreError> {
+        let tmp = self.path.with_extension("compact");
+        {
+            let mut out = File::create(&tmp)?;
+            for user in self.users.values() {
+                writeln!(out, "{}", put_record(user))?;
+            }
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.log = OpenOptions::new().append(true).open(&self.path)?;
+        self.stale = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, record: Json) -> Result<(), StoreError> {
+        writeln!(self.log, "{record}")?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    // called after memory has caught up with the log, compacting earlier would write out the old state
+    fn maybe_compact(&mut self) -> Result<(), StoreError> {
+        if self.stale > COMPACT_THRESHOLD && self.stale > self.users.len() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        if self.folded.contains(&user.username) {
+            return Err(StoreError::Duplicate(user.username));
+        }
+        // write to disk first, so memory never holds a user the log does not
+        self.append(put_record(&user))?;
+        self.folded.add(&user.username);
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    fn get(&self, username: &str) -> Option<&User> {
+        self.users.get(self.folded.resolve(username)?)
+    }
+
+    fn update(&mut self, mut user: User) -> Result<User, StoreError> {
+        let Some(stored) = self.folded.resolve(&user.username) else {
+            return Err(StoreError::NotFound(user.username));
+        };
+        user.username = stored.to_string();
+        self.append(put_record(&user))?;
+        self.stale += 1;
+        let old = self.users.insert(user.username.clone(), user).expect("folded names follow the map");
+        self.maybe_compact()?;
+        Ok(old)
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        let stored = self.folded.resolve(username).ok_or_else(|| StoreError::NotFound(username.to_string()))?.to_string();
+        self.append(Json::object(vec![("op", Json::str("delete")), ("username", Json::str(&stored))]))?;
+        self.stale += 2;  // both the put being removed and the delete record itself are now dead weight
+        let old = self.users.remove(&stored).expect("folded names follow the map");
+        self.folded.remove(&stored);
+        self.maybe_compact()?;
+        Ok(old)
+    }
+
+    fn list(&self) -> Vec<&User> {
+        self.users.values().collect()
+    }
+
+    fn purge_history(&mut self) -> Result<(), StoreError> {
+        self.compact()
+    }
+}
+
+fn put_record(user: &User) -> Json {
+    Json::object(vec![("op", Json::str("put")), ("user", user.to_json())])
+}
+
+// replays one log line, returning how many earlier records it made stale
+fn apply_record(users: &mut BTreeMap<String, User>, line: &str, migrations: &Migrations) -> Result<usize, String> {
+    let record = Json::parse(line).map_err(|e| e.to_string())?;
+    match record.get("op").and_then(Json::as_str) {
+        Some("put") => {
+            let user = User::from_json_with(record.get("user").ok_or("put record without a user")?, migrations)?;
+            Ok(users.insert(user.username.clone(), user).map_or(0, |_| 1))
+        }
+        Some("delete") => {
+            let username = record.get("username").and_then(Json::as_str).ok_or("delete record without a username")?;
+            Ok(users.remove(username).map_or(1, |_| 2))
+        }
+        _ => Err("unknown op".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str) -> User {
+        crate::build_user(format!("{}@example.com", username.to_lowercase()), username.to_string()).unwrap()
+    }
+
+    #[test]
+    fn usernames_differing_only_in_case_are_one_account() {
+        let mut store = MemoryUserStore::new();
+        store.insert(user("AHus")).unwrap();
+        assert!(matches!(store.insert(user("ahus")), Err(StoreError::Duplicate(name)) if name == "ahus"));
+        assert!(matches!(store.insert(user("AHUS")), Err(StoreError::Duplicate(_))));
+        store.delete("AHus").unwrap();
+        store.insert(user("ahus")).unwrap();
+        assert_eq!(store.list().len(), 1);
+    }
+
+    // every lookup goes through the folded name, so nothing can miss an account that insert says exists
+    fn lookups_ignore_case(store: &mut dyn UserStore) {
+        store.insert(user("Ann")).unwrap();
+        assert_eq!(store.get("ann").unwrap().username, "Ann");
+        assert_eq!(store.get("ANN").unwrap().username, "Ann");
+        let mut changed = user("aNN");
+        changed.sign_in_count = 7;
+        assert_eq!(store.update(changed).unwrap().sign_in_count, 1);
+        let stored = store.get("Ann").unwrap();
+        assert_eq!((stored.username.as_str(), stored.sign_in_count), ("Ann", 7));  // the stored spelling stays
+        assert!(matches!(store.update(user("Bob")), Err(StoreError::NotFound(_))));
+        assert_eq!(store.delete("ANN").unwrap().username, "Ann");
+        assert!(store.get("Ann").is_none());
+        assert!(matches!(store.delete("ann"), Err(StoreError::NotFound(_))));
+        store.insert(user("ann")).unwrap();
+    }
+
+    #[test]
+    fn the_memory_store_finds_updates_and_deletes_in_any_case() {
+        lookups_ignore_case(&mut MemoryUserStore::new());
+    }
+
+    #[test]
+    fn the_file_store_finds_updates_and_deletes_in_any_case() {
+        let path = std::env::temp_dir().join(format!("store-lookup-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        lookups_ignore_case(&mut FileUserStore::open(&path).unwrap());
+        let reopened = FileUserStore::open(&path).unwrap();
+        assert_eq!(reopened.list().iter().map(|u| u.username.as_str()).collect::<Vec<_>>(), vec!["ann"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_file_store_remembers_usernames_in_any_case_across_reopening() {
+        let path = std::env::temp_dir().join(format!("store-case-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        FileUserStore::open(&path).unwrap().insert(user("AHus")).unwrap();
+        let mut store = FileUserStore::open(&path).unwrap();
+        assert!(matches!(store.insert(user("ahus")), Err(StoreError::Duplicate(_))));
+        store.delete("AHus").unwrap();
+        store.insert(user("ahus")).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("store-{name}-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn the_file_store_keeps_everything_across_reopening() {
+        let path = temp_path("reopen");
+        {
+            let mut store = FileUserStore::open(&path).unwrap();
+            store.insert(user("ann")).unwrap();
+            store.insert(user("bob")).unwrap();
+            let mut ann = user("ann");
+            ann.sign_in_count = 9;
+            ann.grant_role("admin").unwrap();
+            store.update(ann).unwrap();
+            store.delete("bob").unwrap();
+            store.insert(user("cyd")).unwrap();
+        }
+        let store = FileUserStore::open(&path).unwrap();
+        assert_eq!(store.list().iter().map(|u| u.username.as_str()).collect::<Vec<_>>(), ["ann", "cyd"]);
+        let ann = store.get("ann").unwrap();
+        assert_eq!((ann.sign_in_count, ann.roles.clone()), (9, vec!["admin".to_string()]));
+        assert_eq!(ann.to_json(), FileUserStore::open(&path).unwrap().get("ANN").unwrap().to_json());
+        assert_eq!(store.path(), path.as_path());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction_leaves_one_record_per_user() {
+        let path = temp_path("compact");
+        let mut store = FileUserStore::open(&path).unwrap();
+        store.insert(user("ann")).unwrap();
+        store.insert(user("bob")).unwrap();
+        for count in 0..5 {
+            let mut ann = user("ann");
+            ann.sign_in_count = count;
+            store.update(ann).unwrap();
+        }
+        store.delete("bob").unwrap();
+        assert_eq!(lines(&path), 8);
+        store.compact().unwrap();
+        assert_eq!(lines(&path), 1);
+        // the store keeps appending to the new log
+        store.insert(user("cyd")).unwrap();
+        assert_eq!(lines(&path), 2);
+        let reopened = FileUserStore::open(&path).unwrap();
+        assert_eq!((reopened.list().len(), reopened.get("ann").unwrap().sign_in_count), (2, 4));
+
+        // enough superseded records compact the log on their own
+        for count in 0..=COMPACT_THRESHOLD as u64 {
+            let mut cyd = user("cyd");
+            cyd.sign_in_count = count;
+            store.update(cyd).unwrap();
+        }
+        assert_eq!(lines(&path), 2);
+        assert!(!path.with_extension("compact").exists());
+        assert_eq!(FileUserStore::open(&path).unwrap().get("cyd").unwrap().sign_in_count, COMPACT_THRESHOLD as u64);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_torn_last_line_is_dropped_but_a_bad_line_elsewhere_is_an_error() {
+        let path = temp_path("torn");
+        {
+            let mut store = FileUserStore::open(&path).unwrap();
+            store.insert(user("ann")).unwrap();
+            store.insert(user("bob")).unwrap();
+        }
+        let whole = fs::read_to_string(&path).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"op\":\"put\",\"user\":{{\"username\":\"cyd").unwrap();  // a crash part way through an append
+        drop(file);
+
+        let mut store = FileUserStore::open(&path).unwrap();
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);  // rewritten without the torn line
+        store.insert(user("cyd")).unwrap();
+        assert_eq!(FileUserStore::open(&path).unwrap().list().len(), 3);
+
+        // the same damage followed by a newline was written in full, so it is corruption rather than a crash
+        fs::write(&path, format!("{{\"op\":\"put\"}}\n{whole}")).unwrap();
+        assert!(matches!(FileUserStore::open(&path), Err(StoreError::Corrupt { line: 1, .. })));
+        fs::write(&path, format!("{whole}not json\n")).unwrap();
+        assert!(matches!(FileUserStore::open(&path), Err(StoreError::Corrupt { line: 3, .. })));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+// an optional second factor for sign-in: time based one-time passwords (RFC 6238) on top of HOTP (RFC 4226)
+// enrolment hands out a shared secret, an otpauth:// URI for authenticator apps and a set of single-use recovery codes
+// nothing here talks to a phone or a service, codes are computed from the secret and the clock alone
+
+use std::fmt;
+
+use crate::users::clock::Clock;
+use crate::users::crypto::{constant_time_eq, hmac_sha1, hmac_sha256, hmac_sha512, random_bytes, sha256, to_hex};
+use crate::users::json::Json;
+use crate::User;
+
+const SECRET_LEN: usize = 20;  // 160 bits, what RFC 4226 recommends
+const RECOVERY_CODES: usize = 10;
+// fewer than 6 digits is too easy to guess, and past 10 the 31 bits HOTP truncates to have no more to give
+pub const MIN_DIGITS: u32 = 6;
+pub const MAX_DIGITS: u32 = 10;
+// every period in the window is a code that works, ten either side is already 21 codes in a million for 6 digits
+pub const MAX_SKEW: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512
+}
+
+impl TotpAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "SHA1" => Some(TotpAlgorithm::Sha1),
+            "SHA256" => Some(TotpAlgorithm::Sha256),
+            "SHA512" => Some(TotpAlgorithm::Sha512),
+            _ => None
+        }
+    }
+
+    fn mac(&self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        match self {
+            TotpAlgorithm::Sha1 => hmac_sha1(key, message).to_vec(),
+            TotpAlgorithm::Sha256 => hmac_sha256(key, message).to_vec(),
+            TotpAlgorithm::Sha512 => hmac_sha512(key, message).to_vec()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TotpConfig {
+    pub algorithm: TotpAlgorithm,
+    pub digits: u32,
+    pub period: u64,  // seconds each code is valid for
+    pub skew: u64  // how many periods either side of now are also accepted, to allow for clock drift
+}
+
+// the settings every authenticator app understands
+impl Default for TotpConfig {
+    fn default() -> Self {
+        TotpConfig { algorithm: TotpAlgorithm::Sha1, digits: 6, period: 30, skew: 1 }
+    }
+}
+
+impl TotpConfig {
+    pub fn validate(&self) -> Result<(), TotpError> {
+        if !(MIN_DIGITS..=MAX_DIGITS).contains(&self.digits) {
+            return Err(TotpError::InvalidDigits(self.digits));
+        }
+        if self.period == 0 {
+            return Err(TotpError::ZeroPeriod);
+        }
+        if self.skew > MAX_SKEW {
+            return Err(TotpError::SkewTooLarge(self.skew));
+        }
+        Ok(())
+    }
+}
+
+// RFC 4226 section 5.3: HMAC the counter, pick 4 bytes using the low nibble of the last byte, keep the last `digits` digits
+pub fn hotp(secret: &[u8], counter: u64, digits: u32, algorithm: TotpAlgorithm) -> String {
+    let mac = algorithm.mac(secret, &counter.to_be_bytes());
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    let code = binary as u64 % 10u64.pow(digits);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+// RFC 6238: HOTP with the counter being the number of periods since the unix epoch
+pub fn totp(secret: &[u8], time: u64, config: &TotpConfig) -> String {
+    hotp(secret, time / config.period, config.digits, config.algorithm)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TotpError {
+    NotEnrolled,
+    InvalidCode,
+    InvalidDigits(u32),
+    ZeroPeriod,
+    SkewTooLarge(u64)
+}
+
+impl fmt::Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TotpError::NotEnrolled => write!(f, "two-factor authentication is not set up"),
+            TotpError::InvalidCode => write!(f, "the code is incorrect or has already been used"),
+            TotpError::InvalidDigits(digits) => write!(f, "codes of {digits} digits are not supported, use {MIN_DIGITS} to {MAX_DIGITS}"),
+            TotpError::ZeroPeriod => write!(f, "the period a code is valid for must be at least a second"),
+            TotpError::SkewTooLarge(skew) => write!(f, "accepting codes {skew} periods either side of now is too lenient, the most is {MAX_SKEW}")
+        }
+    }
+}
+
+impl std::error::Error for TotpError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    secret: Vec<u8>,
+    config: TotpConfig,
+    confirmed: bool,  // only a confirmed secret is asked for at sign-in, so a half finished enrolment cannot lock anyone out
+    last_step: Option<u64>,  // the period of the last accepted code, a code cannot be replayed within its window
+    recovery_codes: Vec<String>  // SHA-256 hex of each unused code, the codes themselves are only shown once
+}
+
+// what the user is shown when they enrol, none of it is stored in this form
+pub struct Enrolment {
+    pub secret: String,  // base32, for typing into an app by hand
+    pub uri: String,  // otpauth://totp/..., usually shown as a QR code
+    pub recovery_codes: Vec<String>
+}
+
+impl Totp {
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
+
+    // accepts a code from any period within the skew window that is later than the last accepted one
+    pub fn verify(&mut self, code: &str, now: u64) -> bool {
+        let code = code.trim();
+        let current = now / self.config.period;
+        let first = current.saturating_sub(self.config.skew);
+        for step in first..=current.saturating_add(self.config.skew) {
+            if self.last_step.is_some_and(|last| step <= last) {
+                continue;
+            }
+            let expected = hotp(&self.secret, step, self.config.digits, self.config.algorithm);
+            if constant_time_eq(expected.as_bytes(), code.as_bytes()) {
+                self.last_step = Some(step);
+                return true;
+            }
+        }
+        false
+    }
+
+    // a matching recovery code is removed, so each one works exactly once
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hashed = hash_recovery_code(code);
+        match self.recovery_codes.iter().position(|c| *c == hashed) {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            }
+            None => false
+        }
+    }
+
+    // replaces every recovery code, returning the new ones to show to the user
+    pub fn regenerate_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| new_recovery_code()).collect();
+        self.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        codes
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("secret", Json::String(base32_encode(&self.secret))),
+            ("algorithm", Json::str(self.config.algorithm.name())),
+            ("digits", Json::uint(self.config.digits as u64)),
+            ("period", Json::uint(self.config.period)),
+            ("skew", Json::uint(self.config.skew)),
+            ("confirmed", Json::Bool(self.confirmed)),
+            ("last_step", self.last_step.map_or(Json::Null, Json::uint)),
+            ("recovery_codes", Json::Array(self.recovery_codes.iter().map(|c| Json::str(c)).collect()))
+        ])
+    }
+
+    pub fn from_json(value: &Json) -> Result<Self, String> {
+        let number = |key: &str| value.get(key).and_then(Json::as_u64).ok_or(format!("totp field '{key}' is missing"));
+        let secret = value.get("secret").and_then(Json::as_str).and_then(base32_decode).ok_or("invalid totp secret")?;
+        let algorithm = value.get("algorithm").and_then(Json::as_str).and_then(TotpAlgorithm::from_name).ok_or("invalid totp algorithm")?;
+        let codes = value.get("recovery_codes").and_then(Json::as_array).map_or(&[][..], |v| v);
+        let config = TotpConfig { algorithm, digits: (number("digits")? as u32).clamp(MIN_DIGITS, MAX_DIGITS), period: number("period")?.max(1), skew: number("skew")? };
+        config.validate().map_err(|e| e.to_string())?;
+        Ok(Totp {
+            secret,
+            config,
+            confirmed: value.get("confirmed").and_then(Json::as_bool).unwrap_or(false),
+            last_step: value.get("last_step").and_then(Json::as_u64),
+            recovery_codes: codes.iter().filter_map(Json::as_str).map(String::from).collect()
+        })
+    }
+}
+
+impl User {
+    pub fn has_totp(&self) -> bool {
+        self.credentials.totp().is_some_and(Totp::is_confirmed)
+    }
+
+    // starts enrolment with a fresh secret, replacing any earlier one
+    // the second factor is not asked for at sign-in until confirm_totp has seen a working code
+    pub fn enrol_totp(&mut self, issuer: &str, config: TotpConfig) -> Result<Enrolment, TotpError> {
+        config.validate()?;
+        let secret = random_bytes(SECRET_LEN);
+        let mut totp = Totp { secret, config, confirmed: false, last_step: None, recovery_codes: Vec::new() };
+        let recovery_codes = totp.regenerate_recovery_codes();
+        let enrolment = Enrolment {
+            secret: base32_encode(&totp.secret),
+            uri: provisioning_uri(issuer, &self.username, &totp),
+            recovery_codes
+        };
+        self.credentials.set_totp(Some(totp));
+        Ok(enrolment)
+    }
+
+    pub fn confirm_totp(&mut self, code: &str, clock: &dyn Clock) -> Result<(), TotpError> {
+        let totp = self.credentials.totp_mut().ok_or(TotpError::NotEnrolled)?;
+        if !totp.verify(code, clock.now()) {
+            return Err(TotpError::InvalidCode);
+        }
+        totp.confirmed = true;
+        Ok(())
+    }
+
+    pub fn disable_totp(&mut self) {
+        self.credentials.set_totp(None);
+    }
+}
+
+// the Key Uri Format authenticator apps read from a QR code
+// otpauth://totp/Issuer:username?secret=...&issuer=Issuer&algorithm=SHA1&digits=6&period=30
+fn provisioning_uri(issuer: &str, username: &str, totp: &Totp) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(username),
+        base32_encode(&totp.secret),
+        percent_encode(issuer),
+        totp.config.algorithm.name(),
+        totp.config.digits,
+        totp.config.period
+    )
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}")
+        })
+        .collect()
+}
+
+// ten lowercase base32 characters split as "xxxxx-xxxxx", easy to read out and type
+fn new_recovery_code() -> String {
+    let code = base32_encode(&random_bytes(7)).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+// case, spaces and dashes are ignored so "ABCDE FGHIJ" matches "abcde-fghij"
+fn hash_recovery_code(code: &str) -> String {
+    let normalised: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect();
+    to_hex(&sha256(normalised.as_bytes()))
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 base32 without padding, the form otpauth URIs use
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+// accepts lowercase, spaces and '=' padding, since people copy secrets by hand
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET.iter().position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, 8 digit codes every 30 seconds, each algorithm with its own length of the same seed
+    const VECTORS: [(u64, &str, &str, &str); 6] = [
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826")
+    ];
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let seed = |len: usize| b"1234567890".iter().copied().cycle().take(len).collect::<Vec<u8>>();
+        let config = |algorithm| TotpConfig { algorithm, digits: 8, period: 30, skew: 0 };
+        for (time, sha1, sha256, sha512) in VECTORS {
+            assert_eq!(totp(&seed(20), time, &config(TotpAlgorithm::Sha1)), sha1, "SHA1 at {time}");
+            assert_eq!(totp(&seed(32), time, &config(TotpAlgorithm::Sha256)), sha256, "SHA256 at {time}");
+            assert_eq!(totp(&seed(64), time, &config(TotpAlgorithm::Sha512)), sha512, "SHA512 at {time}");
+        }
+    }
+
+    #[test]
+    fn enrolment_rejects_settings_that_cannot_make_codes() {
+        let mut user = crate::build_user("ahus@example.com".to_string(), "AHus".to_string()).unwrap();
+        let with = |digits, period| TotpConfig { digits, period, ..TotpConfig::default() };
+        assert!(matches!(user.enrol_totp("Basics", with(6, 0)), Err(TotpError::ZeroPeriod)));
+        assert!(matches!(user.enrol_totp("Basics", with(20, 30)), Err(TotpError::InvalidDigits(20))));
+        assert!(matches!(user.enrol_totp("Basics", with(5, 30)), Err(TotpError::InvalidDigits(5))));
+        let skew = |skew| TotpConfig { skew, ..TotpConfig::default() };
+        assert!(matches!(user.enrol_totp("Basics", skew(MAX_SKEW + 1)), Err(TotpError::SkewTooLarge(11))));
+        assert!(matches!(user.enrol_totp("Basics", skew(u64::MAX)), Err(TotpError::SkewTooLarge(u64::MAX))));
+        assert!(user.credentials.totp().is_none());
+        assert!(user.enrol_totp("Basics", with(MAX_DIGITS, 1)).is_ok());
+        assert!(user.enrol_totp("Basics", skew(MAX_SKEW)).is_ok());
+    }
+
+    #[test]
+    fn a_stored_skew_past_the_limit_is_refused_when_read_back() {
+        let mut user = crate::build_user("ahus@example.com".to_string(), "AHus".to_string()).unwrap();
+        user.enrol_totp("Basics", TotpConfig::default()).unwrap();
+        let mut stored = user.credentials.totp().unwrap().to_json();
+        assert!(Totp::from_json(&stored).is_ok());
+        if let Json::Object(fields) = &mut stored {
+            fields.iter_mut().filter(|(key, _)| key == "skew").for_each(|(_, value)| *value = Json::uint(u64::MAX));
+        }
+        let err = Totp::from_json(&stored).unwrap_err();
+        assert!(err.contains("too lenient"), "{err}");
+    }
+
+    #[test]
+    fn codes_are_accepted_within_the_skew_and_only_once() {
+        let secret = b"12345678901234567890".to_vec();
+        let config = TotpConfig { skew: 1, ..TotpConfig::default() };
+        let mut totp = Totp { secret: secret.clone(), config, confirmed: true, last_step: None, recovery_codes: Vec::new() };
+        let now = 1_000_000;
+        assert!(!totp.verify(&self::totp(&secret, now - 60, &config), now));
+        assert!(totp.verify(&self::totp(&secret, now - 30, &config), now));
+        assert!(!totp.verify(&self::totp(&secret, now - 30, &config), now));  // replayed
+        assert!(totp.verify(&self::totp(&secret, now + 30, &config), now));
+        assert!(!totp.verify(&self::totp(&secret, now + 60, &config), now));
+    }
+}
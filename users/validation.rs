@@ -0,0 +1,361 @@
+// checks and normalises the email and username that build_user is given
+// every rule that can fail has its own UserError variant, so a sign-up form can say exactly what is wrong
+
+use std::fmt;
+
+pub const USERNAME_MIN: usize = 3;
+pub const USERNAME_MAX: usize = 32;
+const LOCAL_PART_MAX: usize = 64;  // RFC 5321 limits
+const EMAIL_MAX: usize = 254;
+const LABEL_MAX: usize = 63;
+
+// compared case-insensitively, these would let someone impersonate the service itself
+pub const RESERVED_USERNAMES: [&str; 18] = [
+    "admin", "administrator", "root", "system", "support", "help", "api", "www", "mail", "postmaster",
+    "abuse", "security", "null", "undefined", "me", "settings", "login", "signup"
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserError {
+    EmailEmpty,
+    EmailTooLong { length: usize, max: usize },
+    EmailMissingAt,
+    EmailMultipleAt,
+    EmailLocalPartEmpty,
+    EmailLocalPartTooLong { length: usize, max: usize },
+    EmailLocalPartInvalidChar { ch: char, position: usize },  // position counts characters from the start of the email
+    EmailLocalPartDots,  // a dot at the start or end of the local part, or two in a row
+    EmailDomainEmpty,
+    EmailDomainNoDot,  // "user@localhost" is not deliverable on the internet
+    EmailDomainLabelEmpty,
+    EmailDomainLabelTooLong { label: String, max: usize },
+    EmailDomainLabelHyphen { label: String },  // labels cannot start or end with '-'
+    EmailDomainInvalidChar { ch: char, position: usize },
+    EmailTopLevelNumeric,
+    UsernameTooShort { length: usize, min: usize },
+    UsernameTooLong { length: usize, max: usize },
+    UsernameInvalidChar { ch: char, position: usize },
+    UsernameMustStartWithLetter,
+    UsernameEndsWithSeparator,
+    UsernameReserved(String)
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserError::EmailEmpty => write!(f, "email address is required"),
+            UserError::EmailTooLong { length, max } => write!(f, "email address is {length} characters, the maximum is {max}"),
+            UserError::EmailMissingAt => write!(f, "email address must contain '@'"),
+            UserError::EmailMultipleAt => write!(f, "email address must contain only one '@'"),
+            UserError::EmailLocalPartEmpty => write!(f, "email address needs something before the '@'"),
+            UserError::EmailLocalPartTooLong { length, max } => write!(f, "the part before '@' is {length} characters, the maximum is {max}"),
+            UserError::EmailLocalPartInvalidChar { ch, position } => write!(f, "'{ch}' at position {position} is not allowed before the '@'"),
+            UserError::EmailLocalPartDots => write!(f, "the part before '@' cannot start or end with '.' or contain '..'"),
+            UserError::EmailDomainEmpty => write!(f, "email address needs a domain after the '@'"),
+            UserError::EmailDomainNoDot => write!(f, "email domain must contain a '.'"),
+            UserError::EmailDomainLabelEmpty => write!(f, "email domain cannot start or end with '.' or contain '..'"),
+            UserError::EmailDomainLabelTooLong { label, max } => write!(f, "domain label '{label}' is longer than {max} characters"),
+            UserError::EmailDomainLabelHyphen { label } => write!(f, "domain label '{label}' cannot start or end with '-'"),
+            UserError::EmailDomainInvalidChar { ch, position } => write!(f, "'{ch}' at position {position} is not allowed in a domain"),
+            UserError::EmailTopLevelNumeric => write!(f, "the last part of the email domain cannot be all digits"),
+            UserError::UsernameTooShort { length, min } => write!(f, "username is {length} characters, the minimum is {min}"),
+            UserError::UsernameTooLong { length, max } => write!(f, "username is {length} characters, the maximum is {max}"),
+            UserError::UsernameInvalidChar { ch, position } => write!(f, "'{ch}' at position {position} is not allowed in a username"),
+            UserError::UsernameMustStartWithLetter => write!(f, "username must start with a letter"),
+            UserError::UsernameEndsWithSeparator => write!(f, "username cannot end with '.', '-' or '_'"),
+            UserError::UsernameReserved(name) => write!(f, "username '{name}' is reserved")
+        }
+    }
+}
+
+impl std::error::Error for UserError {}
+
+// returns the email trimmed and lowercased, with any international domain converted to its ASCII (punycode) form
+// the local part is limited to the RFC 5322 dot-atom form, quoted local parts and comments are rejected
+pub fn validate_email(email: &str) -> Result<String, UserError> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Err(UserError::EmailEmpty);
+    }
+    let (local, domain) = match email.split_once('@') {
+        None => return Err(UserError::EmailMissingAt),
+        Some((_, domain)) if domain.contains('@') => return Err(UserError::EmailMultipleAt),
+        Some(parts) => parts
+    };
+
+    if local.is_empty() {
+        return Err(UserError::EmailLocalPartEmpty);
+    }
+    for (position, ch) in local.chars().enumerate() {
+        if !is_atext(ch) && ch != '.' {
+            return Err(UserError::EmailLocalPartInvalidChar { ch, position });
+        }
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return Err(UserError::EmailLocalPartDots);
+    }
+    if local.len() > LOCAL_PART_MAX {
+        return Err(UserError::EmailLocalPartTooLong { length: local.len(), max: LOCAL_PART_MAX });
+    }
+
+    let domain = normalise_domain(domain, local.chars().count() + 1)?;
+    let normalised = format!("{}@{}", local.to_ascii_lowercase(), domain);
+    if normalised.len() > EMAIL_MAX {
+        return Err(UserError::EmailTooLong { length: normalised.len(), max: EMAIL_MAX });
+    }
+    Ok(normalised)
+}
+
+// offset is where the domain starts within the email, so character positions in errors match what the user typed
+fn normalise_domain(domain: &str, offset: usize) -> Result<String, UserError> {
+    if domain.is_empty() {
+        return Err(UserError::EmailDomainEmpty);
+    }
+    let mut labels = Vec::new();
+    let mut position = offset;
+    for label in domain.split('.') {
+        if label.is_empty() {
+            return Err(UserError::EmailDomainLabelEmpty);
+        }
+        let lower = label.to_lowercase();
+        for (i, ch) in lower.chars().enumerate() {
+            let allowed = ch.is_ascii_alphanumeric() || ch == '-' || (!ch.is_ascii() && ch.is_alphanumeric());
+            if !allowed {
+                return Err(UserError::EmailDomainInvalidChar { ch, position: position + i });
+            }
+        }
+        // punycode never comes out shorter than what went in, so a label too long already is refused before
+        // the encoding, whose work grows with the square of the label's length
+        let too_long = || UserError::EmailDomainLabelTooLong { label: label.to_string(), max: LABEL_MAX };
+        if lower.chars().count() > LABEL_MAX {
+            return Err(too_long());
+        }
+        // an internationalised label is stored in its ASCII compatible form, e.g. münchen -> xn--mnchen-3ya
+        let ascii = if lower.is_ascii() { lower } else { format!("xn--{}", punycode_encode(&lower).map_err(|_| too_long())?) };
+        if ascii.len() > LABEL_MAX {
+            return Err(too_long());
+        }
+        if ascii.starts_with('-') || ascii.ends_with('-') {
+            return Err(UserError::EmailDomainLabelHyphen { label: label.to_string() });
+        }
+        position += label.chars().count() + 1;
+        labels.push(ascii);
+    }
+    if labels.len() < 2 {
+        return Err(UserError::EmailDomainNoDot);
+    }
+    if labels.last().is_some_and(|tld| tld.chars().all(|c| c.is_ascii_digit())) {
+        return Err(UserError::EmailTopLevelNumeric);
+    }
+    Ok(labels.join("."))
+}
+
+// the characters RFC 5322 allows in an unquoted local part
+fn is_atext(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(ch)
+}
+
+// returns the username with surrounding whitespace removed, the case the user chose is kept for display
+pub fn validate_username(username: &str) -> Result<String, UserError> {
+    let username = username.trim();
+    let length = username.chars().count();
+    if length < USERNAME_MIN {
+        return Err(UserError::UsernameTooShort { length, min: USERNAME_MIN });
+    }
+    if length > USERNAME_MAX {
+        return Err(UserError::UsernameTooLong { length, max: USERNAME_MAX });
+    }
+    for (position, ch) in username.chars().enumerate() {
+        if !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '-' || ch == '.') {
+            return Err(UserError::UsernameInvalidChar { ch, position });
+        }
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(UserError::UsernameMustStartWithLetter);
+    }
+    if username.ends_with(['_', '-', '.']) {
+        return Err(UserError::UsernameEndsWithSeparator);
+    }
+    if RESERVED_USERNAMES.iter().any(|r| r.eq_ignore_ascii_case(username)) {
+        return Err(UserError::UsernameReserved(username.to_string()));
+    }
+    Ok(username.to_string())
+}
+
+// the label has more, or higher, code points than a u32 delta can count, RFC 3492 section 6.4
+#[derive(Debug, Clone, PartialEq)]
+pub struct PunycodeOverflow;
+
+impl fmt::Display for PunycodeOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the label is too long to encode as punycode")
+    }
+}
+
+impl std::error::Error for PunycodeOverflow {}
+
+// RFC 3492 punycode, the encoding IDNA uses to carry unicode domain labels in ASCII
+// the input is a single label with no "xn--" prefix
+pub fn punycode_encode(label: &str) -> Result<String, PunycodeOverflow> {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+
+    let input: Vec<u32> = label.chars().map(|c| c as u32).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let digit = |d: u32| -> char {
+        if d < 26 { (b'a' + d as u8) as char } else { (b'0' + (d - 26) as u8) as char }
+    };
+
+    let mut n: u32 = 128;
+    let mut delta: u32 = 0;
+    let mut bias: u32 = 72;
+    while (handled as usize) < input.len() {
+        // the smallest code point not yet handled, there is always one because handled < len
+        let m = input.iter().copied().filter(|&c| c >= n).min().unwrap_or(n);
+        delta = (m - n).checked_mul(handled + 1).and_then(|d| d.checked_add(delta)).ok_or(PunycodeOverflow)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1).ok_or(PunycodeOverflow)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias { T_MIN } else if k >= bias + T_MAX { T_MAX } else { k - bias };
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = punycode_adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1).ok_or(PunycodeOverflow)?;
+        n += 1;
+    }
+    Ok(output)
+}
+
+fn punycode_adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / 700 } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((36 - 1) * 26) / 2 {
+        delta /= 36 - 1;
+        k += 36;
+    }
+    k + (36 * delta) / (delta + 38)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails_are_trimmed_lowercased_and_their_domains_encoded() {
+        assert_eq!(validate_email("  Ann.Lee+News@Example.COM ").unwrap(), "ann.lee+news@example.com");
+        assert_eq!(validate_email("ann@München.de").unwrap(), "ann@xn--mnchen-3ya.de");
+    }
+
+    #[test]
+    fn every_email_rule_has_its_own_error() {
+        let long_local = "a".repeat(65);
+        let long_label = format!("ann@{}.com", "a".repeat(64));
+        let long_email = format!("ann@{}.com", vec!["a".repeat(60); 5].join("."));
+        let cases = [
+            ("   ", UserError::EmailEmpty),
+            ("ann.example.com", UserError::EmailMissingAt),
+            ("ann@b@example.com", UserError::EmailMultipleAt),
+            ("@example.com", UserError::EmailLocalPartEmpty),
+            (&format!("{long_local}@example.com"), UserError::EmailLocalPartTooLong { length: 65, max: 64 }),
+            ("an n@example.com", UserError::EmailLocalPartInvalidChar { ch: ' ', position: 2 }),
+            ("ann..lee@example.com", UserError::EmailLocalPartDots),
+            (".ann@example.com", UserError::EmailLocalPartDots),
+            ("ann@", UserError::EmailDomainEmpty),
+            ("ann@localhost", UserError::EmailDomainNoDot),
+            ("ann@example..com", UserError::EmailDomainLabelEmpty),
+            (&long_label, UserError::EmailDomainLabelTooLong { label: "a".repeat(64), max: 63 }),
+            ("ann@-example.com", UserError::EmailDomainLabelHyphen { label: "-example".to_string() }),
+            ("ann@exa_mple.com", UserError::EmailDomainInvalidChar { ch: '_', position: 7 }),
+            ("ann@example.123", UserError::EmailTopLevelNumeric),
+            (&long_email, UserError::EmailTooLong { length: 312, max: 254 })
+        ];
+        for (email, expected) in cases {
+            assert_eq!(validate_email(email), Err(expected), "{email}");
+        }
+    }
+
+    #[test]
+    fn a_huge_international_label_is_refused_before_it_is_encoded() {
+        // this used to overflow the punycode delta and panic
+        let email = format!("a@{}\u{20000}.com", "a".repeat(40_000));
+        assert!(matches!(validate_email(&email), Err(UserError::EmailDomainLabelTooLong { max: 63, .. })));
+        let sixty_four = format!("a@{}.com", "ü".repeat(64));
+        assert!(matches!(validate_email(&sixty_four), Err(UserError::EmailDomainLabelTooLong { .. })));
+        // short enough in characters but not once encoded
+        let spread: String = (0..60).map(|i| char::from_u32(0x4e00 + i * 331).unwrap()).collect();
+        let encoded_too_long = format!("a@{spread}.com");
+        assert!(matches!(validate_email(&encoded_too_long), Err(UserError::EmailDomainLabelTooLong { .. })));
+    }
+
+    #[test]
+    fn punycode_matches_the_rfc_3492_samples() {
+        let samples = [
+            ("\u{644}\u{64a}\u{647}\u{645}\u{627}\u{628}\u{62a}\u{643}\u{644}\u{645}\u{648}\u{634}\u{639}\u{631}\u{628}\u{64a}\u{61f}", "egbpdaj6bu4bxfgehfvwxn"),
+            ("\u{4ed6}\u{4eec}\u{4e3a}\u{4ec0}\u{4e48}\u{4e0d}\u{8bf4}\u{4e2d}\u{6587}", "ihqwcrb4cv8a8dqg056pqjye"),
+            ("\u{4ed6}\u{5011}\u{7232}\u{4ec0}\u{9ebd}\u{4e0d}\u{8aaa}\u{4e2d}\u{6587}", "ihqwctvzc91f659drss3x8bo0yb"),
+            (
+                "\u{43f}\u{43e}\u{447}\u{435}\u{43c}\u{443}\u{436}\u{435}\u{43e}\u{43d}\u{438}\u{43d}\u{435}\u{433}\u{43e}\u{432}\u{43e}\u{440}\u{44f}\u{442}\u{43f}\u{43e}\u{440}\u{443}\u{441}\u{441}\u{43a}\u{438}",
+                "b1abfaaepdrnnbgefbadotcwatmq2g4l"
+            ),
+            ("3\u{5e74}B\u{7d44}\u{91d1}\u{516b}\u{5148}\u{751f}", "3B-ww4c5e180e575a65lsy2b"),
+            ("\u{5b89}\u{5ba4}\u{5948}\u{7f8e}\u{6075}-with-SUPER-MONKEYS", "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n"),
+            ("-> $1.00 <-", "-> $1.00 <--"),
+            ("m\u{fc}nchen", "mnchen-3ya")
+        ];
+        for (label, expected) in samples {
+            assert_eq!(punycode_encode(label).unwrap(), expected, "{label}");
+        }
+    }
+
+    #[test]
+    fn every_username_rule_has_its_own_error() {
+        assert_eq!(validate_username("  A.Hus ").unwrap(), "A.Hus");
+        let long = "a".repeat(33);
+        let cases = [
+            ("ab", UserError::UsernameTooShort { length: 2, min: 3 }),
+            (long.as_str(), UserError::UsernameTooLong { length: 33, max: 32 }),
+            ("ann lee", UserError::UsernameInvalidChar { ch: ' ', position: 3 }),
+            ("\u{e5}sa", UserError::UsernameInvalidChar { ch: '\u{e5}', position: 0 }),
+            ("1ann", UserError::UsernameMustStartWithLetter),
+            ("_ann", UserError::UsernameMustStartWithLetter),
+            ("ann_", UserError::UsernameEndsWithSeparator)
+        ];
+        for (username, expected) in cases {
+            assert_eq!(validate_username(username), Err(expected), "{username}");
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_refused_in_any_case() {
+        for name in RESERVED_USERNAMES.iter().filter(|name| name.len() >= USERNAME_MIN) {
+            assert_eq!(validate_username(name), Err(UserError::UsernameReserved(name.to_string())));
+            let shouted = name.to_ascii_uppercase();
+            assert_eq!(validate_username(&shouted), Err(UserError::UsernameReserved(shouted.clone())));
+        }
+        assert!(validate_username("admins").is_ok());
+    }
+}
@@ -0,0 +1,346 @@
+// email verification for new accounts: build_user makes accounts PendingVerification and a signed token activates them
+//
+// a token is base64url(payload) "." base64url(HMAC-SHA256(key, payload)), the payload being
+//     username \n email \n issued_at \n expires_at \n nonce
+// nothing is stored per token, the signature proves the server issued it and the expiry is inside it
+// the verifier only remembers revoked nonces and when each user was last mailed, for throttling resends
+// tokens are single use, and a token for an old email address stops working once the email is changed
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::users::clock::Clock;
+use crate::users::crypto::{base64url_decode, base64url_encode, constant_time_eq, hmac_sha256, random_bytes, to_hex};
+use crate::users::lifecycle::{AccountState, LifecycleError};
+use crate::users::mail::{Email, Mailer};
+use crate::users::store::{StoreError, UserStore};
+use crate::User;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const PURPOSE: &[u8] = b"email-verification\n";  // signed with the payload, so a token made for something else with the same key is rejected
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResendPolicy {
+    pub min_interval: u64,  // seconds between two emails to the same user
+    pub max_sends: usize,  // emails allowed to one user inside the window
+    pub window: u64  // seconds
+}
+
+impl Default for ResendPolicy {
+    fn default() -> Self {
+        ResendPolicy { min_interval: 60, max_sends: 5, window: 24 * 60 * 60 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationToken {
+    pub username: String,
+    pub email: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    nonce: String
+}
+
+#[derive(Debug)]
+pub enum VerificationError {
+    Invalid,  // not a token this server signed, or damaged on the way
+    Expired { at: u64 },
+    Revoked,
+    UnknownUser(String),
+    EmailChanged,  // the token was sent to an address the account no longer has
+    NotPending(AccountState),  // already verified, or the account has moved on to another state
+    Throttled { retry_at: u64 },
+    Lifecycle(LifecycleError),
+    Mail(io::Error),
+    Store(StoreError)
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerificationError::Invalid => write!(f, "the verification link is not valid"),
+            VerificationError::Expired { at } => write!(f, "the verification link expired at {at}"),
+            VerificationError::Revoked => write!(f, "the verification link has been used or withdrawn"),
+            VerificationError::UnknownUser(name) => write!(f, "no user named '{name}'"),
+            VerificationError::EmailChanged => write!(f, "the verification link was sent to an earlier email address"),
+            VerificationError::NotPending(state) => write!(f, "the account is {state}, not waiting for verification"),
+            VerificationError::Throttled { retry_at } => write!(f, "a verification email was sent recently, try again at {retry_at}"),
+            VerificationError::Lifecycle(err) => write!(f, "{err}"),
+            VerificationError::Mail(err) => write!(f, "could not send the verification email: {err}"),
+            VerificationError::Store(err) => write!(f, "{err}")
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+impl From<StoreError> for VerificationError {
+    fn from(err: StoreError) -> Self {
+        VerificationError::Store(err)
+    }
+}
+
+impl From<LifecycleError> for VerificationError {
+    fn from(err: LifecycleError) -> Self {
+        VerificationError::Lifecycle(err)
+    }
+}
+
+impl From<io::Error> for VerificationError {
+    fn from(err: io::Error) -> Self {
+        VerificationError::Mail(err)
+    }
+}
+
+pub struct EmailVerifier {
+    key: Vec<u8>,
+    pub ttl: u64,  // seconds a token stays valid
+    pub resend: ResendPolicy,
+    pub link_prefix: Option<String>,  // e.g. "https://example.com/verify?token=", the email then holds a link instead of a bare token
+    revoked: HashMap<String, u64>,  // nonce -> expiry, kept only until the token would have expired anyway
+    revoked_before: HashMap<String, u64>,  // username -> tokens issued at or before this time are revoked
+    sends: HashMap<String, Vec<u64>>  // username -> times a verification email was sent, inside the resend window
+}
+
+impl EmailVerifier {
+    // the key has to stay the same across restarts, or every token already mailed stops working
+    pub fn new(key: &[u8]) -> Self {
+        EmailVerifier {
+            key: key.to_vec(),
+            ttl: 48 * 60 * 60,
+            resend: ResendPolicy::default(),
+            link_prefix: None,
+            revoked: HashMap::new(),
+            revoked_before: HashMap::new(),
+            sends: HashMap::new()
+        }
+    }
+
+    // a verifier with a fresh random key, for tests and servers that do not keep tokens across restarts
+    pub fn generate() -> Self {
+        EmailVerifier::new(&random_bytes(KEY_LEN))
+    }
+
+    pub fn issue(&self, user: &User, now: u64) -> String {
+        let payload = format!("{}\n{}\n{}\n{}\n{}", user.username, user.email, now, now + self.ttl, to_hex(&random_bytes(NONCE_LEN)));
+        format!("{}.{}", base64url_encode(payload.as_bytes()), base64url_encode(&self.sign(payload.as_bytes())))
+    }
+
+    fn sign(&self, payload: &[u8]) -> [u8; 32] {
+        hmac_sha256(&self.key, &[PURPOSE, payload].concat())
+    }
+
+    // checks the signature and reads the token, without looking at expiry or revocation
+    pub fn decode(&self, token: &str) -> Result<VerificationToken, VerificationError> {
+        let (payload, mac) = token.trim().split_once('.').ok_or(VerificationError::Invalid)?;
+        let payload = base64url_decode(payload).ok_or(VerificationError::Invalid)?;
+        let mac = base64url_decode(mac).ok_or(VerificationError::Invalid)?;
+        if !constant_time_eq(&mac, &self.sign(&payload)) {
+            return Err(VerificationError::Invalid);
+        }
+        // the signature matched, so anything malformed past this point means the key was shared with other code
+        let payload = String::from_utf8(payload).map_err(|_| VerificationError::Invalid)?;
+        let fields: Vec<&str> = payload.split('\n').collect();
+        let [username, email, issued_at, expires_at, nonce] = fields.as_slice() else {
+            return Err(VerificationError::Invalid);
+        };
+        Ok(VerificationToken {
+            username: username.to_string(),
+            email: email.to_string(),
+            issued_at: issued_at.parse().map_err(|_| VerificationError::Invalid)?,
+            expires_at: expires_at.parse().map_err(|_| VerificationError::Invalid)?,
+            nonce: nonce.to_string()
+        })
+    }
+
+    // decode plus the checks that depend on time and on what has been revoked
+    pub fn check(&self, token: &str, now: u64) -> Result<VerificationToken, VerificationError> {
+        let token = self.decode(token)?;
+        if now >= token.expires_at {
+            return Err(VerificationError::Expired { at: token.expires_at });
+        }
+        let revoked_before = self.revoked_before.get(&token.username).is_some_and(|t| token.issued_at <= *t);
+        if revoked_before || self.revoked.contains_key(&token.nonce) {
+            return Err(VerificationError::Revoked);
+        }
+        Ok(token)
+    }
+
+    // activates the account the token was issued for and uses the token up, returns the username
+    pub fn verify(&mut self, store: &mut dyn UserStore, token: &str, clock: &dyn Clock) -> Result<String, VerificationError> {
+        let token = self.check(token, clock.now())?;
+        let mut user = store.get(&token.username).ok_or_else(|| VerificationError::UnknownUser(token.username.clone()))?.clone();
+        if user.email != token.email {
+            return Err(VerificationError::EmailChanged);
+        }
+        if *user.state() != AccountState::PendingVerification {
+            return Err(VerificationError::NotPending(user.state().clone()));
+        }
+        user.activate(clock)?;
+        store.update(user)?;
+        self.sends.remove(&token.username);
+        self.revoked.insert(token.nonce, token.expires_at);
+        Ok(token.username)
+    }
+
+    // withdraws one token, an expired one can still be revoked but there is no need to
+    pub fn revoke(&mut self, token: &str) -> Result<(), VerificationError> {
+        let token = self.decode(token)?;
+        self.revoked.insert(token.nonce, token.expires_at);
+        Ok(())
+    }
+
+    // withdraws every token issued to the user so far, including any issued earlier in the same second
+    pub fn revoke_all(&mut self, username: &str, clock: &dyn Clock) {
+        self.revoked_before.insert(username.to_string(), clock.now());
+    }
+
+    // issues a token and mails it, as long as the user has not been mailed too often recently
+    pub fn send(&mut self, user: &User, mailer: &mut dyn Mailer, clock: &dyn Clock) -> Result<String, VerificationError> {
+        let now = clock.now();
+        if *user.state() != AccountState::PendingVerification {
+            return Err(VerificationError::NotPending(user.state().clone()));
+        }
+        if let Some(retry_at) = self.throttled_until(&user.username, now) {
+            return Err(VerificationError::Throttled { retry_at });
+        }
+        let token = self.issue(user, now);
+        mailer.send(&self.email(user, &token))?;
+        self.sends.entry(user.username.clone()).or_default().push(now);
+        Ok(token)
+    }
+
+    pub fn resend(&mut self, store: &dyn UserStore, mailer: &mut dyn Mailer, username: &str, clock: &dyn Clock) -> Result<String, VerificationError> {
+        let user = store.get(username).ok_or_else(|| VerificationError::UnknownUser(username.to_string()))?;
+        self.send(user, mailer, clock)
+    }
+
+    // builds the user, saves it and mails the first token, which is also returned
+    // if the email cannot be sent the account is still saved and resend can be tried later
+    pub fn register(&mut self, store: &mut dyn UserStore, mailer: &mut dyn Mailer, email: String, username: String, clock: &dyn Clock) -> Result<String, VerificationError> {
+        let user = crate::build_user(email, username).map_err(StoreError::from)?;
+        let username = user.username.clone();
+        store.insert(user)?;
+        self.resend(store, mailer, &username, clock)
+    }
+
+    // None if an email can go out now, otherwise the earliest time one can
+    pub fn throttled_until(&self, username: &str, now: u64) -> Option<u64> {
+        let sends: Vec<u64> = self.sends.get(username)?.iter().copied().filter(|t| now < t + self.resend.window).collect();
+        let last = *sends.last()?;
+        let mut retry_at = last + self.resend.min_interval;
+        if sends.len() >= self.resend.max_sends {
+            // the window has to move past enough old sends to get back under the limit
+            retry_at = retry_at.max(sends[sends.len() - self.resend.max_sends] + self.resend.window);
+        }
+        (now < retry_at).then_some(retry_at)
+    }
+
+    // forgets revoked tokens that have expired and sends that are outside the window, call it now and then
+    pub fn prune(&mut self, clock: &dyn Clock) {
+        let now = clock.now();
+        self.revoked.retain(|_, expires_at| now < *expires_at);
+        self.revoked_before.retain(|_, t| now < *t + self.ttl);
+        let window = self.resend.window;
+        self.sends.retain(|_, sends| {
+            sends.retain(|t| now < t + window);
+            !sends.is_empty()
+        });
+    }
+
+    fn email(&self, user: &User, token: &str) -> Email {
+        let action = match &self.link_prefix {
+            Some(prefix) => format!("Open this link to confirm your email address:\n\n{prefix}{token}"),
+            None => format!("Enter this code to confirm your email address:\n\n{token}")
+        };
+        Email {
+            to: user.email.clone(),
+            subject: String::from("Confirm your email address"),
+            body: format!("Hello {},\n\n{action}\n\nIt stops working in {} hours. If you did not sign up, ignore this email.", user.username, self.ttl / 3600)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::clock::ManualClock;
+    use crate::users::mail::MemoryMailer;
+    use crate::users::store::MemoryUserStore;
+
+    // the token is the line of the email after "Enter this code ..."
+    fn mailed_token(mailer: &MemoryMailer, to: &str) -> String {
+        mailer.last_to(to).unwrap().body.lines().nth(4).unwrap().to_string()
+    }
+
+    fn registered(clock: &ManualClock) -> (EmailVerifier, MemoryUserStore, MemoryMailer) {
+        let (mut verifier, mut store, mut mailer) = (EmailVerifier::new(b"test key"), MemoryUserStore::new(), MemoryMailer::new());
+        verifier.register(&mut store, &mut mailer, "ann@example.com".to_string(), "ann".to_string(), clock).unwrap();
+        (verifier, store, mailer)
+    }
+
+    #[test]
+    fn the_mailed_token_activates_the_account_once() {
+        let clock = ManualClock::new(1_700_000_000);
+        let (mut verifier, mut store, mailer) = registered(&clock);
+        assert_eq!(mailer.sent().len(), 1);
+        let token = mailed_token(&mailer, "ann@example.com");
+        assert_eq!(verifier.verify(&mut store, &token, &clock).unwrap(), "ann");
+        assert_eq!(*store.get("ann").unwrap().state(), AccountState::Active);
+        assert!(matches!(verifier.verify(&mut store, &token, &clock), Err(VerificationError::Revoked)));
+    }
+
+    #[test]
+    fn tokens_stop_working_when_they_expire() {
+        let clock = ManualClock::new(1_700_000_000);
+        let (mut verifier, mut store, mailer) = registered(&clock);
+        let token = mailed_token(&mailer, "ann@example.com");
+        clock.advance(verifier.ttl);
+        assert!(matches!(verifier.verify(&mut store, &token, &clock), Err(VerificationError::Expired { at }) if at == clock.now()));
+        assert_eq!(*store.get("ann").unwrap().state(), AccountState::PendingVerification);
+    }
+
+    #[test]
+    fn revoked_and_tampered_tokens_are_refused() {
+        let clock = ManualClock::new(1_700_000_000);
+        let (mut verifier, mut store, mailer) = registered(&clock);
+        let first = mailed_token(&mailer, "ann@example.com");
+        verifier.revoke(&first).unwrap();
+        assert!(matches!(verifier.verify(&mut store, &first, &clock), Err(VerificationError::Revoked)));
+
+        clock.advance(verifier.resend.min_interval);
+        let second = verifier.resend(&store, &mut mailer.clone(), "ann", &clock).unwrap();
+        verifier.revoke_all("ann", &clock);
+        assert!(matches!(verifier.verify(&mut store, &second, &clock), Err(VerificationError::Revoked)));
+
+        let mut tampered = second.clone();
+        tampered.insert(0, 'x');
+        assert!(matches!(verifier.verify(&mut store, &tampered, &clock), Err(VerificationError::Invalid)));
+        let other_key = EmailVerifier::new(b"another key").issue(store.get("ann").unwrap(), clock.now());
+        assert!(matches!(verifier.verify(&mut store, &other_key, &clock), Err(VerificationError::Invalid)));
+    }
+
+    #[test]
+    fn resending_is_throttled() {
+        let clock = ManualClock::new(1_700_000_000);
+        let (mut verifier, store, mailer) = registered(&clock);
+        let start = clock.now();
+        let policy = verifier.resend;
+        let mut outbox = mailer.clone();
+        // too soon after the first email
+        assert!(matches!(verifier.resend(&store, &mut outbox, "ann", &clock), Err(VerificationError::Throttled { retry_at }) if retry_at == start + policy.min_interval));
+        for _ in 1..policy.max_sends {
+            clock.advance(policy.min_interval);
+            verifier.resend(&store, &mut outbox, "ann", &clock).unwrap();
+        }
+        assert_eq!(mailer.sent().len(), policy.max_sends);
+        // the limit for the window is used up, until the first send drops out of it
+        clock.advance(policy.min_interval);
+        assert!(matches!(verifier.resend(&store, &mut outbox, "ann", &clock), Err(VerificationError::Throttled { retry_at }) if retry_at == start + policy.window));
+        clock.set(start + policy.window);
+        verifier.resend(&store, &mut outbox, "ann", &clock).unwrap();
+        assert_eq!(mailer.sent().len(), policy.max_sends + 1);
+    }
+}